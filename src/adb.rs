@@ -0,0 +1,302 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::capture::CaptureBackend;
+use crate::input::InputBackend;
+use opencv::core::Mat;
+use opencv::core::Size;
+use opencv::core::Vector;
+use opencv::imgcodecs::IMREAD_COLOR;
+use opencv::imgcodecs::imdecode;
+use opencv::imgproc::INTER_AREA;
+use opencv::imgproc::resize;
+use opencv::prelude::MatTraitConst;
+use std::process::Command;
+
+// Перевод координат между экраном устройства и пространством детекции
+// (кадр, приведённый к reference_width x reference_height, под который резались шаблоны)
+#[derive(Debug, Clone, Copy)]
+pub struct ScreenMapping {
+    pub device: (i32, i32),
+    pub detection: (i32, i32),
+}
+
+impl ScreenMapping {
+    pub fn to_device(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            (x as f64 * self.device.0 as f64 / self.detection.0 as f64).round() as i32,
+            (y as f64 * self.device.1 as f64 / self.detection.1 as f64).round() as i32,
+        )
+    }
+
+    pub fn to_detection(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            (x as f64 * self.detection.0 as f64 / self.device.0 as f64).round() as i32,
+            (y as f64 * self.detection.1 as f64 / self.device.1 as f64).round() as i32,
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdbDevice {
+    serial: Option<String>,
+}
+
+impl AdbDevice {
+    pub fn new(serial: Option<String>) -> Self {
+        Self { serial }
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new("adb");
+        if let Some(serial) = &self.serial {
+            command.args(["-s", serial]);
+        }
+        command
+    }
+
+    fn shell(&self, script: &str) -> AppResult<String> {
+        let output = self.command().args(["shell", script]).output()?;
+        if !output.status.success() {
+            return Err(AppError::Input(format!(
+                "adb shell failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    // "Override size" важнее "Physical size": его и использует screencap
+    pub fn screen_size(&self) -> AppResult<(i32, i32)> {
+        let output = self.shell("wm size")?;
+        let parse_size = |prefix: &str| {
+            output
+                .lines()
+                .find(|l| l.trim().starts_with(prefix))
+                .and_then(|l| l.split(':').nth(1))
+                .and_then(|v| v.trim().split_once('x'))
+                .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        };
+
+        parse_size("Override size")
+            .or_else(|| parse_size("Physical size"))
+            .ok_or_else(|| AppError::WindowNotFound("Could not parse adb wm size".to_string()))
+    }
+
+    pub fn screencap(&self) -> AppResult<Mat> {
+        let output = self
+            .command()
+            .args(["exec-out", "screencap", "-p"])
+            .output()?;
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(AppError::ScrotFailed("adb screencap failed".to_string()));
+        }
+
+        let image = imdecode(&Vector::<u8>::from_slice(&output.stdout), IMREAD_COLOR)?;
+        if image.empty() {
+            return Err(AppError::ImageProcessing(
+                "Captured image is empty".to_string(),
+            ));
+        }
+        Ok(image)
+    }
+
+    pub fn mapping(&self, detection: (i32, i32)) -> AppResult<ScreenMapping> {
+        Ok(ScreenMapping {
+            device: self.screen_size()?,
+            detection,
+        })
+    }
+}
+
+pub struct AdbCaptureBackend {
+    device: AdbDevice,
+    mapping: ScreenMapping,
+}
+
+impl AdbCaptureBackend {
+    pub fn new(device: AdbDevice, mapping: ScreenMapping) -> Self {
+        Self { device, mapping }
+    }
+}
+
+impl CaptureBackend for AdbCaptureBackend {
+    // Кадр устройства сразу приводится к пространству детекции, окно начинается в (0, 0)
+    fn capture(&mut self) -> AppResult<(Mat, i32, i32)> {
+        let screen = self.device.screencap()?;
+        let mut image = Mat::default();
+        resize(
+            &screen,
+            &mut image,
+            Size::new(self.mapping.detection.0, self.mapping.detection.1),
+            0.0,
+            0.0,
+            INTER_AREA,
+        )?;
+        Ok((image, 0, 0))
+    }
+
+    fn window_size(&mut self) -> AppResult<(i32, i32)> {
+        Ok(self.mapping.detection)
+    }
+}
+
+// Курсора у телефона нет: позиция хранится в памяти, а перемещение с зажатой
+// кнопкой копится и отправляется одним вызовом при mouse_up
+pub struct AdbInputBackend {
+    device: AdbDevice,
+    mapping: ScreenMapping,
+    position: (i32, i32),
+    held_path: Option<Vec<(i32, i32)>>,
+}
+
+impl AdbInputBackend {
+    pub fn new(device: AdbDevice, mapping: ScreenMapping) -> Self {
+        Self {
+            device,
+            mapping,
+            position: (0, 0),
+            held_path: None,
+        }
+    }
+
+    fn track(&mut self, x: i32, y: i32) {
+        self.position = (x, y);
+        if let Some(path) = self.held_path.as_mut() {
+            path.push((x, y));
+        }
+    }
+}
+
+impl InputBackend for AdbInputBackend {
+    fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+        self.track(x, y);
+        Ok(())
+    }
+
+    fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+        self.track(self.position.0 + dx, self.position.1 + dy);
+        Ok(())
+    }
+
+    fn mouse_down(&mut self) -> AppResult<()> {
+        self.held_path = Some(vec![self.position]);
+        Ok(())
+    }
+
+    fn mouse_up(&mut self) -> AppResult<()> {
+        let Some(mut path) = self.held_path.take() else {
+            return Ok(());
+        };
+        path.dedup();
+
+        let device_path: Vec<(i32, i32)> = path
+            .iter()
+            .map(|&(x, y)| self.mapping.to_device(x, y))
+            .collect();
+
+        let script = match device_path.as_slice() {
+            [] => return Ok(()),
+            [(x, y)] => format!("input tap {} {}", x, y),
+            // Прямое перетаскивание: один swipe быстрее и надёжнее X11-перетаскивания
+            [(x1, y1), (x2, y2)] => format!("input swipe {} {} {} {} 150", x1, y1, x2, y2),
+            // Путь с промежуточными точками (human-like, облако) - одним вызовом adb shell
+            [(x, y), rest @ ..] => {
+                let mut script = format!("input motionevent DOWN {} {}", x, y);
+                for (x, y) in rest {
+                    script.push_str(&format!("; input motionevent MOVE {} {}", x, y));
+                }
+                let (x, y) = rest[rest.len() - 1];
+                script.push_str(&format!("; input motionevent UP {} {}", x, y));
+                script
+            }
+        };
+
+        // При сбое путь возвращается на место, чтобы повтор отправил тот же жест
+        if let Err(e) = self.device.shell(&script) {
+            self.held_path = Some(path);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn get_position(&mut self) -> AppResult<(i32, i32)> {
+        Ok(self.position)
+    }
+
+    // Задержки между точками не нужны: путь всё равно уходит на устройство целиком
+    fn move_along(&mut self, path: &[(i32, i32, u64)]) -> AppResult<()> {
+        for &(x, y, _) in path {
+            self.track(x, y);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_points_map_back_to_the_same_detection_points() {
+        // Телефон 1080x2400, детекция в эталонном окне 433x957
+        let mapping = ScreenMapping {
+            device: (1080, 2400),
+            detection: (433, 957),
+        };
+        assert_eq!(mapping.to_device(0, 0), (0, 0));
+        assert_eq!(mapping.to_device(433, 957), (1080, 2400));
+        assert_eq!(mapping.to_device(216, 478), (539, 1199));
+
+        for &(x, y) in &[
+            (0, 0),
+            (1, 1),
+            (17, 940),
+            (216, 478),
+            (432, 956),
+            (433, 957),
+        ] {
+            let (device_x, device_y) = mapping.to_device(x, y);
+            assert_eq!(mapping.to_detection(device_x, device_y), (x, y));
+        }
+    }
+
+    #[test]
+    fn smaller_device_round_trips_from_its_own_points() {
+        let mapping = ScreenMapping {
+            device: (320, 480),
+            detection: (433, 957),
+        };
+        assert_eq!(mapping.to_detection(160, 240), (217, 479));
+
+        for &(x, y) in &[(0, 0), (3, 5), (160, 240), (319, 479), (320, 480)] {
+            let (detection_x, detection_y) = mapping.to_detection(x, y);
+            assert_eq!(mapping.to_device(detection_x, detection_y), (x, y));
+        }
+    }
+
+    #[test]
+    fn same_size_mapping_is_the_identity() {
+        let mapping = ScreenMapping {
+            device: (433, 957),
+            detection: (433, 957),
+        };
+        assert_eq!(mapping.to_device(-10, 1200), (-10, 1200));
+        assert_eq!(mapping.to_detection(57, 3), (57, 3));
+    }
+
+    #[test]
+    fn failed_release_keeps_the_drag_for_a_retry() {
+        let mapping = ScreenMapping {
+            device: (433, 957),
+            detection: (433, 957),
+        };
+        let device = AdbDevice::new(Some("scrap2_missing_device".to_string()));
+        let mut backend = AdbInputBackend::new(device, mapping);
+        backend.move_to(10, 20).unwrap();
+        backend.mouse_down().unwrap();
+        backend.move_to(30, 40).unwrap();
+
+        assert!(backend.mouse_up().is_err());
+        assert_eq!(backend.held_path, Some(vec![(10, 20), (30, 40)]));
+    }
+}
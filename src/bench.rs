@@ -0,0 +1,469 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::objectdetector::DetectionResult;
+use crate::objectdetector::ObjectDetector;
+use crate::settings::MatchColorSpace;
+use crate::settings::Settings;
+use log::info;
+use log::warn;
+use opencv::imgcodecs::IMREAD_COLOR;
+use opencv::imgcodecs::imread;
+use opencv::prelude::MatTraitConst;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Ожидаемый объект из <картинка>.json рядом со скриншотом
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroundTruth {
+    pub object_name: String,
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Debug, Default)]
+struct Score {
+    true_positives: usize,
+    false_positives: usize,
+    false_negatives: usize,
+}
+
+impl Score {
+    fn add(&mut self, other: &Score) {
+        self.true_positives += other.true_positives;
+        self.false_positives += other.false_positives;
+        self.false_negatives += other.false_negatives;
+    }
+
+    fn precision(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+
+    fn recall(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+
+    fn f1(&self) -> f64 {
+        let (precision, recall) = (self.precision(), self.recall());
+        if precision + recall > 0.0 {
+            2.0 * precision * recall / (precision + recall)
+        } else {
+            0.0
+        }
+    }
+}
+
+fn ratio(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        1.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+// Детекция засчитывается, если рядом (в пределах половины её размера) есть
+// ещё не занятый ожидаемый объект с тем же именем
+fn score(detections: &[DetectionResult], truth: &[GroundTruth]) -> Score {
+    let mut matched = vec![false; truth.len()];
+    let mut score = Score::default();
+
+    for detection in detections {
+        let radius = (detection.width.max(detection.height) / 2).max(1);
+        let hit = truth.iter().enumerate().position(|(i, expected)| {
+            !matched[i]
+                && expected.object_name == detection.object_name
+                && (expected.x - detection.location.x).abs() <= radius
+                && (expected.y - detection.location.y).abs() <= radius
+        });
+        match hit {
+            Some(i) => {
+                matched[i] = true;
+                score.true_positives += 1;
+            }
+            None => score.false_positives += 1,
+        }
+    }
+    score.false_negatives = matched.iter().filter(|m| !**m).count();
+    score
+}
+
+fn bench_images(dir: &str) -> AppResult<Vec<PathBuf>> {
+    let mut images: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| matches!(e.to_lowercase().as_str(), "png" | "jpg" | "jpeg"))
+        })
+        .collect();
+    images.sort();
+    Ok(images)
+}
+
+fn load_ground_truth(image: &Path) -> AppResult<Option<Vec<GroundTruth>>> {
+    let path = image.with_extension("json");
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+}
+
+// Прогоняет детектор по сохранённым скриншотам без захвата окна и мыши;
+// если рядом с картинкой лежит одноимённый .json, считает precision/recall
+pub fn run_bench(
+    detector: &mut ObjectDetector,
+    dir: &str,
+    color_space: MatchColorSpace,
+) -> AppResult<()> {
+    let images = bench_images(dir)?;
+    if images.is_empty() {
+        return Err(AppError::SettingsError(format!(
+            "No png/jpg images in {}",
+            dir
+        )));
+    }
+
+    // Каждый скриншот должен пройти полную детекцию
+    detector.static_frame_threshold = 0.0;
+
+    let mut total_ms = 0;
+    let mut total_counts: BTreeMap<String, usize> = BTreeMap::new();
+    let mut total_score = Score::default();
+    let mut scored_images = 0;
+
+    for path in &images {
+        let image = imread(&path.to_string_lossy(), IMREAD_COLOR)?;
+        if image.empty() {
+            return Err(AppError::ImageProcessing(format!(
+                "Could not read {}",
+                path.display()
+            )));
+        }
+
+        let (detections, elapsed_ms) = detector.detect_objects_optimized(&image, color_space)?;
+        total_ms += elapsed_ms;
+
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for detection in &detections {
+            *counts.entry(detection.object_name.clone()).or_default() += 1;
+            *total_counts
+                .entry(detection.object_name.clone())
+                .or_default() += 1;
+        }
+        let counts_str = counts
+            .iter()
+            .map(|(name, count)| format!("{}: {}", name, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        match load_ground_truth(path)? {
+            Some(truth) => {
+                let image_score = score(&detections, &truth);
+                println!(
+                    "{} {}ms [{}] precision {:.2} recall {:.2}",
+                    file_name,
+                    elapsed_ms,
+                    counts_str,
+                    image_score.precision(),
+                    image_score.recall()
+                );
+                total_score.add(&image_score);
+                scored_images += 1;
+            }
+            None => println!("{} {}ms [{}]", file_name, elapsed_ms, counts_str),
+        }
+    }
+
+    println!(
+        "Total: {} images, {}ms ({:.1}ms per image)",
+        images.len(),
+        total_ms,
+        total_ms as f64 / images.len() as f64
+    );
+    for (name, count) in &total_counts {
+        println!("  {}: {}", name, count);
+    }
+    if scored_images > 0 {
+        println!(
+            "Ground truth for {} images: precision {:.3} recall {:.3}",
+            scored_images,
+            total_score.precision(),
+            total_score.recall()
+        );
+    }
+
+    Ok(())
+}
+
+// Сетка порогов для --calibrate: 0.50, 0.55, ..., 0.95
+fn calibration_thresholds() -> Vec<f64> {
+    (50..=95).step_by(5).map(|i| i as f64 / 100.0).collect()
+}
+
+// Порог с лучшим F1 для одного шаблона по всем размеченным скриншотам
+fn best_threshold(
+    labeled: &[(Vec<DetectionResult>, Vec<GroundTruth>)],
+    name: &str,
+    thresholds: &[f64],
+) -> (f64, f64) {
+    let f1_scores: Vec<(f64, f64)> = thresholds
+        .iter()
+        .map(|&threshold| {
+            let mut total = Score::default();
+            for (detections, truth) in labeled {
+                let detections: Vec<DetectionResult> = detections
+                    .iter()
+                    .filter(|d| d.object_name == name && d.confidence >= threshold)
+                    .cloned()
+                    .collect();
+                let truth: Vec<GroundTruth> = truth
+                    .iter()
+                    .filter(|t| t.object_name == name)
+                    .cloned()
+                    .collect();
+                total.add(&score(&detections, &truth));
+            }
+            (threshold, total.f1())
+        })
+        .collect();
+
+    // Из равных по F1 порогов берём середину: до краёв плато дальше всего
+    let best_f1 = f1_scores.iter().map(|&(_, f1)| f1).fold(0.0, f64::max);
+    let best: Vec<f64> = f1_scores
+        .iter()
+        .filter(|&&(_, f1)| f1 == best_f1)
+        .map(|&(threshold, _)| threshold)
+        .collect();
+    (best[best.len() / 2], best_f1)
+}
+
+// --calibrate: детекция по размеченным скриншотам с самым низким порогом сетки,
+// затем для каждого размеченного шаблона порог с лучшим F1 пишется в settings.json
+pub fn run_calibration(
+    detector: &mut ObjectDetector,
+    dir: &str,
+    color_space: MatchColorSpace,
+    settings_path: &str,
+) -> AppResult<()> {
+    let thresholds = calibration_thresholds();
+
+    // Все шаблоны на каждом скриншоте, с порогом, ниже которого перебор не идёт
+    detector.static_frame_threshold = 0.0;
+    detector.full_range = true;
+    for template in &mut detector.templates {
+        Arc::make_mut(template).threshold = thresholds[0];
+    }
+
+    let mut labeled: Vec<(Vec<DetectionResult>, Vec<GroundTruth>)> = Vec::new();
+    for path in bench_images(dir)? {
+        let Some(truth) = load_ground_truth(&path)? else {
+            continue;
+        };
+        let image = imread(&path.to_string_lossy(), IMREAD_COLOR)?;
+        if image.empty() {
+            return Err(AppError::ImageProcessing(format!(
+                "Could not read {}",
+                path.display()
+            )));
+        }
+        let (detections, _) = detector.detect_objects_optimized(&image, color_space)?;
+        labeled.push((detections, truth));
+    }
+    if labeled.is_empty() {
+        return Err(AppError::SettingsError(format!(
+            "No labeled images (<image>.json next to a png/jpg) in {}",
+            dir
+        )));
+    }
+
+    let names: BTreeSet<&str> = labeled
+        .iter()
+        .flat_map(|(_, truth)| truth.iter().map(|t| t.object_name.as_str()))
+        .collect();
+
+    let mut chosen: BTreeMap<String, f64> = BTreeMap::new();
+    for name in names {
+        if !detector.templates.iter().any(|t| t.name == name) {
+            warn!("{} is labeled but has no template, skipping", name);
+            continue;
+        }
+
+        let (threshold, best_f1) = best_threshold(&labeled, name, &thresholds);
+        println!("{}: threshold {:.2} (F1 {:.3})", name, threshold, best_f1);
+        chosen.insert(name.to_string(), threshold);
+    }
+
+    let mut settings = Settings::reload(settings_path)?;
+    for template in &mut settings.templates {
+        if let Some(&threshold) = chosen.get(&template.name) {
+            template.threshold = threshold;
+        }
+    }
+    settings.validate()?;
+    settings.save(settings_path)?;
+    info!(
+        "Thresholds for {} templates written to {}",
+        chosen.len(),
+        settings_path
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::MatchMethod;
+    use crate::settings::TemplateCategory;
+    use opencv::core::Point;
+    use std::env;
+
+    fn fixture_path(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn detection(name: &str, x: i32, y: i32) -> DetectionResult {
+        DetectionResult {
+            object_name: name.to_string(),
+            location: Point::new(x, y),
+            confidence: 0.95,
+            width: 24,
+            height: 24,
+            category: TemplateCategory::Barrel,
+        }
+    }
+
+    fn truth(name: &str, x: i32, y: i32) -> GroundTruth {
+        GroundTruth {
+            object_name: name.to_string(),
+            x,
+            y,
+        }
+    }
+
+    #[test]
+    fn detections_are_scored_against_ground_truth() {
+        let detections = [
+            detection("Barrel 1", 8, 8),
+            // Сдвиг меньше половины спрайта всё ещё попадание
+            detection("Barrel 1", 51, 45),
+            // Правильное место, но не тот шаблон
+            detection("Barrel 2", 128, 8),
+        ];
+        let expected = [
+            truth("Barrel 1", 8, 8),
+            truth("Barrel 1", 48, 48),
+            truth("Barrel 1", 128, 8),
+        ];
+        let result = score(&detections, &expected);
+        assert_eq!(
+            (
+                result.true_positives,
+                result.false_positives,
+                result.false_negatives
+            ),
+            (2, 1, 1)
+        );
+        assert!((result.precision() - 2.0 / 3.0).abs() < 1e-9);
+        assert!((result.recall() - 2.0 / 3.0).abs() < 1e-9);
+
+        // Одну ожидаемую бочку нельзя засчитать дважды
+        let twice = [detection("Barrel 1", 8, 8), detection("Barrel 1", 9, 8)];
+        let result = score(&twice, &expected[..1]);
+        assert_eq!((result.true_positives, result.false_positives), (1, 1));
+    }
+
+    #[test]
+    fn bench_runs_over_a_fixture_screenshot() {
+        let dir = env::temp_dir().join(format!("scrap2_bench_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy(fixture_path("board.png"), dir.join("board.png")).unwrap();
+        fs::write(
+            dir.join("board.json"),
+            r#"[
+                {"object_name": "Barrel 1", "x": 8, "y": 8},
+                {"object_name": "Barrel 2", "x": 8, "y": 48}
+            ]"#,
+        )
+        .unwrap();
+        // Не картинка - пропускается
+        fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let mut detector = ObjectDetector::new(1.0);
+        detector.set_use_cuda(false);
+        for level in 1..=2 {
+            detector
+                .add_template(
+                    &format!("Barrel {}", level),
+                    &fixture_path(&format!("templates/barrel_{}.png", level)),
+                    0.9,
+                    5.0,
+                    0.0,
+                    0.0,
+                    0.0,
+                    None,
+                    false,
+                    MatchMethod::default(),
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+
+        let images = bench_images(&dir.to_string_lossy()).unwrap();
+        assert_eq!(images, vec![dir.join("board.png")]);
+        assert_eq!(load_ground_truth(&images[0]).unwrap().unwrap().len(), 2);
+        run_bench(&mut detector, &dir.to_string_lossy(), MatchColorSpace::Gray).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bench_without_images_is_an_error() {
+        let dir = env::temp_dir().join(format!("scrap2_bench_empty_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut detector = ObjectDetector::new(1.0);
+        assert!(run_bench(&mut detector, &dir.to_string_lossy(), MatchColorSpace::Gray).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn calibration_picks_a_threshold_between_hits_and_background() {
+        let at = |name: &str, x: i32, y: i32, confidence: f64| DetectionResult {
+            confidence,
+            ..detection(name, x, y)
+        };
+        // Настоящие бочки от 0.93, фон не выше 0.71
+        let labeled = vec![
+            (
+                vec![
+                    at("Barrel 1", 8, 8, 0.97),
+                    at("Barrel 1", 200, 8, 0.62),
+                    at("Barrel 2", 48, 8, 0.99),
+                ],
+                vec![truth("Barrel 1", 8, 8), truth("Barrel 2", 48, 8)],
+            ),
+            (
+                vec![at("Barrel 1", 48, 48, 0.93), at("Barrel 1", 8, 200, 0.71)],
+                vec![truth("Barrel 1", 48, 48)],
+            ),
+        ];
+
+        // F1 = 1 на 0.75..0.90, берётся середина плато
+        let (threshold, f1) = best_threshold(&labeled, "Barrel 1", &calibration_thresholds());
+        assert!((threshold - 0.85).abs() < 1e-9, "{}", threshold);
+        assert_eq!(f1, 1.0);
+    }
+}
@@ -1,13 +1,37 @@
+use crate::adb::AdbCaptureBackend;
+use crate::adb::AdbDevice;
+use crate::input::InputBackend;
+use crate::notify::notify;
+use crate::settings::CaptureBackendKind;
+use crate::settings::CaptureFormat;
+use crate::settings::Notifications;
+use crate::settings::Settings;
+use crate::settings::WindowMatch;
+use crate::settings::WindowRetry;
+use crate::utils::shutdown_requested;
+use crate::wayland::WaylandCaptureBackend;
+#[cfg(all(windows, feature = "windows"))]
+use crate::win32::WindowsCaptureBackend;
 use std::error::Error;
 use std::fmt;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use log::warn;
+use opencv::core::Rect;
 use opencv::core::Vec3b;
+use opencv::core::CV_32F;
 use opencv::core::Mat;
+use opencv::core::Vector;
 use opencv::prelude::MatTraitConst;
 use opencv::Result;
 use opencv::prelude::MatTraitConstManual;
 use opencv::imgcodecs::IMREAD_COLOR;
+use opencv::imgcodecs::imdecode;
 use opencv::imgcodecs::imread;
+use opencv::imgcodecs::imwrite;
+use regex::Regex;
 
 #[derive(Debug)]
 pub enum AppError {
@@ -20,6 +44,7 @@ pub enum AppError {
     SettingsError(String),
     X11Connect(x11rb::errors::ConnectError),
     X11Error(Box<dyn std::error::Error>),
+    Input(String),
 }
 
 impl fmt::Display for AppError {
@@ -34,6 +59,7 @@ impl fmt::Display for AppError {
             AppError::SettingsError(msg) => write!(f, "Settings error: {}", msg),
             AppError::X11Connect(msg) => write!(f, "X11 connect error: {}", msg),
             AppError::X11Error(msg) => write!(f, "X11 error: {}", msg),
+            AppError::Input(msg) => write!(f, "Input error: {}", msg),
         }
     }
 }
@@ -87,18 +113,21 @@ fn get_pixel_safe(img: &Mat, x: i32, y: i32) -> Result<Option<[u8; 3]>> {
     Ok(Some([pixel[0], pixel[1], pixel[2]]))
 }
 
-fn get_dominant_colors(image_path: &str, k: i32) -> Result<()> {
+// Возвращает центры k-means кластеров в порядке (B, G, R)
+pub fn get_dominant_colors(image_path: &str, k: i32) -> Result<Vec<(u8, u8, u8)>> {
     let img = imread(image_path, IMREAD_COLOR)?;
-    let samples = img.reshape(1, img.rows() * img.cols())?.to_mat()?.to_vec_2d::<f32>()?;
-    
+    let mut samples = Mat::default();
+    img.reshape(1, img.rows() * img.cols())?
+        .convert_to(&mut samples, CV_32F, 1.0, 0.0)?;
+
     let mut labels = Mat::default();
     let mut centers = Mat::default();
     let criteria = opencv::core::TermCriteria::new(
-        opencv::core::TermCriteria_Type::COUNT + opencv::core::TermCriteria_Type::EPS,
+        opencv::core::TermCriteria_Type::COUNT as i32 + opencv::core::TermCriteria_Type::EPS as i32,
         10,
         1.0,
     )?;
-    
+
     opencv::core::kmeans(
         &samples,
         k,
@@ -108,30 +137,408 @@ fn get_dominant_colors(image_path: &str, k: i32) -> Result<()> {
         opencv::core::KMEANS_PP_CENTERS,
         &mut centers,
     )?;
-    
-    println!("Доминирующие цвета:");
+
+    let mut colors = Vec::new();
     for i in 0..centers.rows() {
-        let center: Vec<f32> = centers.at_row(i)?.to_vec();
-        println!("Цвет {}: B:{}, G:{}, R:{}", i, center[0], center[1], center[2]);
+        let channel = |c: i32| -> Result<u8> {
+            Ok(centers.at_2d::<f32>(i, c)?.round().clamp(0.0, 255.0) as u8)
+        };
+        colors.push((channel(0)?, channel(1)?, channel(2)?));
     }
-    
-    Ok(())
+
+    Ok(colors)
 }
 
-pub fn capture_window_by_title(window_title: &str, output: &str) -> AppResult<(i32, i32)> {
-    let geometry = Command::new("xwininfo")
-        .args(&["-name", window_title])
-        .output()?;
+// Источник кадров: окно X11 или экран устройства через adb
+pub trait CaptureBackend {
+    // Кадр и экранные координаты его левого верхнего угла
+    fn capture(&mut self) -> AppResult<(Mat, i32, i32)>;
+    fn window_size(&mut self) -> AppResult<(i32, i32)>;
+
+    // Попадает ли экранная точка на реальный монитор; по умолчанию проверять нечего
+    fn point_on_screen(&self, _x: i32, _y: i32) -> bool {
+        true
+    }
+}
+
+// Снимок с ожиданием окна: если оно пропало (перезапуск игры, переподключение scrcpy),
+// ждём с растущей паузой вместо выхода. Второе значение - окно пропадало и вернулось
+pub fn capture_with_retry(
+    capture: &mut dyn CaptureBackend,
+    retry: &WindowRetry,
+    notifications: &Notifications,
+) -> AppResult<((Mat, i32, i32), bool)> {
+    let mut attempt = 0;
+    loop {
+        match capture.capture() {
+            Ok(frame) => return Ok((frame, attempt > 0)),
+            Err(AppError::WindowNotFound(msg))
+                if attempt < retry.max_attempts && !shutdown_requested() =>
+            {
+                if attempt == 0 {
+                    notify(notifications, &format!("Game window lost: {}", msg));
+                }
+                let delay_ms = retry_delay_ms(retry, attempt);
+                warn!(
+                    "Waiting for window ({}), attempt {}/{} in {}ms",
+                    msg,
+                    attempt + 1,
+                    retry.max_attempts,
+                    delay_ms
+                );
+                thread::sleep(Duration::from_millis(delay_ms));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Пауза перед попыткой attempt: удваивается, но не больше чем в 64 раза от базовой
+fn retry_delay_ms(retry: &WindowRetry, attempt: u32) -> u64 {
+    retry.base_delay_ms << attempt.min(6)
+}
+
+pub fn create_capture_backend(settings: &Settings) -> AppResult<Box<dyn CaptureBackend>> {
+    match settings.capture_backend {
+        CaptureBackendKind::X11 => {
+            let window_id = settings
+                .window_id
+                .as_deref()
+                .map(parse_window_id_value)
+                .transpose()?;
+            let mut backend = X11CaptureBackend::new(
+                &settings.window_title,
+                window_id,
+                settings.geometry_refresh_ms,
+            );
+            backend.window_match = settings.window_match;
+            backend.capture_format = settings.capture_format;
+            Ok(Box::new(backend))
+        }
+        CaptureBackendKind::Adb => {
+            let device = AdbDevice::new(settings.adb_serial.clone());
+            let mapping = device.mapping((settings.reference_width, settings.reference_height))?;
+            Ok(Box::new(AdbCaptureBackend::new(device, mapping)))
+        }
+        CaptureBackendKind::Wayland => Ok(Box::new(WaylandCaptureBackend::new(&settings.wayland)?)),
+        #[cfg(all(windows, feature = "windows"))]
+        CaptureBackendKind::Windows => {
+            Ok(Box::new(WindowsCaptureBackend::new(&settings.window_title)))
+        }
+        #[cfg(not(all(windows, feature = "windows")))]
+        CaptureBackendKind::Windows => Err(AppError::SettingsError(
+            "capture_backend windows needs a Windows build with --features windows".to_string(),
+        )),
+    }
+}
+
+pub struct X11CaptureBackend {
+    pub window_title: String,
+    // Если задан, окно ищется по нему, а не по заголовку
+    pub window_id: Option<u32>,
+    pub window_match: WindowMatch,
+    pub capture_format: CaptureFormat,
+    // Прямоугольники мониторов из RandR; пусто, если запрос не удался
+    monitors: Vec<Rect>,
+    geometry: GeometryCache,
+}
+
+// Геометрия окна меняется редко: xwininfo не чаще раза в refresh
+struct GeometryCache {
+    cached: Option<(WindowGeometry, Instant)>,
+    refresh: Duration,
+}
+
+impl GeometryCache {
+    fn new(refresh: Duration) -> Self {
+        Self {
+            cached: None,
+            refresh,
+        }
+    }
+
+    // Свежая копия из кэша или новый запрос; ошибка не кэшируется
+    fn get(
+        &mut self,
+        fetch: impl FnOnce() -> AppResult<WindowGeometry>,
+    ) -> AppResult<WindowGeometry> {
+        if let Some((geometry, fetched_at)) = self.cached {
+            if fetched_at.elapsed() < self.refresh {
+                return Ok(geometry);
+            }
+        }
+        let geometry = fetch()?;
+        self.cached = Some((geometry, Instant::now()));
+        Ok(geometry)
+    }
+
+    fn invalidate(&mut self) {
+        self.cached = None;
+    }
+}
+
+impl X11CaptureBackend {
+    pub fn new(window_title: &str, window_id: Option<u32>, refresh_ms: u64) -> Self {
+        let monitors = monitor_rects().unwrap_or_else(|e| {
+            warn!("Could not query monitors via RandR: {}", e);
+            Vec::new()
+        });
+        Self {
+            window_title: window_title.to_string(),
+            window_id,
+            window_match: WindowMatch::default(),
+            capture_format: CaptureFormat::default(),
+            monitors,
+            geometry: GeometryCache::new(Duration::from_millis(refresh_ms)),
+        }
+    }
+
+    fn geometry(&mut self) -> AppResult<WindowGeometry> {
+        let (window_id, window_title, window_match) =
+            (self.window_id, &self.window_title, self.window_match);
+        self.geometry.get(|| match window_id {
+            Some(id) => query_window_geometry_by_id(id),
+            None => query_window_geometry_matching(window_title, window_match),
+        })
+    }
+}
+
+impl CaptureBackend for X11CaptureBackend {
+    fn capture(&mut self) -> AppResult<(Mat, i32, i32)> {
+        let geometry = self.geometry()?;
+        let result = capture_geometry(
+            &self.window_title,
+            &geometry,
+            self.capture_format,
+            screen_bounds(&self.monitors),
+        );
+        // Окно могло сдвинуться или закрыться: следующий кадр перечитает геометрию
+        if result.is_err() {
+            self.geometry.invalidate();
+        }
+        result
+    }
+
+    fn window_size(&mut self) -> AppResult<(i32, i32)> {
+        let geometry = self.geometry()?;
+        Ok((geometry.width, geometry.height))
+    }
+
+    fn point_on_screen(&self, x: i32, y: i32) -> bool {
+        self.monitors.is_empty() || self.monitors.iter().any(|m| m.contains((x, y).into()))
+    }
+}
+
+// Активные мониторы в координатах корневого окна
+pub fn monitor_rects() -> AppResult<Vec<Rect>> {
+    use x11rb::connection::Connection;
+    use x11rb::protocol::randr::ConnectionExt;
+
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen_num].root;
+    let reply = conn
+        .randr_get_monitors(root, true)
+        .map_err(|e| AppError::X11Error(Box::new(e)))?
+        .reply()
+        .map_err(|e| AppError::X11Error(Box::new(e)))?;
+
+    Ok(reply.monitors.iter().map(monitor_rect).collect())
+}
+
+fn monitor_rect(monitor: &x11rb::protocol::randr::MonitorInfo) -> Rect {
+    Rect::new(
+        monitor.x as i32,
+        monitor.y as i32,
+        monitor.width as i32,
+        monitor.height as i32,
+    )
+}
+
+// Заголовок из свойства окна: без хвостовых NUL, битый UTF-8 не роняет список
+pub fn decode_window_name(value: &[u8]) -> String {
+    let end = value.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    String::from_utf8_lossy(&value[..end]).into_owned()
+}
+
+// _NET_WM_NAME (UTF-8), иначе WM_NAME; окно могло исчезнуть, тогда просто None
+fn window_name<C>(conn: &C, net_wm_name: u32, window: u32) -> Option<String>
+where
+    C: x11rb::connection::Connection,
+{
+    use x11rb::protocol::xproto::AtomEnum;
+    use x11rb::protocol::xproto::ConnectionExt;
+
+    [net_wm_name, AtomEnum::WM_NAME.into()]
+        .into_iter()
+        .find_map(|property| {
+            let reply = conn
+                .get_property(false, window, property, AtomEnum::ANY, 0, 1024)
+                .ok()?
+                .reply()
+                .ok()?;
+            Some(decode_window_name(&reply.value)).filter(|name| !name.is_empty())
+        })
+}
+
+// Окна верхнего уровня с заголовками, для --list-windows
+pub fn list_windows() -> AppResult<Vec<(u32, String)>> {
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::ConnectionExt;
+
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen_num].root;
+    let net_wm_name = conn
+        .intern_atom(false, b"_NET_WM_NAME")
+        .map_err(|e| AppError::X11Error(Box::new(e)))?
+        .reply()
+        .map_err(|e| AppError::X11Error(Box::new(e)))?
+        .atom;
+    let tree = conn
+        .query_tree(root)
+        .map_err(|e| AppError::X11Error(Box::new(e)))?
+        .reply()
+        .map_err(|e| AppError::X11Error(Box::new(e)))?;
+
+    let mut windows = Vec::new();
+    for top in tree.children {
+        // Под оконным менеджером заголовок висит на клиентском окне внутри рамки
+        let named = window_name(&conn, net_wm_name, top)
+            .map(|name| (top, name))
+            .or_else(|| {
+                let children = conn.query_tree(top).ok()?.reply().ok()?.children;
+                children
+                    .into_iter()
+                    .find_map(|child| Some((child, window_name(&conn, net_wm_name, child)?)))
+            });
+        windows.extend(named);
+    }
+    Ok(windows)
+}
+
+// Видимая часть окна: maim не понимает отрицательные смещения в -g
+// (в X-геометрии "-N" означает отступ от правого края), а за правым и нижним
+// краем экрана снимать нечего, поэтому окно снимается с обрезкой по screen.
+// Без screen (RandR недоступен) обрезаются только левый и верхний края
+pub fn visible_geometry(
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    screen: Option<Rect>,
+) -> Option<Rect> {
+    let (mut left, mut top) = (x.max(0), y.max(0));
+    let (mut right, mut bottom) = (x + width, y + height);
+    if let Some(screen) = screen {
+        left = left.max(screen.x);
+        top = top.max(screen.y);
+        right = right.min(screen.x + screen.width);
+        bottom = bottom.min(screen.y + screen.height);
+    }
+    if right <= left || bottom <= top {
+        return None;
+    }
+    Some(Rect::new(left, top, right - left, bottom - top))
+}
+
+// Корневое окно охватывает все мониторы; None, если их список пуст
+pub fn screen_bounds(monitors: &[Rect]) -> Option<Rect> {
+    let left = monitors.iter().map(|m| m.x).min()?;
+    let top = monitors.iter().map(|m| m.y).min()?;
+    let right = monitors.iter().map(|m| m.x + m.width).max()?;
+    let bottom = monitors.iter().map(|m| m.y + m.height).max()?;
+    Some(Rect::new(left, top, right - left, bottom - top))
+}
+
+// Положение и размер окна из одного вызова xwininfo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowGeometry {
+    pub id: u32,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+pub fn query_window_geometry(window_title: &str) -> AppResult<WindowGeometry> {
+    run_xwininfo(&["-name", window_title], &format!("'{}'", window_title))
+}
+
+// Точное имя сначала через xwininfo -name; если не нашлось или режим другой,
+// заголовки перебираются здесь, где пробелы и регистр под нашим контролем
+pub fn query_window_geometry_matching(
+    pattern: &str,
+    mode: WindowMatch,
+) -> AppResult<WindowGeometry> {
+    if mode == WindowMatch::Exact {
+        if let Ok(geometry) = query_window_geometry(pattern) {
+            return Ok(geometry);
+        }
+    }
+    let windows = list_windows()?;
+    let titles = windows.iter().map(|(_, title)| title.as_str());
+    match match_window_title(titles, pattern, mode)? {
+        Some(i) => query_window_geometry_by_id(windows[i].0),
+        None => Err(AppError::WindowNotFound(format!(
+            "'{}' ({:?})",
+            pattern, mode
+        ))),
+    }
+}
+
+// Индекс первого подходящего заголовка; пробелы по краям не учитываются
+pub fn match_window_title<'a>(
+    titles: impl IntoIterator<Item = &'a str>,
+    pattern: &str,
+    mode: WindowMatch,
+) -> AppResult<Option<usize>> {
+    let pattern = pattern.trim();
+    let regex = match mode {
+        WindowMatch::Regex => Some(Regex::new(pattern).map_err(|e| {
+            AppError::SettingsError(format!("window_title is not a valid regex: {}", e))
+        })?),
+        _ => None,
+    };
+    let lowered = pattern.to_lowercase();
+
+    Ok(titles.into_iter().position(|title| {
+        let title = title.trim();
+        match mode {
+            WindowMatch::Exact => title == pattern,
+            WindowMatch::Contains => title.to_lowercase().contains(&lowered),
+            WindowMatch::Regex => regex.as_ref().is_some_and(|regex| regex.is_match(title)),
+        }
+    }))
+}
+
+// По id окно находится однозначно, даже если заголовков несколько или он меняется
+pub fn query_window_geometry_by_id(id: u32) -> AppResult<WindowGeometry> {
+    let id = format!("0x{:x}", id);
+    run_xwininfo(&["-id", &id], &id)
+}
+
+// Подписи полей xwininfo переводятся (LC_ALL=de_DE даёт "Breite"), а разбор ищет
+// английские, поэтому локаль принудительно C
+fn xwininfo_command(args: &[&str]) -> Command {
+    let mut command = Command::new("xwininfo");
+    command.args(args).env("LC_ALL", "C").env("LANG", "C");
+    command
+}
+
+fn run_xwininfo(args: &[&str], label: &str) -> AppResult<WindowGeometry> {
+    let geometry = xwininfo_command(args).output()?;
 
     if !geometry.status.success() {
         return Err(AppError::WindowNotFound(format!(
-            "Window '{}' not found",
-            window_title
+            "Window {} not found",
+            label
         )));
     }
 
-    let geometry_output = String::from_utf8(geometry.stdout)?;
+    parse_xwininfo(&String::from_utf8(geometry.stdout)?)
+}
 
+pub fn parse_xwininfo(geometry_output: &str) -> AppResult<WindowGeometry> {
     let parse_value = |s: &str| -> AppResult<i32> {
         geometry_output
             .lines()
@@ -144,95 +551,132 @@ pub fn capture_window_by_title(window_title: &str, output: &str) -> AppResult<(i
             })
     };
 
-    let x = parse_value("Absolute upper-left X")?;
-    let y = parse_value("Absolute upper-left Y")?;
-    let width = parse_value("Width")?;
-    let height = parse_value("Height")?;
-
-    let geometry_str = format!("{}x{}+{}+{}", width, height, x, y);
-    let output_file = format!("{}", output.replace(" ", "_"));
-
-    let status = Command::new("maim")
-        .args(&["-g", &geometry_str, &output_file])
-        .status()?;
-
-    if !status.success() {
-        let status_import = Command::new("import")
-            .args(&[
-                "-window",
-                &format!("0x{:x}", parse_window_id(&geometry_output)?),
-                &output_file,
-            ])
-            .status();
-
-        if status_import.is_err() || !status_import.unwrap().success() {
-            return Err(AppError::ScrotFailed(format!(
-                "Failed to capture window area with both maim and import"
-            )));
-        }
-    }
+    Ok(WindowGeometry {
+        id: parse_window_id(geometry_output)?,
+        x: parse_value("Absolute upper-left X")?,
+        y: parse_value("Absolute upper-left Y")?,
+        width: parse_value("Width")?,
+        height: parse_value("Height")?,
+    })
+}
 
-    if !std::path::Path::new(&output_file).exists() {
-        return Err(AppError::ScrotFailed("Output file not created".to_string()));
-    }
+pub fn capture_window_to_mat(window_title: &str) -> AppResult<(Mat, i32, i32)> {
+    let monitors = monitor_rects().unwrap_or_default();
+    capture_geometry(
+        window_title,
+        &query_window_geometry(window_title)?,
+        CaptureFormat::default(),
+        screen_bounds(&monitors),
+    )
+}
 
-    Ok((x, y))
+// Без имени файла maim пишет кадр в stdout; формат задаём явно, imdecode
+// определит его по сигнатуре
+fn maim_args(visible: Rect, format: CaptureFormat) -> Vec<String> {
+    let geometry_str = format!(
+        "{}x{}+{}+{}",
+        visible.width, visible.height, visible.x, visible.y
+    );
+    vec![
+        "-g".to_string(),
+        geometry_str,
+        "-f".to_string(),
+        format.extension().to_string(),
+    ]
 }
 
-pub fn get_window_size(window_title: &str) -> AppResult<(i32, i32)> {
-    let output = Command::new("xwininfo")
-        .args(&["-name", window_title])
+// import пишет в stdout, когда вместо файла стоит "формат:-"
+fn import_args(id: u32, format: CaptureFormat) -> Vec<String> {
+    vec![
+        "-window".to_string(),
+        format!("0x{:x}", id),
+        format!("{}:-", format.extension()),
+    ]
+}
+
+pub fn capture_geometry(
+    window_title: &str,
+    geometry: &WindowGeometry,
+    format: CaptureFormat,
+    screen: Option<Rect>,
+) -> AppResult<(Mat, i32, i32)> {
+    let WindowGeometry {
+        id,
+        x,
+        y,
+        width,
+        height,
+    } = *geometry;
+
+    let visible = visible_geometry(x, y, width, height, screen).ok_or_else(|| {
+        AppError::WindowNotFound(format!("Window '{}' is entirely off-screen", window_title))
+    })?;
+    if visible != Rect::new(x, y, width, height) {
+        warn!(
+            "Window '{}' extends past the screen edge at ({}, {}), capturing the visible part only",
+            window_title, x, y
+        );
+    }
+
+    let maim = Command::new("maim")
+        .args(maim_args(visible, format))
         .output()?;
 
-    if !output.status.success() {
-        return Err(AppError::WindowNotFound(format!(
-            "Window '{}' not found",
-            window_title
-        )));
+    // Начало кадра: у maim - видимая часть, у import - окно целиком
+    let (encoded, origin) = if maim.status.success() && !maim.stdout.is_empty() {
+        (maim.stdout, (visible.x, visible.y))
+    } else {
+        let import = Command::new("import")
+            .args(import_args(id, format))
+            .output();
+
+        match import {
+            Ok(import) if import.status.success() && !import.stdout.is_empty() => {
+                (import.stdout, (x, y))
+            }
+            _ => {
+                return Err(AppError::ScrotFailed(
+                    "Failed to capture window area with both maim and import".to_string(),
+                ));
+            }
+        }
+    };
+
+    // IMREAD_COLOR всегда даёт BGR CV_8UC3, как и imread с диска
+    let image = imdecode(&Vector::<u8>::from_slice(&encoded), IMREAD_COLOR)?;
+    if image.empty() {
+        return Err(AppError::ImageProcessing(
+            "Captured image is empty".to_string(),
+        ));
     }
 
-    let output_str = String::from_utf8(output.stdout)?;
+    Ok((image, origin.0, origin.1))
+}
 
-    let width = output_str
-        .lines()
-        .find(|l| l.trim().starts_with("Width"))
-        .and_then(|l| l.split(':').nth(1))
-        .and_then(|v| v.trim().split(' ').next())
-        .and_then(|v| v.parse().ok())
-        .ok_or_else(|| AppError::WindowNotFound("Could not parse width".to_string()))?;
+pub fn capture_window_by_title(window_title: &str, output: &str) -> AppResult<(i32, i32)> {
+    let (image, x, y) = capture_window_to_mat(window_title)?;
 
-    let height = output_str
-        .lines()
-        .find(|l| l.trim().starts_with("Height"))
-        .and_then(|l| l.split(':').nth(1))
-        .and_then(|v| v.trim().split(' ').next())
-        .and_then(|v| v.parse().ok())
-        .ok_or_else(|| AppError::WindowNotFound("Could not parse height".to_string()))?;
+    let output_file = output.replace(" ", "_");
+    if !imwrite(&output_file, &image, &Vector::new())? {
+        return Err(AppError::ScrotFailed("Output file not created".to_string()));
+    }
+
+    Ok((x, y))
+}
 
-    Ok((width, height))
+pub fn get_window_size(window_title: &str) -> AppResult<(i32, i32)> {
+    let geometry = query_window_geometry(window_title)?;
+    Ok((geometry.width, geometry.height))
 }
 
 pub fn is_cursor_in_window(
+    input: &mut dyn InputBackend,
     window_x: i32,
     window_y: i32,
     window_width: i32,
     window_height: i32,
 ) -> AppResult<bool> {
-    let output = Command::new("xdotool")
-        .args(&["getmouselocation", "--shell"])
-        .output()?;
-
-    let output_str = String::from_utf8(output.stdout)?;
-    let mut cursor_x = 0;
-    let mut cursor_y = 0;
-
-    for line in output_str.lines() {
-        if line.starts_with("X=") {
-            cursor_x = line[2..].parse().unwrap_or(0);
-        } else if line.starts_with("Y=") {
-            cursor_y = line[2..].parse().unwrap_or(0);
-        }
-    }
+    let (cursor_x, cursor_y) = input.get_position()?;
 
     Ok(cursor_x >= window_x
         && cursor_x <= window_x + window_width
@@ -245,12 +689,379 @@ pub fn parse_window_id(geometry_output: &str) -> AppResult<u32> {
         .lines()
         .find(|l| l.contains("Window id:"))
         .and_then(|l| l.split_whitespace().nth(3))
-        .and_then(|id| {
-            if id.starts_with("0x") {
-                u32::from_str_radix(&id[2..], 16).ok()
-            } else {
-                id.parse().ok()
-            }
-        })
+        .and_then(|id| parse_window_id_value(id).ok())
         .ok_or_else(|| AppError::WindowNotFound("Could not parse window ID".to_string()))
 }
+
+// Id окна в виде 0x... (как печатают xwininfo и xdotool) или десятичном
+pub fn parse_window_id_value(id: &str) -> AppResult<u32> {
+    let parsed = match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => id.parse().ok(),
+    };
+    parsed.ok_or_else(|| AppError::SettingsError(format!("Invalid window id: {}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::CV_8UC3;
+    use opencv::core::Scalar;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn dominant_color_of_a_solid_image_is_that_color() {
+        let path = std::env::temp_dir().join(format!("scrap2_solid_{}.png", std::process::id()));
+        let path = path.to_str().unwrap();
+        let image =
+            Mat::new_rows_cols_with_default(16, 16, CV_8UC3, Scalar::new(10.0, 120.0, 230.0, 0.0))
+                .unwrap();
+        imwrite(path, &image, &Vector::new()).unwrap();
+
+        let colors = get_dominant_colors(path, 1);
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(colors.unwrap(), vec![(10, 120, 230)]);
+    }
+
+    #[test]
+    fn visible_geometry_clips_every_screen_edge() {
+        let screen = Some(Rect::new(0, 0, 1920, 1080));
+
+        // Целиком на экране - без изменений
+        assert_eq!(
+            visible_geometry(100, 50, 433, 957, screen),
+            Some(Rect::new(100, 50, 433, 957))
+        );
+        // Левый и верхний края
+        assert_eq!(
+            visible_geometry(-30, -20, 433, 957, screen),
+            Some(Rect::new(0, 0, 403, 937))
+        );
+        // Правый и нижний края
+        assert_eq!(
+            visible_geometry(1700, 500, 433, 957, screen),
+            Some(Rect::new(1700, 500, 220, 580))
+        );
+        // Окно больше экрана
+        assert_eq!(
+            visible_geometry(-10, -10, 4000, 3000, screen),
+            Some(Rect::new(0, 0, 1920, 1080))
+        );
+        // Целиком за краем
+        assert_eq!(visible_geometry(1920, 0, 433, 957, screen), None);
+        assert_eq!(visible_geometry(-433, 0, 433, 957, screen), None);
+        // Без мониторов правый и нижний края не известны
+        assert_eq!(
+            visible_geometry(1700, -20, 433, 957, None),
+            Some(Rect::new(1700, 0, 433, 937))
+        );
+    }
+
+    #[test]
+    fn screen_bounds_cover_all_monitors() {
+        let monitors = [
+            Rect::new(0, 0, 1920, 1080),
+            Rect::new(1920, -200, 1080, 1920),
+        ];
+        assert_eq!(
+            screen_bounds(&monitors),
+            Some(Rect::new(0, -200, 3000, 1920))
+        );
+        assert_eq!(screen_bounds(&[]), None);
+    }
+
+    // Вывод xwininfo -name для окна с кириллицей в заголовке, как есть
+    const XWININFO_OUTPUT: &str = "
+xwininfo: Window id: 0x3a00007 \"Scrap II — Игра\"
+
+  Absolute upper-left X:  -30
+  Absolute upper-left Y:  64
+  Relative upper-left X:  0
+  Relative upper-left Y:  0
+  Width: 433
+  Height: 957
+  Depth: 24
+  Visual: 0x21
+  Visual Class: TrueColor
+  Border width: 0
+  Class: InputOutput
+  Colormap: 0x22 (installed)
+  Bit Gravity State: NorthWestGravity
+  Window Gravity State: NorthWestGravity
+  Backing Store State: NotUseful
+  Save Under State: no
+  Map State: IsViewable
+  Override Redirect State: no
+  Corners:  +-30+64  -1517+64  -1517-59  +-30-59
+  -geometry 433x957+-30+64
+";
+
+    #[test]
+    fn xwininfo_output_is_parsed_in_one_pass() {
+        assert_eq!(
+            parse_xwininfo(XWININFO_OUTPUT).unwrap(),
+            WindowGeometry {
+                id: 0x3a00007,
+                x: -30,
+                y: 64,
+                width: 433,
+                height: 957,
+            }
+        );
+
+        let without_height = XWININFO_OUTPUT.replace("  Height: 957\n", "");
+        assert!(parse_xwininfo(&without_height).is_err());
+        assert!(parse_xwininfo("xwininfo: error: No window with name Scrap exists!").is_err());
+    }
+
+    #[test]
+    fn window_ids_parse_in_hex_and_decimal() {
+        let cases = [
+            ("0x3a00007", Some(0x3a00007)),
+            ("0X3A00007", Some(0x3a00007)),
+            ("60817415", Some(60817415)),
+            ("0xffffffff", Some(u32::MAX)),
+            ("0x", None),
+            ("0x1g", None),
+            ("-1", None),
+            ("", None),
+        ];
+        for (id, expected) in cases {
+            assert_eq!(parse_window_id_value(id).ok(), expected, "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn window_names_decode_utf8_without_trailing_nuls() {
+        assert_eq!(
+            decode_window_name("Scrap II — Игра\0\0".as_bytes()),
+            "Scrap II — Игра"
+        );
+        assert_eq!(decode_window_name(b"\0\0"), "");
+        // Битый UTF-8 заменяется, а не роняет список окон
+        assert_eq!(decode_window_name(b"Scrap \xff"), "Scrap \u{fffd}");
+    }
+
+    // Окно пропадает на failures снимков, потом возвращается
+    struct FlakyCapture {
+        failures: u32,
+        calls: u32,
+    }
+
+    impl CaptureBackend for FlakyCapture {
+        fn capture(&mut self) -> AppResult<(Mat, i32, i32)> {
+            self.calls += 1;
+            if self.calls <= self.failures {
+                return Err(AppError::WindowNotFound("Scrap II".to_string()));
+            }
+            let frame = Mat::new_rows_cols_with_default(4, 4, CV_8UC3, Scalar::all(0.0))?;
+            Ok((frame, 10, 20))
+        }
+
+        fn window_size(&mut self) -> AppResult<(i32, i32)> {
+            Ok((4, 4))
+        }
+    }
+
+    #[test]
+    fn capture_waits_for_a_window_that_comes_back() {
+        let retry = WindowRetry {
+            max_attempts: 5,
+            base_delay_ms: 1,
+        };
+        let mut capture = FlakyCapture {
+            failures: 2,
+            calls: 0,
+        };
+        let ((frame, x, y), returned) =
+            capture_with_retry(&mut capture, &retry, &Notifications::default()).unwrap();
+        assert_eq!(capture.calls, 3);
+        assert!(returned);
+        assert_eq!((frame.cols(), frame.rows(), x, y), (4, 4, 10, 20));
+
+        // Окно на месте - ожидания не было
+        let mut capture = FlakyCapture {
+            failures: 0,
+            calls: 0,
+        };
+        let (_, returned) =
+            capture_with_retry(&mut capture, &retry, &Notifications::default()).unwrap();
+        assert!(!returned);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_a_cap() {
+        let retry = WindowRetry {
+            max_attempts: 10,
+            base_delay_ms: 500,
+        };
+        let delays: Vec<u64> = (0..9).map(|a| retry_delay_ms(&retry, a)).collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 8000, 16000, 32000, 32000, 32000]
+        );
+    }
+
+    #[test]
+    fn capture_gives_up_after_the_last_attempt() {
+        let retry = WindowRetry {
+            max_attempts: 3,
+            base_delay_ms: 1,
+        };
+        let mut capture = FlakyCapture {
+            failures: u32::MAX,
+            calls: 0,
+        };
+        let result = capture_with_retry(&mut capture, &retry, &Notifications::default());
+        assert!(matches!(result, Err(AppError::WindowNotFound(_))));
+        // Первый снимок и ещё max_attempts повторов
+        assert_eq!(capture.calls, 4);
+    }
+
+    #[test]
+    fn geometry_cache_refetches_after_invalidation_or_expiry() {
+        let geometry = |x| WindowGeometry {
+            id: 0x3a00007,
+            x,
+            y: 20,
+            width: 433,
+            height: 957,
+        };
+        let fetches = std::cell::Cell::new(0);
+        let fetch = |x| {
+            fetches.set(fetches.get() + 1);
+            Ok(geometry(x))
+        };
+
+        let mut cache = GeometryCache::new(Duration::from_secs(60));
+        assert_eq!(cache.get(|| fetch(10)).unwrap(), geometry(10));
+        // Окно сдвинулось, но до истечения refresh отдаётся кэш
+        assert_eq!(cache.get(|| fetch(50)).unwrap(), geometry(10));
+        assert_eq!(fetches.get(), 1);
+
+        // Неудачный снимок сбрасывает кэш: следующий кадр видит новое положение
+        cache.invalidate();
+        assert_eq!(cache.get(|| fetch(50)).unwrap(), geometry(50));
+        assert_eq!(fetches.get(), 2);
+
+        // Пропавшее окно не кэшируется
+        cache.invalidate();
+        let missing = cache.get(|| Err(AppError::WindowNotFound("Scrap II".to_string())));
+        assert!(missing.is_err());
+        assert_eq!(cache.get(|| fetch(70)).unwrap(), geometry(70));
+
+        // refresh 0 - запрос на каждом кадре
+        let mut uncached = GeometryCache::new(Duration::ZERO);
+        uncached.get(|| fetch(10)).unwrap();
+        uncached.get(|| fetch(10)).unwrap();
+        assert_eq!(fetches.get(), 5);
+    }
+
+    #[test]
+    fn points_between_monitors_are_off_screen() {
+        let monitor = |x: i16, y: i16, width: u16, height: u16| {
+            monitor_rect(&x11rb::protocol::randr::MonitorInfo {
+                name: 0,
+                primary: x == 0,
+                automatic: true,
+                x,
+                y,
+                width,
+                height,
+                width_in_millimeters: 0,
+                height_in_millimeters: 0,
+                outputs: Vec::new(),
+            })
+        };
+        // Основной 1920x1080, повёрнутый справа выше него и ещё один слева с зазором
+        let monitors = vec![
+            monitor(0, 0, 1920, 1080),
+            monitor(1920, -200, 1080, 1920),
+            monitor(-1300, 0, 1280, 1024),
+        ];
+        assert_eq!(monitors[1], Rect::new(1920, -200, 1080, 1920));
+        assert_eq!(monitors[2], Rect::new(-1300, 0, 1280, 1024));
+
+        let backend = X11CaptureBackend {
+            window_title: String::new(),
+            window_id: None,
+            window_match: WindowMatch::default(),
+            capture_format: CaptureFormat::default(),
+            monitors,
+            geometry: GeometryCache::new(Duration::ZERO),
+        };
+        assert!(backend.point_on_screen(100, 100));
+        assert!(backend.point_on_screen(2500, -150));
+        assert!(backend.point_on_screen(-1300, 500));
+        // Над основным монитором, под правым и в зазоре слева
+        assert!(!backend.point_on_screen(100, -10));
+        assert!(!backend.point_on_screen(2500, 1800));
+        assert!(!backend.point_on_screen(-10, 500));
+        // Правая и нижняя границы не входят
+        assert!(!backend.point_on_screen(3000, 0));
+        assert!(!backend.point_on_screen(100, 1080));
+    }
+
+    #[test]
+    fn window_titles_match_in_every_mode() {
+        let titles = [
+            "Terminal",
+            "scrcpy - Pixel 6 (fps 60)",
+            "  M2006C3MNG  ",
+            "Scrap II — Игра",
+        ];
+        let find = |pattern: &str, mode| {
+            match_window_title(titles.iter().copied(), pattern, mode).unwrap()
+        };
+
+        // Пробелы по краям не мешают ни заголовку, ни шаблону
+        assert_eq!(find("M2006C3MNG", WindowMatch::Exact), Some(2));
+        assert_eq!(find(" Terminal ", WindowMatch::Exact), Some(0));
+        assert_eq!(find("scrcpy", WindowMatch::Exact), None);
+
+        assert_eq!(find("PIXEL 6", WindowMatch::Contains), Some(1));
+        assert_eq!(find("игра", WindowMatch::Contains), Some(3));
+        assert_eq!(find("Android", WindowMatch::Contains), None);
+
+        assert_eq!(
+            find(r"^scrcpy - .+ \(fps \d+\)$", WindowMatch::Regex),
+            Some(1)
+        );
+        assert_eq!(find(r"^M\d{4}", WindowMatch::Regex), Some(2));
+        assert_eq!(find(r"^Pixel", WindowMatch::Regex), None);
+        assert!(match_window_title(titles.iter().copied(), "(", WindowMatch::Regex).is_err());
+    }
+
+    #[test]
+    fn xwininfo_runs_under_the_c_locale() {
+        let command = xwininfo_command(&["-id", "0x3c00007"]);
+        assert_eq!(command.get_program(), "xwininfo");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["-id", "0x3c00007"]);
+
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("LC_ALL"), Some(OsStr::new("C")))));
+        assert!(envs.contains(&(OsStr::new("LANG"), Some(OsStr::new("C")))));
+    }
+
+    #[test]
+    fn maim_and_import_write_the_configured_format_to_stdout() {
+        let visible = Rect::new(10, 20, 433, 957);
+        assert_eq!(
+            maim_args(visible, CaptureFormat::Png),
+            ["-g", "433x957+10+20", "-f", "png"]
+        );
+        assert_eq!(
+            maim_args(visible, CaptureFormat::Bmp),
+            ["-g", "433x957+10+20", "-f", "bmp"]
+        );
+        assert_eq!(
+            import_args(0x3c00007, CaptureFormat::Png),
+            ["-window", "0x3c00007", "png:-"]
+        );
+        assert_eq!(
+            import_args(0x3c00007, CaptureFormat::Bmp),
+            ["-window", "0x3c00007", "bmp:-"]
+        );
+    }
+}
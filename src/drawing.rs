@@ -1,9 +1,27 @@
 use crate::objectdetector::DetectionResult;
 use crate::objectdetector::ObjectTemplate;
+use crate::objectdetector::max_barrel_level;
 use crate::processors::calculate_required_merges;
+use crate::settings::ColorMode;
+use crate::settings::TemplateCategory;
+use crate::stats::FrameStats;
+use crate::stats::MergeStats;
+use crate::utils::clear_screen_sequence;
+use serde::Serialize;
+use std::env;
 use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
-pub fn get_contrast_text_color(bg_r: f32, bg_g: f32, bg_b: f32) -> &'static str {
+#[derive(Serialize)]
+struct FrameReport<'a> {
+    timestamp_ms: u128,
+    fps: f64,
+    detection_time_ms: u128,
+    detections: &'a [DetectionResult],
+}
+
+pub fn get_contrast_text_color(bg_r: f32, bg_g: f32, bg_b: f32, mode: ColorMode) -> &'static str {
     // Конвертируем значения RGB в диапазон 0-1
     let r = bg_r / 255.0;
     let g = bg_g / 255.0;
@@ -14,21 +32,69 @@ pub fn get_contrast_text_color(bg_r: f32, bg_g: f32, bg_b: f32) -> &'static str
 
     // Пороговое значение для выбора цвета текста (как в VS Code)
     // VS Code использует порог 0.5, но можно настроить
-    if luminance > 0.5 {
-        // Для светлых фонов - черный текст
-        "\x1b[38;2;0;0;0m" // ANSI escape для черного цвета (#000000)
+    match (mode, luminance > 0.5) {
+        // Для светлых фонов - черный текст (#000000)
+        (ColorMode::Truecolor, true) => "\x1b[38;2;0;0;0m",
+        // Для темных фонов - белый текст (#FFFFFF)
+        (ColorMode::Truecolor, false) => "\x1b[38;2;255;255;255m",
+        (ColorMode::Ansi256, true) => "\x1b[30m",
+        (ColorMode::Ansi256, false) => "\x1b[97m",
+        (ColorMode::Mono, _) => "",
+    }
+}
+
+// Ближайший цвет из куба 6x6x6 палитры на 256 цветов
+fn ansi256_index(r: f32, g: f32, b: f32) -> u8 {
+    let level = |c: f32| (c.clamp(0.0, 255.0) / 255.0 * 5.0).round() as u8;
+    16 + 36 * level(r) + 6 * level(g) + level(b)
+}
+
+// Ячейка таблицы с номером бочки; в mono только номер, без фона
+fn paint_cell(num: u32, (r, g, b): (f32, f32, f32), mode: ColorMode, uncertain: bool) -> String {
+    let text_color = get_contrast_text_color(r, g, b, mode);
+    // Шаткое совпадение у самого порога: номер со знаком вопроса
+    let num = if uncertain {
+        format!("{}?", num)
     } else {
-        // Для темных фонов - белый текст
-        "\x1b[38;2;255;255;255m" // ANSI escape для белого цвета (#FFFFFF)
+        num.to_string()
+    };
+    match mode {
+        ColorMode::Truecolor => format!(
+            "{} \x1b[48;2;{:.0};{:.0};{:.0}m{:^3}\x1b[0m ",
+            text_color, r, g, b, num
+        ),
+        ColorMode::Ansi256 => format!(
+            "{} \x1b[48;5;{}m{:^3}\x1b[0m ",
+            text_color,
+            ansi256_index(r, g, b),
+            num
+        ),
+        ColorMode::Mono => format!(" {:^3} ", num),
     }
 }
 
-pub fn_draw_captcha() {
-    
+// COLORTERM выставляют терминалы с поддержкой 24-битного цвета
+pub fn terminal_supports_truecolor() -> bool {
+    env::var("COLORTERM").is_ok_and(|v| v == "truecolor" || v == "24bit")
+}
+
+// Как рисовать таблицу: цветовой режим и пометка об OpenCL в подвале
+#[derive(Debug, Clone, Copy)]
+pub struct TableStyle {
+    pub color_mode: ColorMode,
+    pub opencl: bool,
+    pub low_confidence_margin: f64,
 }
 
-pub fn draw_cloud(drop_positions: &[usize], is_moving_right: bool, line_length: usize) {
-    print!("\x1B[2J\x1B[1;1H"); // Очистка экрана
+pub fn draw_captcha() {}
+
+pub fn draw_cloud(
+    drop_positions: &[usize],
+    is_moving_right: bool,
+    line_length: usize,
+    preserve_scrollback: bool,
+) {
+    print!("{}", clear_screen_sequence(preserve_scrollback)); // Очистка экрана
 
     // Верхняя граница (как в таблице)
     print!("╔");
@@ -94,13 +160,59 @@ pub fn draw_cloud(drop_positions: &[usize], is_moving_right: bool, line_length:
     println!("╝");
 }
 
+// Число различных значений с допуском tolerance: соседние по сетке бочки
+// отстоят друг от друга на ширину/высоту ячейки, а одна колонка гуляет на пиксели
+fn count_lines(mut values: Vec<i32>, tolerance: i32) -> usize {
+    values.sort_unstable();
+    let mut count = 0;
+    let mut last = None;
+    for value in values {
+        if last.is_none_or(|last| value - last > tolerance) {
+            count += 1;
+        }
+        last = Some(value);
+    }
+    count
+}
+
+// Размер сетки из настроек, а если он не задан - по разбросу найденных бочек и пустых клеток
+pub fn grid_size(
+    cols: Option<usize>,
+    rows: Option<usize>,
+    detections: &[DetectionResult],
+) -> (usize, usize) {
+    let cells: Vec<&DetectionResult> = detections
+        .iter()
+        .filter(|d| {
+            matches!(
+                d.category,
+                TemplateCategory::Barrel | TemplateCategory::Empty
+            )
+        })
+        .collect();
+    let detect = |coord: fn(&DetectionResult) -> (i32, i32)| {
+        let tolerance = cells.iter().map(|d| coord(d).1).min().unwrap_or(0) / 2;
+        count_lines(cells.iter().map(|d| coord(d).0).collect(), tolerance).max(1)
+    };
+
+    (
+        cols.unwrap_or_else(|| detect(|d| (d.location.x, d.width))),
+        rows.unwrap_or_else(|| detect(|d| (d.location.y, d.height))),
+    )
+}
+
+// Совпадение меньше чем на margin выше порога шаблона; margin 0 - пометка выключена
+fn low_confidence(detection: &DetectionResult, template: &ObjectTemplate, margin: f64) -> bool {
+    margin > 0.0 && detection.confidence < template.threshold + margin
+}
+
 pub fn display_results_as_table(
     detections: &[DetectionResult],
     cols: usize,
     rows: usize,
     templates: &[Arc<ObjectTemplate>],
-    detection_time: usize,
-    fps: f64,
+    frame: &FrameStats,
+    style: TableStyle,
 ) {
     if detections.is_empty() {
         print!("No objects detected\n");
@@ -110,7 +222,7 @@ pub fn display_results_as_table(
     // Фильтруем только бочки (Barrel) и игнорируем Empty, Event и другие не-бочки
     let barrels: Vec<&DetectionResult> = detections
         .iter()
-        .filter(|d| d.object_name.starts_with("Barrel"))
+        .filter(|d| d.category == TemplateCategory::Barrel)
         .collect();
 
     if barrels.is_empty() {
@@ -136,8 +248,11 @@ pub fn display_results_as_table(
         1.0
     };
 
+    let max_level = max_barrel_level(templates);
+
     // Создаем таблицу с дополнительной информацией о цвете
-    let mut table: Vec<Vec<Option<(u32, (f32, f32, f32))>>> = vec![vec![None; cols]; rows];
+    // Уровень, цвет и признак совпадения у самого порога
+    let mut table: Vec<Vec<Option<(u32, (f32, f32, f32), bool)>>> = vec![vec![None; cols]; rows];
 
     // Заполняем таблицу только бочками
     for barrel in detections {
@@ -152,26 +267,35 @@ pub fn display_results_as_table(
             0
         };
 
-        if barrel.object_name == "Empty" {
+        if barrel.category == TemplateCategory::Empty {
             if row < rows && col < cols {
-                table[row][col] = Some((0, (0.0, 0.0, 0.0)));
+                table[row][col] = Some((0, (0.0, 0.0, 0.0), false));
             }
-        } else {
-            let number = barrel
-                .object_name
-                .chars()
-                .filter_map(|c| c.to_digit(10))
-                .fold(0, |acc, digit| acc * 10 + digit);
-
+        } else if let Some(number) = barrel.barrel_level() {
             if row < rows && col < cols {
                 if let Some(template) = templates.iter().find(|t| t.name == barrel.object_name) {
-                    table[row][col] = Some((number, (template.red, template.green, template.blue)));
+                    // Бочки максимального уровня готовы, показываем их приглушённо
+                    let dim = if max_level.is_some_and(|max| number >= max) {
+                        0.4
+                    } else {
+                        1.0
+                    };
+                    let uncertain = low_confidence(barrel, template, style.low_confidence_margin);
+                    table[row][col] = Some((
+                        number,
+                        (
+                            template.red * dim,
+                            template.green * dim,
+                            template.blue * dim,
+                        ),
+                        uncertain,
+                    ));
                 }
             }
         }
     }
 
-    let (min_lvl, max_lvl, merges_remaining) = calculate_required_merges(
+    let (min_lvl, max_lvl, merges_remaining, final_lvl) = calculate_required_merges(
         &barrels
             .iter()
             .map(|b| (*b).clone())
@@ -196,12 +320,11 @@ pub fn display_results_as_table(
     for row in 0..rows - 1 {
         print!("║");
         for col in 0..cols {
-            if let Some((num, (r, g, b))) = &table[row][col] {
+            if let Some((num, (r, g, b), uncertain)) = &table[row][col] {
                 if *num != 0 {
-                    let text_color = get_contrast_text_color(*r, *g, *b);
                     print!(
-                        "{} \x1b[48;2;{:.0};{:.0};{:.0}m{:^3}\x1b[0m ",
-                        text_color, r, g, b, num
+                        "{}",
+                        paint_cell(*num, (*r, *g, *b), style.color_mode, *uncertain)
                     );
                 } else {
                     print!("{}", empty_cell);
@@ -227,12 +350,11 @@ pub fn display_results_as_table(
     // Print last row with FPS
     print!("║");
     for col in 0..cols {
-        if let Some((num, (r, g, b))) = &table[rows - 1][col] {
+        if let Some((num, (r, g, b), uncertain)) = &table[rows - 1][col] {
             if *num != 0 {
-                let text_color = get_contrast_text_color(*r, *g, *b);
                 print!(
-                    "{} \x1b[48;2;{:.0};{:.0};{:.0}m{:^3}\x1b[0m ",
-                    text_color, r, g, b, num
+                    "{}",
+                    paint_cell(*num, (*r, *g, *b), style.color_mode, *uncertain)
                 );
             } else {
                 print!("{}", empty_cell);
@@ -242,7 +364,7 @@ pub fn display_results_as_table(
         }
         print!("║");
     }
-    println!(" {:.0}fps", fps);
+    println!(" {:.0}fps", frame.fps);
 
     // Print table footer with detection time
     print!("╚");
@@ -252,7 +374,11 @@ pub fn display_results_as_table(
             print!("╩");
         }
     }
-    println!("╝ {}ms", detection_time);
+    println!(
+        "╝ {}ms{}",
+        frame.detection_ms,
+        if style.opencl { " OpenCL" } else { "" }
+    );
 
     // Statistics section
     let min_w = 5;
@@ -266,8 +392,263 @@ pub fn display_results_as_table(
         "║ {:^min_w$} {:^max_w$} {:^target_w$} {:>merges_w$} ║",
         format!("⭣{}", min_lvl),
         format!("⭡{}", max_lvl),
-        format!("⭢{}", max_lvl + 1),
+        format!("⭢{}", final_lvl),
         format!("⭤{}", merges_remaining)
     );
     println!("╚{}╝", "═".repeat(total_width));
 }
+
+// Одна строка JSON на кадр, для --format json
+pub fn print_detections_json(detections: &[DetectionResult], frame: &FrameStats) {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+
+    if let Ok(line) = detections_json(detections, frame, timestamp_ms) {
+        println!("{}", line);
+    }
+}
+
+// serde_json::to_string не вставляет переводов строк, так что кадр - ровно одна строка
+fn detections_json(
+    detections: &[DetectionResult],
+    frame: &FrameStats,
+    timestamp_ms: u128,
+) -> serde_json::Result<String> {
+    serde_json::to_string(&FrameReport {
+        timestamp_ms,
+        fps: frame.fps,
+        detection_time_ms: frame.detection_ms,
+        detections,
+    })
+}
+
+pub fn display_stats(stats: &MergeStats) {
+    let runtime = stats.runtime().as_secs();
+    let lines = [
+        format!("Merges: {}", stats.merges_performed),
+        format!("Highest level: {}", stats.highest_level),
+        format!("Merges/min: {:.1}", stats.merges_per_minute()),
+        format!(
+            "Failed merges: {} ({:.1}%)",
+            stats.failed_merges,
+            stats.failure_rate()
+        ),
+        format!("Collected: {}", stats.collected),
+        format!(
+            "Runtime: {:02}:{:02}:{:02}",
+            runtime / 3600,
+            runtime / 60 % 60,
+            runtime % 60
+        ),
+    ];
+    let width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) + 2;
+
+    println!("╔{}╗", "═".repeat(width));
+    for line in &lines {
+        println!("║ {:<w$} ║", line, w = width - 2);
+    }
+    println!("╚{}╝", "═".repeat(width));
+}
+
+// Гистограмма уверенностей по корзинам шириной 0.05, порог шаблона отмечен стрелкой
+pub fn print_confidence_histogram(template_name: &str, confidences: &[f64], threshold: f64) {
+    const BUCKETS: usize = 20;
+    const BAR_WIDTH: usize = 40;
+
+    let mut counts = [0usize; BUCKETS];
+    for &confidence in confidences {
+        let bucket = (confidence.clamp(0.0, 1.0) * BUCKETS as f64) as usize;
+        counts[bucket.min(BUCKETS - 1)] += 1;
+    }
+    let max_count = counts.iter().copied().max().unwrap_or(0).max(1);
+    let threshold_bucket = ((threshold.clamp(0.0, 1.0) * BUCKETS as f64) as usize).min(BUCKETS - 1);
+
+    println!(
+        "{}: {} peaks, threshold {:.2}",
+        template_name,
+        confidences.len(),
+        threshold
+    );
+    // Сверху вниз: реальные совпадения обычно отрываются от шума в верхних корзинах
+    for bucket in (0..BUCKETS).rev() {
+        let from = bucket as f64 / BUCKETS as f64;
+        let bar = "#".repeat(counts[bucket] * BAR_WIDTH / max_count);
+        let marker = if bucket == threshold_bucket {
+            " <- threshold"
+        } else {
+            ""
+        };
+        println!(
+            "{:.2}-{:.2} {:>4} {}{}",
+            from,
+            from + 1.0 / BUCKETS as f64,
+            counts[bucket],
+            bar,
+            marker
+        );
+    }
+
+    let top: Vec<String> = confidences
+        .iter()
+        .take(10)
+        .map(|c| format!("{:.3}", c))
+        .collect();
+    println!("Top: {}", top.join(" "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::CV_8UC3;
+    use opencv::core::Mat;
+    use opencv::core::Point;
+    use opencv::core::Scalar;
+    use opencv::core::Vector;
+    use opencv::imgcodecs;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    #[test]
+    fn every_json_line_parses_back_into_the_frame() {
+        let frames = [
+            vec![],
+            vec![DetectionResult {
+                object_name: "Barrel 3".to_string(),
+                location: Point::new(40, 120),
+                confidence: 0.93,
+                width: 38,
+                height: 40,
+                category: TemplateCategory::Barrel,
+            }],
+            vec![DetectionResult {
+                // Кавычки и перевод строки в имени не должны ломать построчный формат
+                object_name: "Event \"2\"\n".to_string(),
+                location: Point::new(-5, 7),
+                confidence: 0.81,
+                width: 10,
+                height: 12,
+                category: TemplateCategory::Other,
+            }],
+        ];
+
+        let mut output = String::new();
+        for (i, detections) in frames.iter().enumerate() {
+            let frame = FrameStats::new(12, Duration::from_millis(250), (0, 5));
+            output.push_str(&detections_json(detections, &frame, 1000 + i as u128).unwrap());
+            output.push('\n');
+        }
+
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), frames.len());
+        for ((i, line), detections) in lines.iter().enumerate().zip(&frames) {
+            let report: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(report["timestamp_ms"], 1000 + i as u64);
+            assert_eq!(report["fps"], 4.0);
+            assert_eq!(report["detection_time_ms"], 12);
+
+            let parsed = report["detections"].as_array().unwrap();
+            assert_eq!(parsed.len(), detections.len());
+            for (value, detection) in parsed.iter().zip(detections) {
+                assert_eq!(value["object_name"], detection.object_name.as_str());
+                assert_eq!(value["location"]["x"], detection.location.x);
+                assert_eq!(value["location"]["y"], detection.location.y);
+                assert_eq!(value["confidence"], detection.confidence);
+                assert_eq!(value["width"], detection.width);
+                assert_eq!(value["height"], detection.height);
+            }
+        }
+    }
+
+    #[test]
+    fn mono_cells_carry_no_color_escapes() {
+        let color = (200.0, 100.0, 50.0);
+        let mono = paint_cell(5, color, ColorMode::Mono, false);
+        assert!(!mono.contains("48;2;"));
+        assert!(!mono.contains('\x1b'));
+        assert!(mono.contains('5'));
+        assert_eq!(
+            get_contrast_text_color(200.0, 100.0, 50.0, ColorMode::Mono),
+            ""
+        );
+
+        assert!(paint_cell(5, color, ColorMode::Truecolor, false).contains("48;2;200;100;50m"));
+        let ansi = paint_cell(5, color, ColorMode::Ansi256, false);
+        assert!(ansi.contains("48;5;"));
+        assert!(!ansi.contains("48;2;"));
+    }
+
+    fn barrel_template(level: u32, threshold: f64) -> Arc<ObjectTemplate> {
+        let image = Mat::new_rows_cols_with_default(8, 8, CV_8UC3, Scalar::all(100.0)).unwrap();
+        let mut buffer = Vector::<u8>::new();
+        imgcodecs::imencode(".png", &image, &mut buffer, &Vector::new()).unwrap();
+        Arc::new(
+            ObjectTemplate::from_bytes(
+                &format!("Barrel {}", level),
+                buffer.as_slice(),
+                threshold,
+                10.0,
+                200.0,
+                100.0,
+                50.0,
+                None,
+                false,
+                Default::default(),
+                None,
+                None,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn a_three_by_three_board_fits_its_table() {
+        let templates: Vec<Arc<ObjectTemplate>> =
+            (1..=3).map(|level| barrel_template(level, 0.9)).collect();
+
+        // Ячейки 60x50, координаты соседних бочек гуляют на пиксель
+        let detections: Vec<DetectionResult> = (0..9)
+            .map(|i| DetectionResult {
+                object_name: format!("Barrel {}", i % 3 + 1),
+                location: Point::new(100 + (i % 3) * 60 + i % 2, 40 + (i / 3) * 50),
+                confidence: 0.95,
+                width: 40,
+                height: 40,
+                category: TemplateCategory::Barrel,
+            })
+            .collect();
+
+        assert_eq!(grid_size(None, None, &detections), (3, 3));
+        assert_eq!(grid_size(Some(3), Some(3), &detections), (3, 3));
+
+        let frame = FrameStats::new(5, Duration::from_millis(100), (1, 3));
+        let style = TableStyle {
+            color_mode: ColorMode::Mono,
+            opencl: false,
+            low_confidence_margin: 0.0,
+        };
+        // Запись за пределы table[row][col] упала бы с паникой
+        display_results_as_table(&detections, 3, 3, &templates, &frame, style);
+        // Настроенная сетка меньше реальной тоже не должна выходить за границы
+        display_results_as_table(&detections, 2, 2, &templates, &frame, style);
+    }
+
+    #[test]
+    fn near_threshold_barrels_are_flagged() {
+        let template = barrel_template(1, 0.9);
+        let detection = |confidence| DetectionResult {
+            object_name: "Barrel 1".to_string(),
+            location: Point::new(0, 0),
+            confidence,
+            width: 40,
+            height: 40,
+            category: TemplateCategory::Barrel,
+        };
+
+        assert!(low_confidence(&detection(0.92), &template, 0.05));
+        assert!(!low_confidence(&detection(0.97), &template, 0.05));
+        // margin 0 выключает пометку
+        assert!(!low_confidence(&detection(0.9), &template, 0.0));
+    }
+}
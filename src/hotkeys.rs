@@ -0,0 +1,72 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::utils::PAUSED;
+use std::sync::atomic::Ordering;
+use std::thread;
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::GrabMode;
+use x11rb::protocol::xproto::ModMask;
+
+// XK_F1 из keysymdef.h, F2..F12 идут подряд
+const XK_F1: u32 = 0xffbe;
+
+fn parse_keysym(key: &str) -> AppResult<u32> {
+    key.strip_prefix('F')
+        .and_then(|n| n.parse::<u32>().ok())
+        .filter(|n| (1..=12).contains(n))
+        .map(|n| XK_F1 + n - 1)
+        .ok_or_else(|| AppError::SettingsError(format!("Unsupported hotkey: {}", key)))
+}
+
+fn x11_error(e: impl std::error::Error + 'static) -> AppError {
+    AppError::X11Error(Box::new(e))
+}
+
+// Глобально захватывает клавишу на корневом окне и переключает PAUSED по каждому нажатию
+pub fn spawn_pause_listener(key: &str) -> AppResult<()> {
+    let keysym = parse_keysym(key)?;
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen_num].root;
+    let min_keycode = conn.setup().min_keycode;
+    let max_keycode = conn.setup().max_keycode;
+
+    let mapping = conn
+        .get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1)
+        .map_err(x11_error)?
+        .reply()
+        .map_err(x11_error)?;
+
+    let per_keycode = mapping.keysyms_per_keycode as usize;
+    let keycode = mapping
+        .keysyms
+        .chunks(per_keycode.max(1))
+        .position(|keysyms| keysyms.contains(&keysym))
+        .map(|i| min_keycode + i as u8)
+        .ok_or_else(|| AppError::SettingsError(format!("No keycode for hotkey {}", key)))?;
+
+    // ModMask::ANY, чтобы NumLock и CapsLock не мешали срабатыванию
+    conn.grab_key(
+        true,
+        root,
+        ModMask::ANY,
+        keycode,
+        GrabMode::ASYNC,
+        GrabMode::ASYNC,
+    )
+    .map_err(x11_error)?
+    .check()
+    .map_err(x11_error)?;
+    conn.flush().map_err(x11_error)?;
+
+    thread::spawn(move || {
+        while let Ok(event) = conn.wait_for_event() {
+            if let Event::KeyPress(_) = event {
+                PAUSED.fetch_xor(true, Ordering::SeqCst);
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,354 @@
+use crate::adb::AdbDevice;
+use crate::adb::AdbInputBackend;
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::replay::ActionLogBackend;
+use crate::settings::InputBackendKind;
+use crate::settings::Settings;
+use crate::utils::get_current_mouse_position;
+use crate::utils::retry;
+use crate::wayland::YdotoolBackend;
+#[cfg(feature = "enigo")]
+use enigo::Button;
+#[cfg(feature = "enigo")]
+use enigo::Coordinate;
+#[cfg(feature = "enigo")]
+use enigo::Direction;
+#[cfg(feature = "enigo")]
+use enigo::Enigo;
+#[cfg(feature = "enigo")]
+use enigo::Mouse;
+use log::info;
+use std::ffi::OsStr;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// Low-level mouse control used by the movement and merge code.
+///
+/// The drag-to-merge flow issues `mouse_down`, then one or more moves, then
+/// `mouse_up`, so a backend must keep the button held across moves.
+pub trait InputBackend {
+    fn move_to(&mut self, x: i32, y: i32) -> AppResult<()>;
+    fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()>;
+    fn mouse_down(&mut self) -> AppResult<()>;
+    fn mouse_up(&mut self) -> AppResult<()>;
+    fn get_position(&mut self) -> AppResult<(i32, i32)>;
+
+    // Проход по точкам (x, y, задержка в мс); бэкенды могут сделать это за один вызов
+    fn move_along(&mut self, path: &[(i32, i32, u64)]) -> AppResult<()> {
+        for &(x, y, delay_ms) in path {
+            self.move_to(x, y)?;
+            thread::sleep(Duration::from_millis(delay_ms));
+        }
+        Ok(())
+    }
+}
+
+pub fn create_input_backend(settings: &Settings) -> AppResult<Box<dyn InputBackend>> {
+    let backend: Box<dyn InputBackend> = if settings.dry_run {
+        Box::new(DryRunBackend::default())
+    } else {
+        device_backend(settings)?
+    };
+    match &settings.paths.action_log {
+        Some(path) => Ok(Box::new(ActionLogBackend::new(path, backend)?)),
+        None => Ok(backend),
+    }
+}
+
+fn device_backend(settings: &Settings) -> AppResult<Box<dyn InputBackend>> {
+    let backend: Box<dyn InputBackend> = match settings.input_backend {
+        InputBackendKind::Xdotool => Box::new(XdotoolBackend::new(settings.mouse_button)),
+        #[cfg(feature = "enigo")]
+        InputBackendKind::Enigo => Box::new(EnigoBackend::new(settings.mouse_button)?),
+        #[cfg(not(feature = "enigo"))]
+        InputBackendKind::Enigo => {
+            return Err(AppError::SettingsError(
+                "input_backend enigo needs a build with --features enigo".to_string(),
+            ));
+        }
+        InputBackendKind::Adb => {
+            let device = AdbDevice::new(settings.adb_serial.clone());
+            let mapping = device.mapping((settings.reference_width, settings.reference_height))?;
+            Box::new(AdbInputBackend::new(device, mapping))
+        }
+        InputBackendKind::Ydotool => Box::new(YdotoolBackend::new(settings.mouse_button)),
+    };
+    Ok(Box::new(RetryingBackend {
+        inner: backend,
+        attempts: settings.input_retry.attempts,
+        delay_ms: settings.input_retry.delay_ms,
+    }))
+}
+
+// Повторяет идемпотентные команды бэкенда при сбое, чтобы разовая ошибка не обрывала прогон
+pub struct RetryingBackend {
+    inner: Box<dyn InputBackend>,
+    attempts: u32,
+    delay_ms: u64,
+}
+
+impl InputBackend for RetryingBackend {
+    fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+        retry(self.attempts, self.delay_ms, || self.inner.move_to(x, y))
+    }
+
+    // Относительный сдвиг не повторяется: после частичной попытки курсор ушёл бы дальше цели
+    fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+        self.inner.move_relative(dx, dy)
+    }
+
+    fn mouse_down(&mut self) -> AppResult<()> {
+        retry(self.attempts, self.delay_ms, || self.inner.mouse_down())
+    }
+
+    fn mouse_up(&mut self) -> AppResult<()> {
+        retry(self.attempts, self.delay_ms, || self.inner.mouse_up())
+    }
+
+    fn get_position(&mut self) -> AppResult<(i32, i32)> {
+        retry(self.attempts, self.delay_ms, || self.inner.get_position())
+    }
+
+    // Путь задан абсолютными точками, повтор после частичного прохода всё равно приходит в цель
+    fn move_along(&mut self, path: &[(i32, i32, u64)]) -> AppResult<()> {
+        retry(self.attempts, self.delay_ms, || self.inner.move_along(path))
+    }
+}
+
+// Ненулевой код xdotool тоже ошибка, иначе повторять было бы нечего
+fn xdotool<I, S>(args: I) -> AppResult<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let status = Command::new("xdotool").args(args).status()?;
+    if !status.success() {
+        return Err(AppError::Input(format!("xdotool exited with {}", status)));
+    }
+    Ok(())
+}
+
+pub struct XdotoolBackend {
+    button: String,
+}
+
+impl XdotoolBackend {
+    pub fn new(mouse_button: u8) -> Self {
+        Self {
+            button: mouse_button.to_string(),
+        }
+    }
+
+    // mousedown/mouseup с номером кнопки из настроек
+    fn button_args<'a>(&'a self, action: &'a str) -> [&'a str; 2] {
+        [action, self.button.as_str()]
+    }
+}
+
+impl InputBackend for XdotoolBackend {
+    fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+        xdotool(["mousemove", &x.to_string(), &y.to_string()])
+    }
+
+    fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+        xdotool(["mousemove_relative", "--", &dx.to_string(), &dy.to_string()])
+    }
+
+    fn mouse_down(&mut self) -> AppResult<()> {
+        xdotool(self.button_args("mousedown"))
+    }
+
+    fn mouse_up(&mut self) -> AppResult<()> {
+        xdotool(self.button_args("mouseup"))
+    }
+
+    fn get_position(&mut self) -> AppResult<(i32, i32)> {
+        get_current_mouse_position()
+    }
+
+    // Весь путь отдаём одному процессу xdotool цепочкой mousemove/sleep
+    fn move_along(&mut self, path: &[(i32, i32, u64)]) -> AppResult<()> {
+        let mut xdotool_args: Vec<String> = Vec::new();
+        for &(x, y, delay_ms) in path {
+            xdotool_args.extend([
+                "mousemove".to_string(),
+                x.to_string(),
+                y.to_string(),
+                "sleep".to_string(),
+                format!("{:.3}", delay_ms as f64 / 1000.0),
+            ]);
+        }
+
+        if xdotool_args.is_empty() {
+            return Ok(());
+        }
+        xdotool(&xdotool_args)
+    }
+}
+
+#[cfg(feature = "enigo")]
+pub struct EnigoBackend {
+    enigo: Enigo,
+    button: Button,
+}
+
+#[cfg(feature = "enigo")]
+impl EnigoBackend {
+    pub fn new(mouse_button: u8) -> AppResult<Self> {
+        let enigo =
+            Enigo::new(&enigo::Settings::default()).map_err(|e| AppError::Input(e.to_string()))?;
+        let button = match mouse_button {
+            2 => Button::Middle,
+            3 => Button::Right,
+            _ => Button::Left,
+        };
+        Ok(Self { enigo, button })
+    }
+}
+
+#[cfg(feature = "enigo")]
+impl InputBackend for EnigoBackend {
+    fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+        self.enigo
+            .move_mouse(x, y, Coordinate::Abs)
+            .map_err(|e| AppError::Input(e.to_string()))
+    }
+
+    fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+        self.enigo
+            .move_mouse(dx, dy, Coordinate::Rel)
+            .map_err(|e| AppError::Input(e.to_string()))
+    }
+
+    fn mouse_down(&mut self) -> AppResult<()> {
+        self.enigo
+            .button(self.button, Direction::Press)
+            .map_err(|e| AppError::Input(e.to_string()))
+    }
+
+    fn mouse_up(&mut self) -> AppResult<()> {
+        self.enigo
+            .button(self.button, Direction::Release)
+            .map_err(|e| AppError::Input(e.to_string()))
+    }
+
+    fn get_position(&mut self) -> AppResult<(i32, i32)> {
+        self.enigo
+            .location()
+            .map_err(|e| AppError::Input(e.to_string()))
+    }
+}
+
+// Ничего не двигает: позиция курсора хранится только в памяти, нажатия пишутся в лог
+#[derive(Default)]
+pub struct DryRunBackend {
+    position: (i32, i32),
+}
+
+impl InputBackend for DryRunBackend {
+    fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+        self.position = (x, y);
+        Ok(())
+    }
+
+    fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+        self.position = (self.position.0 + dx, self.position.1 + dy);
+        Ok(())
+    }
+
+    fn mouse_down(&mut self) -> AppResult<()> {
+        info!("Press at ({},{})", self.position.0, self.position.1);
+        Ok(())
+    }
+
+    fn mouse_up(&mut self) -> AppResult<()> {
+        info!("Release at ({},{})", self.position.0, self.position.1);
+        Ok(())
+    }
+
+    fn get_position(&mut self) -> AppResult<(i32, i32)> {
+        Ok(self.position)
+    }
+
+    fn move_along(&mut self, path: &[(i32, i32, u64)]) -> AppResult<()> {
+        if let Some(&(x, y, _)) = path.last() {
+            self.position = (x, y);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Первый вызов каждой команды падает
+    #[derive(Default)]
+    struct Flaky {
+        calls: Vec<&'static str>,
+        position: (i32, i32),
+    }
+
+    impl Flaky {
+        fn fail_once(&mut self, name: &'static str) -> AppResult<()> {
+            let first = !self.calls.contains(&name);
+            self.calls.push(name);
+            if first {
+                return Err(AppError::Input(format!("{} failed", name)));
+            }
+            Ok(())
+        }
+    }
+
+    impl InputBackend for Flaky {
+        fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+            self.fail_once("move_to")?;
+            self.position = (x, y);
+            Ok(())
+        }
+
+        fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+            self.position = (self.position.0 + dx, self.position.1 + dy);
+            self.fail_once("move_relative")
+        }
+
+        fn mouse_down(&mut self) -> AppResult<()> {
+            self.fail_once("mouse_down")
+        }
+
+        fn mouse_up(&mut self) -> AppResult<()> {
+            self.fail_once("mouse_up")
+        }
+
+        fn get_position(&mut self) -> AppResult<(i32, i32)> {
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn only_absolute_moves_are_retried() {
+        let mut backend = RetryingBackend {
+            inner: Box::new(Flaky::default()),
+            attempts: 3,
+            delay_ms: 0,
+        };
+
+        backend.move_to(10, 10).unwrap();
+        assert_eq!(backend.get_position().unwrap(), (10, 10));
+
+        // Сдвиг успел пройти, но вернул ошибку: повтор увёл бы курсор на (20, 10)
+        assert!(backend.move_relative(5, 0).is_err());
+        assert_eq!(backend.get_position().unwrap(), (15, 10));
+    }
+
+    #[test]
+    fn xdotool_presses_the_configured_button() {
+        let backend = XdotoolBackend::new(3);
+        assert_eq!(backend.button_args("mousedown"), ["mousedown", "3"]);
+        assert_eq!(backend.button_args("mouseup"), ["mouseup", "3"]);
+        let left = XdotoolBackend::new(1);
+        assert_eq!(left.button_args("mousedown"), ["mousedown", "1"]);
+    }
+}
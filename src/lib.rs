@@ -1,8 +1,23 @@
+pub mod adb;
+pub mod bench;
 pub mod capture;
 pub mod drawing;
+pub mod hotkeys;
+pub mod input;
 pub mod moving;
+pub mod notify;
 pub mod objectdetector;
 pub mod processors;
+pub mod recorder;
+pub mod replay;
 pub mod settings;
+pub mod stats;
+#[cfg(feature = "http")]
+pub mod status;
+pub mod tracker;
 pub mod utils;
+pub mod wayland;
+#[cfg(all(windows, feature = "windows"))]
+pub mod win32;
+pub mod wizard;
 pub mod open_cv_drawing;
\ No newline at end of file
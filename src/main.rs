@@ -1,233 +1,1224 @@
 use crossterm::{execute, terminal::SetTitle};
+use env_logger::Env;
+use log::info;
+use log::warn;
 use opencv::core::Vector;
 use opencv::imgcodecs;
-use opencv::imgcodecs::IMREAD_COLOR;
-use opencv::prelude::MatTraitConst;
+use scrap2_bot::bench::run_bench;
+use scrap2_bot::bench::run_calibration;
 use scrap2_bot::capture::AppError;
 use scrap2_bot::capture::AppResult;
-use scrap2_bot::capture::capture_window_by_title;
+use scrap2_bot::capture::CaptureBackend;
+use scrap2_bot::capture::capture_with_retry;
+use scrap2_bot::capture::create_capture_backend;
+use scrap2_bot::capture::get_dominant_colors;
 use scrap2_bot::capture::get_window_size;
 use scrap2_bot::capture::is_cursor_in_window;
+use scrap2_bot::capture::list_windows;
+use scrap2_bot::drawing::TableStyle;
 use scrap2_bot::drawing::display_results_as_table;
+use scrap2_bot::drawing::display_stats;
+use scrap2_bot::drawing::grid_size;
+use scrap2_bot::drawing::print_confidence_histogram;
+use scrap2_bot::drawing::print_detections_json;
+use scrap2_bot::drawing::terminal_supports_truecolor;
+use scrap2_bot::hotkeys::spawn_pause_listener;
+use scrap2_bot::input::InputBackend;
+use scrap2_bot::input::create_input_backend;
 use scrap2_bot::moving::human_like_move;
+use scrap2_bot::moving::idle_wiggle;
+use scrap2_bot::notify::notify;
 use scrap2_bot::objectdetector::DetectionResult;
 use scrap2_bot::objectdetector::ObjectDetector;
+use scrap2_bot::objectdetector::check_template_files;
+use scrap2_bot::processors::process_anticaptcha;
 use scrap2_bot::processors::process_barrels;
+use scrap2_bot::processors::process_collect;
 use scrap2_bot::processors::process_magnets_cloud;
-use scrap2_bot::settings::AntiCaptcha;
-use scrap2_bot::settings::Automation;
-use scrap2_bot::settings::HumanLikeMovementSettings;
-use scrap2_bot::settings::Merge;
-use scrap2_bot::settings::RandomOffsetSettings;
+use scrap2_bot::processors::process_ui;
+use scrap2_bot::recorder::FrameRecorder;
+use scrap2_bot::recorder::FrameRing;
+use scrap2_bot::replay::read_action_log;
+use scrap2_bot::replay::replay_actions;
+use scrap2_bot::settings::CaptureBackendKind;
+use scrap2_bot::settings::ColorMode;
+use scrap2_bot::settings::DEFAULT_WINDOW_TITLE;
+use scrap2_bot::settings::InputBackendKind;
+use scrap2_bot::settings::OutputFormat;
+use scrap2_bot::settings::SETTINGS_PATH;
+use scrap2_bot::settings::SETTINGS_VERSION;
 use scrap2_bot::settings::Settings;
-use scrap2_bot::settings::Shtorm;
-use scrap2_bot::utils;
+use scrap2_bot::settings::StopLimits;
+use scrap2_bot::settings::TemplateCategory;
+use scrap2_bot::settings::WindowMatch;
+use scrap2_bot::stats::BotState;
+use scrap2_bot::stats::FrameStats;
+use scrap2_bot::stats::MergeStats;
+#[cfg(feature = "http")]
+use scrap2_bot::status::spawn_status_server;
+use scrap2_bot::utils::PAUSED;
+use scrap2_bot::utils::RescanBackoff;
+use scrap2_bot::utils::SHUTDOWN_REQUESTED;
 use scrap2_bot::utils::check_and_suggest_window_size;
+use scrap2_bot::utils::check_dependencies;
 use scrap2_bot::utils::clear_screen;
+use scrap2_bot::utils::failsafe_triggered;
+use scrap2_bot::utils::paused;
+use scrap2_bot::utils::seed_rng;
+use scrap2_bot::utils::shutdown_requested;
+use scrap2_bot::utils::spawn_failsafe_watcher;
+use scrap2_bot::wizard::run_template_wizard;
 use std::env;
 use std::fs;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 
 fn load_or_create_settings(window_title: &str) -> AppResult<Settings> {
-    let settings_path = "settings.json";
-
-    if let Ok(settings_content) = fs::read_to_string(settings_path) {
-        serde_json::from_str(&settings_content).map_err(Into::into)
+    if fs::metadata(SETTINGS_PATH).is_ok() {
+        if let Some(version) = Settings::migrate_file(SETTINGS_PATH)? {
+            info!(
+                "{} upgraded from version {} to {}, old copy kept as {}.v{}.bak",
+                SETTINGS_PATH, version, SETTINGS_VERSION, SETTINGS_PATH, version
+            );
+        }
+        Settings::reload(SETTINGS_PATH)
     } else {
         let (width, height) = get_window_size(window_title)?;
 
         let settings = Settings {
             window_title: window_title.to_string(),
-            resolution: 0.38,
-            rescan_delay: 250,
             reference_width: width,
             reference_height: height,
-            convert_to_grayscale: true,
-            random_offset: RandomOffsetSettings {
-                enabled: true,
-                max_x_offset: 5,
-                max_y_offset: 5,
-            },
-            human_like_movement: HumanLikeMovementSettings {
-                // Добавлено
-                enabled: true,
-                max_deviation: 10.0,
-                speed_variation: 0.3,
-                curve_smoothness: 5,
-                min_pause_ms: 10,
-                max_pause_ms: 50,
-                base_speed: 0.1,
-                min_down_ms: 2,
-                max_down_ms: 5,
-                min_up_ms: 2,
-                max_up_ms: 6,
-                min_move_delay_ms: 5,
-                max_move_delay_ms: 12,
-            },
-            automation: Automation {
-                merge: Merge { enabled: true },
-                shtorm: Shtorm {
-                    enabled: true,
-                    retries: 1,
-                },
-                anticaptcha: AntiCaptcha {
-                    enabled: true,
-                    mode: "mask"
-                }
-            },
-            templates: Vec::new(),
+            ..Settings::default()
         };
 
-        let serialized = serde_json::to_string_pretty(&settings)?;
-        fs::write(settings_path, serialized)?;
+        settings.save(SETTINGS_PATH)?;
 
         Ok(settings)
     }
 }
 
-fn main() -> AppResult<()> {
-    let args: Vec<String> = env::args().collect();
-    let infinite_mode = args.iter().any(|arg| arg == "--infinite" || arg == "-i");
-    let debug_mode = args.iter().any(|arg| arg == "--debug" || arg == "-d");
-    execute!(std::io::stdout(), SetTitle("Scrap II Bot"))?;
+// Итоговые настройки без побочных эффектов: файл мигрируется только в памяти,
+// а без файла берутся значения по умолчанию вместо опроса окна
+fn resolve_settings(path: &str, overrides: &CliOverrides) -> AppResult<Settings> {
+    let mut settings = if fs::metadata(path).is_ok() {
+        Settings::reload(path)?
+    } else {
+        Settings {
+            window_title: DEFAULT_WINDOW_TITLE.to_string(),
+            ..Settings::default()
+        }
+    };
+    overrides.apply(&mut settings);
+    Ok(settings)
+}
 
-    let window_title = "M2006C3MNG";
-    let settings = load_or_create_settings(window_title)?;
+// То, что печатает --print-config
+fn config_json(path: &str, overrides: &CliOverrides) -> AppResult<String> {
+    let settings = resolve_settings(path, overrides)?;
+    Ok(serde_json::to_string_pretty(&settings)?)
+}
 
-    check_and_suggest_window_size(
-        &settings.window_title,
-        settings.reference_width,
-        settings.reference_height,
-    )?;
+// Включённый флаг в переменной окружения: 1, true или yes
+fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .is_ok_and(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+fn env_value<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        warn!("Ignoring {}={:?}: not a valid value", name, value);
+    }
+    parsed
+}
+
+// Переменные SCRAP2_* для контейнеров и CI: важнее settings.json, но уступают флагам
+fn apply_env_overrides(settings: &mut Settings) {
+    if let Ok(window_title) = env::var("SCRAP2_WINDOW_TITLE") {
+        settings.window_title = window_title;
+    }
+    if let Some(resolution) = env_value::<f64>("SCRAP2_RESOLUTION") {
+        if resolution > 0.0 {
+            settings.resolution = resolution;
+        } else {
+            warn!(
+                "Ignoring SCRAP2_RESOLUTION={}: must be positive",
+                resolution
+            );
+        }
+    }
+    if let Some(rescan_delay) = env_value("SCRAP2_RESCAN_DELAY") {
+        settings.rescan_delay = rescan_delay;
+        // Иначе backoff начнёт с задержки больше своего потолка
+        settings.rescan_delay_max = settings.rescan_delay_max.max(rescan_delay);
+    }
+}
+
+// Флаги командной строки, которые важнее settings.json
+#[derive(Default)]
+struct CliOverrides<'a> {
+    window_title: Option<&'a str>,
+    window_id: Option<&'a str>,
+    screenshot_path: Option<&'a str>,
+    debug_path: Option<&'a str>,
+    action_log: Option<&'a str>,
+    dry_run: bool,
+    quiet: bool,
+    format: Option<OutputFormat>,
+    seed: Option<u64>,
+    max_runtime_secs: Option<u64>,
+    max_merges: Option<u64>,
+    target_level: Option<u32>,
+}
 
+impl CliOverrides<'_> {
+    // Порядок приоритета: значения по умолчанию < settings.json < SCRAP2_* < флаги
+    fn apply(&self, settings: &mut Settings) {
+        apply_env_overrides(settings);
+        if let Some(window_title) = self.window_title {
+            settings.window_title = window_title.to_string();
+        }
+        if let Some(window_id) = self.window_id {
+            settings.window_id = Some(window_id.to_string());
+        }
+        if let Some(path) = self.screenshot_path {
+            settings.paths.screenshot = Some(path.to_string());
+        }
+        if let Some(path) = self.debug_path {
+            settings.paths.debug_output = Some(path.to_string());
+        }
+        if let Some(path) = self.action_log {
+            settings.paths.action_log = Some(path.to_string());
+        }
+        settings.dry_run |= self.dry_run;
+        if self.seed.is_some() {
+            settings.seed = self.seed;
+        }
+        if self.max_runtime_secs.is_some() {
+            settings.limits.max_runtime_secs = self.max_runtime_secs;
+        }
+        if self.max_merges.is_some() {
+            settings.limits.max_merges = self.max_merges;
+        }
+        if self.target_level.is_some() {
+            settings.limits.target_level = self.target_level;
+        }
+        settings.quiet |= self.quiet;
+        if let Some(format) = self.format {
+            settings.output_format = format;
+        }
+        settings.adapt_to_session();
+    }
+}
+
+fn create_detector(settings: &Settings) -> AppResult<ObjectDetector> {
     let mut detector = ObjectDetector::new(settings.resolution);
+    detector.multiscale = settings.multiscale;
+    detector.suppression_scale = settings.suppression_scale;
+    detector.static_frame_threshold = settings.static_frame_threshold;
+    detector.tracker.ttl = settings.tracking_ttl_frames;
+    detector.ambiguity_margin = settings.ambiguity_margin;
+    detector.set_use_opencl(settings.use_opencl);
+    detector.set_roi(
+        settings.roi,
+        (settings.reference_width, settings.reference_height),
+    );
 
-    for template_settings in settings.templates.iter() {
-        detector.add_template(
-            &template_settings.name,
-            &template_settings.path,
-            template_settings.threshold,
-            template_settings.min_distance,
-            template_settings.red,
-            template_settings.green,
-            template_settings.blue,
-            template_settings.resolution,
-            template_settings.always_active,
-        )?;
-    }
-
-    // Инициализируем начальный диапазон
-    detector.active_range = (0, 50); // Начинаем с Empty + первые 5 бочек
-    let mut last_frame_time = std::time::Instant::now();
-    loop {
-        let screenshot_path = "screenshot.png";
-        let (window_x, window_y) =
-            capture_window_by_title(&settings.window_title, screenshot_path)?;
-        let mut image = imgcodecs::imread(screenshot_path, IMREAD_COLOR)
-            .map_err(|e| AppError::ImageProcessing(format!("Failed to load screenshot: {}", e)))?;
-
-        if image.empty() {
-            return Err(AppError::ImageProcessing(
-                "Loaded image is empty".to_string(),
-            ));
+    check_template_files(&settings.templates)?;
+    detector.load_templates(&settings.templates)?;
+    Ok(detector)
+}
+
+// Одно окно игры со своим захватом и детектором; мышь у всех окон общая
+struct Instance {
+    settings: Settings,
+    capture: Box<dyn CaptureBackend>,
+    detector: ObjectDetector,
+    // wmctrl ищет окно по заголовку, при заданном id размер не проверяем
+    check_window_size: bool,
+    last_frame_time: Instant,
+    rescan: RescanBackoff,
+    // Раньше этого момента окно не снимаем: пауза после действий или на паузе
+    next_due: Instant,
+    // Последние кадры этого окна на случай ошибки
+    crash_frames: FrameRing,
+}
+
+impl Instance {
+    fn new(settings: Settings) -> AppResult<Self> {
+        let capture = create_capture_backend(&settings)?;
+        let mut detector = create_detector(&settings)?;
+
+        // Инициализируем начальный диапазон
+        detector.active_range = (0, 50); // Начинаем с Empty + первые 5 бочек
+
+        Ok(Self {
+            check_window_size: check_window_size(&settings),
+            rescan: RescanBackoff::new(settings.rescan_delay, settings.rescan_delay_max),
+            crash_frames: FrameRing::new(settings.crash_frames),
+            settings,
+            capture,
+            detector,
+            last_frame_time: Instant::now(),
+            next_due: Instant::now(),
+        })
+    }
+
+    // Перечитанные настройки для того же окна: шаблоны обновляются на месте
+    fn apply(&mut self, settings: Settings) -> AppResult<()> {
+        let detector = &mut self.detector;
+        check_template_files(&settings.templates)?;
+        detector.load_templates(&settings.templates)?;
+        detector.set_base_scale_factor(settings.resolution)?;
+        detector.multiscale = settings.multiscale;
+        detector.suppression_scale = settings.suppression_scale;
+        detector.static_frame_threshold = settings.static_frame_threshold;
+        detector.tracker.ttl = settings.tracking_ttl_frames;
+        detector.ambiguity_margin = settings.ambiguity_margin;
+        detector.set_use_opencl(settings.use_opencl);
+        detector.set_roi(
+            settings.roi,
+            (settings.reference_width, settings.reference_height),
+        );
+
+        self.capture = create_capture_backend(&settings)?;
+        self.check_window_size = check_window_size(&settings);
+        self.rescan = RescanBackoff::new(settings.rescan_delay, settings.rescan_delay_max);
+        self.crash_frames = FrameRing::new(settings.crash_frames);
+        self.settings = settings;
+        Ok(())
+    }
+
+    fn suggest_window_size(&self) -> AppResult<()> {
+        if !self.check_window_size {
+            return Ok(());
         }
+        check_and_suggest_window_size(
+            &self.settings.window_title,
+            self.settings.reference_width,
+            self.settings.reference_height,
+        )
+    }
+}
 
-        let (detections, detection_time) =
-            detector.detect_objects_optimized(&image, settings.convert_to_grayscale)?;
+fn check_window_size(settings: &Settings) -> bool {
+    settings.capture_backend == CaptureBackendKind::X11
+        && settings.window_id.is_none()
+        && settings.window_match == WindowMatch::Exact
+}
 
-        let (window_width, window_height) = get_window_size(&settings.window_title)?;
+fn create_instances(settings: &Settings) -> AppResult<Vec<Instance>> {
+    settings
+        .instance_settings()
+        .into_iter()
+        .map(Instance::new)
+        .collect()
+}
 
-        let is_on_window = is_cursor_in_window(window_x, window_y, window_width, window_height)?;
+// Общее для всех окон: мышь, статистика и режимы запуска
+struct Session {
+    input: Box<dyn InputBackend>,
+    merge_stats: MergeStats,
+    recorder: Option<FrameRecorder>,
+    debug_mode: bool,
+    infinite_mode: bool,
+    // Куда вернуть курсор после действий
+    original_position: (i32, i32),
+    // Есть только при --serve
+    status: Option<Arc<Mutex<BotState>>>,
+}
 
-        let current_time = std::time::Instant::now();
-        let frame_time = current_time.duration_since(last_frame_time).as_secs_f64();
-        let fps = 1.0 / frame_time;
-        last_frame_time = current_time;
+#[cfg(feature = "http")]
+fn serve_status(host: &str, port: u16) -> AppResult<Option<Arc<Mutex<BotState>>>> {
+    let state = Arc::new(Mutex::new(BotState::default()));
+    spawn_status_server(host, port, Arc::clone(&state))?;
+    Ok(Some(state))
+}
 
-        if debug_mode {
-            detector.draw_detections(&mut image, &detections)?;
-            imgcodecs::imwrite("result.png", &image, &Vector::new())?;
+#[cfg(not(feature = "http"))]
+fn serve_status(_host: &str, _port: u16) -> AppResult<Option<Arc<Mutex<BotState>>>> {
+    warn!("--serve needs a build with --features http, status endpoint disabled");
+    Ok(None)
+}
+
+// Применяет перечитанный settings.json к уже работающему боту
+fn apply_reloaded_settings(
+    settings: &mut Settings,
+    mut reloaded: Settings,
+    instances: &mut Vec<Instance>,
+    input: &mut Box<dyn InputBackend>,
+    overrides: &CliOverrides,
+) -> AppResult<()> {
+    overrides.apply(&mut reloaded);
+
+    // Тот же набор окон обновляем на месте, иначе собираем заново
+    let configs = reloaded.instance_settings();
+    if configs.len() == instances.len() {
+        for (instance, config) in instances.iter_mut().zip(configs) {
+            instance.apply(config)?;
         }
+    } else {
+        *instances = create_instances(&reloaded)?;
+    }
+
+    // adb-бэкенды зависят от reference_width/height и серийного номера
+    if reloaded.input_backend != settings.input_backend
+        || reloaded.dry_run != settings.dry_run
+        || reloaded.mouse_button != settings.mouse_button
+        || reloaded.input_backend == InputBackendKind::Adb
+    {
+        *input = create_input_backend(&reloaded)?;
+    }
 
-        let (original_x, original_y) = utils::get_currect_mouse_potision()?;
+    *settings = reloaded;
+    Ok(())
+}
+
+// Один кадр одного окна: снимок, детекция и действия.
+// false - пора остановиться (одиночный проход без --infinite)
+fn run_tick(instance: &mut Instance, session: &mut Session) -> AppResult<bool> {
+    let ((mut image, window_x, window_y), window_returned) = capture_with_retry(
+        instance.capture.as_mut(),
+        &instance.settings.window_retry,
+        &instance.settings.notifications,
+    )?;
+    // Окно могли открыть заново уже другого размера
+    if window_returned {
+        instance.suggest_window_size()?;
+    }
+    instance.crash_frames.push(&image)?;
 
-        // Обработка облака мангинитов
-        let cloud: Vec<DetectionResult> = detections
-            .clone()
-            .into_iter()
-            .filter(|d| d.object_name.starts_with("Cloud"))
-            .collect();
+    let settings = &instance.settings;
+    let capture = &mut instance.capture;
+    let detector = &mut instance.detector;
+    let input = &mut session.input;
 
-        if cloud.len() > 0 && settings.automation.shtorm.enabled {
-            for _ in 1..settings.automation.shtorm.retries {
-                process_magnets_cloud(window_x, window_y, window_width, window_height)?;
+    let render_tui = settings.renders_tui();
 
-                // После обработки облака продолжаем основной цикл
-                thread::sleep(Duration::from_millis(3));
-            }
+    let (detections, detection_time) =
+        detector.detect_objects_optimized(&image, settings.color_space())?;
+    // Таблица берёт отслеживаемые объекты, чтобы бочки не мигали на границе порога
+    let tracked = detector.tracker.update(&detections);
+
+    let (window_width, window_height) = capture.window_size()?;
+
+    let is_on_window = is_cursor_in_window(
+        input.as_mut(),
+        window_x,
+        window_y,
+        window_width,
+        window_height,
+    )?;
+
+    let current_time = Instant::now();
+    let frame = FrameStats::new(
+        detection_time,
+        current_time.duration_since(instance.last_frame_time),
+        detector.active_range,
+    );
+    instance.last_frame_time = current_time;
+
+    if let Some(status) = &session.status {
+        let mut state = status.lock().unwrap_or_else(|e| e.into_inner());
+        state.window = settings.window_title.clone();
+        state.update_frame(&frame);
+        state.paused = paused();
+        state.update_stats(&session.merge_stats);
+    }
+
+    if settings.output_format == OutputFormat::Json {
+        print_detections_json(&detections, &frame);
+    }
+
+    // Снимок до разметки и после неё
+    if session.debug_mode {
+        let path = settings.paths.screenshot_path();
+        imgcodecs::imwrite(&path.to_string_lossy(), &image, &Vector::new())?;
+    }
+    if session.debug_mode || session.recorder.is_some() {
+        detector.draw_detections(&mut image, &detections)?;
+    }
+    if session.debug_mode {
+        let path = settings.paths.debug_output_path();
+        imgcodecs::imwrite(&path.to_string_lossy(), &image, &Vector::new())?;
+    }
+    if let Some(recorder) = &session.recorder {
+        recorder.record(&image)?;
+    }
+
+    // На паузе показываем состояние, но мышью не двигаем
+    if paused() {
+        if render_tui {
+            clear_screen(settings.display.preserve_scrollback)?;
+            let (cols, rows) = grid_size(settings.grid_cols, settings.grid_rows, &tracked);
+            display_results_as_table(
+                &tracked,
+                cols,
+                rows,
+                &detector.templates,
+                &frame,
+                TableStyle {
+                    color_mode: settings.display.color_mode,
+                    opencl: detector.use_opencl,
+                    low_confidence_margin: settings.display.low_confidence_margin,
+                },
+            );
+            println!("Paused, press {} to resume", settings.hotkeys.pause);
+        }
+        instance.next_due = Instant::now() + Duration::from_millis(settings.rescan_delay);
+        return Ok(true);
+    }
 
-            if !is_on_window && settings.human_like_movement.enabled {
-                human_like_move(original_x, original_y, &settings.human_like_movement)?;
-            } else if !&settings.human_like_movement.enabled {
-                human_like_move(original_x, original_y, &settings.human_like_movement)?;
+    session.original_position = input.get_position()?;
+    let (original_x, original_y) = session.original_position;
+
+    // Капча перекрывает поле: пока она на экране, облако и бочки ждут
+    let captcha = detections
+        .iter()
+        .find(|d| d.category == TemplateCategory::Captcha);
+    if let Some(captcha) = captcha {
+        notify(
+            &settings.notifications,
+            &format!("{} on screen", captcha.object_name),
+        );
+        if settings.automation.anticaptcha.enabled {
+            if process_anticaptcha(input.as_mut(), window_x, window_y, captcha, settings)? {
+                PAUSED.store(true, Ordering::SeqCst);
             }
-            continue;
+            return Ok(true);
         }
+    }
+
+    // Окно конца уровня закрывает поле: жмём продолжение и начинаем новый уровень
+    if process_ui(
+        input.as_mut(),
+        window_x,
+        window_y,
+        &detections,
+        detector,
+        settings,
+    )? {
+        return Ok(true);
+    }
+
+    // Обработка облака мангинитов
+    let cloud: Vec<DetectionResult> = detections
+        .clone()
+        .into_iter()
+        .filter(|d| d.category == TemplateCategory::Cloud)
+        .collect();
 
-        // Обработка бочек
-        let mut barrels: Vec<DetectionResult> = detections
-            .clone()
-            .into_iter()
-            .filter(|d| d.object_name.starts_with("Barrel"))
-            .collect();
+    if cloud.len() > 0 && settings.automation.shtorm.enabled {
+        for _ in 1..settings.automation.shtorm.retries {
+            process_magnets_cloud(
+                input.as_mut(),
+                window_x,
+                window_y,
+                window_width,
+                window_height,
+                settings,
+            )?;
 
-        if barrels.len() > 0 && settings.automation.merge.enabled {
+            // После обработки облака продолжаем основной цикл
+            thread::sleep(Duration::from_millis(3));
+        }
+
+        if !is_on_window && settings.human_like_movement.enabled {
+            human_like_move(
+                input.as_mut(),
+                original_x,
+                original_y,
+                &settings.human_like_movement,
+            )?;
+        } else if !&settings.human_like_movement.enabled {
+            human_like_move(
+                input.as_mut(),
+                original_x,
+                original_y,
+                &settings.human_like_movement,
+            )?;
+        }
+        return Ok(true);
+    }
+
+    // Обработка бочек
+    let merge_source = if settings.automation.merge.use_tracked {
+        &tracked
+    } else {
+        &detections
+    };
+    let mut barrels: Vec<DetectionResult> = merge_source
+        .clone()
+        .into_iter()
+        .filter(|d| d.category == TemplateCategory::Barrel)
+        .collect();
+
+    let automation = &settings.automation;
+    if barrels.len() > 0 && (automation.merge.enabled || automation.collect.enabled) {
+        if render_tui {
             // Очищаем терминал и выводим информацию
-            clear_screen()?;
+            clear_screen(settings.display.preserve_scrollback)?;
+            let (cols, rows) = grid_size(settings.grid_cols, settings.grid_rows, &tracked);
             display_results_as_table(
-                &detections,
-                4,
-                5,
+                &tracked,
+                cols,
+                rows,
                 &detector.templates,
-                detection_time.try_into().unwrap_or(0),
-                fps,
+                &frame,
+                TableStyle {
+                    color_mode: settings.display.color_mode,
+                    opencl: detector.use_opencl,
+                    low_confidence_margin: settings.display.low_confidence_margin,
+                },
             );
+        }
 
-            // Сортируем бочки по номеру (от меньшего к большему)
-            barrels.sort_by(|a, b| {
-                let num_a = a
-                    .object_name
-                    .split_whitespace()
-                    .last()
-                    .unwrap_or("0")
-                    .parse()
-                    .unwrap_or(0);
-                let num_b = b
-                    .object_name
-                    .split_whitespace()
-                    .last()
-                    .unwrap_or("0")
-                    .parse()
-                    .unwrap_or(0);
-                num_a.cmp(&num_b)
-            });
-
-            let _ = process_barrels(window_x, window_y, barrels, &mut detector, &settings)?;
-
-            if !is_on_window && settings.human_like_movement.enabled {
-                human_like_move(original_x, original_y, &settings.human_like_movement)?;
-            } else if !&settings.human_like_movement.enabled {
-                human_like_move(original_x, original_y, &settings.human_like_movement)?;
-            }
-            if !infinite_mode {
-                break;
+        // Сортируем бочки по номеру (от меньшего к большему)
+        barrels.sort_by_key(|barrel| barrel.barrel_level().unwrap_or(0));
+
+        // Продаём то, что осталось после слияний
+        let merges_before = session.merge_stats.merges_performed;
+        let mut collected = 0;
+        if automation.merge.enabled {
+            let (cols, rows) = grid_size(settings.grid_cols, settings.grid_rows, merge_source);
+            let grid = ObjectDetector::build_occupancy_grid(merge_source, rows, cols);
+            barrels = process_barrels(
+                input.as_mut(),
+                capture.as_mut(),
+                window_x,
+                window_y,
+                barrels,
+                grid,
+                detector,
+                settings,
+                &mut session.merge_stats,
+            )?;
+        }
+        // До продажи: унесённая бочка нужного уровня тоже засчитывается
+        if let Some(level) = barrels.iter().filter_map(|b| b.barrel_level()).max() {
+            session.merge_stats.observe_level(level);
+        }
+        if automation.collect.enabled {
+            collected = process_collect(
+                input.as_mut(),
+                capture.as_mut(),
+                window_x,
+                window_y,
+                &barrels,
+                detector,
+                settings,
+                &mut session.merge_stats,
+            )?;
+        }
+        if render_tui {
+            display_stats(&session.merge_stats);
+        }
+
+        if !is_on_window && settings.human_like_movement.enabled {
+            human_like_move(
+                input.as_mut(),
+                original_x,
+                original_y,
+                &settings.human_like_movement,
+            )?;
+        } else if !&settings.human_like_movement.enabled {
+            human_like_move(
+                input.as_mut(),
+                original_x,
+                original_y,
+                &settings.human_like_movement,
+            )?;
+        }
+        if !session.infinite_mode {
+            return Ok(false);
+        }
+
+        if !is_on_window && !settings.dry_run {
+            idle_wiggle(input.as_mut(), &settings.human_like_movement)?;
+        }
+        let actions = session.merge_stats.merges_performed - merges_before + collected as u64;
+        instance.next_due = Instant::now() + instance.rescan.next(actions);
+    }
+
+    Ok(true)
+}
+
+// Окна обслуживаются по очереди, по одному кадру за проход; окно, которое
+// ещё ждёт своей паузы, пропускается и не задерживает остальные.
+// false - пора остановиться; после Ctrl-C следующие окна уже не трогаем
+fn run_pass(
+    instances: &mut [Instance],
+    session: &mut Session,
+    settings: &Settings,
+) -> AppResult<bool> {
+    let mut keep_running = true;
+    for instance in instances {
+        if shutdown_requested() {
+            return Ok(false);
+        }
+        if instance.next_due > Instant::now() {
+            continue;
+        }
+        keep_running &= run_tick(instance, session).inspect_err(|e| {
+            dump_crash_frames(&instance.crash_frames, settings);
+            notify(&settings.notifications, &format!("Bot stopped: {}", e));
+        })?;
+    }
+    Ok(keep_running)
+}
+
+// Сон до ближайшего окна, которому пора снимать кадр, но не меньше 5 мс
+fn idle_time(instances: &[Instance], now: Instant) -> Duration {
+    instances
+        .iter()
+        .map(|instance| instance.next_due.saturating_duration_since(now))
+        .min()
+        .unwrap_or_default()
+        .max(Duration::from_millis(5))
+}
+
+// Кадры перед ошибкой, чтобы её можно было воспроизвести и приложить к баг-репорту
+fn dump_crash_frames(frames: &FrameRing, settings: &Settings) {
+    let dir = settings.paths.crash_dir_path();
+    match frames.dump(&dir) {
+        Ok(0) => {}
+        Ok(count) => warn!("Last {} frames saved to {}", count, dir.display()),
+        Err(e) => warn!("Could not save frames before the error: {}", e),
+    }
+}
+
+fn stop_reason(limits: &StopLimits, stats: &MergeStats) -> Option<String> {
+    if let Some(target_level) = limits.target_level {
+        if stats.highest_seen >= target_level {
+            return Some(format!(
+                "Barrel {} reached in {}s after {} merges",
+                target_level,
+                stats.runtime().as_secs(),
+                stats.merges_performed
+            ));
+        }
+    }
+    if let Some(max_merges) = limits.max_merges {
+        if stats.merges_performed >= max_merges {
+            return Some(format!("Reached {} merges", stats.merges_performed));
+        }
+    }
+    if let Some(max_runtime_secs) = limits.max_runtime_secs {
+        if stats.runtime() >= Duration::from_secs(max_runtime_secs) {
+            return Some(format!("Ran for {}s", max_runtime_secs));
+        }
+    }
+    None
+}
+
+fn arg_value<'a>(args: &'a [String], names: &[&str]) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| names.contains(&arg.as_str()))
+        .and_then(|i| args.get(i + 1))
+        .map(|value| value.as_str())
+}
+
+fn parse_output_format(value: &str) -> AppResult<OutputFormat> {
+    match value {
+        "table" => Ok(OutputFormat::Table),
+        "json" => Ok(OutputFormat::Json),
+        _ => Err(AppError::SettingsError(format!(
+            "Invalid --format: {} (expected table or json)",
+            value
+        ))),
+    }
+}
+
+fn main() -> AppResult<()> {
+    let args: Vec<String> = env::args().collect();
+    let infinite_mode =
+        args.iter().any(|arg| arg == "--infinite" || arg == "-i") || env_flag("SCRAP2_INFINITE");
+    let debug_mode =
+        args.iter().any(|arg| arg == "--debug" || arg == "-d") || env_flag("SCRAP2_DEBUG");
+    // Диагностика идёт в stderr через log, stdout остаётся за таблицей и json
+    let mut logger = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    if let Some(level) = arg_value(&args, &["--log-level"]) {
+        logger.parse_filters(level);
+    }
+    logger.init();
+
+    // Палитра для подбора цветов нового шаблона
+    if let Some(image_path) = arg_value(&args, &["--dominant-colors"]) {
+        let k = arg_value(&args, &["--k"])
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+        // Результат команды, а не диагностика: в stdout
+        println!("Доминирующие цвета:");
+        for (i, (b, g, r)) in get_dominant_colors(image_path, k)?.iter().enumerate() {
+            println!("Цвет {}: B:{}, G:{}, R:{}", i, b, g, r);
+        }
+        return Ok(());
+    }
+
+    // Заголовки окон в кавычках: видно хвостовые пробелы, которые ломают xwininfo -name
+    if args.iter().any(|arg| arg == "--list-windows") {
+        for (id, title) in list_windows()? {
+            println!("0x{:08x}  {:?}", id, title);
+        }
+        return Ok(());
+    }
+
+    // Замер детекции на сохранённых скриншотах, без окна и мыши
+    if let Some(dir) = arg_value(&args, &["--bench"]) {
+        let settings = Settings::reload(SETTINGS_PATH)?;
+        let mut detector = create_detector(&settings)?;
+        return run_bench(&mut detector, dir, settings.color_space());
+    }
+
+    // Пороги шаблонов по размеченным скриншотам, результат сразу в settings.json
+    if let Some(dir) = arg_value(&args, &["--calibrate"]) {
+        let settings = Settings::reload(SETTINGS_PATH)?;
+        let mut detector = create_detector(&settings)?;
+        return run_calibration(&mut detector, dir, settings.color_space(), SETTINGS_PATH);
+    }
+
+    // Флаг важнее settings.json, встроенное имя окна - крайний случай
+    let overrides = CliOverrides {
+        window_title: arg_value(&args, &["--window-title", "-w"]),
+        window_id: arg_value(&args, &["--window-id"]),
+        screenshot_path: arg_value(&args, &["--screenshot-path"]),
+        debug_path: arg_value(&args, &["--debug-path"]),
+        action_log: arg_value(&args, &["--action-log"]),
+        dry_run: args.iter().any(|arg| arg == "--dry-run"),
+        quiet: args.iter().any(|arg| arg == "--quiet" || arg == "-q"),
+        // Таблица по умолчанию, json - одна строка на кадр для внешних инструментов
+        format: arg_value(&args, &["--format"])
+            .map(parse_output_format)
+            .transpose()?,
+        seed: arg_value(&args, &["--seed"]).and_then(|v| v.parse().ok()),
+        max_runtime_secs: arg_value(&args, &["--max-runtime"]).and_then(|v| v.parse().ok()),
+        max_merges: arg_value(&args, &["--max-merges"]).and_then(|v| v.parse().ok()),
+        target_level: arg_value(&args, &["--target-level"]).and_then(|v| v.parse().ok()),
+    };
+
+    // Настройки, с которыми бот бы запустился, для отчётов об ошибках
+    if args.iter().any(|arg| arg == "--print-config") {
+        println!("{}", config_json(SETTINGS_PATH, &overrides)?);
+        return Ok(());
+    }
+
+    ctrlc::set_handler(|| SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst))
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    let window_title = overrides
+        .window_title
+        .map(str::to_string)
+        .or_else(|| env::var("SCRAP2_WINDOW_TITLE").ok())
+        .unwrap_or_else(|| DEFAULT_WINDOW_TITLE.to_string());
+    let mut settings = load_or_create_settings(&window_title)?;
+    overrides.apply(&mut settings);
+    // Заголовок терминала - тоже escape-последовательность в stdout
+    if settings.renders_tui() {
+        execute!(std::io::stdout(), SetTitle("Scrap II Bot"))?;
+    }
+    seed_rng(settings.seed);
+    if let Some(seed) = settings.seed {
+        info!("Random seed: {}", seed);
+    }
+    check_dependencies(&settings)?;
+    match &settings.window_id {
+        Some(window_id) => info!("Target window id: {}", window_id),
+        None => info!("Target window: {}", settings.window_title),
+    }
+    if settings.display.color_mode == ColorMode::Truecolor && !terminal_supports_truecolor() {
+        warn!("COLORTERM does not report truecolor, consider display.color_mode ansi256 or mono");
+    }
+
+    // Записанные --action-log команды мыши без захвата и детекции
+    if let Some(log) = arg_value(&args, &["--replay"]) {
+        let actions = read_action_log(log)?;
+        // Воспроизведение не дописывает журнал, который само читает
+        settings.paths.action_log = None;
+        let mut input = create_input_backend(&settings)?;
+        let replayed = replay_actions(&actions, input.as_mut())?;
+        info!(
+            "Replayed {} of {} actions from {}",
+            replayed,
+            actions.len(),
+            log
+        );
+        return Ok(());
+    }
+
+    // Пики одного шаблона на текущем кадре, чтобы подобрать ему threshold
+    if let Some(template_name) = arg_value(&args, &["--threshold-scan"]) {
+        let top_k = arg_value(&args, &["--k"])
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(50);
+        let mut capture = create_capture_backend(&settings)?;
+        let detector = create_detector(&settings)?;
+        let (image, _, _) = capture.capture()?;
+        let confidences =
+            detector.scan_confidences(&image, template_name, settings.color_space(), top_k)?;
+        let threshold = detector
+            .templates
+            .iter()
+            .find(|t| t.name == template_name)
+            .map_or(0.0, |t| t.threshold);
+        print_confidence_histogram(template_name, &confidences, threshold);
+        return Ok(());
+    }
+
+    // Новый шаблон из текущего кадра окна
+    if args.iter().any(|arg| arg == "--add-template") {
+        let mut capture = create_capture_backend(&settings)?;
+        return run_template_wizard(capture.as_mut(), &settings, SETTINGS_PATH);
+    }
+
+    let mut instances = create_instances(&settings)?;
+    for instance in &instances {
+        instance.suggest_window_size()?;
+    }
+    if instances.len() > 1 {
+        info!("Serving {} game windows", instances.len());
+    }
+
+    let input = create_input_backend(&settings)?;
+
+    // Без X11-захвата клавиши бот работает как раньше, просто без паузы
+    if let Err(e) = spawn_pause_listener(&settings.hotkeys.pause) {
+        warn!("Pause hotkey unavailable: {}", e);
+    }
+
+    // Физический курсор читается через xdotool, поэтому только для X11
+    let failsafe = &settings.failsafe;
+    if failsafe.enabled && settings.capture_backend == CaptureBackendKind::X11 {
+        if let Err(e) = spawn_failsafe_watcher(failsafe.margin_px, failsafe.poll_ms) {
+            warn!("Failsafe unavailable: {}", e);
+        }
+    }
+
+    // Размеченные кадры в каталог, при --record-max только последние N
+    let recorder = match arg_value(&args, &["--record"]) {
+        Some(dir) => {
+            let max_frames = arg_value(&args, &["--record-max"]).and_then(|v| v.parse().ok());
+            info!("Recording annotated frames to {}", dir);
+            Some(FrameRecorder::new(dir, max_frames)?)
+        }
+        None => None,
+    };
+
+    // Статус по умолчанию слушается только локально, --serve-host открывает его в сеть
+    let status = match arg_value(&args, &["--serve"]) {
+        Some(port) => serve_status(
+            arg_value(&args, &["--serve-host"]).unwrap_or("127.0.0.1"),
+            port.parse()
+                .map_err(|_| AppError::SettingsError(format!("Invalid --serve port: {}", port)))?,
+        )?,
+        None => None,
+    };
+
+    let mut session = Session {
+        original_position: (0, 0),
+        input,
+        merge_stats: MergeStats::new(),
+        recorder,
+        debug_mode,
+        infinite_mode,
+        status,
+    };
+    session.original_position = session.input.get_position()?;
+    let mut settings_modified = Settings::modified_at(SETTINGS_PATH);
+    loop {
+        if shutdown_requested() {
+            break;
+        }
+
+        // Подхватываем правки settings.json без перезапуска
+        let modified = Settings::modified_at(SETTINGS_PATH);
+        if modified != settings_modified {
+            settings_modified = modified;
+            match Settings::reload(SETTINGS_PATH).and_then(|reloaded| {
+                apply_reloaded_settings(
+                    &mut settings,
+                    reloaded,
+                    &mut instances,
+                    &mut session.input,
+                    &overrides,
+                )
+            }) {
+                Ok(()) => info!("Settings reloaded from {}", SETTINGS_PATH),
+                Err(e) => warn!("Keeping previous settings: {}", e),
             }
+        }
 
-            thread::sleep(Duration::from_millis(settings.rescan_delay));
+        if !run_pass(&mut instances, &mut session, &settings)? {
+            break;
         }
-        thread::sleep(Duration::from_millis(5));
+        // Проверяется после кадра, так что начатое слияние успевает закончиться
+        if let Some(reason) = stop_reason(&settings.limits, &session.merge_stats) {
+            info!("{}, stopping", reason);
+            break;
+        }
+        thread::sleep(idle_time(&instances, Instant::now()));
+    }
+
+    let input = &mut session.input;
+    let (original_x, original_y) = session.original_position;
+    // Ctrl-C: гарантированно отпускаем кнопку и возвращаем курсор на место.
+    // После failsafe только отпускаем: мышь сейчас у пользователя
+    if shutdown_requested() {
+        input.mouse_up()?;
+        if !failsafe_triggered() {
+            input.move_to(original_x, original_y)?;
+        }
+        if settings.renders_tui() {
+            clear_screen(settings.display.preserve_scrollback)?;
+        }
+    }
+    if settings.renders_tui() {
+        display_stats(&session.merge_stats);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::CV_8UC3;
+    use opencv::core::Mat;
+    use opencv::core::Scalar;
+    use scrap2_bot::input::DryRunBackend;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn stops_once_merges_reach_the_cap() {
+        let limits = StopLimits {
+            max_merges: Some(3),
+            ..StopLimits::default()
+        };
+        let mut stats = MergeStats::new();
+
+        let mut frames = 0;
+        while stop_reason(&limits, &stats).is_none() {
+            assert!(frames < 10, "limit of 3 merges never stopped the loop");
+            stats.record_merge(2);
+            frames += 1;
+        }
+        assert_eq!(stats.merges_performed, 3);
+        assert_eq!(frames, 3);
+    }
+
+    #[test]
+    fn no_limits_never_stop() {
+        let mut stats = MergeStats::new();
+        for _ in 0..100 {
+            stats.record_merge(5);
+        }
+        assert!(stop_reason(&StopLimits::default(), &stats).is_none());
+    }
+
+    #[test]
+    fn stops_on_the_frame_where_the_target_level_first_appears() {
+        let limits = StopLimits {
+            target_level: Some(5),
+            ..StopLimits::default()
+        };
+        let mut stats = MergeStats::new();
+
+        // Высший уровень на поле по кадрам; пятёрка появляется на пятом
+        let levels = [2, 3, 3, 4, 5, 6];
+        let stopped_at = levels.iter().position(|&level| {
+            stats.observe_level(level);
+            stop_reason(&limits, &stats).is_some()
+        });
+        assert_eq!(stopped_at, Some(4));
+        let reason = stop_reason(&limits, &stats).unwrap();
+        assert!(reason.starts_with("Barrel 5 reached"), "{}", reason);
+
+        // Бочка, слитая самим ботом, тоже считается
+        let mut stats = MergeStats::new();
+        stats.record_merge(4);
+        assert!(stop_reason(&limits, &stats).is_none());
+        stats.record_merge(5);
+        assert!(stop_reason(&limits, &stats).is_some());
+    }
+
+    #[test]
+    fn env_overrides_the_file_and_cli_overrides_env() {
+        // SAFETY: переменные SCRAP2_* в тестах меняет и читает только этот тест
+        unsafe {
+            env::set_var("SCRAP2_WINDOW_TITLE", "From env");
+            env::set_var("SCRAP2_RESOLUTION", "0.5");
+            env::set_var("SCRAP2_RESCAN_DELAY", "3000");
+        }
+
+        let mut settings = Settings::default();
+        CliOverrides::default().apply(&mut settings);
+        assert_eq!(settings.window_title, "From env");
+        assert_eq!(settings.resolution, 0.5);
+        assert_eq!(settings.rescan_delay, 3000);
+        assert_eq!(settings.rescan_delay_max, 3000);
+
+        let mut settings = Settings::default();
+        CliOverrides {
+            window_title: Some("From CLI"),
+            ..CliOverrides::default()
+        }
+        .apply(&mut settings);
+        assert_eq!(settings.window_title, "From CLI");
+        assert_eq!(settings.resolution, 0.5);
+
+        unsafe {
+            env::remove_var("SCRAP2_WINDOW_TITLE");
+            env::remove_var("SCRAP2_RESOLUTION");
+            env::remove_var("SCRAP2_RESCAN_DELAY");
+        }
+    }
+
+    // Окно-заглушка: считает снимки и отдаёт серый кадр
+    struct CountingCapture(Rc<Cell<usize>>);
+
+    impl CaptureBackend for CountingCapture {
+        fn capture(&mut self) -> AppResult<(Mat, i32, i32)> {
+            self.0.set(self.0.get() + 1);
+            let frame =
+                Mat::new_rows_cols_with_default(40, 40, CV_8UC3, Scalar::all(128.0)).unwrap();
+            Ok((frame, 0, 0))
+        }
+
+        fn window_size(&mut self) -> AppResult<(i32, i32)> {
+            Ok((40, 40))
+        }
+    }
+
+    fn test_instance(captures: &Rc<Cell<usize>>) -> Instance {
+        let settings = Settings {
+            quiet: true,
+            ..Settings::default()
+        };
+        Instance {
+            capture: Box::new(CountingCapture(Rc::clone(captures))),
+            detector: ObjectDetector::new(1.0),
+            check_window_size: false,
+            last_frame_time: Instant::now(),
+            rescan: RescanBackoff::new(settings.rescan_delay, settings.rescan_delay_max),
+            next_due: Instant::now(),
+            crash_frames: FrameRing::new(2),
+            settings,
+        }
+    }
+
+    fn test_session() -> Session {
+        Session {
+            input: Box::new(DryRunBackend::default()),
+            merge_stats: MergeStats::new(),
+            recorder: None,
+            debug_mode: false,
+            infinite_mode: true,
+            original_position: (0, 0),
+            status: None,
+        }
+    }
+
+    #[test]
+    fn shutdown_skips_the_remaining_windows() {
+        let captures = Rc::new(Cell::new(0));
+        let mut instances = vec![test_instance(&captures), test_instance(&captures)];
+        let mut session = test_session();
+
+        // Флаг читают только run_pass и то, что он вызывает, другие тесты здесь его не ждут
+        SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+        let keep_running = run_pass(&mut instances, &mut session, &Settings::default());
+        SHUTDOWN_REQUESTED.store(false, Ordering::SeqCst);
+
+        assert!(!keep_running.unwrap());
+        assert_eq!(captures.get(), 0);
+    }
+
+    #[test]
+    fn format_flag_switches_stdout_to_json() {
+        let mut settings = Settings::default();
+        CliOverrides {
+            format: Some(parse_output_format("json").unwrap()),
+            ..CliOverrides::default()
+        }
+        .apply(&mut settings);
+        assert_eq!(settings.output_format, OutputFormat::Json);
+        assert!(!settings.renders_tui());
+
+        assert!(parse_output_format("xml").is_err());
+    }
+
+    #[test]
+    fn instances_keep_their_own_range_schedule_and_frames() {
+        let first_captures = Rc::new(Cell::new(0));
+        let second_captures = Rc::new(Cell::new(0));
+        let mut instances = vec![
+            test_instance(&first_captures),
+            test_instance(&second_captures),
+        ];
+        let mut session = test_session();
+
+        // Второе окно только что закончило слияния и ждёт своей паузы
+        let now = Instant::now();
+        instances[0].detector.active_range = (0, 50);
+        instances[1].detector.active_range = (3, 7);
+        instances[1].next_due = now + Duration::from_secs(60);
+
+        assert!(run_pass(&mut instances, &mut session, &Settings::default()).unwrap());
+        assert_eq!((first_captures.get(), second_captures.get()), (1, 0));
+        // Пустое поле сбрасывает диапазон первого окна, второе его не трогает
+        assert_eq!(instances[0].detector.active_range, (0, 0));
+        assert_eq!(instances[1].detector.active_range, (3, 7));
+
+        // Кадр попал только в кольцо снятого окна
+        let dir = env::temp_dir().join(format!("scrap2_instances_{}", std::process::id()));
+        assert_eq!(instances[0].crash_frames.dump(&dir).unwrap(), 1);
+        assert_eq!(instances[1].crash_frames.dump(&dir).unwrap(), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Первое окно готово сразу, поэтому основной цикл не спит дольше минимума
+        assert_eq!(idle_time(&instances, now), Duration::from_millis(5));
+        instances[0].next_due = now + Duration::from_secs(2);
+        assert_eq!(idle_time(&instances, now), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn printed_config_is_the_file_merged_with_the_flags() {
+        let dir = env::temp_dir().join(format!("scrap2_print_config_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.json");
+        let path = path.to_str().unwrap();
+        let mut file = Settings::default();
+        file.mouse_button = 3;
+        file.seed = Some(1);
+        file.limits.max_merges = Some(10);
+        file.save(path).unwrap();
+
+        let overrides = CliOverrides {
+            dry_run: true,
+            seed: Some(42),
+            ..CliOverrides::default()
+        };
+        let printed: serde_json::Value =
+            serde_json::from_str(&config_json(path, &overrides).unwrap()).unwrap();
+        // Из файла то, что флаги не трогали, а флаги важнее файла
+        assert_eq!(printed["mouse_button"], 3);
+        assert_eq!(printed["limits"]["max_merges"], 10);
+        assert_eq!(printed["seed"], 42);
+        assert_eq!(printed["dry_run"], true);
+
+        // Без файла - значения по умолчанию с теми же флагами
+        fs::remove_file(path).unwrap();
+        let printed: serde_json::Value =
+            serde_json::from_str(&config_json(path, &overrides).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(printed["mouse_button"], Settings::default().mouse_button);
+        assert_eq!(printed["limits"]["max_merges"], serde_json::Value::Null);
+        assert_eq!(printed["seed"], 42);
+    }
+}
@@ -1,18 +1,39 @@
 use crate::capture::AppResult;
+use crate::input::InputBackend;
+use crate::settings::Easing;
 use crate::settings::HumanLikeMovementSettings;
+use crate::utils::jitter;
+use crate::utils::rng;
 use rand::Rng;
-use std::f64::consts::PI;
-use std::process::Command;
 use std::thread;
 use std::time::Duration;
 
+// Прямоугольник, за который путь не должен выходить (обычно окно игры)
+#[derive(Debug, Clone, Copy)]
+pub struct Bounds {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Bounds {
+    pub fn clamp(&self, (x, y): (i32, i32)) -> (i32, i32) {
+        (
+            x.clamp(self.x, self.x + self.width),
+            y.clamp(self.y, self.y + self.height),
+        )
+    }
+}
+
 // Функция для генерации кривой Безье с человеческими характеристиками
 pub fn generate_human_like_path(
     start: (i32, i32),
     end: (i32, i32),
     settings: &HumanLikeMovementSettings,
+    bounds: Option<Bounds>,
 ) -> Vec<(i32, i32)> {
-    let mut rng = rand::thread_rng();
+    let mut rng = rng();
     let mut path = Vec::new();
 
     if !settings.enabled {
@@ -24,27 +45,47 @@ pub fn generate_human_like_path(
     // Добавляем начальную точку
     path.push(start);
 
-    // Создаем контрольные точки для кривой Безье
-    let dx = end.0 - start.0;
-    let dy = end.1 - start.1;
-
-    // Количество промежуточных точек
-    let num_points = settings.curve_smoothness.max(2);
+    // Кубическая кривая Безье: контрольные точки на трети и двух третях пути,
+    // сдвинутые перпендикулярно ему. Проекция на направление движения остаётся
+    // линейной, поэтому курсор не пятится, а сама кривая - одна плавная дуга
+    let dx = (end.0 - start.0) as f64;
+    let dy = (end.1 - start.1) as f64;
+    let length = (dx * dx + dy * dy).sqrt();
 
-    // Генерируем небольшие отклонения
-    for i in 1..num_points {
-        let t = i as f64 / num_points as f64;
+    if length >= 1.0 {
+        // На коротких перетаскиваниях изгиб не больше четверти длины, иначе получится петля
+        let budget = settings.max_deviation.min(length / 4.0);
+        let (nx, ny) = (-dy / length, dx / length);
+        let offset1 = jitter(&mut rng, -budget, budget);
+        let offset2 = jitter(&mut rng, -budget, budget);
 
-        // Базовое линейное перемещение
-        let x = start.0 as f64 + dx as f64 * t;
-        let y = start.1 as f64 + dy as f64 * t;
+        let p0 = (start.0 as f64, start.1 as f64);
+        let p1 = (
+            p0.0 + dx / 3.0 + nx * offset1,
+            p0.1 + dy / 3.0 + ny * offset1,
+        );
+        let p2 = (
+            p0.0 + dx * 2.0 / 3.0 + nx * offset2,
+            p0.1 + dy * 2.0 / 3.0 + ny * offset2,
+        );
+        let p3 = (end.0 as f64, end.1 as f64);
 
-        // Добавляем случайное отклонение
-        let deviation_scale = (t * PI).sin().abs() * settings.max_deviation;
-        let dev_x = rng.gen_range(-deviation_scale..deviation_scale);
-        let dev_y = rng.gen_range(-deviation_scale..deviation_scale);
+        let num_points = settings.curve_smoothness.max(2);
+        for i in 1..num_points {
+            let t = i as f64 / num_points as f64;
+            let u = 1.0 - t;
+            let (a, b, c, d) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+            let x = a * p0.0 + b * p1.0 + c * p2.0 + d * p3.0;
+            let y = a * p0.1 + b * p1.1 + c * p2.1 + d * p3.1;
+            path.push((x.round() as i32, y.round() as i32));
+        }
+    }
 
-        path.push(((x + dev_x).round() as i32, (y + dev_y).round() as i32));
+    // Иногда проскакиваем цель и возвращаемся; без границ окна не рискуем
+    if let Some(bounds) = bounds {
+        if rng.gen_bool(settings.overshoot_chance.clamp(0.0, 1.0)) {
+            add_overshoot(&mut path, start, end, settings, bounds, &mut rng);
+        }
     }
 
     // Добавляем конечную точку
@@ -53,39 +94,88 @@ pub fn generate_human_like_path(
     path
 }
 
-// Модифицированная функция перемещения
-pub fn human_like_move(x: i32, y: i32, settings: &HumanLikeMovementSettings) -> AppResult<()> {
-    let mut rng = rand::thread_rng();
-
-    if !settings.enabled {
-        Command::new("xdotool")
-            .args(&["mousemove", &x.to_string(), &y.to_string()])
-            .status()?;
-        return Ok(());
+// Точка за end по направлению движения и точка коррекции на полпути обратно
+fn add_overshoot(
+    path: &mut Vec<(i32, i32)>,
+    start: (i32, i32),
+    end: (i32, i32),
+    settings: &HumanLikeMovementSettings,
+    bounds: Bounds,
+    rng: &mut impl Rng,
+) {
+    let dx = (end.0 - start.0) as f64;
+    let dy = (end.1 - start.1) as f64;
+    let length = (dx * dx + dy * dy).sqrt();
+    if length < 1.0 || settings.overshoot_max_px < 1 {
+        return;
     }
 
-    // Получаем текущую позицию курсора
-    let output = Command::new("xdotool")
-        .args(&["getmouselocation", "--shell"])
-        .output()?;
+    let distance = jitter(rng, 1, settings.overshoot_max_px) as f64;
+    let overshoot = bounds.clamp((
+        end.0 + (dx / length * distance).round() as i32,
+        end.1 + (dy / length * distance).round() as i32,
+    ));
+    if overshoot == end {
+        return;
+    }
 
-    let output_str = String::from_utf8(output.stdout)?;
-    let mut current_x = 0;
-    let mut current_y = 0;
+    path.push(overshoot);
+    path.push((
+        (overshoot.0 + end.0) / 2 + jitter(rng, -1, 1),
+        (overshoot.1 + end.1) / 2 + jitter(rng, -1, 1),
+    ));
+}
 
-    for line in output_str.lines() {
-        if line.starts_with("X=") {
-            current_x = line[2..].parse().unwrap_or(0);
-        } else if line.starts_with("Y=") {
-            current_y = line[2..].parse().unwrap_or(0);
+// Момент времени (0..1), к которому курсор проходит долю пути progress;
+// обратная функция к кривой easing
+fn eased_time(easing: Easing, progress: f64) -> f64 {
+    match easing {
+        Easing::Linear => progress,
+        Easing::EaseOut => 1.0 - (1.0 - progress).cbrt(),
+        Easing::EaseInOut => {
+            if progress < 0.5 {
+                (progress / 4.0).cbrt()
+            } else {
+                1.0 - (2.0 * (1.0 - progress)).cbrt() / 2.0
+            }
         }
     }
+}
+
+// Модифицированная функция перемещения
+pub fn human_like_move(
+    input: &mut dyn InputBackend,
+    x: i32,
+    y: i32,
+    settings: &HumanLikeMovementSettings,
+) -> AppResult<()> {
+    human_like_move_within(input, x, y, settings, None)
+}
+
+// То же, но путь не выходит за bounds, что разрешает проскок цели
+pub fn human_like_move_within(
+    input: &mut dyn InputBackend,
+    x: i32,
+    y: i32,
+    settings: &HumanLikeMovementSettings,
+    bounds: Option<Bounds>,
+) -> AppResult<()> {
+    let mut rng = rng();
+
+    if !settings.enabled {
+        return input.move_to(x, y);
+    }
+
+    // Получаем текущую позицию курсора
+    let (current_x, current_y) = input.get_position()?;
 
     // Генерируем путь
-    let path = generate_human_like_path((current_x, current_y), (x, y), settings);
+    let path = generate_human_like_path((current_x, current_y), (x, y), settings, bounds);
 
-    // Двигаемся по пути с переменной скоростью
-    for i in 0..path.len() - 1 {
+    // Время на каждый отрезок: миллисекунды на пиксель с небольшой вариацией
+    let segments = path.len() - 1;
+    let mut move_times = Vec::with_capacity(segments);
+    for i in 0..segments {
         let (from_x, from_y) = path[i];
         let (to_x, to_y) = path[i + 1];
 
@@ -94,24 +184,236 @@ pub fn human_like_move(x: i32, y: i32, settings: &HumanLikeMovementSettings) ->
         let dy = to_y - from_y;
         let distance = ((dx * dx + dy * dy) as f64).sqrt();
 
-        // Базовое время движения (миллисекунды на пиксель)
         let base_speed = settings.base_speed
-            + rng.gen_range(-settings.speed_variation..settings.speed_variation);
-        let move_time = (distance * base_speed).max(1.0) as u64;
+            + jitter(
+                &mut rng,
+                -settings.speed_variation,
+                settings.speed_variation,
+            );
+        move_times.push((distance * base_speed).max(1.0));
+    }
 
-        // Плавное перемещение между точками
-        Command::new("xdotool")
-            .args(&["mousemove_relative", "--", &dx.to_string(), &dy.to_string()])
-            .status()?;
+    // Easing перераспределяет то же общее время: медленно у краёв, быстро в середине
+    if settings.easing != Easing::Linear {
+        let total: f64 = move_times.iter().sum();
+        for (i, move_time) in move_times.iter_mut().enumerate() {
+            let start = eased_time(settings.easing, i as f64 / segments as f64);
+            let end = eased_time(settings.easing, (i + 1) as f64 / segments as f64);
+            *move_time = (total * (end - start)).max(1.0);
+        }
+    }
+
+    // Собираем точки с задержками, бэкенд проходит их за один вызов
+    let mut steps = Vec::with_capacity(segments);
+    for (i, move_time) in move_times.into_iter().enumerate() {
+        let (to_x, to_y) = path[i + 1];
+        let mut delay_ms = move_time as u64;
 
         // Случайная пауза для имитации человеческой реакции
-        if i < path.len() - 2 {
-            let pause_time = rng.gen_range(settings.min_pause_ms..settings.max_pause_ms);
-            thread::sleep(Duration::from_millis(pause_time));
+        if i < segments - 1 {
+            delay_ms += jitter(&mut rng, settings.min_pause_ms, settings.max_pause_ms);
         }
 
-        thread::sleep(Duration::from_millis(move_time));
+        steps.push((to_x, to_y, delay_ms));
+    }
+
+    input.move_along(&steps)
+}
+
+// Случайное шевеление в паузе между сканированиями: короткая дуга в сторону,
+// задержка и возврат ровно в исходную точку, чтобы курсор не уползал
+pub fn idle_wiggle(
+    input: &mut dyn InputBackend,
+    settings: &HumanLikeMovementSettings,
+) -> AppResult<()> {
+    let wiggle = &settings.idle_wiggle;
+    let mut rng = rng();
+    if !wiggle.enabled || wiggle.amplitude_px < 1 || !rng.gen_bool(wiggle.chance.clamp(0.0, 1.0)) {
+        return Ok(());
     }
 
-    Ok(())
+    let (start_x, start_y) = input.get_position()?;
+    let amplitude = wiggle.amplitude_px;
+    let aside_x = start_x + jitter(&mut rng, -amplitude, amplitude);
+    let aside_y = start_y + jitter(&mut rng, -amplitude, amplitude);
+
+    human_like_move(input, aside_x, aside_y, settings)?;
+    thread::sleep(Duration::from_millis(jitter(
+        &mut rng,
+        settings.min_pause_ms,
+        settings.max_pause_ms,
+    )));
+    human_like_move(input, start_x, start_y, settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Запоминает точки и задержки, которые движение отдало бэкенду
+    struct Steps(Vec<(i32, i32, u64)>);
+
+    impl InputBackend for Steps {
+        fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+            self.0.push((x, y, 0));
+            Ok(())
+        }
+
+        fn move_relative(&mut self, _dx: i32, _dy: i32) -> AppResult<()> {
+            Ok(())
+        }
+
+        fn mouse_down(&mut self) -> AppResult<()> {
+            Ok(())
+        }
+
+        fn mouse_up(&mut self) -> AppResult<()> {
+            Ok(())
+        }
+
+        // Курсор в последней записанной точке
+        fn get_position(&mut self) -> AppResult<(i32, i32)> {
+            Ok(self.0.last().map_or((0, 0), |&(x, y, _)| (x, y)))
+        }
+
+        fn move_along(&mut self, path: &[(i32, i32, u64)]) -> AppResult<()> {
+            self.0.extend_from_slice(path);
+            Ok(())
+        }
+    }
+
+    fn curve_settings() -> HumanLikeMovementSettings {
+        HumanLikeMovementSettings {
+            curve_smoothness: 12,
+            ..HumanLikeMovementSettings::default()
+        }
+    }
+
+    #[test]
+    fn bezier_path_ends_exactly_and_never_backs_up() {
+        let settings = curve_settings();
+        for _ in 0..50 {
+            let path = generate_human_like_path((20, 300), (340, 300), &settings, None);
+            assert_eq!(path.first(), Some(&(20, 300)));
+            assert_eq!(path.last(), Some(&(340, 300)));
+            assert_eq!(path.len(), settings.curve_smoothness as usize + 1);
+            assert!(
+                path.windows(2).all(|pair| pair[0].0 <= pair[1].0),
+                "x goes back in {:?}",
+                path
+            );
+            // Контрольные точки сдвинуты не дальше max_deviation, кривая тоже
+            assert!(path.iter().all(|&(_, y)| (y - 300).abs() <= 10));
+
+            let path = generate_human_like_path((50, 400), (60, 40), &settings, None);
+            assert_eq!(path.last(), Some(&(60, 40)));
+            assert!(path.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        }
+    }
+
+    #[test]
+    fn short_moves_bend_at_most_a_quarter_of_their_length() {
+        let settings = curve_settings();
+        for _ in 0..50 {
+            let path = generate_human_like_path((100, 100), (108, 100), &settings, None);
+            assert!(path.iter().all(|&(_, y)| (y - 100).abs() <= 2));
+        }
+    }
+
+    #[test]
+    fn disabled_movement_is_a_straight_jump() {
+        let settings = HumanLikeMovementSettings {
+            enabled: false,
+            ..HumanLikeMovementSettings::default()
+        };
+        assert_eq!(
+            generate_human_like_path((1, 2), (300, 400), &settings, None),
+            vec![(1, 2), (300, 400)]
+        );
+    }
+
+    #[test]
+    fn overshoot_returns_to_the_target_inside_the_bounds() {
+        let settings = HumanLikeMovementSettings {
+            overshoot_chance: 1.0,
+            overshoot_max_px: 12,
+            ..curve_settings()
+        };
+        let bounds = Bounds {
+            x: 0,
+            y: 0,
+            width: 400,
+            height: 400,
+        };
+        for _ in 0..50 {
+            let path = generate_human_like_path((20, 200), (300, 200), &settings, Some(bounds));
+            assert_eq!(path.last(), Some(&(300, 200)));
+            assert!(
+                path.iter().any(|&(x, _)| x > 300),
+                "no overshoot in {:?}",
+                path
+            );
+
+            // У края окна проскок упирается в границу
+            let path = generate_human_like_path((20, 200), (398, 200), &settings, Some(bounds));
+            assert_eq!(path.last(), Some(&(398, 200)));
+            assert!(
+                path.iter()
+                    .all(|&(x, y)| (0..=400).contains(&x) && (0..=400).contains(&y))
+            );
+        }
+    }
+
+    fn segment_delays(easing: Easing) -> Vec<u64> {
+        let settings = HumanLikeMovementSettings {
+            max_deviation: 0.0,
+            speed_variation: 0.0,
+            curve_smoothness: 10,
+            min_pause_ms: 0,
+            max_pause_ms: 0,
+            base_speed: 1.0,
+            easing,
+            ..HumanLikeMovementSettings::default()
+        };
+        let mut steps = Steps(Vec::new());
+        human_like_move_within(&mut steps, 300, 0, &settings, None).unwrap();
+        assert_eq!(steps.0.last().map(|&(x, y, _)| (x, y)), Some((300, 0)));
+        steps.0.iter().map(|&(_, _, delay)| delay).collect()
+    }
+
+    #[test]
+    fn ease_in_out_is_fastest_mid_path() {
+        let delays = segment_delays(Easing::EaseInOut);
+        assert_eq!(delays.len(), 10);
+        let middle = delays[4].min(delays[5]);
+        assert!(middle < delays[0], "{:?}", delays);
+        assert!(middle < delays[9], "{:?}", delays);
+
+        // Общее время то же, что и без easing
+        let linear = segment_delays(Easing::Linear);
+        assert!(linear.iter().all(|&delay| delay == 30), "{:?}", linear);
+        let total: u64 = delays.iter().sum();
+        assert!(total.abs_diff(300) <= delays.len() as u64, "{:?}", delays);
+    }
+
+    #[test]
+    fn idle_wiggle_returns_to_where_it_started() {
+        let mut settings = curve_settings();
+        settings.min_pause_ms = 0;
+        settings.max_pause_ms = 0;
+        settings.idle_wiggle.enabled = true;
+        settings.idle_wiggle.chance = 1.0;
+
+        for _ in 0..20 {
+            let mut steps = Steps(vec![(150, 90, 0)]);
+            idle_wiggle(&mut steps, &settings).unwrap();
+            assert!(steps.0.len() > 1, "the cursor never moved");
+            assert_eq!(steps.0.last().map(|&(x, y, _)| (x, y)), Some((150, 90)));
+        }
+
+        settings.idle_wiggle.enabled = false;
+        let mut steps = Steps(vec![(150, 90, 0)]);
+        idle_wiggle(&mut steps, &settings).unwrap();
+        assert_eq!(steps.0.len(), 1);
+    }
 }
@@ -0,0 +1,118 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::settings::Notifications;
+use crate::settings::NotifyMethod;
+use log::warn;
+use std::io;
+use std::io::Write;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+// Одно и то же сообщение не чаще раза в минуту: капча видна на каждом кадре
+const REPEAT_AFTER: Duration = Duration::from_secs(60);
+
+static LAST_SENT: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+// Зовёт пользователя к компьютеру; сбой самого уведомления только логируется
+pub fn notify(settings: &Notifications, message: &str) {
+    dispatch(settings, message, send);
+}
+
+fn dispatch(
+    settings: &Notifications,
+    message: &str,
+    send: impl FnOnce(NotifyMethod, &str) -> AppResult<()>,
+) {
+    if !settings.enabled || !due(message) {
+        return;
+    }
+    if let Err(e) = send(settings.method, message) {
+        warn!("Notification via {:?} failed: {}", settings.method, e);
+    }
+}
+
+fn due(message: &str) -> bool {
+    let mut sent = LAST_SENT.lock().unwrap_or_else(|e| e.into_inner());
+    sent.retain(|(_, at)| at.elapsed() < REPEAT_AFTER);
+    if sent.iter().any(|(text, _)| text == message) {
+        return false;
+    }
+    sent.push((message.to_string(), Instant::now()));
+    true
+}
+
+fn send(method: NotifyMethod, message: &str) -> AppResult<()> {
+    match method {
+        NotifyMethod::Bell => {
+            // Не в stdout: там может идти json
+            eprint!("\x07");
+            io::stderr().flush()?;
+            Ok(())
+        }
+        NotifyMethod::NotifySend => {
+            let status = Command::new("notify-send")
+                .args(["Scrap II Bot", message])
+                .status()?;
+            if !status.success() {
+                return Err(AppError::IO(io::Error::other(format!(
+                    "notify-send exited with {}",
+                    status
+                ))));
+            }
+            Ok(())
+        }
+        #[cfg(feature = "notify")]
+        NotifyMethod::Desktop => notify_rust::Notification::new()
+            .summary("Scrap II Bot")
+            .body(message)
+            .show()
+            .map(|_| ())
+            .map_err(|e| AppError::IO(io::Error::other(e.to_string()))),
+        #[cfg(not(feature = "notify"))]
+        NotifyMethod::Desktop => Err(AppError::SettingsError(
+            "notifications.method desktop needs a build with --features notify".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Какими способами и с каким текстом ушли уведомления
+    fn sent(settings: &Notifications, messages: &[&str]) -> Vec<(NotifyMethod, String)> {
+        let mut sent = Vec::new();
+        for message in messages {
+            dispatch(settings, message, |method, text| {
+                sent.push((method, text.to_string()));
+                Ok(())
+            });
+        }
+        sent
+    }
+
+    #[test]
+    fn dispatcher_uses_the_configured_method_only_when_enabled() {
+        let mut settings = Notifications {
+            enabled: false,
+            method: NotifyMethod::NotifySend,
+        };
+        assert!(sent(&settings, &["notify test: disabled"]).is_empty());
+
+        settings.enabled = true;
+        // Повтор того же текста в течение минуты не отправляется
+        let messages = ["notify test: captcha", "notify test: captcha"];
+        assert_eq!(
+            sent(&settings, &messages),
+            vec![(NotifyMethod::NotifySend, messages[0].to_string())]
+        );
+
+        settings.method = NotifyMethod::Bell;
+        assert_eq!(
+            sent(&settings, &["notify test: window lost"]),
+            vec![(NotifyMethod::Bell, "notify test: window lost".to_string())]
+        );
+    }
+}
@@ -1,37 +1,77 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::settings::MatchColorSpace;
+use crate::settings::MatchMethod;
+use crate::settings::TemplateCategory;
+use crate::settings::TemplateSettings;
+use crate::tracker::DetectionTracker;
 use crate::utils::extract_barrel_number;
+use log::debug;
+use log::info;
 use opencv::Result as OpenCVResult;
 use opencv::core::AlgorithmHint;
+use opencv::core::BORDER_CONSTANT;
 use opencv::core::CV_8U;
 use opencv::core::Mat;
 use opencv::core::Point;
+use opencv::core::Point2f;
 use opencv::core::Rect;
 use opencv::core::Scalar;
 use opencv::core::Size;
+use opencv::core::StsBadArg;
+use opencv::core::UMat;
+use opencv::core::Vector;
+use opencv::core::absdiff;
+use opencv::core::extract_channel;
+use opencv::core::have_opencl;
+use opencv::core::in_range;
 use opencv::core::min_max_loc;
+use opencv::core::no_array;
+use opencv::core::set_use_opencl;
 use opencv::imgcodecs;
 use opencv::imgcodecs::IMREAD_COLOR;
+use opencv::imgcodecs::IMREAD_UNCHANGED;
 use opencv::imgproc;
 use opencv::imgproc::COLOR_BGR2GRAY;
+use opencv::imgproc::COLOR_BGR2HSV;
 use opencv::imgproc::FILLED;
 use opencv::imgproc::INTER_AREA;
+use opencv::imgproc::INTER_LINEAR;
+use opencv::imgproc::INTER_NEAREST;
 use opencv::imgproc::LineTypes;
 use opencv::imgproc::THRESH_BINARY;
 use opencv::imgproc::TM_CCOEFF_NORMED;
+use opencv::imgproc::TM_CCORR_NORMED;
+use opencv::imgproc::TM_SQDIFF_NORMED;
 use opencv::imgproc::cvt_color;
 use opencv::imgproc::resize;
 use opencv::imgproc::threshold;
 use opencv::opencv_has_inherent_feature_cuda;
+use opencv::prelude::MatTrait;
 use opencv::prelude::MatTraitConst;
+use opencv::prelude::UMatTraitConst;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
+use serde::Serialize;
+use serde::Serializer;
+use serde::ser::SerializeStruct;
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use std::time::Instant;
 
 #[derive(Clone)]
 pub struct ObjectTemplate {
     pub name: String,
+    pub path: String,
     pub template: Mat,
     pub gray_template: Mat,
+    pub scaled_template: Mat,
+    pub scaled_gray_template: Mat,
+    // Получается из scaled_template при масштабировании, исходник не хранится
+    pub scaled_hsv_template: Mat,
+    pub scale_factor: f64,
     pub threshold: f64,
     pub min_distance: f32,
     pub red: f32,
@@ -39,6 +79,90 @@ pub struct ObjectTemplate {
     pub blue: f32,
     pub resolution: Option<f64>,
     pub always_active: bool,
+    pub match_method: MatchMethod,
+    pub category: TemplateCategory,
+    pub rotation_tolerant: bool,
+    pub mask_color: Option<(u8, u8, u8)>,
+    pub mask_tolerance: u8,
+    // Альфа-канал спрайта как маска для match_template; пустой Mat - без маски
+    pub alpha_mask: Mat,
+    pub scaled_alpha_mask: Mat,
+    // Дополнительные кадры анимации (BGR) и их масштабированные копии;
+    // альфа-маска у всех кадров общая
+    pub frame_paths: Vec<String>,
+    pub frames: Vec<Mat>,
+    pub scaled_frames: Vec<ScaledFrame>,
+}
+
+// Масштабированный кадр анимации во всех пространствах цветов
+#[derive(Clone, Default)]
+pub struct ScaledFrame {
+    pub bgr: Mat,
+    pub gray: Mat,
+    pub hsv: Mat,
+}
+
+impl ScaledFrame {
+    fn new(frame: &Mat, scale_factor: f64) -> OpenCVResult<Self> {
+        let mut scaled = Self::default();
+        resize(
+            frame,
+            &mut scaled.bgr,
+            Size::new(0, 0),
+            scale_factor,
+            scale_factor,
+            INTER_AREA,
+        )?;
+        cvt_color(
+            &scaled.bgr,
+            &mut scaled.gray,
+            COLOR_BGR2GRAY,
+            0,
+            AlgorithmHint::ALGO_HINT_DEFAULT,
+        )?;
+        cvt_color(
+            &scaled.bgr,
+            &mut scaled.hsv,
+            COLOR_BGR2HSV,
+            0,
+            AlgorithmHint::ALGO_HINT_DEFAULT,
+        )?;
+        Ok(scaled)
+    }
+
+    fn get(&self, color_space: MatchColorSpace) -> &Mat {
+        match color_space {
+            MatchColorSpace::Gray => &self.gray,
+            MatchColorSpace::Bgr => &self.bgr,
+            MatchColorSpace::Hsv => &self.hsv,
+        }
+    }
+}
+
+// Допуск по каждому каналу, если в настройках задан только mask_color
+pub const DEFAULT_MASK_TOLERANCE: u8 = 4;
+
+fn zero_background(image: &mut Mat, color: (u8, u8, u8), tolerance: u8) -> OpenCVResult<()> {
+    let bound = |channel: u8, delta: i32| (channel as i32 + delta).clamp(0, u8::MAX as i32) as f64;
+    let tolerance = tolerance as i32;
+    let (b, g, r) = color;
+    let lower = Scalar::new(
+        bound(b, -tolerance),
+        bound(g, -tolerance),
+        bound(r, -tolerance),
+        0.0,
+    );
+    let upper = Scalar::new(
+        bound(b, tolerance),
+        bound(g, tolerance),
+        bound(r, tolerance),
+        0.0,
+    );
+
+    let mut mask = Mat::default();
+    in_range(&*image, &lower, &upper, &mut mask)?;
+    image.set_to(&Scalar::all(0.0), &mask)?;
+    Ok(())
 }
 
 impl ObjectTemplate {
@@ -52,9 +176,84 @@ impl ObjectTemplate {
         blue: f32,
         resolution: Option<f64>,
         always_active: bool,
+        match_method: MatchMethod,
+        mask_color: Option<(u8, u8, u8)>,
+        mask_tolerance: Option<u8>,
     ) -> OpenCVResult<Self> {
         let template = imgcodecs::imread(template_path, IMREAD_COLOR)?;
+        // imread не падает на отсутствующем файле, а молча отдаёт пустой Mat
+        if template.empty() {
+            return Err(opencv::Error::new(
+                StsBadArg,
+                format!("Template {} could not be read", template_path),
+            ));
+        }
+
+        let mut object_template = Self::from_image(
+            name,
+            template,
+            threshold,
+            min_distance,
+            red,
+            green,
+            blue,
+            resolution,
+            always_active,
+            match_method,
+            mask_color,
+            mask_tolerance,
+        )?;
+        object_template.path = template_path.to_string();
+        Ok(object_template)
+    }
+
+    // Шаблон из закодированного PNG в памяти, например из include_bytes!
+    pub fn from_bytes(
+        name: &str,
+        data: &[u8],
+        threshold: f64,
+        min_distance: f32,
+        red: f32,
+        green: f32,
+        blue: f32,
+        resolution: Option<f64>,
+        always_active: bool,
+        match_method: MatchMethod,
+        mask_color: Option<(u8, u8, u8)>,
+        mask_tolerance: Option<u8>,
+    ) -> OpenCVResult<Self> {
+        let template = imgcodecs::imdecode(&Vector::<u8>::from_slice(data), IMREAD_COLOR)?;
+
+        Self::from_image(
+            name,
+            template,
+            threshold,
+            min_distance,
+            red,
+            green,
+            blue,
+            resolution,
+            always_active,
+            match_method,
+            mask_color,
+            mask_tolerance,
+        )
+    }
 
+    fn from_image(
+        name: &str,
+        template: Mat,
+        threshold: f64,
+        min_distance: f32,
+        red: f32,
+        green: f32,
+        blue: f32,
+        resolution: Option<f64>,
+        always_active: bool,
+        match_method: MatchMethod,
+        mask_color: Option<(u8, u8, u8)>,
+        mask_tolerance: Option<u8>,
+    ) -> OpenCVResult<Self> {
         let mut gray_template = Mat::default();
         cvt_color(
             &template,
@@ -64,10 +263,15 @@ impl ObjectTemplate {
             AlgorithmHint::ALGO_HINT_DEFAULT,
         )?;
 
-        Ok(Self {
+        let mut object_template = Self {
             name: name.to_string(),
+            path: String::new(),
             template,
             gray_template,
+            scaled_template: Mat::default(),
+            scaled_gray_template: Mat::default(),
+            scaled_hsv_template: Mat::default(),
+            scale_factor: 0.0,
             threshold,
             min_distance,
             red,
@@ -75,15 +279,478 @@ impl ObjectTemplate {
             blue,
             resolution,
             always_active,
+            match_method,
+            category: TemplateCategory::from_name(name),
+            rotation_tolerant: false,
+            mask_color: None,
+            mask_tolerance: 0,
+            alpha_mask: Mat::default(),
+            scaled_alpha_mask: Mat::default(),
+            frame_paths: Vec::new(),
+            frames: Vec::new(),
+            scaled_frames: Vec::new(),
+        };
+        if let Some(color) = mask_color {
+            object_template
+                .mask_background(color, mask_tolerance.unwrap_or(DEFAULT_MASK_TOLERANCE))?;
+        }
+        Ok(object_template)
+    }
+
+    pub fn from_settings(settings: &TemplateSettings) -> OpenCVResult<Self> {
+        let mut template = Self::new(
+            &settings.name,
+            &settings.path,
+            settings.threshold,
+            settings.min_distance,
+            settings.red,
+            settings.green,
+            settings.blue,
+            settings.resolution,
+            settings.always_active,
+            settings.match_method,
+            settings.mask_color,
+            settings.mask_tolerance,
+        )?;
+        template.category = settings.category();
+        template.rotation_tolerant = settings.rotation_tolerant;
+        template.load_frames(&settings.frames)?;
+        if settings.use_alpha {
+            template.load_alpha_mask(&settings.path)?;
+        }
+        Ok(template)
+    }
+
+    // Кадры анимации должны совпадать с основным спрайтом по размеру,
+    // иначе маска и положение детекции для них не подходят
+    pub fn load_frames(&mut self, paths: &[String]) -> OpenCVResult<()> {
+        let mut frames = Vec::with_capacity(paths.len());
+        for path in paths {
+            let frame = imgcodecs::imread(path, IMREAD_COLOR)?;
+            if frame.empty() {
+                return Err(opencv::Error::new(
+                    StsBadArg,
+                    format!("Template frame {} could not be read", path),
+                ));
+            }
+            if frame.size()? != self.template.size()? {
+                return Err(opencv::Error::new(
+                    StsBadArg,
+                    format!(
+                        "Template frame {} is {}x{}, {} is {}x{}",
+                        path,
+                        frame.cols(),
+                        frame.rows(),
+                        self.name,
+                        self.template.cols(),
+                        self.template.rows()
+                    ),
+                ));
+            }
+            frames.push(frame);
+        }
+        // Фон кадров зануляется той же маской, что и у основного спрайта
+        if let Some(color) = self.mask_color {
+            for frame in &mut frames {
+                zero_background(frame, color, self.mask_tolerance)?;
+            }
+        }
+
+        self.frame_paths = paths.to_vec();
+        self.frames = frames;
+        // Масштабированные копии устарели
+        self.scale_factor = 0.0;
+
+        Ok(())
+    }
+
+    // IMREAD_COLOR отбрасывает альфу, поэтому читаем файл ещё раз целиком
+    pub fn load_alpha_mask(&mut self, template_path: &str) -> OpenCVResult<()> {
+        let rgba = imgcodecs::imread(template_path, IMREAD_UNCHANGED)?;
+        if rgba.channels() != 4 {
+            return Err(opencv::Error::new(
+                StsBadArg,
+                format!("Template {} has no alpha channel", template_path),
+            ));
+        }
+
+        extract_channel(&rgba, &mut self.alpha_mask, 3)?;
+        // Масштабированные копии устарели
+        self.scale_factor = 0.0;
+
+        Ok(())
+    }
+
+    // Тот же файл с той же маской: можно обновить параметры без перечитывания
+    pub fn same_source(&self, settings: &TemplateSettings) -> bool {
+        let mask = |color: Option<(u8, u8, u8)>, tolerance: u8| color.map(|c| (c, tolerance));
+
+        self.name == settings.name
+            && self.path == settings.path
+            && self.frame_paths == settings.frames
+            && self.alpha_mask.empty() != settings.use_alpha
+            && mask(self.mask_color, self.mask_tolerance)
+                == mask(
+                    settings.mask_color,
+                    settings.mask_tolerance.unwrap_or(DEFAULT_MASK_TOLERANCE),
+                )
+    }
+
+    // Зануляет фон цвета color ± tolerance (порядок B, G, R, как в --dominant-colors)
+    pub fn mask_background(&mut self, color: (u8, u8, u8), tolerance: u8) -> OpenCVResult<()> {
+        for image in std::iter::once(&mut self.template).chain(&mut self.frames) {
+            zero_background(image, color, tolerance)?;
+        }
+
+        cvt_color(
+            &self.template,
+            &mut self.gray_template,
+            COLOR_BGR2GRAY,
+            0,
+            AlgorithmHint::ALGO_HINT_DEFAULT,
+        )?;
+
+        self.mask_color = Some(color);
+        self.mask_tolerance = tolerance;
+        // Масштабированные копии устарели
+        self.scale_factor = 0.0;
+
+        Ok(())
+    }
+
+    // Обновляет параметры из настроек без повторного чтения файла шаблона
+    pub fn apply_settings(&mut self, settings: &TemplateSettings) {
+        self.threshold = settings.threshold;
+        self.min_distance = settings.min_distance;
+        self.red = settings.red;
+        self.green = settings.green;
+        self.blue = settings.blue;
+        self.resolution = settings.resolution;
+        self.always_active = settings.always_active;
+        self.match_method = settings.match_method;
+        self.category = settings.category();
+        self.rotation_tolerant = settings.rotation_tolerant;
+    }
+
+    // Пустые клетки, облако, капча и кнопка продолжения ищутся на каждом кадре,
+    // вне зависимости от диапазона бочек
+    pub fn is_always_active(&self) -> bool {
+        self.always_active
+            || matches!(
+                self.category,
+                TemplateCategory::Empty
+                    | TemplateCategory::Cloud
+                    | TemplateCategory::Captcha
+                    | TemplateCategory::Continue
+            )
+    }
+
+    // Уровень есть только у бочек
+    pub fn barrel_level(&self) -> Option<u32> {
+        match self.category {
+            TemplateCategory::Barrel => extract_barrel_number(&self.name),
+            _ => None,
+        }
+    }
+
+    // Масштабированный шаблон в том же пространстве цветов, что и кадр
+    pub fn scaled_for(&self, color_space: MatchColorSpace) -> &Mat {
+        match color_space {
+            MatchColorSpace::Gray => &self.scaled_gray_template,
+            MatchColorSpace::Bgr => &self.scaled_template,
+            MatchColorSpace::Hsv => &self.scaled_hsv_template,
+        }
+    }
+
+    // Основной спрайт и кадры анимации в пространстве цветов кадра
+    pub fn scaled_variants(&self, color_space: MatchColorSpace) -> impl Iterator<Item = &Mat> {
+        std::iter::once(self.scaled_for(color_space)).chain(
+            self.scaled_frames
+                .iter()
+                .map(move |frame| frame.get(color_space)),
+        )
+    }
+
+    pub fn effective_scale(&self, base_scale_factor: f64) -> f64 {
+        self.resolution.unwrap_or(base_scale_factor)
+    }
+
+    // Пересчитывает кэш масштабированных шаблонов под новый коэффициент
+    pub fn rescale(&mut self, base_scale_factor: f64) -> OpenCVResult<()> {
+        let scale_factor = self.effective_scale(base_scale_factor);
+
+        resize(
+            &self.template,
+            &mut self.scaled_template,
+            Size::new(0, 0),
+            scale_factor,
+            scale_factor,
+            INTER_AREA,
+        )?;
+        resize(
+            &self.gray_template,
+            &mut self.scaled_gray_template,
+            Size::new(0, 0),
+            scale_factor,
+            scale_factor,
+            INTER_AREA,
+        )?;
+        cvt_color(
+            &self.scaled_template,
+            &mut self.scaled_hsv_template,
+            COLOR_BGR2HSV,
+            0,
+            AlgorithmHint::ALGO_HINT_DEFAULT,
+        )?;
+        self.scaled_frames = self
+            .frames
+            .iter()
+            .map(|frame| ScaledFrame::new(frame, scale_factor))
+            .collect::<OpenCVResult<_>>()?;
+        if !self.alpha_mask.empty() {
+            // Маска должна остаться бинарной, поэтому без интерполяции
+            resize(
+                &self.alpha_mask,
+                &mut self.scaled_alpha_mask,
+                Size::new(0, 0),
+                scale_factor,
+                scale_factor,
+                INTER_NEAREST,
+            )?;
+        }
+        self.scale_factor = scale_factor;
+
+        Ok(())
+    }
+}
+
+// Константа OpenCV для метода из настроек
+fn opencv_method(match_method: MatchMethod) -> i32 {
+    match match_method {
+        MatchMethod::CcoeffNormed => TM_CCOEFF_NORMED,
+        MatchMethod::CcorrNormed => TM_CCORR_NORMED,
+        MatchMethod::SqdiffNormed => TM_SQDIFF_NORMED,
+    }
+}
+
+// Для SQDIFF переводим результат в 1 - x, чтобы порог и поиск максимума
+// работали одинаково для всех методов сопоставления
+fn normalize_match_result(result_mat: &mut Mat, match_method: MatchMethod) -> OpenCVResult<()> {
+    if match_method.is_sqdiff() {
+        let mut inverted = Mat::default();
+        result_mat.convert_to(&mut inverted, -1, -1.0, 1.0)?;
+        *result_mat = inverted;
+    }
+    Ok(())
+}
+
+// То же сопоставление через UMat: OpenCV сам выполняет его на OpenCL-устройстве.
+// Результат скачивается обратно в Mat, дальше обработка та же, что на CPU
+fn match_template_opencl(
+    image: &Mat,
+    template: &Mat,
+    mask: &Mat,
+    match_method: MatchMethod,
+) -> OpenCVResult<Mat> {
+    let upload = |mat: &Mat| -> OpenCVResult<UMat> {
+        let mut umat = UMat::new_def();
+        mat.copy_to(&mut umat)?;
+        Ok(umat)
+    };
+
+    let mut result = UMat::new_def();
+    imgproc::match_template(
+        &upload(image)?,
+        &upload(template)?,
+        &mut result,
+        opencv_method(match_method),
+        &upload(mask)?,
+    )?;
+
+    let mut result_mat = Mat::default();
+    result.copy_to(&mut result_mat)?;
+    Ok(result_mat)
+}
+
+// Поворот вокруг центра на том же холсте, поэтому левый верхний угол совпадения
+// остаётся положением неповёрнутого спрайта. Уголки холста закрывает маска
+fn rotate_template(template: &Mat, mask: &Mat, angle: f64) -> OpenCVResult<(Mat, Mat)> {
+    let size = template.size()?;
+    let center = Point2f::new(size.width as f32 / 2.0, size.height as f32 / 2.0);
+    let rotation = imgproc::get_rotation_matrix_2d(center, angle, 1.0)?;
+
+    let mut rotated = Mat::default();
+    imgproc::warp_affine(
+        template,
+        &mut rotated,
+        &rotation,
+        size,
+        INTER_LINEAR,
+        BORDER_CONSTANT,
+        Scalar::all(0.0),
+    )?;
+
+    // Без альфа-маски маской служит весь спрайт
+    let full_mask;
+    let source_mask = if mask.empty() {
+        full_mask = Mat::new_size_with_default(size, CV_8U, Scalar::all(255.0))?;
+        &full_mask
+    } else {
+        mask
+    };
+    let mut rotated_mask = Mat::default();
+    imgproc::warp_affine(
+        source_mask,
+        &mut rotated_mask,
+        &rotation,
+        size,
+        INTER_NEAREST,
+        BORDER_CONSTANT,
+        Scalar::all(0.0),
+    )?;
+
+    Ok((rotated, rotated_mask))
+}
+
+// Меньше этого шаблон после масштабирования вырождается в пару пикселей
+const MIN_TEMPLATE_SIDE: i32 = 4;
+
+fn template_file_problem(path: &str) -> Option<String> {
+    if !Path::new(path).is_file() {
+        return Some("file not found".to_string());
+    }
+    match imgcodecs::imread(path, IMREAD_COLOR) {
+        Err(e) => Some(e.to_string()),
+        Ok(image) if image.empty() => Some("not a readable image".to_string()),
+        Ok(image) if image.cols() < MIN_TEMPLATE_SIDE || image.rows() < MIN_TEMPLATE_SIDE => {
+            Some(format!("{}x{} is too small", image.cols(), image.rows()))
+        }
+        Ok(_) => None,
+    }
+}
+
+// Проверяет файлы всех шаблонов до загрузки, чтобы сразу показать каждый битый путь
+pub fn check_template_files(templates: &[TemplateSettings]) -> AppResult<()> {
+    let broken: Vec<String> = templates
+        .iter()
+        .flat_map(|t| {
+            std::iter::once(&t.path)
+                .chain(&t.frames)
+                .map(move |path| (t, path))
         })
+        .filter_map(|(t, path)| {
+            let problem = template_file_problem(path)?;
+            Some(format!("{} ({}): {}", t.name, path, problem))
+        })
+        .collect();
+
+    if broken.is_empty() {
+        return Ok(());
     }
+    Err(AppError::ImageProcessing(format!(
+        "Broken templates:\n  {}",
+        broken.join("\n  ")
+    )))
 }
 
-#[derive(Debug, Clone)]
+pub fn max_barrel_level(templates: &[Arc<ObjectTemplate>]) -> Option<u32> {
+    templates.iter().filter_map(|t| t.barrel_level()).max()
+}
+
+// opencv::core::Point не реализует Serialize, пишем его как {"x", "y"}
+fn serialize_point<S: Serializer>(point: &Point, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut state = serializer.serialize_struct("Point", 2)?;
+    state.serialize_field("x", &point.x)?;
+    state.serialize_field("y", &point.y)?;
+    state.end()
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct DetectionResult {
     pub object_name: String,
+    #[serde(serialize_with = "serialize_point")]
     pub location: Point,
     pub confidence: f64,
+    // Размер найденной области в координатах исходного кадра
+    pub width: i32,
+    pub height: i32,
+    pub category: TemplateCategory,
+}
+
+impl DetectionResult {
+    pub fn barrel_level(&self) -> Option<u32> {
+        match self.category {
+            TemplateCategory::Barrel => extract_barrel_number(&self.object_name),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GridCell {
+    // Ни бочки, ни пустой клетки здесь не нашлось
+    Unknown,
+    Empty,
+    // Уровень бочки; бочка без номера считается нулевым
+    Barrel(u32),
+}
+
+// Занятость игрового поля по клеткам: где свободно, где бочка какого уровня
+#[derive(Debug, Clone)]
+pub struct OccupancyGrid {
+    pub rows: usize,
+    pub cols: usize,
+    cells: Vec<GridCell>,
+    // Левый верхний угол первой клетки и шаг сетки в координатах окна
+    origin: (i32, i32),
+    step: (f32, f32),
+}
+
+impl OccupancyGrid {
+    // Клетка (строка, столбец) под детекцией, если она попадает в поле
+    pub fn cell_of(&self, detection: &DetectionResult) -> Option<(usize, usize)> {
+        let index = |value: i32, origin: i32, step: f32| {
+            if step > 0.0 {
+                ((value - origin) as f32 / step).round()
+            } else {
+                0.0
+            }
+        };
+        let row = index(detection.location.y, self.origin.1, self.step.1);
+        let col = index(detection.location.x, self.origin.0, self.step.0);
+        (row >= 0.0 && col >= 0.0 && (row as usize) < self.rows && (col as usize) < self.cols)
+            .then_some((row as usize, col as usize))
+    }
+
+    pub fn get(&self, (row, col): (usize, usize)) -> GridCell {
+        if row < self.rows && col < self.cols {
+            self.cells[row * self.cols + col]
+        } else {
+            GridCell::Unknown
+        }
+    }
+
+    pub fn set(&mut self, (row, col): (usize, usize), cell: GridCell) {
+        if row < self.rows && col < self.cols {
+            self.cells[row * self.cols + col] = cell;
+        }
+    }
+
+    // Соседи по стороне, без диагоналей
+    pub fn neighbors(&self, (row, col): (usize, usize)) -> impl Iterator<Item = GridCell> + '_ {
+        [(-1, 0), (1, 0), (0, -1), (0, 1)]
+            .into_iter()
+            .filter_map(move |(dr, dc): (isize, isize)| {
+                Some((row.checked_add_signed(dr)?, col.checked_add_signed(dc)?))
+            })
+            .map(|cell| self.get(cell))
+    }
+
+    pub fn empty_cells(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..self.rows)
+            .flat_map(move |row| (0..self.cols).map(move |col| (row, col)))
+            .filter(|&cell| self.get(cell) == GridCell::Empty)
+    }
 }
 
 pub struct ObjectDetector {
@@ -92,6 +759,66 @@ pub struct ObjectDetector {
     pub active_range: (usize, usize), // (start, end) индексы активных шаблонов
     pub full_range: bool,
     pub use_cuda: bool,
+    pub use_opencl: bool,
+    pub multiscale: bool,
+    // Множитель размера области, которую гасим вокруг найденного пика
+    pub suppression_scale: f64,
+    // Область поиска в координатах эталонного окна и сам размер эталона
+    roi: Option<Rect>,
+    reference_size: (i32, i32),
+    // Разница самой изменившейся клетки уменьшенных кадров (0-255), ниже которой
+    // кадр считается неизменным и детекция не запускается; 0 - искать всегда
+    pub static_frame_threshold: f64,
+    // На сколько лучший шаблон должен обойти другой шаблон в том же месте; 0 - не проверять
+    pub ambiguity_margin: f64,
+    previous_frame: Mat,
+    previous_detections: Vec<DetectionResult>,
+    // Когда с объектом последний раз что-то делали, по округлённому положению
+    cooldowns: HashMap<(String, i32, i32), Instant>,
+    pub tracker: DetectionTracker,
+}
+
+// Шаг округления координат для ключа кулдауна: детекция соседних кадров
+// гуляет на пару пикселей
+const COOLDOWN_GRID: i32 = 8;
+
+// Во сколько раз уменьшать кадр для сравнения с предыдущим
+const FRAME_DIFF_SCALE: f64 = 0.125;
+
+// Множители вокруг base_scale_factor для мультимасштабного поиска (±10%)
+const MULTISCALE_FACTORS: [f64; 3] = [0.9, 1.0, 1.1];
+
+// Углы в градусах для rotation_tolerant шаблонов, помимо исходного
+const ROTATION_ANGLES: [f64; 4] = [-6.0, -3.0, 3.0, 6.0];
+
+// Предел пиков одного шаблона за кадр: на однотонном кадре карта совпадений
+// может целиком пройти порог, и гасить её по кусочку слишком долго
+const MAX_MATCHES_PER_TEMPLATE: usize = 256;
+
+// Область размером с шаблон (умноженным на scale) вокруг пика, обрезанная по
+// карте совпадений: у края кадра она иначе уходит в отрицательные координаты
+fn suppression_rect(center: Point, width: i32, height: i32, scale: f64, bounds: &Mat) -> Rect {
+    // Не меньше пикселя, иначе сам пик не погасится
+    let width = ((width as f64 * scale).round() as i32).max(1);
+    let height = ((height as f64 * scale).round() as i32).max(1);
+    let left = center.x - width / 2;
+    let top = center.y - height / 2;
+    let x = left.clamp(0, bounds.cols());
+    let y = top.clamp(0, bounds.rows());
+    let right = (left + width).clamp(x, bounds.cols());
+    let bottom = (top + height).clamp(y, bounds.rows());
+    Rect::new(x, y, right - x, bottom - y)
+}
+
+fn suppress(mat: &mut Mat, rect: Rect) -> OpenCVResult<()> {
+    imgproc::rectangle(
+        mat,
+        rect,
+        Scalar::all(0.0),
+        FILLED,
+        LineTypes::LINE_8.into(),
+        0,
+    )
 }
 
 // CUDA-specific implementations
@@ -102,14 +829,19 @@ opencv_has_inherent_feature_cuda! {
         use opencv::cudawarping;
 
         impl ObjectDetector {
-            fn prepare_image_cuda(&self, image: &Mat, convert_to_grayscale: bool) -> OpenCVResult<GpuMat> {
+            fn prepare_image_cuda(&self, image: &Mat, color_space: MatchColorSpace) -> OpenCVResult<GpuMat> {
                 let mut gpu_img = GpuMat::new()?;
                 gpu_img.upload(image)?;
 
-                if convert_to_grayscale {
-                    let mut gray = GpuMat::new()?;
-                    cudaimgproc::cvt_color(&gpu_img, &mut gray, COLOR_BGR2GRAY, 0, &mut opencv::core::Stream::default()?)?;
-                    gpu_img = gray;
+                let code = match color_space {
+                    MatchColorSpace::Gray => Some(COLOR_BGR2GRAY),
+                    MatchColorSpace::Bgr => None,
+                    MatchColorSpace::Hsv => Some(COLOR_BGR2HSV),
+                };
+                if let Some(code) = code {
+                    let mut converted = GpuMat::new()?;
+                    cudaimgproc::cvt_color(&gpu_img, &mut converted, code, 0, &mut opencv::core::Stream::default()?)?;
+                    gpu_img = converted;
                 }
 
                 let mut resized = GpuMat::new()?;
@@ -131,19 +863,22 @@ opencv_has_inherent_feature_cuda! {
                 image: &GpuMat,
                 template: &GpuMat,
                 threshold: f64,
+                match_method: MatchMethod,
+                category: TemplateCategory,
             ) -> OpenCVResult<Vec<DetectionResult>> {
                 let mut result_mat = GpuMat::new()?;
                 cudaimgproc::match_template(
                     image,
                     template,
                     &mut result_mat,
-                    TM_CCOEFF_NORMED,
+                    opencv_method(match_method),
                     &GpuMat::new()?,
                     &mut opencv::core::Stream::default()?,
                 )?;
 
                 let mut result_mat_cpu = Mat::default();
                 result_mat.download(&mut result_mat_cpu)?;
+                normalize_match_result(&mut result_mat_cpu, match_method)?;
 
                 let mut thresholded = Mat::default();
                 threshold(
@@ -161,7 +896,7 @@ opencv_has_inherent_feature_cuda! {
                 let mut max_val = f64::MIN;
                 let mut max_loc = Point::default();
 
-                loop {
+                while results.len() < MAX_MATCHES_PER_TEMPLATE {
                     min_max_loc(
                         &result_mat_cpu,
                         None,
@@ -177,41 +912,23 @@ opencv_has_inherent_feature_cuda! {
 
                     results.push(DetectionResult {
                         object_name: template.name.clone(),
-                        location: Point::new(
-                            (max_loc.x as f64 / self.base_scale_factor) as i32,
-                            (max_loc.y as f64 / self.base_scale_factor) as i32,
-                        ),
+                        location: max_loc,
                         confidence: max_val,
+                        width: template.cols()?,
+                        height: template.rows()?,
+                        category,
                     });
 
                     // Обнуляем найденную область
-                    let _ = imgproc::rectangle(
-                        &mut result_mat_cpu,
-                        Rect::new(
-                            max_loc.x - template.cols()? / 2,
-                            max_loc.y - template.rows()? / 2,
-                            template.cols()?,
-                            template.rows()?,
-                        ),
-                        Scalar::all(0.0),
-                        FILLED,
-                        LineTypes::LINE_8.into(),
-                        0,
-                    );
-
-                    let _ = imgproc::rectangle(
-                        &mut mask_8u,
-                        Rect::new(
-                            max_loc.x - template.cols()? / 2,
-                            max_loc.y - template.rows()? / 2,
-                            template.cols()?,
-                            template.rows()?,
-                        ),
-                        Scalar::all(0.0),
-                        FILLED,
-                        LineTypes::LINE_8.into(),
-                        0,
+                    let suppressed = suppression_rect(
+                        max_loc,
+                        template.cols()?,
+                        template.rows()?,
+                        self.suppression_scale,
+                        &result_mat_cpu,
                     );
+                    suppress(&mut result_mat_cpu, suppressed)?;
+                    suppress(&mut mask_8u, suppressed)?;
 
                     max_val = f64::MIN;
                 }
@@ -226,7 +943,7 @@ impl ObjectDetector {
     pub fn new(base_scale_factor: f64) -> Self {
         let cuda_available = opencv_has_inherent_feature_cuda! {
             { opencv::core::get_cuda_enabled_device_count().unwrap_or(0) > 0 }
-            else {  println!("CUDA not found"); false }
+            else {  info!("CUDA not found"); false }
         };
 
         Self {
@@ -235,6 +952,17 @@ impl ObjectDetector {
             active_range: (0, 0), // Будет установлено при добавлении шаблонов
             full_range: true,     // Флаг полного диапазона
             use_cuda: cuda_available,
+            use_opencl: false,
+            multiscale: false,
+            suppression_scale: 1.0,
+            roi: None,
+            reference_size: (0, 0),
+            static_frame_threshold: 0.0,
+            ambiguity_margin: 0.0,
+            previous_frame: Mat::default(),
+            previous_detections: Vec::new(),
+            cooldowns: HashMap::new(),
+            tracker: DetectionTracker::new(0),
         }
     }
 
@@ -245,55 +973,41 @@ impl ObjectDetector {
         // Фильтруем только бочки и извлекаем их номера
         let barrel_numbers: Vec<u32> = filtered_detections
             .iter()
-            .filter(|d| d.object_name.starts_with("Barrel"))
-            .filter_map(|d| extract_barrel_number(&d.object_name))
+            .filter_map(|d| d.barrel_level())
             .collect();
 
-        //println!("DEBUG: Barrel numbers detected: {:?}", barrel_numbers);
+        debug!("Barrel numbers detected: {:?}", barrel_numbers);
 
         if barrel_numbers.is_empty() {
             self.active_range = (0, self.templates.len().saturating_sub(1));
             self.full_range = true;
-            //println!("DEBUG: No barrels detected, using full range");
+            debug!("No barrels detected, using full range");
             return;
         }
 
         let min_detected = *barrel_numbers.iter().min().unwrap();
         let max_detected = *barrel_numbers.iter().max().unwrap();
-        //println!(
-        //    "DEBUG: Min barrel: {}, Max barrel: {}",
-        //    min_detected, max_detected
-        //);
+        debug!("Min barrel: {}, Max barrel: {}", min_detected, max_detected);
 
         // Calculate the desired number range
         let target_min_number = min_detected.saturating_sub(8); // 303 - 5 = 298
         let target_max_number = max_detected + 4; // 371 + 8 = 379
 
-        //println!(
-        //    "DEBUG: Target number range: {} - {}",
-        //    target_min_number, target_max_number
-        //);
+        debug!(
+            "Target number range: {} - {}",
+            target_min_number, target_max_number
+        );
 
-        // Find template indices that match this number range
-        let mut start_index = None;
-        let mut end_index = None;
+        // Find the lowest and highest template indices whose number is in range,
+        // independent of the order templates were added in
+        let mut start_index: Option<usize> = None;
+        let mut end_index: Option<usize> = None;
 
-        // Find start index (first template with number >= target_min_number)
         for (i, template) in self.templates.iter().enumerate() {
-            if let Some(number) = extract_barrel_number(&template.name) {
-                if number >= target_min_number {
-                    start_index = Some(i);
-                    break;
-                }
-            }
-        }
-
-        // Find end index (last template with number <= target_max_number)
-        for (i, template) in self.templates.iter().enumerate().rev() {
-            if let Some(number) = extract_barrel_number(&template.name) {
-                if number <= target_max_number {
-                    end_index = Some(i);
-                    break;
+            if let Some(number) = template.barrel_level() {
+                if (target_min_number..=target_max_number).contains(&number) {
+                    start_index = Some(start_index.map_or(i, |start| start.min(i)));
+                    end_index = Some(end_index.map_or(i, |end| end.max(i)));
                 }
             }
         }
@@ -302,29 +1016,112 @@ impl ObjectDetector {
         if let (Some(start), Some(end)) = (start_index, end_index) {
             self.active_range = (start, end);
             self.full_range = false;
-
-            // Get actual numbers for the range boundaries
-            //let actual_min = extract_barrel_number(&self.templates[start].name).unwrap();
-            //let actual_max = extract_barrel_number(&self.templates[end].name).unwrap();
-
-            //println!(
-            //    "DEBUG: Active range set: indices {} - {} (numbers {} - {})",
-            //    start, end, actual_min, actual_max
-            //);
+            debug!("Active range set: indices {} - {}", start, end);
         } else {
             // Fallback to full range
             self.active_range = (0, self.templates.len().saturating_sub(1));
             self.full_range = true;
-            //println!("DEBUG: Could not find matching templates, using full range");
+            debug!("Could not find matching templates, using full range");
+        }
+    }
+
+    // Новый уровень: прежние бочки и их места ничего не говорят о новом поле
+    pub fn reset_active_range(&mut self) {
+        self.active_range = (0, self.templates.len().saturating_sub(1));
+        self.full_range = true;
+        self.tracker.clear();
+        self.cooldowns.clear();
+    }
+
+    // Ключ кулдауна: имя и положение, округлённое до клетки COOLDOWN_GRID
+    fn cooldown_key(detection: &DetectionResult) -> (String, i32, i32) {
+        (
+            detection.object_name.clone(),
+            (detection.location.x as f64 / COOLDOWN_GRID as f64).round() as i32,
+            (detection.location.y as f64 / COOLDOWN_GRID as f64).round() as i32,
+        )
+    }
+
+    // С объектом уже работали меньше cooldown назад: анимация слияния ещё идёт
+    pub fn on_cooldown(&self, detection: &DetectionResult, cooldown: Duration) -> bool {
+        self.cooldowns
+            .get(&Self::cooldown_key(detection))
+            .is_some_and(|acted_at| acted_at.elapsed() < cooldown)
+    }
+
+    pub fn mark_acted(&mut self, detection: &DetectionResult, cooldown: Duration) {
+        self.cooldowns
+            .retain(|_, acted_at| acted_at.elapsed() < cooldown);
+        self.cooldowns
+            .insert(Self::cooldown_key(detection), Instant::now());
+    }
+
+    // Самый высокий уровень бочки, для которого есть шаблон
+    pub fn max_barrel_level(&self) -> Option<u32> {
+        max_barrel_level(&self.templates)
+    }
+
+    // Сетка rows x cols по бочкам и пустым клеткам кадра. Шаг считается по
+    // крайним найденным клеткам, как в таблице; бочка важнее пустой клетки под ней
+    pub fn build_occupancy_grid(
+        detections: &[DetectionResult],
+        rows: usize,
+        cols: usize,
+    ) -> OccupancyGrid {
+        let cells: Vec<&DetectionResult> = detections
+            .iter()
+            .filter(|d| {
+                matches!(
+                    d.category,
+                    TemplateCategory::Barrel | TemplateCategory::Empty
+                )
+            })
+            .collect();
+
+        let min_x = cells.iter().map(|d| d.location.x).min().unwrap_or(0);
+        let max_x = cells.iter().map(|d| d.location.x).max().unwrap_or(0);
+        let min_y = cells.iter().map(|d| d.location.y).min().unwrap_or(0);
+        let max_y = cells.iter().map(|d| d.location.y).max().unwrap_or(0);
+        let step = |span: i32, count: usize| {
+            if count > 1 {
+                span as f32 / (count - 1) as f32
+            } else {
+                0.0
+            }
+        };
+
+        let mut grid = OccupancyGrid {
+            rows,
+            cols,
+            cells: vec![GridCell::Unknown; rows * cols],
+            origin: (min_x, min_y),
+            step: (step(max_x - min_x, cols), step(max_y - min_y, rows)),
+        };
+        for detection in cells {
+            let Some(cell) = grid.cell_of(detection) else {
+                continue;
+            };
+            match detection.category {
+                TemplateCategory::Empty => {
+                    if grid.get(cell) == GridCell::Unknown {
+                        grid.set(cell, GridCell::Empty);
+                    }
+                }
+                _ => grid.set(
+                    cell,
+                    GridCell::Barrel(detection.barrel_level().unwrap_or(0)),
+                ),
+            }
         }
+        grid
     }
 
     pub fn get_active_templates(&self) -> Vec<Arc<ObjectTemplate>> {
         let mut result = Vec::new();
 
-        // Всегда добавляем шаблоны с флагом always_active
+        // Всегда добавляем шаблоны с флагом always_active, а также Empty и Cloud
         for template in &self.templates {
-            if template.always_active {
+            if template.is_always_active() {
                 result.push(template.clone());
             }
         }
@@ -332,7 +1129,7 @@ impl ObjectDetector {
         // Добавляем шаблоны из активного диапазона (исключая уже добавленные always_active)
         let (start, end) = self.active_range;
         for i in start..=end {
-            if i < self.templates.len() && !self.templates[i].always_active {
+            if i < self.templates.len() && !self.templates[i].is_always_active() {
                 // Проверяем, что шаблон еще не добавлен (по имени)
                 if !result.iter().any(|t| t.name == self.templates[i].name) {
                     result.push(self.templates[i].clone());
@@ -354,6 +1151,9 @@ impl ObjectDetector {
         blue: f32,
         resolution: Option<f64>,
         always_active: bool,
+        match_method: MatchMethod,
+        mask_color: Option<(u8, u8, u8)>,
+        mask_tolerance: Option<u8>,
     ) -> OpenCVResult<()> {
         let template = ObjectTemplate::new(
             name,
@@ -365,76 +1165,275 @@ impl ObjectDetector {
             blue,
             resolution,
             always_active,
+            match_method,
+            mask_color,
+            mask_tolerance,
         )?;
-        self.templates.push(Arc::new(template));
-
-        self.active_range = (0, self.templates.len() - 1);
-        self.full_range = true;
-
-        Ok(())
+        self.push_template(template)
     }
 
-    pub fn detect_objects_optimized(
+    pub fn add_template_bytes(
         &mut self,
-        image: &Mat,
-        convert_to_grayscale: bool,
-    ) -> OpenCVResult<(Vec<DetectionResult>, u128)> {
-        let start_time = Instant::now();
-
-        opencv_has_inherent_feature_cuda! {
-            {
-                if self.use_cuda {
-                    return self.detect_objects_cuda(image, convert_to_grayscale);
-                }
-            }
-        }
-
-        // Подготовка изображения
-        let working_image = if convert_to_grayscale {
-            let mut gray = Mat::default();
-            cvt_color(
-                image,
-                &mut gray,
-                COLOR_BGR2GRAY,
-                0,
-                AlgorithmHint::ALGO_HINT_DEFAULT,
-            )?;
-            gray
+        name: &str,
+        data: &[u8],
+        threshold: f64,
+        min_distance: f32,
+        red: f32,
+        green: f32,
+        blue: f32,
+        resolution: Option<f64>,
+        always_active: bool,
+        match_method: MatchMethod,
+        mask_color: Option<(u8, u8, u8)>,
+        mask_tolerance: Option<u8>,
+    ) -> OpenCVResult<()> {
+        let template = ObjectTemplate::from_bytes(
+            name,
+            data,
+            threshold,
+            min_distance,
+            red,
+            green,
+            blue,
+            resolution,
+            always_active,
+            match_method,
+            mask_color,
+            mask_tolerance,
+        )?;
+        self.push_template(template)
+    }
+
+    fn push_template(&mut self, mut template: ObjectTemplate) -> OpenCVResult<()> {
+        template.rescale(self.base_scale_factor)?;
+        self.templates.push(Arc::new(template));
+
+        self.active_range = (0, self.templates.len() - 1);
+        self.full_range = true;
+
+        Ok(())
+    }
+
+    // Синхронизирует шаблоны с настройками: если набор файлов тот же, параметры
+    // меняются на месте, иначе все шаблоны загружаются заново
+    pub fn load_templates(&mut self, templates: &[TemplateSettings]) -> OpenCVResult<()> {
+        self.invalidate_frame_cache();
+
+        let same_files = self.templates.len() == templates.len()
+            && self
+                .templates
+                .iter()
+                .zip(templates)
+                .all(|(template, settings)| template.same_source(settings));
+
+        if same_files {
+            for (template, settings) in self.templates.iter_mut().zip(templates) {
+                Arc::make_mut(template).apply_settings(settings);
+            }
+            return self.refresh_scaled_templates();
+        }
+
+        // Собираем новый набор целиком, чтобы ошибка чтения не оставила детектор наполовину пустым
+        let mut loaded = Vec::with_capacity(templates.len());
+        for settings in templates {
+            let mut template = ObjectTemplate::from_settings(settings)?;
+            template.rescale(self.base_scale_factor)?;
+            loaded.push(Arc::new(template));
+        }
+        self.templates = loaded;
+
+        self.active_range = (0, self.templates.len().saturating_sub(1));
+        self.full_range = true;
+
+        Ok(())
+    }
+
+    pub fn set_base_scale_factor(&mut self, base_scale_factor: f64) -> OpenCVResult<()> {
+        self.base_scale_factor = base_scale_factor;
+        self.invalidate_frame_cache();
+        self.refresh_scaled_templates()
+    }
+
+    // Перестраивает только те шаблоны, чей кэш не совпадает с текущим масштабом
+    fn refresh_scaled_templates(&mut self) -> OpenCVResult<()> {
+        let base_scale_factor = self.base_scale_factor;
+        for template in self.templates.iter_mut() {
+            if template.scale_factor != template.effective_scale(base_scale_factor) {
+                Arc::make_mut(template).rescale(base_scale_factor)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Полосы кадра (смещение по y, изображение) с перекрытием не меньше высоты
+    // самого высокого шаблона, чтобы совпадение на стыке целиком попало в одну
+    // из полос; дубликаты из перекрытия потом убирает filter_close_detections
+    fn horizontal_strips(
+        &self,
+        resized: &Mat,
+        templates: &[Arc<ObjectTemplate>],
+        strip_count: usize,
+    ) -> OpenCVResult<Vec<(i32, Mat)>> {
+        let max_scale = if self.multiscale {
+            MULTISCALE_FACTORS.iter().cloned().fold(1.0, f64::max)
         } else {
-            image.clone()
+            1.0
         };
+        let overlap = templates
+            .iter()
+            .map(|t| (t.scaled_template.rows() as f64 * max_scale).ceil() as i32)
+            .max()
+            .unwrap_or(0);
+
+        let rows = resized.rows();
+        let strip_height = (rows + strip_count as i32 - 1) / strip_count as i32;
+        let mut strips = Vec::with_capacity(strip_count);
+        let mut y = 0;
+        while y < rows {
+            let height = (strip_height + overlap).min(rows - y);
+            let strip = Mat::roi(resized, Rect::new(0, y, resized.cols(), height))?.try_clone()?;
+            strips.push((y, strip));
+            y += strip_height;
+        }
+        Ok(strips)
+    }
 
-        // Масштабирование изображения
-        let mut resized = Mat::default();
-        resize(
-            &working_image,
-            &mut resized,
-            Size::new(0, 0),
-            self.base_scale_factor,
-            self.base_scale_factor,
-            INTER_AREA,
-        )?;
+    // Карта совпадений, где больше значит лучше для любого метода
+    fn match_result(
+        &self,
+        resized: &Mat,
+        template: &ObjectTemplate,
+        scaled_template: &Mat,
+        scaled_mask: &Mat,
+    ) -> OpenCVResult<Mat> {
+        let mut result_mat = if self.use_opencl {
+            match_template_opencl(resized, scaled_template, scaled_mask, template.match_method)?
+        } else {
+            let mut result_mat = Mat::default();
+            imgproc::match_template(
+                resized,
+                scaled_template,
+                &mut result_mat,
+                opencv_method(template.match_method),
+                scaled_mask,
+            )?;
+            result_mat
+        };
+        normalize_match_result(&mut result_mat, template.match_method)?;
+        Ok(result_mat)
+    }
 
-        // Параллельное сопоставление шаблонов
-        let active_templates = self.get_active_templates();
-        let all_results: Vec<Vec<DetectionResult>> = active_templates
-            .par_iter()
-            .map(|template| {
-                let template_image = if convert_to_grayscale {
-                    &template.gray_template
-                } else {
-                    &template.template
-                };
+    fn match_template_cpu(
+        &self,
+        resized: &Mat,
+        template: &ObjectTemplate,
+        scaled_template: &Mat,
+        scaled_mask: &Mat,
+    ) -> Vec<DetectionResult> {
+        let Ok(mut result_mat) = self.match_result(resized, template, scaled_template, scaled_mask)
+        else {
+            return Vec::new();
+        };
+
+        let mut thresholded = Mat::default();
+        if threshold(
+            &result_mat,
+            &mut thresholded,
+            template.threshold,
+            1.0,
+            THRESH_BINARY,
+        )
+        .is_err()
+        {
+            return Vec::new();
+        }
+
+        let mut mask_8u = Mat::default();
+        if thresholded
+            .convert_to(&mut mask_8u, CV_8U, 255.0, 0.0)
+            .is_err()
+        {
+            return Vec::new();
+        }
+
+        let mut local_results = Vec::new();
+        let mut max_val = f64::MIN;
+        let mut max_loc = Point::default();
+
+        while local_results.len() < MAX_MATCHES_PER_TEMPLATE {
+            if min_max_loc(
+                &result_mat,
+                None,
+                Some(&mut max_val),
+                None,
+                Some(&mut max_loc),
+                &mask_8u,
+            )
+            .is_err()
+            {
+                break;
+            }
+
+            if max_val < template.threshold {
+                break;
+            }
+
+            // Маска над однотонной или прозрачной областью даёт в CCORR/SQDIFF NaN
+            // и бесконечности: это не совпадение, но погасить пик всё равно нужно
+            if max_val.is_finite() {
+                local_results.push(DetectionResult {
+                    object_name: template.name.clone(),
+                    location: max_loc,
+                    confidence: max_val,
+                    width: scaled_template.cols(),
+                    height: scaled_template.rows(),
+                    category: template.category,
+                });
+            }
+
+            // Обнуляем найденную область; если не вышло, тот же пик найдётся снова
+            let suppressed = suppression_rect(
+                max_loc,
+                scaled_template.cols(),
+                scaled_template.rows(),
+                self.suppression_scale,
+                &result_mat,
+            );
+            if suppress(&mut result_mat, suppressed).is_err()
+                || suppress(&mut mask_8u, suppressed).is_err()
+            {
+                break;
+            }
+
+            max_val = f64::MIN;
+        }
+
+        local_results
+    }
 
-                // Масштабирование шаблона
-                let mut scaled_template = Mat::default();
-                let scale_factor = template.resolution.unwrap_or(self.base_scale_factor);
+    // Мультимасштабный поиск: дубликаты с разных масштабов потом
+    // отсеивает filter_close_detections, оставляя лучший по уверенности
+    fn match_multiscale(
+        &self,
+        image: &Mat,
+        template: &ObjectTemplate,
+        scaled_template: &Mat,
+        scaled_mask: &Mat,
+    ) -> Vec<DetectionResult> {
+        MULTISCALE_FACTORS
+            .iter()
+            .flat_map(|&factor| {
+                if factor == 1.0 {
+                    return self.match_template_cpu(image, template, scaled_template, scaled_mask);
+                }
+
+                let mut rescaled = Mat::default();
                 if resize(
-                    template_image,
-                    &mut scaled_template,
+                    scaled_template,
+                    &mut rescaled,
                     Size::new(0, 0),
-                    scale_factor,
-                    scale_factor,
+                    factor,
+                    factor,
                     INTER_AREA,
                 )
                 .is_err()
@@ -442,106 +1441,381 @@ impl ObjectDetector {
                     return Vec::new();
                 }
 
-                let mut result_mat = Mat::default();
-                if imgproc::match_template(
-                    &resized,
-                    &scaled_template,
-                    &mut result_mat,
-                    TM_CCOEFF_NORMED,
-                    &Mat::default(),
-                )
-                .is_err()
+                let mut rescaled_mask = Mat::default();
+                if !scaled_mask.empty()
+                    && resize(
+                        scaled_mask,
+                        &mut rescaled_mask,
+                        Size::new(0, 0),
+                        factor,
+                        factor,
+                        INTER_NEAREST,
+                    )
+                    .is_err()
                 {
                     return Vec::new();
                 }
 
-                let mut thresholded = Mat::default();
-                if threshold(
-                    &result_mat,
-                    &mut thresholded,
-                    template.threshold,
-                    1.0,
-                    THRESH_BINARY,
-                )
-                .is_err()
-                {
-                    return Vec::new();
-                }
+                self.match_template_cpu(image, template, &rescaled, &rescaled_mask)
+            })
+            .collect()
+    }
 
-                let mut mask_8u = Mat::default();
-                if thresholded
-                    .convert_to(&mut mask_8u, CV_8U, 255.0, 0.0)
-                    .is_err()
-                {
-                    return Vec::new();
-                }
+    // Сопоставление идёт в уменьшенном кадре, и шаблон с собственным resolution
+    // ищется в том же кадре, поэтому обратно переводим только по масштабу кадра.
+    // Смещения полос уже добавлены в том же пространстве, округляем один раз
+    fn to_window_space(&self, results: Vec<Vec<DetectionResult>>) -> Vec<Vec<DetectionResult>> {
+        let unscale = |value: i32| (value as f64 / self.base_scale_factor).round() as i32;
+        results
+            .into_iter()
+            .map(|detections| {
+                detections
+                    .into_iter()
+                    .map(|mut detection| {
+                        detection.location = Point::new(
+                            unscale(detection.location.x),
+                            unscale(detection.location.y),
+                        );
+                        detection.width = unscale(detection.width);
+                        detection.height = unscale(detection.height);
+                        detection
+                    })
+                    .collect()
+            })
+            .collect()
+    }
 
-                let mut local_results = Vec::new();
-                let mut max_val = f64::MIN;
-                let mut max_loc = Point::default();
+    // Кадр в том виде, в котором с ним сравниваются масштабированные шаблоны
+    fn prepare_image(&self, image: &Mat, color_space: MatchColorSpace) -> OpenCVResult<Mat> {
+        // Подготовка изображения
+        let working_image = match color_space {
+            MatchColorSpace::Bgr => image.clone(),
+            MatchColorSpace::Gray | MatchColorSpace::Hsv => {
+                let code = if color_space == MatchColorSpace::Gray {
+                    COLOR_BGR2GRAY
+                } else {
+                    COLOR_BGR2HSV
+                };
+                let mut converted = Mat::default();
+                cvt_color(
+                    image,
+                    &mut converted,
+                    code,
+                    0,
+                    AlgorithmHint::ALGO_HINT_DEFAULT,
+                )?;
+                converted
+            }
+        };
 
-                loop {
-                    if min_max_loc(
-                        &result_mat,
-                        None,
-                        Some(&mut max_val),
-                        None,
-                        Some(&mut max_loc),
-                        &mask_8u,
+        // Масштабирование изображения
+        let mut resized = Mat::default();
+        resize(
+            &working_image,
+            &mut resized,
+            Size::new(0, 0),
+            self.base_scale_factor,
+            self.base_scale_factor,
+            INTER_AREA,
+        )?;
+
+        Ok(resized)
+    }
+
+    // Есть ли шаблон template_name в области region кадра; для проверки слияний
+    pub fn match_in_region(
+        &self,
+        image: &Mat,
+        region: Rect,
+        template_name: &str,
+        color_space: MatchColorSpace,
+    ) -> OpenCVResult<bool> {
+        let Some(template) = self.templates.iter().find(|t| t.name == template_name) else {
+            return Ok(false);
+        };
+
+        // Обрезаем область по границам кадра
+        let x = region.x.clamp(0, image.cols());
+        let y = region.y.clamp(0, image.rows());
+        let width = (region.x + region.width).min(image.cols()) - x;
+        let height = (region.y + region.height).min(image.rows()) - y;
+        if width <= 0 || height <= 0 {
+            return Ok(false);
+        }
+
+        let crop = Mat::roi(image, Rect::new(x, y, width, height))?.try_clone()?;
+        let resized = self.prepare_image(&crop, color_space)?;
+        let scaled_template = template.scaled_for(color_space);
+        if resized.cols() < scaled_template.cols() || resized.rows() < scaled_template.rows() {
+            return Ok(false);
+        }
+
+        // Анимированная бочка может попасть в снимок любым своим кадром
+        Ok(template
+            .scaled_variants(color_space)
+            .any(|scaled_template| {
+                !self
+                    .match_template_cpu(
+                        &resized,
+                        template,
+                        scaled_template,
+                        &template.scaled_alpha_mask,
                     )
-                    .is_err()
-                    {
-                        break;
-                    }
+                    .is_empty()
+            }))
+    }
 
-                    if max_val < template.threshold {
-                        break;
-                    }
+    // Лучшие top_k пиков шаблона без учёта порога, по убыванию; для подбора threshold.
+    // Вокруг каждого пика область размером с шаблон гасится, как и при детекции
+    pub fn scan_confidences(
+        &self,
+        image: &Mat,
+        template_name: &str,
+        color_space: MatchColorSpace,
+        top_k: usize,
+    ) -> OpenCVResult<Vec<f64>> {
+        let Some(template) = self.templates.iter().find(|t| t.name == template_name) else {
+            return Err(opencv::Error::new(
+                StsBadArg,
+                format!("Unknown template: {}", template_name),
+            ));
+        };
 
-                    local_results.push(DetectionResult {
-                        object_name: template.name.clone(),
-                        location: Point::new(
-                            (max_loc.x as f64 / self.base_scale_factor) as i32,
-                            (max_loc.y as f64 / self.base_scale_factor) as i32,
-                        ),
-                        confidence: max_val,
-                    });
+        let resized = self.prepare_image(image, color_space)?;
+        let scaled_template = template.scaled_for(color_space);
+        if resized.cols() < scaled_template.cols() || resized.rows() < scaled_template.rows() {
+            return Ok(Vec::new());
+        }
 
-                    // Обнуляем найденную область
-                    let _ = imgproc::rectangle(
-                        &mut result_mat,
-                        Rect::new(
-                            max_loc.x - scaled_template.cols() / 2,
-                            max_loc.y - scaled_template.rows() / 2,
-                            scaled_template.cols(),
-                            scaled_template.rows(),
-                        ),
-                        Scalar::all(0.0),
-                        FILLED,
-                        LineTypes::LINE_8.into(),
-                        0,
-                    );
+        let mut result_mat = self.match_result(
+            &resized,
+            template,
+            scaled_template,
+            &template.scaled_alpha_mask,
+        )?;
 
-                    let _ = imgproc::rectangle(
-                        &mut mask_8u,
-                        Rect::new(
-                            max_loc.x - scaled_template.cols() / 2,
-                            max_loc.y - scaled_template.rows() / 2,
-                            scaled_template.cols(),
-                            scaled_template.rows(),
-                        ),
-                        Scalar::all(0.0),
-                        FILLED,
-                        LineTypes::LINE_8.into(),
-                        0,
-                    );
+        let mut confidences = Vec::with_capacity(top_k);
+        while confidences.len() < top_k {
+            let mut max_val = f64::MIN;
+            let mut max_loc = Point::default();
+            min_max_loc(
+                &result_mat,
+                None,
+                Some(&mut max_val),
+                None,
+                Some(&mut max_loc),
+                &no_array(),
+            )?;
+            // Всё погашено, дальше только нули
+            if max_val <= 0.0 {
+                break;
+            }
+            if max_val.is_finite() {
+                confidences.push(max_val);
+            }
 
-                    max_val = f64::MIN;
+            let suppressed = suppression_rect(
+                max_loc,
+                scaled_template.cols(),
+                scaled_template.rows(),
+                self.suppression_scale,
+                &result_mat,
+            );
+            suppress(&mut result_mat, suppressed)?;
+        }
+
+        Ok(confidences)
+    }
+
+    pub fn set_roi(&mut self, roi: Option<(i32, i32, i32, i32)>, reference_size: (i32, i32)) {
+        self.roi = roi.map(|(x, y, width, height)| Rect::new(x, y, width, height));
+        self.reference_size = reference_size;
+        self.invalidate_frame_cache();
+    }
+
+    // Следующий кадр пройдёт полную детекцию, даже если экран не менялся
+    pub fn invalidate_frame_cache(&mut self) {
+        self.previous_frame = Mat::default();
+        self.previous_detections.clear();
+        self.tracker.clear();
+    }
+
+    // Уменьшенная серая копия кадра; возвращает её и признак того, что
+    // кадр почти не отличается от предыдущего
+    fn frame_is_static(&self, image: &Mat) -> OpenCVResult<(Mat, bool)> {
+        let mut gray = Mat::default();
+        if image.channels() == 1 {
+            gray = image.clone();
+        } else {
+            cvt_color(
+                image,
+                &mut gray,
+                COLOR_BGR2GRAY,
+                0,
+                AlgorithmHint::ALGO_HINT_DEFAULT,
+            )?;
+        }
+        let mut thumbnail = Mat::default();
+        resize(
+            &gray,
+            &mut thumbnail,
+            Size::new(0, 0),
+            FRAME_DIFF_SCALE,
+            FRAME_DIFF_SCALE,
+            INTER_AREA,
+        )?;
+
+        if self.static_frame_threshold <= 0.0
+            || self.previous_frame.empty()
+            || self.previous_frame.size()? != thumbnail.size()?
+        {
+            return Ok((thumbnail, false));
+        }
+
+        // Максимум, а не среднее: новая бочка в одной клетке почти не сдвигает
+        // среднюю разницу по всему кадру, и бот действовал бы по старой детекции
+        let mut diff = Mat::default();
+        absdiff(&thumbnail, &self.previous_frame, &mut diff)?;
+        let mut difference = 0.0;
+        min_max_loc(&diff, None, Some(&mut difference), None, None, &no_array())?;
+        Ok((thumbnail, difference < self.static_frame_threshold))
+    }
+
+    // ROI в пикселях кадра: окно может быть не того размера, под который его задавали
+    fn roi_in_image(&self, image: &Mat) -> Option<Rect> {
+        let roi = self.roi?;
+        let (reference_width, reference_height) = self.reference_size;
+        if reference_width <= 0 || reference_height <= 0 {
+            return None;
+        }
+
+        let sx = image.cols() as f64 / reference_width as f64;
+        let sy = image.rows() as f64 / reference_height as f64;
+        let x = ((roi.x as f64 * sx).round() as i32).clamp(0, image.cols());
+        let y = ((roi.y as f64 * sy).round() as i32).clamp(0, image.rows());
+        let right = (((roi.x + roi.width) as f64 * sx).round() as i32).min(image.cols());
+        let bottom = (((roi.y + roi.height) as f64 * sy).round() as i32).min(image.rows());
+        (right > x && bottom > y).then(|| Rect::new(x, y, right - x, bottom - y))
+    }
+
+    /// Runs template matching over `image` without any console output;
+    /// callers decide when to clear the screen or render results.
+    pub fn detect_objects_optimized(
+        &mut self,
+        image: &Mat,
+        color_space: MatchColorSpace,
+    ) -> OpenCVResult<(Vec<DetectionResult>, u128)> {
+        // Между событиями экран часто стоит на месте: отдаём прошлый результат
+        let (thumbnail, is_static) = self.frame_is_static(image)?;
+        if is_static {
+            return Ok((self.previous_detections.clone(), 0));
+        }
+
+        let (detections, elapsed_ms) = self.detect_in_roi(image, color_space)?;
+        self.previous_frame = thumbnail;
+        self.previous_detections = detections.clone();
+        Ok((detections, elapsed_ms))
+    }
+
+    fn detect_in_roi(
+        &mut self,
+        image: &Mat,
+        color_space: MatchColorSpace,
+    ) -> OpenCVResult<(Vec<DetectionResult>, u128)> {
+        let Some(roi) = self.roi_in_image(image) else {
+            return self.detect_in_image(image, color_space);
+        };
+
+        // Ищем только внутри ROI: интерфейс вокруг игрового поля даёт ложные
+        // совпадения и просто тратит время. Координаты возвращаем в кадр целиком
+        let crop = Mat::roi(image, roi)?.try_clone()?;
+        let (mut detections, elapsed_ms) = self.detect_in_image(&crop, color_space)?;
+        for detection in &mut detections {
+            detection.location.x += roi.x;
+            detection.location.y += roi.y;
+        }
+        Ok((detections, elapsed_ms))
+    }
+
+    fn detect_in_image(
+        &mut self,
+        image: &Mat,
+        color_space: MatchColorSpace,
+    ) -> OpenCVResult<(Vec<DetectionResult>, u128)> {
+        let start_time = Instant::now();
+
+        self.refresh_scaled_templates()?;
+
+        opencv_has_inherent_feature_cuda! {
+            {
+                if self.cuda_usable() {
+                    return self.detect_objects_cuda(image, color_space);
+                }
+            }
+        }
+
+        let resized = self.prepare_image(image, color_space)?;
+
+        let match_template =
+            |template: &Arc<ObjectTemplate>, image: &Mat| -> Vec<DetectionResult> {
+                let scaled_mask = &template.scaled_alpha_mask;
+                let mut results = Vec::new();
+
+                // Шаблон уже масштабирован при добавлении. Кадры анимации ищутся как
+                // отдельные спрайты с тем же именем, в каждом месте остаётся лучший
+                for scaled_template in template.scaled_variants(color_space) {
+                    results.extend(if self.multiscale {
+                        self.match_multiscale(image, template, scaled_template, scaled_mask)
+                    } else {
+                        self.match_template_cpu(image, template, scaled_template, scaled_mask)
+                    });
+
+                    // Повёрнутые копии ищутся только на основном масштабе; лучший вариант
+                    // в каждом месте так же оставляет filter_close_detections
+                    if template.rotation_tolerant {
+                        for &angle in &ROTATION_ANGLES {
+                            if let Ok((rotated, rotated_mask)) =
+                                rotate_template(scaled_template, scaled_mask, angle)
+                            {
+                                results.extend(self.match_template_cpu(
+                                    image,
+                                    template,
+                                    &rotated,
+                                    &rotated_mask,
+                                ));
+                            }
+                        }
+                    }
                 }
 
-                local_results
-            })
-            .collect();
+                results
+            };
+
+        let active_templates = self.get_active_templates();
+        let strip_count = rayon::current_num_threads() / active_templates.len().max(1);
+
+        // Параллельное сопоставление шаблонов; если шаблонов меньше, чем ядер,
+        // кадр дополнительно режется на горизонтальные полосы
+        let all_results: Vec<Vec<DetectionResult>> = if strip_count < 2 {
+            active_templates
+                .par_iter()
+                .map(|template| match_template(template, &resized))
+                .collect()
+        } else {
+            let strips = self.horizontal_strips(&resized, &active_templates, strip_count)?;
+            active_templates
+                .par_iter()
+                .flat_map(|template| strips.par_iter().map(move |strip| (template, strip)))
+                .map(|(template, (offset_y, strip))| {
+                    let mut results = match_template(template, strip);
+                    for result in &mut results {
+                        result.location.y += *offset_y;
+                    }
+                    results
+                })
+                .collect()
+        };
+        let all_results = self.to_window_space(all_results);
         let elapsed = start_time.elapsed();
         let elapsed_ms = elapsed.as_millis();
 
@@ -564,48 +1838,37 @@ impl ObjectDetector {
             fn detect_objects_cuda(
                 &mut self,
                 image: &Mat,
-                convert_to_grayscale: bool,
+                color_space: MatchColorSpace,
             ) -> OpenCVResult<(Vec<DetectionResult>, u128)> {
                 use opencv::core::GpuMat;
-                use opencv::cudawarping;
 
                 let start_time = Instant::now();
 
                 // Подготовка изображения на GPU
-                let gpu_image = self.prepare_image_cuda(image, convert_to_grayscale)?;
+                let gpu_image = self.prepare_image_cuda(image, color_space)?;
 
                 let active_templates = self.get_active_templates();
                 let all_results: Vec<Vec<DetectionResult>> = active_templates
                     .par_iter()
                     .map(|template| {
-                        let template_image = if convert_to_grayscale {
-                            &template.gray_template
-                        } else {
-                            &template.template
-                        };
-
-                        // Загрузка шаблона на GPU
-                        let mut gpu_template = GpuMat::new().ok()?;
-                        gpu_template.upload(template_image).ok()?;
+                        let template_image = template.scaled_for(color_space);
 
-                        // Масштабирование шаблона
+                        // Загрузка уже масштабированного шаблона на GPU
                         let mut scaled_template = GpuMat::new().ok()?;
-                        let scale_factor = template.resolution.unwrap_or(self.base_scale_factor);
-                        cudawarping::resize(
-                            &gpu_template,
-                            &mut scaled_template,
-                            Size::new(0, 0),
-                            scale_factor,
-                            scale_factor,
-                            INTER_AREA,
-                            &mut opencv::core::Stream::default().ok()?,
-                        ).ok()?;
+                        scaled_template.upload(template_image).ok()?;
 
                         // Сопоставление шаблонов на GPU
-                        self.match_template_cuda(&gpu_image, &scaled_template, template.threshold)
-                            .unwrap_or_default()
+                        self.match_template_cuda(
+                            &gpu_image,
+                            &scaled_template,
+                            template.threshold,
+                            template.match_method,
+                            template.category,
+                        )
+                        .unwrap_or_default()
                     })
                     .collect();
+                let all_results = self.to_window_space(all_results);
 
                 let elapsed = start_time.elapsed();
                 let elapsed_ms = elapsed.as_millis();
@@ -628,11 +1891,58 @@ impl ObjectDetector {
             };
     }
 
-    pub fn filter_close_detections(
-        &self,
-        mut results: Vec<DetectionResult>,
-    ) -> Vec<DetectionResult> {
-        results.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap());
+    // cudaimgproc::match_template не принимает маску, поэтому при активных
+    // шаблонах с альфа-каналом кадр целиком ищется на CPU
+    pub fn cuda_usable(&self) -> bool {
+        self.use_cuda
+            && self
+                .get_active_templates()
+                .iter()
+                .all(|template| template.alpha_mask.empty())
+    }
+
+    // Включается, только если OpenCV действительно видит OpenCL-устройство
+    pub fn set_use_opencl(&mut self, use_opencl: bool) {
+        self.use_opencl = use_opencl && have_opencl().unwrap_or(false);
+        let _ = set_use_opencl(self.use_opencl);
+    }
+
+    // Похожие спрайты (Barrel 7 и Barrel 8) проходят порог в одном месте с близкой
+    // уверенностью; такие места выкидываем целиком, чтобы класс не прыгал между кадрами.
+    // Сравниваются только совпадения выше своих порогов
+    fn drop_ambiguous(&self, results: Vec<DetectionResult>) -> Vec<DetectionResult> {
+        if self.ambiguity_margin <= 0.0 {
+            return results;
+        }
+
+        let overlaps = |a: &DetectionResult, b: &DetectionResult| {
+            (a.location.x - b.location.x).abs() * 2 < a.width.min(b.width)
+                && (a.location.y - b.location.y).abs() * 2 < a.height.min(b.height)
+        };
+
+        results
+            .iter()
+            .filter(|result| {
+                let ambiguous = results.iter().any(|other| {
+                    other.object_name != result.object_name
+                        && overlaps(result, other)
+                        && (other.confidence - result.confidence).abs() < self.ambiguity_margin
+                });
+                if ambiguous {
+                    debug!(
+                        "Ambiguous {} at ({}, {}) dropped",
+                        result.object_name, result.location.x, result.location.y
+                    );
+                }
+                !ambiguous
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn filter_close_detections(&self, results: Vec<DetectionResult>) -> Vec<DetectionResult> {
+        let mut results = self.drop_ambiguous(results);
+        results.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
 
         let mut filtered = Vec::new();
         let mut occupied = Vec::new();
@@ -679,8 +1989,8 @@ impl ObjectDetector {
                     Rect::new(
                         detection.location.x,
                         detection.location.y,
-                        template.template.cols(),
-                        template.template.rows(),
+                        detection.width,
+                        detection.height,
                     ),
                     color,
                     2,
@@ -704,3 +2014,769 @@ impl ObjectDetector {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::CV_8UC3;
+    use opencv::core::Vec3b;
+
+    fn encode(image: &Mat) -> Vec<u8> {
+        let mut buffer = Vector::<u8>::new();
+        imgcodecs::imencode(".png", image, &mut buffer, &Vector::new()).unwrap();
+        buffer.to_vec()
+    }
+
+    fn png_bytes() -> Vec<u8> {
+        encode(&Mat::new_rows_cols_with_default(8, 8, CV_8UC3, Scalar::all(100.0)).unwrap())
+    }
+
+    // Спрайт с неповторяющимся узором, чтобы он совпадал только в одном месте
+    fn sprite(size: i32) -> Mat {
+        let mut image =
+            Mat::new_rows_cols_with_default(size, size, CV_8UC3, Scalar::all(0.0)).unwrap();
+        for y in 0..size {
+            for x in 0..size {
+                let value = ((x * 53 + y * 97 + x * y * 13) % 200 + 30) as u8;
+                *image.at_2d_mut::<Vec3b>(y, x).unwrap() = Vec3b::all(value);
+            }
+        }
+        image
+    }
+
+    fn place(board: &mut Mat, sprite: &Mat, x: i32, y: i32) {
+        for row in 0..sprite.rows() {
+            for col in 0..sprite.cols() {
+                *board.at_2d_mut::<Vec3b>(y + row, x + col).unwrap() =
+                    *sprite.at_2d::<Vec3b>(row, col).unwrap();
+            }
+        }
+    }
+
+    // Однотонный кадр 64x64 со спрайтом, левый верхний угол которого в (x, y)
+    fn board_with(sprite: &Mat, background: f64, x: i32, y: i32) -> Mat {
+        let mut board =
+            Mat::new_rows_cols_with_default(64, 64, CV_8UC3, Scalar::all(background)).unwrap();
+        place(&mut board, sprite, x, y);
+        board
+    }
+
+    fn detector_for(data: &[u8], match_method: MatchMethod) -> ObjectDetector {
+        let mut detector = ObjectDetector::new(1.0);
+        detector.set_use_cuda(false);
+        detector
+            .add_template_bytes(
+                "Barrel 1",
+                data,
+                0.9,
+                5.0,
+                0.0,
+                0.0,
+                0.0,
+                None,
+                false,
+                match_method,
+                None,
+                None,
+            )
+            .unwrap();
+        detector
+    }
+
+    fn locations(detections: &[DetectionResult]) -> Vec<(i32, i32)> {
+        detections
+            .iter()
+            .map(|d| (d.location.x, d.location.y))
+            .collect()
+    }
+
+    fn detector_with(templates: &[(&str, bool)]) -> ObjectDetector {
+        let data = png_bytes();
+        let mut detector = ObjectDetector::new(1.0);
+        for &(name, always_active) in templates {
+            detector
+                .add_template_bytes(
+                    name,
+                    &data,
+                    0.9,
+                    5.0,
+                    0.0,
+                    0.0,
+                    0.0,
+                    None,
+                    always_active,
+                    MatchMethod::default(),
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+        detector
+    }
+
+    fn active_names(detector: &ObjectDetector) -> Vec<String> {
+        detector
+            .get_active_templates()
+            .iter()
+            .map(|t| t.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn always_active_template_outside_the_range_is_active_once() {
+        let mut detector = detector_with(&[
+            ("Barrel 1", false),
+            ("Barrel 2", false),
+            ("Barrel 3", false),
+            ("Event", true),
+        ]);
+        detector.active_range = (0, 1);
+
+        let names = active_names(&detector);
+        assert_eq!(names.iter().filter(|name| *name == "Event").count(), 1);
+        assert!(names.contains(&"Barrel 1".to_string()));
+        assert!(names.contains(&"Barrel 2".to_string()));
+        assert!(!names.contains(&"Barrel 3".to_string()));
+    }
+
+    #[test]
+    fn always_active_template_inside_the_range_is_not_duplicated() {
+        let mut detector = detector_with(&[("Barrel 1", false), ("Event", true)]);
+        detector.active_range = (0, 1);
+
+        assert_eq!(active_names(&detector), vec!["Event", "Barrel 1"]);
+    }
+
+    #[test]
+    fn sqdiff_template_is_found_at_its_location() {
+        let sprite = sprite(12);
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+
+        let board = board_with(&sprite, 100.0, 20, 30);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+    }
+
+    fn barrel_at(level: u32, x: i32, y: i32) -> DetectionResult {
+        DetectionResult {
+            object_name: format!("Barrel {}", level),
+            location: Point::new(x, y),
+            confidence: 0.95,
+            width: 8,
+            height: 8,
+            category: TemplateCategory::Barrel,
+        }
+    }
+
+    #[test]
+    fn active_range_narrows_around_detected_levels() {
+        let names: Vec<String> = (1..=30).map(|level| format!("Barrel {}", level)).collect();
+        let templates: Vec<(&str, bool)> =
+            names.iter().map(|name| (name.as_str(), false)).collect();
+        let mut detector = detector_with(&templates);
+
+        detector.update_active_range(&[
+            barrel_at(10, 0, 0),
+            barrel_at(11, 100, 0),
+            barrel_at(12, 200, 0),
+        ]);
+
+        // Уровни 10-12 дают окно 2..=16, то есть шаблоны с индексами 1..=15
+        assert_eq!(detector.active_range, (1, 15));
+        assert!(!detector.full_range);
+    }
+
+    #[test]
+    fn template_from_bytes_keeps_its_dimensions() {
+        let image =
+            Mat::new_rows_cols_with_default(6, 9, CV_8UC3, Scalar::new(10.0, 20.0, 30.0, 0.0))
+                .unwrap();
+        let template = ObjectTemplate::from_bytes(
+            "Barrel 1",
+            &encode(&image),
+            0.9,
+            5.0,
+            0.0,
+            0.0,
+            0.0,
+            None,
+            false,
+            MatchMethod::CcoeffNormed,
+        )
+        .unwrap();
+
+        assert_eq!(template.template.size().unwrap(), Size::new(9, 6));
+        assert_eq!(template.gray_template.size().unwrap(), Size::new(9, 6));
+        assert_eq!(template.template.channels(), 3);
+        assert_eq!(template.gray_template.channels(), 1);
+    }
+
+    #[test]
+    fn masked_template_matches_on_another_background() {
+        let sprite = sprite(16);
+        let mut template =
+            Mat::new_rows_cols_with_default(24, 24, CV_8UC3, Scalar::new(154.0, 195.0, 161.0, 0.0))
+                .unwrap();
+        for row in 0..16 {
+            for col in 0..16 {
+                *template.at_2d_mut::<Vec3b>(row + 4, col + 4).unwrap() =
+                    *sprite.at_2d::<Vec3b>(row, col).unwrap();
+            }
+        }
+        let data = encode(&template);
+        let board = board_with(&sprite, 0.0, 20, 30);
+
+        let mut masked = ObjectDetector::new(1.0);
+        masked.set_use_cuda(false);
+        masked
+            .add_template_bytes(
+                "Barrel 1",
+                &data,
+                0.9,
+                5.0,
+                0.0,
+                0.0,
+                0.0,
+                None,
+                false,
+                MatchMethod::CcoeffNormed,
+                Some((154, 195, 161)),
+                None,
+            )
+            .unwrap();
+        let (detections, _) = masked
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(16, 26)]);
+
+        // Без маски зелёная рамка шаблона не совпадает с чёрным фоном
+        let mut unmasked = detector_for(&data, MatchMethod::CcoeffNormed);
+        let (detections, _) = unmasked
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert!(detections.is_empty());
+    }
+
+    #[test]
+    fn alpha_template_falls_back_to_cpu_matching() {
+        let sprite = sprite(12);
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        // Путь CUDA включается напрямую, set_use_cuda без устройства его не даст
+        detector.use_cuda = true;
+        assert!(detector.cuda_usable());
+
+        // Прозрачная рамка в 2 пикселя: фон вокруг спрайта не участвует в сравнении
+        let mut alpha = Mat::new_rows_cols_with_default(12, 12, CV_8U, Scalar::all(0.0)).unwrap();
+        Mat::roi_mut(&mut alpha, Rect::new(2, 2, 8, 8))
+            .unwrap()
+            .set_to(&Scalar::all(255.0), &no_array())
+            .unwrap();
+        let mut template = (*detector.templates[0]).clone();
+        template.alpha_mask = alpha;
+        template.scale_factor = 0.0;
+        detector.templates[0] = Arc::new(template);
+        assert!(!detector.cuda_usable());
+
+        let mut board = board_with(&sprite, 100.0, 20, 30);
+        // Мусор на месте прозрачной рамки
+        Mat::roi_mut(&mut board, Rect::new(20, 30, 12, 2))
+            .unwrap()
+            .set_to(&Scalar::all(250.0), &no_array())
+            .unwrap();
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+    }
+
+    #[test]
+    fn matches_outside_the_roi_are_ignored() {
+        let sprite = sprite(12);
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        // ROI задан для окна 128x128, кадр вдвое меньше
+        detector.set_roi(Some((16, 32, 80, 80)), (128, 128));
+
+        let outside = board_with(&sprite, 100.0, 48, 2);
+        let (detections, _) = detector
+            .detect_objects_optimized(&outside, MatchColorSpace::Gray)
+            .unwrap();
+        assert!(detections.is_empty(), "{:?}", locations(&detections));
+
+        // Внутри ROI координаты возвращаются в кадр целиком
+        let inside = board_with(&sprite, 100.0, 20, 30);
+        let (detections, _) = detector
+            .detect_objects_optimized(&inside, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+    }
+
+    #[test]
+    fn a_change_in_one_cell_is_not_a_static_frame() {
+        let mut detector = detector_for(&encode(&sprite(12)), MatchMethod::SqdiffNormed);
+        detector.static_frame_threshold = 0.5;
+
+        let first = Mat::new_rows_cols_with_default(64, 64, CV_8UC3, Scalar::all(100.0)).unwrap();
+        detector
+            .detect_objects_optimized(&first, MatchColorSpace::Gray)
+            .unwrap();
+        assert!(detector.frame_is_static(&first).unwrap().1);
+
+        // Пятно 4x4 внутри одной клетки: средняя разница по кадру меньше 0.5
+        let mut changed = first.clone();
+        Mat::roi_mut(&mut changed, Rect::new(16, 16, 4, 4))
+            .unwrap()
+            .set_to(&Scalar::all(200.0), &no_array())
+            .unwrap();
+        assert!(!detector.frame_is_static(&changed).unwrap().1);
+
+        // Изменившийся кадр проходит детекцию и находит новый спрайт
+        let board = board_with(&sprite(12), 100.0, 20, 30);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+    }
+
+    #[test]
+    fn strips_find_the_same_objects_as_the_whole_frame() {
+        let sprite = sprite(12);
+        let mut board = board_with(&sprite, 100.0, 4, 2);
+        // Второй спрайт лежит на стыке полос по 8 строк
+        place(&mut board, &sprite, 30, 19);
+        place(&mut board, &sprite, 44, 45);
+
+        // Один поток - кадр целиком, восемь потоков на один шаблон - восемь полос
+        let detect_with_threads = |threads: usize| {
+            let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let (detections, _) = pool
+                .install(|| detector.detect_objects_optimized(&board, MatchColorSpace::Gray))
+                .unwrap();
+            let mut found = locations(&detections);
+            found.sort();
+            found
+        };
+
+        let whole = detect_with_threads(1);
+        assert_eq!(whole, vec![(4, 2), (30, 19), (44, 45)]);
+        assert_eq!(detect_with_threads(8), whole);
+    }
+
+    #[test]
+    fn opencl_request_falls_back_to_cpu_without_a_device() {
+        let sprite = sprite(12);
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        detector.set_use_opencl(true);
+        // В CI устройства обычно нет: флаг молча остаётся выключенным
+        assert_eq!(detector.use_opencl, have_opencl().unwrap_or(false));
+
+        let board = board_with(&sprite, 100.0, 20, 30);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        detector.set_use_opencl(false);
+
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+    }
+
+    #[test]
+    fn scan_shows_a_peak_for_every_copy_of_the_template() {
+        let sprite = sprite(12);
+        let detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        let mut board = board_with(&sprite, 100.0, 4, 4);
+        place(&mut board, &sprite, 40, 36);
+
+        let confidences = detector
+            .scan_confidences(&board, "Barrel 1", MatchColorSpace::Gray, 5)
+            .unwrap();
+        assert_eq!(confidences.len(), 5);
+        // Две настоящие копии у единицы, дальше заметный провал до шума
+        let (peaks, noise) = confidences.split_at(2);
+        assert!(peaks.iter().all(|&c| c > 0.99), "{:?}", confidences);
+        assert!(noise.iter().all(|&c| c < 0.9), "{:?}", confidences);
+
+        assert!(
+            detector
+                .scan_confidences(&board, "Barrel 9", MatchColorSpace::Gray, 5)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn empty_and_cloud_are_active_wherever_they_were_added() {
+        let orders: [&[&str]; 3] = [
+            &["Empty", "Cloud", "Barrel 1", "Barrel 2", "Barrel 3"],
+            &["Barrel 1", "Barrel 2", "Barrel 3", "Cloud", "Empty"],
+            &["Barrel 1", "Cloud", "Barrel 2", "Empty", "Barrel 3"],
+        ];
+        for order in orders {
+            let templates: Vec<(&str, bool)> = order.iter().map(|&name| (name, false)).collect();
+            let mut detector = detector_with(&templates);
+            let barrel_2 = order.iter().position(|&name| name == "Barrel 2").unwrap();
+            detector.active_range = (barrel_2, barrel_2);
+
+            let mut names = active_names(&detector);
+            names.sort();
+            assert_eq!(names, vec!["Barrel 2", "Cloud", "Empty"], "{:?}", order);
+        }
+    }
+
+    // Детектор из Barrel 1 = sprite и второго шаблона Barrel 2
+    fn detector_with_second(sprite: &Mat, second: &Mat, ambiguity_margin: f64) -> ObjectDetector {
+        let mut detector = detector_for(&encode(sprite), MatchMethod::SqdiffNormed);
+        detector
+            .add_template_bytes(
+                "Barrel 2",
+                &encode(second),
+                0.9,
+                5.0,
+                0.0,
+                0.0,
+                0.0,
+                None,
+                false,
+                MatchMethod::SqdiffNormed,
+                None,
+                None,
+            )
+            .unwrap();
+        detector.ambiguity_margin = ambiguity_margin;
+        detector
+    }
+
+    fn names(detections: &[DetectionResult]) -> Vec<&str> {
+        detections.iter().map(|d| d.object_name.as_str()).collect()
+    }
+
+    #[test]
+    fn overlapping_matches_of_similar_templates_are_dropped() {
+        let sprite = sprite(12);
+        let board = board_with(&sprite, 100.0, 20, 30);
+        // Второй шаблон отличается четырьмя пикселями и проходит порог там же
+        let mut similar = sprite.clone();
+        for i in 0..4 {
+            let pixel = similar.at_2d_mut::<Vec3b>(i * 3, i * 3).unwrap();
+            *pixel = Vec3b::all(pixel[0].saturating_add(40));
+        }
+
+        let mut detector = detector_with_second(&sprite, &similar, 0.0);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+        assert_eq!(names(&detections), vec!["Barrel 1"]);
+
+        let mut detector = detector_with_second(&sprite, &similar, 0.05);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert!(detections.is_empty(), "{:?}", names(&detections));
+
+        // Непохожий второй шаблон под порогом: совпадение остаётся
+        let mut transposed = Mat::default();
+        opencv::core::transpose(&sprite, &mut transposed).unwrap();
+        let mut detector = detector_with_second(&sprite, &transposed, 0.05);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(names(&detections), vec!["Barrel 1"]);
+    }
+
+    #[test]
+    fn rotation_tolerant_template_finds_a_tilted_sprite() {
+        let sprite = sprite(24);
+        let (tilted, _) = rotate_template(&sprite, &Mat::default(), ROTATION_ANGLES[3]).unwrap();
+        let board = board_with(&tilted, 100.0, 20, 30);
+
+        // Прямой шаблон на наклон в 6 градусов не набирает порога
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert!(detections.is_empty(), "{:?}", locations(&detections));
+
+        // Положение - левый верхний угол неповёрнутого спрайта
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        Arc::make_mut(&mut detector.templates[0]).rotation_tolerant = true;
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+    }
+
+    #[test]
+    fn every_broken_template_path_is_reported() {
+        let dir = std::env::temp_dir().join(format!("scrap2_templates_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("good.png");
+        let corrupt = dir.join("corrupt.png");
+        let tiny = dir.join("tiny.png");
+        let missing = dir.join("missing.png");
+        std::fs::write(&good, png_bytes()).unwrap();
+        std::fs::write(&corrupt, b"not a png").unwrap();
+        let two_by_two = Mat::new_rows_cols_with_default(2, 2, CV_8UC3, Scalar::all(100.0));
+        std::fs::write(&tiny, encode(&two_by_two.unwrap())).unwrap();
+
+        let template = |name: &str, path: &Path| -> TemplateSettings {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "path": path.to_str().unwrap(),
+                "threshold": 0.9,
+                "min_distance": 5.0,
+                "red": 0.0,
+                "green": 0.0,
+                "blue": 0.0,
+                "resolution": null,
+            }))
+            .unwrap()
+        };
+        assert!(check_template_files(&[template("Barrel 1", &good)]).is_ok());
+
+        let templates = [
+            template("Barrel 1", &good),
+            template("Barrel 2", &missing),
+            template("Barrel 3", &corrupt),
+            template("Barrel 4", &tiny),
+        ];
+        let message = check_template_files(&templates).unwrap_err().to_string();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Все три битых файла сразу, каждый со своим путём и причиной
+        assert!(!message.contains("good.png"), "{}", message);
+        for (path, problem) in [
+            (&missing, "file not found"),
+            (&corrupt, "not a readable image"),
+            (&tiny, "2x2 is too small"),
+        ] {
+            let line = format!("{}: {}", path.display(), problem);
+            assert!(message.contains(&line), "{} missing from {}", line, message);
+        }
+
+        let error = ObjectTemplate::new(
+            "Barrel 2",
+            missing.to_str().unwrap(),
+            0.9,
+            5.0,
+            0.0,
+            0.0,
+            0.0,
+            None,
+            false,
+            MatchMethod::default(),
+            None,
+            None,
+        )
+        .unwrap_err();
+        assert!(error.message.contains("missing.png"), "{}", error.message);
+    }
+
+    #[test]
+    fn template_with_its_own_resolution_reports_window_pixels() {
+        // Спрайт нарезан с экрана вдвое крупнее: resolution 0.25 приводит его к кадру,
+        // который детектор уменьшает до 0.5
+        let sprite = sprite(24);
+        let mut doubled = Mat::default();
+        resize(
+            &sprite,
+            &mut doubled,
+            Size::default(),
+            2.0,
+            2.0,
+            INTER_NEAREST,
+        )
+        .unwrap();
+        let mut detector = ObjectDetector::new(0.5);
+        detector.set_use_cuda(false);
+        detector
+            .add_template_bytes(
+                "Barrel 1",
+                &encode(&doubled),
+                0.9,
+                5.0,
+                0.0,
+                0.0,
+                0.0,
+                Some(0.25),
+                false,
+                MatchMethod::SqdiffNormed,
+                None,
+                None,
+            )
+            .unwrap();
+
+        let board = board_with(&sprite, 100.0, 20, 30);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+        assert_eq!((detections[0].width, detections[0].height), (24, 24));
+    }
+
+    // Узор одного цвета на чёрном, форма та же, что у sprite
+    fn colored_sprite(color: Vec3b) -> Mat {
+        let mut image = Mat::new_rows_cols_with_default(12, 12, CV_8UC3, Scalar::all(0.0)).unwrap();
+        for y in 0..12 {
+            for x in 0..12 {
+                if (x * 53 + y * 97 + x * y * 13) % 3 == 0 {
+                    *image.at_2d_mut::<Vec3b>(y, x).unwrap() = color;
+                }
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn hsv_tells_apart_sprites_that_differ_only_in_hue() {
+        // Синий 255 и красный 98 в оттенках серого оба дают 29
+        let blue = colored_sprite(Vec3b::from([255, 0, 0]));
+        let red = colored_sprite(Vec3b::from([0, 0, 98]));
+        let board = board_with(&blue, 100.0, 20, 30);
+
+        let found = |template: &Mat, color_space| {
+            let mut detector = detector_for(&encode(template), MatchMethod::SqdiffNormed);
+            let (detections, _) = detector
+                .detect_objects_optimized(&board, color_space)
+                .unwrap();
+            locations(&detections)
+        };
+        assert_eq!(found(&blue, MatchColorSpace::Hsv), vec![(20, 30)]);
+        assert_eq!(found(&red, MatchColorSpace::Hsv), vec![]);
+        assert_eq!(found(&red, MatchColorSpace::Gray), vec![(20, 30)]);
+    }
+
+    #[test]
+    fn suppression_at_the_corners_stays_inside_the_match_map() {
+        // Карта совпадений кадра 64x64 для шаблона 12x12
+        let map = Mat::new_rows_cols_with_default(53, 53, CV_8U, Scalar::all(0.0)).unwrap();
+        let top_left = suppression_rect(Point::new(0, 0), 12, 12, 1.0, &map);
+        assert_eq!(top_left, Rect::new(0, 0, 6, 6));
+        let bottom_right = suppression_rect(Point::new(52, 52), 12, 12, 1.0, &map);
+        assert_eq!(bottom_right, Rect::new(46, 46, 7, 7));
+
+        let sprite = sprite(12);
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        let board = board_with(&sprite, 100.0, 0, 0);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(0, 0)]);
+
+        // Однотонный шаблон проходит порог по всей карте, но поиск всё равно заканчивается
+        let mut detector = detector_for(&png_bytes(), MatchMethod::SqdiffNormed);
+        let board = Mat::new_rows_cols_with_default(64, 64, CV_8UC3, Scalar::all(100.0)).unwrap();
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert!(!detections.is_empty());
+        assert!(detections.len() <= MAX_MATCHES_PER_TEMPLATE);
+    }
+
+    #[test]
+    fn suppression_scale_decides_whether_overlapping_copies_both_count() {
+        // Вторая копия закрывает угол первой, центры в 11 пикселях - дальше min_distance
+        let sprite = sprite(12);
+        let mut board = board_with(&sprite, 100.0, 10, 10);
+        place(&mut board, &sprite, 18, 18);
+
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(18, 18), (10, 10)]);
+
+        // Вдвое большая гасимая область накрывает и пик частично закрытой копии
+        let mut detector = detector_for(&encode(&sprite), MatchMethod::SqdiffNormed);
+        detector.suppression_scale = 2.0;
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(18, 18)]);
+    }
+
+    #[test]
+    fn mixed_detections_map_onto_the_expected_cells() {
+        let cell = |name: &str, category, x, y| DetectionResult {
+            object_name: name.to_string(),
+            category,
+            ..barrel_at(1, x, y)
+        };
+        // Поле 2x3 с шагом 50; координаты гуляют на пару пикселей
+        let detections = vec![
+            barrel_at(1, 0, 0),
+            cell("Empty", TemplateCategory::Empty, 51, 1),
+            barrel_at(3, 100, 0),
+            cell("Empty", TemplateCategory::Empty, 1, 50),
+            barrel_at(2, 48, 52),
+            // Пустая клетка поверх бочки её не затирает, облако и кнопки в сетку не идут
+            cell("Empty", TemplateCategory::Empty, 2, 1),
+            cell("Cloud", TemplateCategory::Cloud, 100, 50),
+            cell("Continue", TemplateCategory::Continue, 70, 30),
+        ];
+
+        let grid = ObjectDetector::build_occupancy_grid(&detections, 2, 3);
+        let rows: Vec<Vec<GridCell>> = (0..2)
+            .map(|row| (0..3).map(|col| grid.get((row, col))).collect())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec![GridCell::Barrel(1), GridCell::Empty, GridCell::Barrel(3)],
+                vec![GridCell::Empty, GridCell::Barrel(2), GridCell::Unknown],
+            ]
+        );
+        assert_eq!(grid.cell_of(&barrel_at(2, 48, 52)), Some((1, 1)));
+        assert_eq!(grid.empty_cells().collect::<Vec<_>>(), vec![(0, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn cooldown_covers_the_same_cell_only() {
+        let mut detector = detector_with(&[("Barrel 1", false)]);
+        let cooldown = Duration::from_secs(60);
+        detector.mark_acted(&barrel_at(1, 96, 96), cooldown);
+
+        // Сдвиг на 2 пикселя остаётся в той же клетке COOLDOWN_GRID, на 8 - уже нет
+        assert!(detector.on_cooldown(&barrel_at(1, 98, 94), cooldown));
+        assert!(!detector.on_cooldown(&barrel_at(1, 104, 96), cooldown));
+        assert!(!detector.on_cooldown(&barrel_at(1, 96, 88), cooldown));
+        // Другая бочка на том же месте - другой ключ
+        assert!(!detector.on_cooldown(&barrel_at(2, 96, 96), cooldown));
+    }
+
+    #[test]
+    fn object_matching_only_the_second_frame_keeps_the_template_name() {
+        let first = sprite(12);
+        let mut second = Mat::default();
+        opencv::core::transpose(&first, &mut second).unwrap();
+        let board = board_with(&second, 100.0, 20, 30);
+
+        // Без кадров анимации спрайт во второй фазе не находится
+        let mut detector = detector_for(&encode(&first), MatchMethod::SqdiffNormed);
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert!(detections.is_empty());
+
+        let path = std::env::temp_dir().join(format!("scrap2_frame_{}.png", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, encode(&second)).unwrap();
+        let mut detector = detector_for(&encode(&first), MatchMethod::SqdiffNormed);
+        Arc::make_mut(&mut detector.templates[0])
+            .load_frames(std::slice::from_ref(&path))
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (detections, _) = detector
+            .detect_objects_optimized(&board, MatchColorSpace::Gray)
+            .unwrap();
+        assert_eq!(locations(&detections), vec![(20, 30)]);
+        assert_eq!(names(&detections), vec!["Barrel 1"]);
+    }
+}
@@ -1,74 +1,457 @@
-use crate::capture::{AppError, AppResult};
+use crate::capture::AppResult;
+use crate::capture::CaptureBackend;
 use crate::drawing::draw_cloud;
+use crate::input::InputBackend;
+use crate::moving::Bounds;
 use crate::moving::human_like_move;
-use crate::objectdetector::{DetectionResult, ObjectDetector};
-use crate::settings::{HumanLikeMovementSettings, Settings};
-use opencv::prelude::MatTraitConst;
+use crate::moving::human_like_move_within;
+use crate::objectdetector::{DetectionResult, GridCell, ObjectDetector, OccupancyGrid};
+use crate::settings::{
+    AntiCaptchaMode, CloudPattern, CollectMode, Easing, HumanLikeMovementSettings, IdleWiggle,
+    MergeDirection, MergeGesture, Settings, TemplateCategory,
+};
+use crate::stats::MergeStats;
+use crate::utils::jitter;
+use crate::utils::paused;
+use crate::utils::rng;
+use crate::utils::shutdown_requested;
+use log::info;
+use log::warn;
+use opencv::core::Rect;
 use rand::Rng;
-use std::process::Command;
 use std::thread;
 use std::time::Duration;
 
-pub fn anti_anti_captcha() {
-    
+// Точка внутри найденной капчи или кнопки по долям её размера, в координатах экрана
+fn captcha_point(
+    (window_x, window_y): (i32, i32),
+    captcha: &DetectionResult,
+    (fraction_x, fraction_y): (f64, f64),
+) -> (i32, i32) {
+    (
+        window_x + captcha.location.x + (captcha.width as f64 * fraction_x).round() as i32,
+        window_y + captcha.location.y + (captcha.height as f64 * fraction_y).round() as i32,
+    )
 }
 
-pub fn calculate_required_merges(barrels: &[DetectionResult]) -> (u32, u32, u32) {
+// Капча по automation.anticaptcha.mode; пока она на экране, бочки не трогаем.
+// true - решать будет человек, бот встаёт на паузу до горячей клавиши
+pub fn process_anticaptcha(
+    input: &mut dyn InputBackend,
+    window_x: i32,
+    window_y: i32,
+    captcha: &DetectionResult,
+    settings: &Settings,
+) -> AppResult<bool> {
+    let anticaptcha = &settings.automation.anticaptcha;
+    match anticaptcha.mode {
+        AntiCaptchaMode::Notify => {
+            // Звонок в stderr: stdout может быть занят json
+            eprint!("\x07");
+            warn!(
+                "{} on screen, paused until {} is pressed",
+                captcha.object_name, settings.hotkeys.pause
+            );
+            Ok(true)
+        }
+        _ if settings.dry_run => {
+            info!(
+                "{} {:?} at ({},{})",
+                captcha.object_name,
+                anticaptcha.mode,
+                window_x + captcha.location.x,
+                window_y + captcha.location.y
+            );
+            Ok(false)
+        }
+        AntiCaptchaMode::Mask => {
+            let mut rng = rng();
+            for &fraction in &anticaptcha.mask_points {
+                if shutdown_requested() || paused() {
+                    break;
+                }
+                let (x, y) = captcha_point((window_x, window_y), captcha, fraction);
+                human_like_move(input, x, y, &settings.human_like_movement)?;
+                tap(input, &mut rng, settings)?;
+            }
+            Ok(false)
+        }
+        AntiCaptchaMode::Solve => {
+            let Some((&first, rest)) = anticaptcha.solve_path.split_first() else {
+                return Ok(false);
+            };
+            let (x, y) = captcha_point((window_x, window_y), captcha, first);
+            human_like_move(input, x, y, &settings.human_like_movement)?;
+            if let Err(e) = input.mouse_down() {
+                let _ = input.mouse_up();
+                return Err(e);
+            }
+
+            // Кнопка зажата: отпускаем её даже при ошибке
+            let drag_result = rest.iter().try_for_each(|&fraction| {
+                let (x, y) = captcha_point((window_x, window_y), captcha, fraction);
+                human_like_move(input, x, y, &settings.human_like_movement)
+            });
+            input.mouse_up()?;
+            drag_result.map(|_| false)
+        }
+    }
+}
+
+// Окно конца уровня: тап по центру кнопки продолжения, и новый уровень начинается
+// с полным диапазоном шаблонов. Другие кнопки интерфейса не трогаем.
+// true - кнопка найдена и нажата, кадр обработан
+pub fn process_ui(
+    input: &mut dyn InputBackend,
+    window_x: i32,
+    window_y: i32,
+    detections: &[DetectionResult],
+    detector: &mut ObjectDetector,
+    settings: &Settings,
+) -> AppResult<bool> {
+    if !settings.automation.auto_continue {
+        return Ok(false);
+    }
+    let Some(button) = detections
+        .iter()
+        .filter(|d| d.category == TemplateCategory::Continue)
+        .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+    else {
+        return Ok(false);
+    };
+
+    // В dry run тап получает DryRunBackend и только пишет его в лог
+    let (x, y) = captcha_point((window_x, window_y), button, (0.5, 0.5));
+    human_like_move(input, x, y, &settings.human_like_movement)?;
+    tap(input, &mut rng(), settings)?;
+    detector.reset_active_range();
+    info!("{} pressed, next level", button.object_name);
+    Ok(true)
+}
+
+// Симулирует слияния снизу вверх, как их делает process_barrels: каждая пара
+// одинаковых бочек даёт бочку следующего уровня, которая тоже может слиться.
+// Возвращает (мин. уровень, макс. уровень, число слияний, итоговый макс. уровень)
+pub fn calculate_required_merges(barrels: &[DetectionResult]) -> (u32, u32, u32, u32) {
     // Собираем статистику по уровням бочек
-    let mut level_counts = std::collections::HashMap::new();
+    let mut level_counts = std::collections::BTreeMap::new();
     for barrel in barrels {
-        let level = barrel
-            .object_name
-            .split_whitespace()
-            .last()
-            .unwrap_or("0")
-            .parse()
-            .unwrap_or(0);
-        *level_counts.entry(level).or_insert(0) += 1;
+        let level = barrel.barrel_level().unwrap_or(0);
+        *level_counts.entry(level).or_insert(0u32) += 1;
     }
 
-    let min_level = *level_counts.keys().min().unwrap_or(&0);
-    let max_level = *level_counts.keys().max().unwrap_or(&0);
-
-    // Вычисляем сколько соединений нужно с учетом уже имеющихся бочек
-    let mut merges_needed = 0;
-    let mut needed = 2; // Для получения 1 бочки следующего уровня нужно 2 текущего
+    let min_level = *level_counts.keys().next().unwrap_or(&0);
+    let max_level = *level_counts.keys().next_back().unwrap_or(&0);
 
-    for level in (min_level..=max_level).rev() {
+    let mut merges = 0;
+    let mut level = min_level;
+    let mut final_level = max_level;
+    loop {
         let available = *level_counts.get(&level).unwrap_or(&0);
+        let pairs = available / 2;
+        if pairs > 0 {
+            merges += pairs;
+            *level_counts.entry(level + 1).or_insert(0) += pairs;
+            final_level = final_level.max(level + 1);
+        }
 
-        if available >= needed {
-            // Хватает бочек этого уровня
-            merges_needed += needed / 2;
-            needed = 0;
+        // Выше max_level бочки появляются только из слияний
+        if level >= final_level {
             break;
-        } else {
-            // Не хватает, считаем сколько нужно получить из более низких уровней
-            let missing = needed - available;
-            merges_needed += available / 2;
-            needed = missing * 2;
         }
+        level += 1;
     }
 
-    // Если все еще нужно бочки (не хватило даже минимальных)
-    if needed > 0 {
-        merges_needed += needed / 2;
+    (min_level, max_level, merges, final_level)
+}
+
+fn center(barrel: &DetectionResult) -> (i32, i32) {
+    (
+        barrel.location.x + barrel.width / 2,
+        barrel.location.y + barrel.height / 2,
+    )
+}
+
+// Пара индексов, первой - бочка ближе к point (в координатах окна)
+fn nearest_first(
+    barrels: &[DetectionResult],
+    i: usize,
+    j: usize,
+    point: (i32, i32),
+) -> (usize, usize) {
+    let distance = |index: usize| {
+        let (x, y) = center(&barrels[index]);
+        let (dx, dy) = ((x - point.0) as i64, (y - point.1) as i64);
+        dx * dx + dy * dy
+    };
+    if distance(j) < distance(i) {
+        (j, i)
+    } else {
+        (i, j)
     }
+}
 
-    (min_level, max_level, merges_needed)
+// Сколько соседей клетки бочки уже стоят на уровне level
+fn equal_neighbors(grid: &OccupancyGrid, barrel: &DetectionResult, level: u32) -> usize {
+    grid.cell_of(barrel).map_or(0, |cell| {
+        grid.neighbors(cell)
+            .filter(|&neighbor| neighbor == GridCell::Barrel(level))
+            .count()
+    })
+}
+
+// Источник и цель слияния по merge.direction
+fn orient_merge(
+    input: &mut dyn InputBackend,
+    barrels: &[DetectionResult],
+    (i, j): (usize, usize),
+    (window_x, window_y): (i32, i32),
+    direction: MergeDirection,
+    grid: &OccupancyGrid,
+    next_level: u32,
+) -> AppResult<(usize, usize)> {
+    Ok(match direction {
+        MergeDirection::Consolidate => {
+            let into_i = equal_neighbors(grid, &barrels[i], next_level);
+            let into_j = equal_neighbors(grid, &barrels[j], next_level);
+            if into_i == into_j {
+                return orient_merge(
+                    input,
+                    barrels,
+                    (i, j),
+                    (window_x, window_y),
+                    MergeDirection::SpatialStable,
+                    grid,
+                    next_level,
+                );
+            }
+            if into_i > into_j { (j, i) } else { (i, j) }
+        }
+        MergeDirection::FirstIntoSecond => (i, j),
+        MergeDirection::NearestIntoFarthest => {
+            let (cursor_x, cursor_y) = input.get_position()?;
+            nearest_first(barrels, i, j, (cursor_x - window_x, cursor_y - window_y))
+        }
+        MergeDirection::SpatialStable => {
+            let count = barrels.len().max(1) as i32;
+            let (sum_x, sum_y) = barrels
+                .iter()
+                .map(center)
+                .fold((0, 0), |(sx, sy), (x, y)| (sx + x, sy + y));
+            let (near, far) = nearest_first(barrels, i, j, (sum_x / count, sum_y / count));
+            (far, near)
+        }
+    })
+}
+
+// Перетаскивание бочки из from в to
+fn drag_barrel(
+    input: &mut dyn InputBackend,
+    rng: &mut impl Rng,
+    (abs_from_x, abs_from_y): (i32, i32),
+    (abs_to_x, abs_to_y): (i32, i32),
+    bounds: Bounds,
+    settings: &Settings,
+) -> AppResult<()> {
+    // Перемещаемся к начальной точке
+    human_like_move_within(
+        input,
+        abs_from_x,
+        abs_from_y,
+        &settings.human_like_movement,
+        Some(bounds),
+    )?;
+
+    // Небольшая пауза перед кликом
+    if settings.human_like_movement.enabled {
+        thread::sleep(Duration::from_millis(jitter(
+            rng,
+            settings.human_like_movement.min_down_ms,
+            settings.human_like_movement.max_down_ms,
+        )));
+    } else {
+        thread::sleep(Duration::from_millis(rng.gen_range(15..17)));
+    }
+    human_like_move(input, abs_from_x, abs_from_y, &settings.human_like_movement)?;
+    // Нажимаем кнопку мыши; после сбоя кнопка могла остаться нажатой, пробуем отпустить
+    if let Err(e) = input.mouse_down() {
+        let _ = input.mouse_up();
+        return Err(e);
+    }
+
+    // Кнопка зажата: отпускаем её даже при ошибке или остановке
+    let drag_result = move_while_held(input, rng, abs_to_x, abs_to_y, bounds, settings);
+    input.mouse_up()?;
+    drag_result
+}
+
+// Клик с человеческой длительностью нажатия
+fn tap(input: &mut dyn InputBackend, rng: &mut impl Rng, settings: &Settings) -> AppResult<()> {
+    input.mouse_down()?;
+    let hold_ms = if settings.human_like_movement.enabled {
+        jitter(
+            rng,
+            settings.human_like_movement.min_up_ms,
+            settings.human_like_movement.max_up_ms,
+        )
+    } else {
+        rng.gen_range(15..17)
+    };
+    thread::sleep(Duration::from_millis(hold_ms));
+    input.mouse_up()
+}
+
+// Слияние двумя тапами: выбрать исходную бочку, затем целевую
+fn tap_barrels(
+    input: &mut dyn InputBackend,
+    rng: &mut impl Rng,
+    (abs_from_x, abs_from_y): (i32, i32),
+    (abs_to_x, abs_to_y): (i32, i32),
+    bounds: Bounds,
+    settings: &Settings,
+) -> AppResult<()> {
+    human_like_move_within(
+        input,
+        abs_from_x,
+        abs_from_y,
+        &settings.human_like_movement,
+        Some(bounds),
+    )?;
+    tap(input, rng, settings)?;
+
+    if shutdown_requested() {
+        return Ok(());
+    }
+
+    // Пауза, пока игра подсвечивает выбранную бочку
+    if settings.human_like_movement.enabled {
+        thread::sleep(Duration::from_millis(jitter(
+            rng,
+            settings.human_like_movement.min_move_delay_ms,
+            settings.human_like_movement.max_move_delay_ms,
+        )));
+    } else {
+        thread::sleep(Duration::from_millis(rng.gen_range(30..40)));
+    }
+
+    human_like_move_within(
+        input,
+        abs_to_x,
+        abs_to_y,
+        &settings.human_like_movement,
+        Some(bounds),
+    )?;
+    tap(input, rng, settings)
+}
+
+// Перемещение к цели с зажатой кнопкой мыши, сама кнопка остаётся на вызывающем
+fn move_while_held(
+    input: &mut dyn InputBackend,
+    rng: &mut impl Rng,
+    abs_to_x: i32,
+    abs_to_y: i32,
+    bounds: Bounds,
+    settings: &Settings,
+) -> AppResult<()> {
+    if shutdown_requested() {
+        return Ok(());
+    }
+
+    // Небольшая пауза перед началом перемещения
+    if settings.human_like_movement.enabled {
+        thread::sleep(Duration::from_millis(jitter(
+            rng,
+            settings.human_like_movement.min_move_delay_ms,
+            settings.human_like_movement.max_move_delay_ms,
+        )));
+    } else {
+        thread::sleep(Duration::from_millis(rng.gen_range(5..7)));
+    }
+
+    // Перемещаемся к конечной точке
+    human_like_move_within(
+        input,
+        abs_to_x,
+        abs_to_y,
+        &settings.human_like_movement,
+        Some(bounds),
+    )?;
+
+    // Небольшая пауза перед отпусканием
+    thread::sleep(Duration::from_millis(rng.gen_range(15..16)));
+    if settings.human_like_movement.enabled {
+        thread::sleep(Duration::from_millis(jitter(
+            rng,
+            settings.human_like_movement.min_up_ms,
+            settings.human_like_movement.max_up_ms,
+        )));
+    } else {
+        thread::sleep(Duration::from_millis(rng.gen_range(16..17)));
+    }
+
+    // Отпускаем кнопку мыши
+    human_like_move(input, abs_to_x, abs_to_y, &settings.human_like_movement)
+}
+
+// Сколько ждать, пока игра доиграет анимацию слияния, перед проверочным снимком;
+// merge.settle_ms уже отсчитан к этому моменту
+const VERIFY_SETTLE_MS: u64 = 150;
+
+// Снимает окно заново и ищет бочку next_level рядом с местом назначения
+fn verify_merge(
+    capture: &mut dyn CaptureBackend,
+    detector: &ObjectDetector,
+    to: &DetectionResult,
+    next_level: u32,
+    settings: &Settings,
+) -> AppResult<bool> {
+    let settle_ms = VERIFY_SETTLE_MS.saturating_sub(settings.automation.merge.settle_ms);
+    thread::sleep(Duration::from_millis(settle_ms));
+    let (image, _, _) = capture.capture()?;
+
+    // Запас в половину бочки с каждой стороны на случай небольшого сдвига
+    let region = Rect::new(
+        to.location.x - to.width / 2,
+        to.location.y - to.height / 2,
+        to.width * 2,
+        to.height * 2,
+    );
+    detector
+        .match_in_region(
+            &image,
+            region,
+            &format!("Barrel {}", next_level),
+            settings.color_space(),
+        )
+        .map_err(Into::into)
 }
 
 pub fn process_barrels(
+    input: &mut dyn InputBackend,
+    capture: &mut dyn CaptureBackend,
     window_x: i32,
     window_y: i32,
     mut barrels: Vec<DetectionResult>,
+    mut grid: OccupancyGrid,
     detector: &mut ObjectDetector,
     settings: &Settings,
+    stats: &mut MergeStats,
 ) -> AppResult<Vec<DetectionResult>> {
-    let mut rng = rand::thread_rng();
+    let mut rng = rng();
+    let max_level = detector.max_barrel_level();
+    let cooldown = Duration::from_millis(settings.automation.merge.cooldown_ms);
+
+    // Проскок курсора при перетаскивании не должен выходить за окно
+    let (window_width, window_height) = capture.window_size()?;
+    let bounds = Bounds {
+        x: window_x,
+        y: window_y,
+        width: window_width,
+        height: window_height,
+    };
 
     let mut merged = true;
-    while merged {
+    while merged && !shutdown_requested() {
         merged = false;
 
         // Находим ВСЕ возможные пары для слияния
@@ -80,19 +463,26 @@ pub fn process_barrels(
                 continue;
             }
 
+            // Только что перетащенные бочки могут ещё доигрывать анимацию слияния
+            if detector.on_cooldown(&barrels[i], cooldown) {
+                continue;
+            }
+
+            // Бочки максимального уровня сливать не во что, не тратим на них ход
+            let level = barrels[i].barrel_level();
+            if let (Some(level), Some(max_level)) = (level, max_level) {
+                if level >= max_level {
+                    continue;
+                }
+            }
+
             for j in (i + 1)..barrels.len() {
-                if used_indices.contains(&j) {
+                if used_indices.contains(&j) || detector.on_cooldown(&barrels[j], cooldown) {
                     continue;
                 }
 
                 if barrels[i].object_name == barrels[j].object_name {
-                    let current_level: u32 = barrels[i]
-                        .object_name
-                        .split_whitespace()
-                        .last()
-                        .unwrap_or("0")
-                        .parse()
-                        .unwrap_or(0);
+                    let current_level = barrels[i].barrel_level().unwrap_or(0);
                     let next_level = current_level + 1;
 
                     if detector
@@ -122,34 +512,39 @@ pub fn process_barrels(
 
             // Сначала обрабатываем все слияния
             for (i, j, next_level) in merges {
-                let from = &barrels[i];
-                let to = &barrels[j];
+                // Новых перетаскиваний после Ctrl-C или на паузе не начинаем
+                if shutdown_requested() || paused() {
+                    break;
+                }
 
-                let from_template = detector
-                    .templates
-                    .iter()
-                    .find(|t| t.name == from.object_name)
-                    .ok_or_else(|| AppError::ImageProcessing("Template not found".to_string()))?;
+                let (i, j) = orient_merge(
+                    input,
+                    &barrels,
+                    (i, j),
+                    (window_x, window_y),
+                    settings.automation.merge.direction,
+                    &grid,
+                    next_level,
+                )?;
 
-                let to_template = detector
-                    .templates
-                    .iter()
-                    .find(|t| t.name == to.object_name)
-                    .ok_or_else(|| AppError::ImageProcessing("Template not found".to_string()))?;
+                let from = &barrels[i];
+                let to = &barrels[j];
 
-                let from_size = (from_template.template.cols(), from_template.template.rows());
-                let to_size = (to_template.template.cols(), to_template.template.rows());
+                let from_size = (from.width, from.height);
+                let to_size = (to.width, to.height);
 
                 // Вычисляем целевые позиции с учетом случайного смещения
                 let (from_offset_x, from_offset_y) = if settings.random_offset.enabled {
                     (
-                        rng.gen_range(
-                            -settings.random_offset.max_x_offset
-                                ..=settings.random_offset.max_x_offset,
+                        jitter(
+                            &mut rng,
+                            -settings.random_offset.max_x_offset,
+                            settings.random_offset.max_x_offset,
                         ),
-                        rng.gen_range(
-                            -settings.random_offset.max_y_offset
-                                ..=settings.random_offset.max_y_offset,
+                        jitter(
+                            &mut rng,
+                            -settings.random_offset.max_y_offset,
+                            settings.random_offset.max_y_offset,
                         ),
                     )
                 } else {
@@ -158,13 +553,15 @@ pub fn process_barrels(
 
                 let (to_offset_x, to_offset_y) = if settings.random_offset.enabled {
                     (
-                        rng.gen_range(
-                            -settings.random_offset.max_x_offset
-                                ..=settings.random_offset.max_x_offset,
+                        jitter(
+                            &mut rng,
+                            -settings.random_offset.max_x_offset,
+                            settings.random_offset.max_x_offset,
                         ),
-                        rng.gen_range(
-                            -settings.random_offset.max_y_offset
-                                ..=settings.random_offset.max_y_offset,
+                        jitter(
+                            &mut rng,
+                            -settings.random_offset.max_y_offset,
+                            settings.random_offset.max_y_offset,
                         ),
                     )
                 } else {
@@ -177,55 +574,62 @@ pub fn process_barrels(
                 let abs_to_x = window_x + to.location.x + to_size.0 / 2 + to_offset_x;
                 let abs_to_y = window_y + to.location.y + to_size.1 / 2 + to_offset_y;
 
-                // Перемещаемся к начальной точке
-                human_like_move(abs_from_x, abs_from_y, &settings.human_like_movement)?;
-
-                // Небольшая пауза перед кликом
-                if settings.human_like_movement.enabled {
-                    thread::sleep(Duration::from_millis(rng.gen_range(
-                        settings.human_like_movement.min_down_ms
-                            ..settings.human_like_movement.max_down_ms,
-                    )));
-                } else {
-                    thread::sleep(Duration::from_millis(rng.gen_range(15..17)));
-                }
-                human_like_move(abs_from_x, abs_from_y, &settings.human_like_movement)?;
-                // Нажимаем кнопку мыши
-                Command::new("xdotool").args(&["mousedown", "1"]).status()?;
-
-                // Небольшая пауза перед началом перемещения
-                if settings.human_like_movement.enabled {
-                    thread::sleep(Duration::from_millis(rng.gen_range(
-                        settings.human_like_movement.min_move_delay_ms
-                            ..settings.human_like_movement.max_move_delay_ms,
-                    )));
-                } else {
-                    thread::sleep(Duration::from_millis(rng.gen_range(5..7)));
+                // Окно может частично висеть за краем монитора
+                if !capture.point_on_screen(abs_from_x, abs_from_y)
+                    || !capture.point_on_screen(abs_to_x, abs_to_y)
+                {
+                    warn!(
+                        "Drag ({},{}) -> ({},{}) leaves the visible monitors",
+                        abs_from_x, abs_from_y, abs_to_x, abs_to_y
+                    );
                 }
 
-                // Перемещаемся к конечной точке
-                human_like_move(abs_to_x, abs_to_y, &settings.human_like_movement)?;
+                detector.mark_acted(from, cooldown);
+                detector.mark_acted(to, cooldown);
 
-                // Небольшая пауза перед отпусканием
-                thread::sleep(Duration::from_millis(rng.gen_range(15..16)));
-                if settings.human_like_movement.enabled {
-                    thread::sleep(Duration::from_millis(rng.gen_range(
-                        settings.human_like_movement.min_up_ms
-                            ..settings.human_like_movement.max_up_ms,
-                    )));
+                if settings.dry_run {
+                    info!(
+                        "{} ({},{}) -> ({},{})",
+                        from.object_name, abs_from_x, abs_from_y, abs_to_x, abs_to_y
+                    );
                 } else {
-                    thread::sleep(Duration::from_millis(rng.gen_range(16..17)));
-                }
+                    let from_point = (abs_from_x, abs_from_y);
+                    let to_point = (abs_to_x, abs_to_y);
+                    match settings.automation.merge.gesture {
+                        MergeGesture::Drag => {
+                            drag_barrel(input, &mut rng, from_point, to_point, bounds, settings)?
+                        }
+                        MergeGesture::TapTap => {
+                            tap_barrels(input, &mut rng, from_point, to_point, bounds, settings)?
+                        }
+                    }
+                    thread::sleep(Duration::from_millis(settings.automation.merge.settle_ms));
 
-                // Отпускаем кнопку мыши
-                human_like_move(abs_to_x, abs_to_y, &settings.human_like_movement)?;
-                Command::new("xdotool").args(&["mouseup", "1"]).status()?;
+                    // Не подтвердилось - не выдумываем новую бочку, обе исходные
+                    // выпадают из модели до следующего полного сканирования
+                    if settings.automation.merge.verify_merges
+                        && !verify_merge(capture, detector, to, next_level, settings)?
+                    {
+                        stats.record_failed_merge();
+                        continue;
+                    }
+                }
 
-                // Сохраняем новую бочку
+                // Сохраняем новую бочку, источник на сетке освобождается
+                stats.record_merge(next_level);
+                if let Some(cell) = grid.cell_of(from) {
+                    grid.set(cell, GridCell::Empty);
+                }
+                if let Some(cell) = grid.cell_of(to) {
+                    grid.set(cell, GridCell::Barrel(next_level));
+                }
                 new_barrels.push(DetectionResult {
                     object_name: format!("Barrel {}", next_level),
                     location: to.location.clone(),
                     confidence: to.confidence.clone(),
+                    width: to.width,
+                    height: to.height,
+                    category: to.category,
                 });
 
                 thread::sleep(Duration::from_millis(rng.gen_range(12..13)));
@@ -246,12 +650,190 @@ pub fn process_barrels(
     Ok(barrels)
 }
 
+// Бочка достигла уровня продажи; бочки без номера не трогаем
+pub fn should_collect(barrel: &DetectionResult, level: u32) -> bool {
+    barrel
+        .barrel_level()
+        .is_some_and(|barrel_level| barrel_level >= level)
+}
+
+// Уносит бочки уровня automation.collect.level и выше на точку продажи
+// или зажимает их на месте, возвращает число обработанных бочек
+pub fn process_collect(
+    input: &mut dyn InputBackend,
+    capture: &mut dyn CaptureBackend,
+    window_x: i32,
+    window_y: i32,
+    barrels: &[DetectionResult],
+    detector: &mut ObjectDetector,
+    settings: &Settings,
+    stats: &mut MergeStats,
+) -> AppResult<usize> {
+    let collect = &settings.automation.collect;
+    let mut rng = rng();
+    let cooldown = Duration::from_millis(settings.automation.merge.cooldown_ms);
+
+    let (window_width, window_height) = capture.window_size()?;
+    let bounds = Bounds {
+        x: window_x,
+        y: window_y,
+        width: window_width,
+        height: window_height,
+    };
+    let (target_x, target_y) = (window_x + collect.target_x, window_y + collect.target_y);
+
+    let mut collected = 0;
+    for barrel in barrels {
+        if shutdown_requested() || paused() {
+            break;
+        }
+        if !should_collect(barrel, collect.level) || detector.on_cooldown(barrel, cooldown) {
+            continue;
+        }
+
+        let abs_x = window_x + barrel.location.x + barrel.width / 2;
+        let abs_y = window_y + barrel.location.y + barrel.height / 2;
+        detector.mark_acted(barrel, cooldown);
+
+        if settings.dry_run {
+            match collect.mode {
+                CollectMode::Drag => info!(
+                    "Collect {} ({},{}) -> ({},{})",
+                    barrel.object_name, abs_x, abs_y, target_x, target_y
+                ),
+                CollectMode::LongPress => {
+                    info!("Collect {} hold ({},{})", barrel.object_name, abs_x, abs_y)
+                }
+            }
+        } else {
+            match collect.mode {
+                CollectMode::Drag => drag_barrel(
+                    input,
+                    &mut rng,
+                    (abs_x, abs_y),
+                    (target_x, target_y),
+                    bounds,
+                    settings,
+                )?,
+                CollectMode::LongPress => {
+                    human_like_move_within(
+                        input,
+                        abs_x,
+                        abs_y,
+                        &settings.human_like_movement,
+                        Some(bounds),
+                    )?;
+                    input.mouse_down()?;
+                    thread::sleep(Duration::from_millis(collect.long_press_ms));
+                    input.mouse_up()?;
+                }
+            }
+        }
+
+        stats.record_collect();
+        collected += 1;
+        thread::sleep(Duration::from_millis(rng.gen_range(12..13)));
+    }
+
+    Ok(collected)
+}
+
+// Область, в которой водим курсором по облаку, в экранных координатах
+#[derive(Debug, Clone, Copy)]
+pub struct CloudArea {
+    pub left: i32,
+    pub right: i32,
+    pub top: i32,
+    pub bottom: i32,
+}
+
+// Точки маршрута по облаку; jitter по X не выводит за область, т.к. она уже сужена на max_x_offset
+pub fn cloud_waypoints(
+    pattern: CloudPattern,
+    area: CloudArea,
+    step: i32,
+    max_x_offset: i32,
+    rng: &mut impl Rng,
+) -> Vec<(i32, i32)> {
+    let step = step.max(1);
+    let mut points = Vec::new();
+
+    match pattern {
+        // Змейка сверху вниз: горизонтальные проходы со случайным краем, спуск на step
+        CloudPattern::Zigzag => {
+            let mut y = (area.top + step).min(area.bottom);
+            points.push((area.left + jitter(rng, -max_x_offset, max_x_offset), y));
+            while y < area.bottom - step {
+                points.push((area.right + jitter(rng, -max_x_offset, max_x_offset), y));
+                y += step;
+                points.push((area.right, y));
+                points.push((area.left + jitter(rng, -max_x_offset, max_x_offset), y));
+                if y < area.bottom - step {
+                    y += step;
+                    points.push((area.left, y));
+                }
+            }
+        }
+        // Прямоугольная спираль от краёв к центру
+        CloudPattern::Spiral => {
+            let (mut left, mut right, mut top, mut bottom) =
+                (area.left, area.right, area.top, area.bottom);
+            while left < right && top < bottom {
+                points.extend([(left, top), (right, top), (right, bottom), (left, bottom)]);
+                left += step;
+                right -= step;
+                top += step;
+                bottom -= step;
+            }
+        }
+        // Вертикальная змейка слева направо
+        CloudPattern::Columns => {
+            let mut x = area.left;
+            let mut downwards = true;
+            while x <= area.right {
+                let (from_y, to_y) = if downwards {
+                    (area.top, area.bottom)
+                } else {
+                    (area.bottom, area.top)
+                };
+                points.extend([(x, from_y), (x, to_y)]);
+                x += step;
+                downwards = !downwards;
+            }
+        }
+        // Случайные точки, примерно по две на каждую полосу высотой step
+        CloudPattern::Random => {
+            let count = ((area.bottom - area.top) / step * 2).max(2);
+            for _ in 0..count {
+                points.push((
+                    jitter(rng, area.left, area.right),
+                    jitter(rng, area.top, area.bottom),
+                ));
+            }
+        }
+    }
+
+    points
+}
+
 pub fn process_magnets_cloud(
+    input: &mut dyn InputBackend,
     window_x: i32,
     window_y: i32,
     window_width: i32,
     window_height: i32,
+    settings: &Settings,
 ) -> AppResult<()> {
+    if settings.dry_run {
+        info!(
+            "Cloud sweep ({},{}) {}x{}",
+            window_x, window_y, window_width, window_height
+        );
+        return Ok(());
+    }
+
+    let mut rng = rng();
+
     let fast_movement_settings = HumanLikeMovementSettings {
         enabled: true,
         max_deviation: 0.000001,
@@ -266,6 +848,10 @@ pub fn process_magnets_cloud(
         max_up_ms: 1,
         min_move_delay_ms: 0,
         max_move_delay_ms: 1,
+        easing: Easing::Linear,
+        overshoot_chance: 0.0,
+        overshoot_max_px: 0,
+        idle_wiggle: IdleWiggle::default(),
     };
 
     // Параметры отрисовки
@@ -273,69 +859,750 @@ pub fn process_magnets_cloud(
     let line_length = 4 * (cell_width + 2) + 1;
     let mut drop_positions = vec![0usize; 5]; // Позиции 5 капель
 
-    // Вычисляем шаг для зигзага (примерно 1/8 высоты окна)
-    let step_height = window_height / 22;
+    // Шаг маршрута как доля высоты окна
+    let step = (window_height as f64 * settings.automation.shtorm.step_fraction) as i32;
 
-    // Создаем зигзагообразный маршрут от верха до низа окна
-    let mut current_y = window_y + 50 + step_height;
-    let left_x = window_x + 2;
-    let right_x = window_x + window_width - 2;
+    // Отступаем от краёв на величину случайного смещения, чтобы не выйти за окно
+    let max_x_offset = if settings.random_offset.enabled {
+        settings.random_offset.max_x_offset
+    } else {
+        0
+    };
+    // Сверху и снизу у окна игры интерфейс, облако между ними
+    let area = CloudArea {
+        left: window_x + 2 + max_x_offset,
+        right: window_x + window_width - 2 - max_x_offset,
+        top: window_y + 50,
+        bottom: window_y + window_height - 80,
+    };
+    let waypoints = cloud_waypoints(
+        settings.automation.shtorm.pattern,
+        area,
+        step,
+        max_x_offset,
+        &mut rng,
+    );
+    let Some((&(start_x, start_y), rest)) = waypoints.split_first() else {
+        return Ok(());
+    };
+
+    // В --quiet и при json анимацию не рисуем, само движение не меняется
+    let render = |drop_positions: &[usize], is_moving_right: bool| {
+        if settings.renders_tui() {
+            draw_cloud(
+                drop_positions,
+                is_moving_right,
+                line_length,
+                settings.display.preserve_scrollback,
+            );
+        }
+    };
 
-    // 1. Перемещаемся к начальной точке (левый верхний угол) с human-like движением
-    human_like_move(left_x, current_y, &fast_movement_settings)?;
+    // 1. Перемещаемся к начальной точке с human-like движением
+    human_like_move(input, start_x, start_y, &fast_movement_settings)?;
     for i in 0..5 {
         drop_positions[i as usize] = (i * 3) % (line_length - 4);
     }
-    draw_cloud(&drop_positions, true, line_length);
+    render(&drop_positions, true);
     thread::sleep(Duration::from_millis(1));
 
-    // 2. Нажимаем кнопку мыши
-    Command::new("xdotool").args(&["mousedown", "1"]).status()?;
+    // 2. Нажимаем кнопку мыши и проходим маршрут
+    if let Err(e) = input.mouse_down() {
+        let _ = input.mouse_up();
+        return Err(e);
+    }
 
-    while current_y < window_y + window_height - 80 - step_height {
-        // Движение вправо - с human-like движением
-        human_like_move(right_x, current_y, &fast_movement_settings)?;
-        for i in 0..5 {
-            drop_positions[i as usize] = (drop_positions[i as usize] + 5) % (line_length - 4);
+    // Кнопка зажата: отпускаем её даже при ошибке посреди маршрута
+    let mut sweep_result = Ok(());
+    let mut previous_x = start_x;
+    let mut is_moving_right = true;
+    for &(x, y) in rest {
+        if shutdown_requested() || paused() {
+            break;
         }
-        draw_cloud(&drop_positions, true, line_length);
-        thread::sleep(Duration::from_millis(1));
 
-        // Движение вниз - прямое перемещение без human-like
-        current_y += step_height;
-        Command::new("xdotool")
-            .args(&["mousemove", &right_x.to_string(), &current_y.to_string()])
-            .status()?;
-        for i in 0..5 {
-            drop_positions[i as usize] = (drop_positions[i as usize] + 2) % (line_length - 4);
+        if let Err(e) = human_like_move(input, x, y, &fast_movement_settings) {
+            sweep_result = Err(e);
+            break;
         }
-        draw_cloud(&drop_positions, true, line_length);
-        thread::sleep(Duration::from_millis(1));
 
-        // Движение влево - с human-like движением
-        human_like_move(left_x, current_y, &fast_movement_settings)?;
-        for i in 0..5 {
-            drop_positions[i as usize] =
-                (drop_positions[i as usize].max(5) - 5) % (line_length - 4);
+        // Капли сдвигаются по направлению движения, при спуске чуть вперёд
+        let shift = if x > previous_x {
+            is_moving_right = true;
+            5
+        } else if x < previous_x {
+            is_moving_right = false;
+            line_length - 4 - 5
+        } else {
+            2
+        };
+        for position in drop_positions.iter_mut() {
+            *position = (*position + shift) % (line_length - 4);
         }
-        draw_cloud(&drop_positions, false, line_length);
+        previous_x = x;
+
+        render(&drop_positions, is_moving_right);
         thread::sleep(Duration::from_millis(1));
+    }
+
+    // Отпускаем кнопку мыши
+    input.mouse_up()?;
+    sweep_result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::InputAction;
+    use crate::settings::MatchMethod;
+    use opencv::core::{CV_8UC3, Mat, Point, Scalar, Vector};
+    use opencv::imgcodecs;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::time::Instant;
+
+    fn barrel(level: u32, x: i32, y: i32) -> DetectionResult {
+        DetectionResult {
+            object_name: format!("Barrel {}", level),
+            location: Point::new(x, y),
+            confidence: 0.9,
+            width: 40,
+            height: 40,
+            category: TemplateCategory::Barrel,
+        }
+    }
+
+    #[test]
+    fn four_equal_barrels_take_three_merges() {
+        let barrels: Vec<DetectionResult> = (0..4).map(|i| barrel(1, i * 50, 0)).collect();
+        // Две пары дают две бочки 2, они сливаются в 3
+        assert_eq!(calculate_required_merges(&barrels), (1, 1, 3, 3));
+    }
+
+    #[test]
+    fn merged_barrels_join_the_next_level() {
+        let barrels = vec![
+            barrel(1, 0, 0),
+            barrel(1, 50, 0),
+            barrel(1, 100, 0),
+            barrel(2, 150, 0),
+        ];
+        // 1+1 -> 2, затем 2+2 -> 3; третья бочка 1 остаётся без пары
+        assert_eq!(calculate_required_merges(&barrels), (1, 2, 2, 3));
+    }
+
+    #[test]
+    fn no_pairs_means_no_merges() {
+        let barrels = vec![barrel(3, 0, 0), barrel(5, 50, 0)];
+        assert_eq!(calculate_required_merges(&barrels), (3, 5, 0, 5));
+        assert_eq!(calculate_required_merges(&[]), (0, 0, 0, 0));
+    }
 
-        // Движение вниз (если не вышли за границы) - прямое перемещение без human-like
-        if current_y < window_y + window_height - step_height {
-            current_y += step_height;
-            Command::new("xdotool")
-                .args(&["mousemove", &left_x.to_string(), &current_y.to_string()])
-                .status()?;
-            for i in 0..5 {
-                drop_positions[i as usize] = (drop_positions[i as usize] + 3) % (line_length - 4);
+    #[test]
+    fn cloud_waypoints_stay_inside_the_window() {
+        let (window_width, window_height, max_x_offset) = (433, 957, 5);
+        // Та же область, что строит process_magnets_cloud для окна в (0, 0)
+        let area = CloudArea {
+            left: 2 + max_x_offset,
+            right: window_width - 2 - max_x_offset,
+            top: 50,
+            bottom: window_height - 80,
+        };
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for pattern in [
+            CloudPattern::Zigzag,
+            CloudPattern::Spiral,
+            CloudPattern::Columns,
+            CloudPattern::Random,
+        ] {
+            let points = cloud_waypoints(pattern, area, window_height / 22, max_x_offset, &mut rng);
+            assert!(!points.is_empty(), "{:?} gave no waypoints", pattern);
+            for (x, y) in points {
+                assert!(
+                    (0..window_width).contains(&x) && (area.top..=area.bottom).contains(&y),
+                    "{:?} left the window at ({}, {})",
+                    pattern,
+                    x,
+                    y
+                );
             }
-            draw_cloud(&drop_positions, false, line_length);
-            thread::sleep(Duration::from_millis(2));
         }
     }
 
-    // Отпускаем кнопку мыши
-    Command::new("xdotool").args(&["mouseup", "1"]).status()?;
-    Ok(())
+    #[test]
+    fn collects_only_at_or_above_the_level() {
+        assert!(!should_collect(&barrel(9, 0, 0), 10));
+        assert!(should_collect(&barrel(10, 0, 0), 10));
+        assert!(should_collect(&barrel(11, 0, 0), 10));
+
+        // Пустая клетка без номера не продаётся
+        let empty = DetectionResult {
+            object_name: "Empty".to_string(),
+            category: TemplateCategory::Empty,
+            ..barrel(1, 0, 0)
+        };
+        assert!(!should_collect(&empty, 1));
+    }
+
+    // Мышь, которая только сообщает, где курсор
+    struct CursorAt(i32, i32);
+
+    impl InputBackend for CursorAt {
+        fn move_to(&mut self, _x: i32, _y: i32) -> AppResult<()> {
+            Ok(())
+        }
+
+        fn move_relative(&mut self, _dx: i32, _dy: i32) -> AppResult<()> {
+            Ok(())
+        }
+
+        fn mouse_down(&mut self) -> AppResult<()> {
+            Ok(())
+        }
+
+        fn mouse_up(&mut self) -> AppResult<()> {
+            Ok(())
+        }
+
+        fn get_position(&mut self) -> AppResult<(i32, i32)> {
+            Ok((self.0, self.1))
+        }
+    }
+
+    // Пара (0, 1) в окне, стоящем в (100, 50); следующий уровень 2
+    fn orient(
+        barrels: &[DetectionResult],
+        grid: &OccupancyGrid,
+        cursor: (i32, i32),
+        direction: MergeDirection,
+    ) -> (usize, usize) {
+        let mut input = CursorAt(cursor.0, cursor.1);
+        orient_merge(&mut input, barrels, (0, 1), (100, 50), direction, grid, 2).unwrap()
+    }
+
+    #[test]
+    fn merge_direction_picks_source_and_target() {
+        // Центры бочек 0 и 1 в (20, 20) и (220, 20); бочка 2 тянет центр скопления к первой
+        let barrels = vec![barrel(1, 0, 0), barrel(1, 200, 0), barrel(3, 20, 0)];
+        let grid = ObjectDetector::build_occupancy_grid(&barrels, 1, 3);
+        let near_second = (100 + 220, 50 + 20);
+        let near_first = (100 + 10, 50 + 10);
+
+        let first_into_second = MergeDirection::FirstIntoSecond;
+        assert_eq!(
+            orient(&barrels, &grid, near_second, first_into_second),
+            (0, 1)
+        );
+
+        let nearest = MergeDirection::NearestIntoFarthest;
+        assert_eq!(orient(&barrels, &grid, near_second, nearest), (1, 0));
+        assert_eq!(orient(&barrels, &grid, near_first, nearest), (0, 1));
+
+        // Дальняя от центра скопления (93, 20) перетаскивается в ближнюю
+        let spatial_stable = MergeDirection::SpatialStable;
+        assert_eq!(orient(&barrels, &grid, near_second, spatial_stable), (1, 0));
+    }
+
+    #[test]
+    fn consolidate_merges_next_to_equal_neighbors() {
+        // Сетка 2x3 с шагом 50: под первой бочкой уже стоит бочка 2
+        let barrels = vec![barrel(1, 0, 0), barrel(1, 100, 0), barrel(2, 0, 50)];
+        let grid = ObjectDetector::build_occupancy_grid(&barrels, 2, 3);
+
+        let consolidate = MergeDirection::Consolidate;
+        assert_eq!(orient(&barrels, &grid, (0, 0), consolidate), (1, 0));
+    }
+
+    // Запоминает всё, что бот сделал мышью
+    struct Recorded(Vec<InputAction>);
+
+    impl InputBackend for Recorded {
+        fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+            self.0.push(InputAction::MoveTo { x, y });
+            Ok(())
+        }
+
+        fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+            self.0.push(InputAction::MoveRelative { dx, dy });
+            Ok(())
+        }
+
+        fn mouse_down(&mut self) -> AppResult<()> {
+            self.0.push(InputAction::MouseDown);
+            Ok(())
+        }
+
+        fn mouse_up(&mut self) -> AppResult<()> {
+            self.0.push(InputAction::MouseUp);
+            Ok(())
+        }
+
+        // Курсор там, куда его последним передвинули
+        fn get_position(&mut self) -> AppResult<(i32, i32)> {
+            let last_move = self.0.iter().rev().find_map(|action| match action {
+                InputAction::MoveTo { x, y } => Some((*x, *y)),
+                _ => None,
+            });
+            Ok(last_move.unwrap_or((0, 0)))
+        }
+    }
+
+    // Детектор с тремя шаблонами бочек, суженный до второго, как в середине уровня
+    fn narrowed_detector() -> ObjectDetector {
+        let image = Mat::new_rows_cols_with_default(8, 8, CV_8UC3, Scalar::all(100.0)).unwrap();
+        let mut data = Vector::<u8>::new();
+        imgcodecs::imencode(".png", &image, &mut data, &Vector::new()).unwrap();
+
+        let mut detector = ObjectDetector::new(1.0);
+        for level in 1..=3 {
+            detector
+                .add_template_bytes(
+                    &format!("Barrel {}", level),
+                    &data.to_vec(),
+                    0.9,
+                    5.0,
+                    0.0,
+                    0.0,
+                    0.0,
+                    None,
+                    false,
+                    MatchMethod::default(),
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+        detector.active_range = (1, 1);
+        detector.full_range = false;
+        detector
+    }
+
+    fn button(name: &str, category: TemplateCategory) -> DetectionResult {
+        DetectionResult {
+            object_name: name.to_string(),
+            location: Point::new(40, 60),
+            confidence: 0.95,
+            width: 20,
+            height: 10,
+            category,
+        }
+    }
+
+    fn ui_settings(auto_continue: bool) -> Settings {
+        let mut settings = Settings::default();
+        settings.automation.auto_continue = auto_continue;
+        settings.human_like_movement.enabled = false;
+        settings
+    }
+
+    #[test]
+    fn continue_popup_is_tapped_and_resets_the_range() {
+        let mut input = Recorded(Vec::new());
+        let mut detector = narrowed_detector();
+        let detections = vec![
+            barrel(2, 0, 0),
+            button("Continue", TemplateCategory::Continue),
+        ];
+
+        let handled = process_ui(
+            &mut input,
+            100,
+            50,
+            &detections,
+            &mut detector,
+            &ui_settings(true),
+        )
+        .unwrap();
+
+        assert!(handled);
+        // Один тап в центр кнопки
+        assert_eq!(
+            input.0,
+            vec![
+                InputAction::MoveTo { x: 150, y: 115 },
+                InputAction::MouseDown,
+                InputAction::MouseUp,
+            ]
+        );
+        assert_eq!(detector.active_range, (0, 2));
+        assert!(detector.full_range);
+    }
+
+    #[test]
+    fn dry_run_leaves_the_continue_tap_to_the_backend() {
+        let mut settings = ui_settings(true);
+        settings.dry_run = true;
+        let mut input = Recorded(Vec::new());
+        let detections = [button("Continue", TemplateCategory::Continue)];
+
+        let handled = process_ui(
+            &mut input,
+            100,
+            50,
+            &detections,
+            &mut narrowed_detector(),
+            &settings,
+        )
+        .unwrap();
+
+        assert!(handled);
+        assert_eq!(
+            presses(&input.0),
+            vec![
+                (InputAction::MouseDown, (150, 115)),
+                (InputAction::MouseUp, (150, 115)),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_ui_buttons_and_disabled_auto_continue_are_ignored() {
+        let cases = [
+            (button("Shop", TemplateCategory::Ui), true),
+            (button("Continue", TemplateCategory::Continue), false),
+        ];
+        for (detection, auto_continue) in cases {
+            let mut input = Recorded(Vec::new());
+            let mut detector = narrowed_detector();
+
+            let handled = process_ui(
+                &mut input,
+                100,
+                50,
+                &[detection],
+                &mut detector,
+                &ui_settings(auto_continue),
+            )
+            .unwrap();
+
+            assert!(!handled);
+            assert!(input.0.is_empty());
+            assert_eq!(detector.active_range, (1, 1));
+            assert!(!detector.full_range);
+        }
+    }
+
+    #[test]
+    fn cloud_sweep_keeps_the_random_offset_inside_the_window() {
+        let mut settings = Settings::default();
+        settings.quiet = true;
+        settings.random_offset.enabled = true;
+        settings.random_offset.max_x_offset = 40;
+        let mut input = Recorded(Vec::new());
+
+        process_magnets_cloud(&mut input, 100, 50, 433, 957, &settings).unwrap();
+
+        // С зажатой кнопкой курсор не должен уходить за края окна ни на каком смещении
+        let sweep_start = input
+            .0
+            .iter()
+            .position(|action| *action == InputAction::MouseDown)
+            .unwrap();
+        let sweep_xs: Vec<i32> = input.0[sweep_start..]
+            .iter()
+            .filter_map(|action| match action {
+                InputAction::MoveTo { x, .. } => Some(*x),
+                _ => None,
+            })
+            .collect();
+        assert!(!sweep_xs.is_empty());
+        for x in sweep_xs {
+            assert!(
+                (102..=100 + 433 - 2).contains(&x),
+                "sweep left the window at x={}",
+                x
+            );
+        }
+    }
+
+    // Окно 400x400 в (0, 0); в dry_run кадр не нужен
+    struct FixedWindow;
+
+    impl CaptureBackend for FixedWindow {
+        fn capture(&mut self) -> AppResult<(Mat, i32, i32)> {
+            Ok((Mat::default(), 0, 0))
+        }
+
+        fn window_size(&mut self) -> AppResult<(i32, i32)> {
+            Ok((400, 400))
+        }
+    }
+
+    #[test]
+    fn dry_run_plans_merges_without_touching_the_mouse() {
+        let mut settings = Settings::default();
+        settings.dry_run = true;
+        let barrels = vec![barrel(1, 0, 0), barrel(1, 100, 0)];
+        let grid = ObjectDetector::build_occupancy_grid(&barrels, 1, 2);
+        let mut input = Recorded(Vec::new());
+        let mut stats = MergeStats::new();
+
+        let remaining = process_barrels(
+            &mut input,
+            &mut FixedWindow,
+            0,
+            0,
+            barrels,
+            grid,
+            &mut narrowed_detector(),
+            &settings,
+            &mut stats,
+        )
+        .unwrap();
+
+        assert!(input.0.is_empty(), "dry run sent {:?}", input.0);
+        assert_eq!(stats.merges_performed, 1);
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].object_name, "Barrel 2");
+    }
+
+    #[test]
+    fn max_level_barrels_are_left_alone() {
+        let mut settings = Settings::default();
+        settings.human_like_movement.enabled = false;
+        let barrels = vec![barrel(3, 0, 0), barrel(3, 100, 0), barrel(3, 200, 0)];
+        let grid = ObjectDetector::build_occupancy_grid(&barrels, 1, 3);
+        let mut input = Recorded(Vec::new());
+        let mut stats = MergeStats::new();
+
+        let remaining = process_barrels(
+            &mut input,
+            &mut FixedWindow,
+            0,
+            0,
+            barrels.clone(),
+            grid,
+            &mut narrowed_detector(),
+            &settings,
+            &mut stats,
+        )
+        .unwrap();
+
+        assert!(input.0.is_empty(), "sent {:?}", input.0);
+        assert_eq!(stats.merges_performed, 0);
+        let names_and_places = |barrels: &[DetectionResult]| {
+            barrels
+                .iter()
+                .map(|b| (b.object_name.clone(), b.location.x, b.location.y))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names_and_places(&remaining), names_and_places(&barrels));
+    }
+
+    #[test]
+    fn the_same_pair_is_skipped_on_the_next_frame_within_the_cooldown() {
+        let mut settings = Settings::default();
+        settings.dry_run = true;
+        settings.automation.merge.cooldown_ms = 60_000;
+        let mut detector = narrowed_detector();
+        let mut stats = MergeStats::new();
+
+        // Анимация ещё не закончилась: следующий кадр видит ту же пару на месте
+        for _ in 0..2 {
+            let barrels = vec![barrel(1, 0, 0), barrel(1, 100, 0)];
+            let grid = ObjectDetector::build_occupancy_grid(&barrels, 1, 2);
+            process_barrels(
+                &mut Recorded(Vec::new()),
+                &mut FixedWindow,
+                0,
+                0,
+                barrels,
+                grid,
+                &mut detector,
+                &settings,
+                &mut stats,
+            )
+            .unwrap();
+        }
+        assert_eq!(stats.merges_performed, 1);
+
+        // Без кулдауна та же пара сливается снова
+        settings.automation.merge.cooldown_ms = 0;
+        let barrels = vec![barrel(1, 0, 0), barrel(1, 100, 0)];
+        let grid = ObjectDetector::build_occupancy_grid(&barrels, 1, 2);
+        process_barrels(
+            &mut Recorded(Vec::new()),
+            &mut FixedWindow,
+            0,
+            0,
+            barrels,
+            grid,
+            &mut detector,
+            &settings,
+            &mut stats,
+        )
+        .unwrap();
+        assert_eq!(stats.merges_performed, 2);
+    }
+
+    // Где был курсор при каждом нажатии и отпускании кнопки
+    fn presses(actions: &[InputAction]) -> Vec<(InputAction, (i32, i32))> {
+        let mut position = (0, 0);
+        let mut presses = Vec::new();
+        for action in actions {
+            match action {
+                InputAction::MoveTo { x, y } => position = (*x, *y),
+                InputAction::MouseDown | InputAction::MouseUp => {
+                    presses.push((action.clone(), position))
+                }
+                _ => {}
+            }
+        }
+        presses
+    }
+
+    #[test]
+    fn tap_tap_clicks_source_and_target_instead_of_dragging() {
+        let mut settings = Settings::default();
+        settings.human_like_movement.enabled = false;
+        let bounds = Bounds {
+            x: 0,
+            y: 0,
+            width: 400,
+            height: 400,
+        };
+        let (source, target) = ((60, 70), (260, 70));
+        let mut rng = StdRng::seed_from_u64(3);
+
+        let mut tapped = Recorded(Vec::new());
+        tap_barrels(&mut tapped, &mut rng, source, target, bounds, &settings).unwrap();
+        assert_eq!(
+            presses(&tapped.0),
+            vec![
+                (InputAction::MouseDown, source),
+                (InputAction::MouseUp, source),
+                (InputAction::MouseDown, target),
+                (InputAction::MouseUp, target),
+            ]
+        );
+
+        let mut dragged = Recorded(Vec::new());
+        drag_barrel(&mut dragged, &mut rng, source, target, bounds, &settings).unwrap();
+        assert_eq!(
+            presses(&dragged.0),
+            vec![
+                (InputAction::MouseDown, source),
+                (InputAction::MouseUp, target),
+            ]
+        );
+    }
+
+    #[test]
+    fn each_anticaptcha_mode_runs_its_own_handler() {
+        let mut settings = Settings::default();
+        settings.human_like_movement.enabled = false;
+        settings.automation.anticaptcha.mask_points = vec![(0.25, 0.5), (0.75, 0.5)];
+        settings.automation.anticaptcha.solve_path = vec![(0.1, 0.5), (0.5, 0.5), (0.9, 0.5)];
+        // Капча 100x40 в (20, 30) окна, стоящего в (100, 50)
+        let captcha = DetectionResult {
+            object_name: "Captcha".to_string(),
+            location: Point::new(20, 30),
+            confidence: 0.95,
+            width: 100,
+            height: 40,
+            category: TemplateCategory::Captcha,
+        };
+        let mut run = |mode| {
+            settings.automation.anticaptcha.mode = mode;
+            let mut input = Recorded(Vec::new());
+            let pause = process_anticaptcha(&mut input, 100, 50, &captcha, &settings).unwrap();
+            (pause, presses(&input.0))
+        };
+
+        // Mask: по тапу в каждую точку маски
+        assert_eq!(
+            run(AntiCaptchaMode::Mask),
+            (
+                false,
+                vec![
+                    (InputAction::MouseDown, (145, 100)),
+                    (InputAction::MouseUp, (145, 100)),
+                    (InputAction::MouseDown, (195, 100)),
+                    (InputAction::MouseUp, (195, 100)),
+                ]
+            )
+        );
+        // Solve: одно перетаскивание от первой точки пути до последней
+        assert_eq!(
+            run(AntiCaptchaMode::Solve),
+            (
+                false,
+                vec![
+                    (InputAction::MouseDown, (130, 100)),
+                    (InputAction::MouseUp, (210, 100)),
+                ]
+            )
+        );
+        // Notify: мышь не трогаем, просим паузу
+        assert_eq!(run(AntiCaptchaMode::Notify), (true, vec![]));
+    }
+
+    // Когда нажималась и отпускалась кнопка
+    #[derive(Default)]
+    struct Timed {
+        position: (i32, i32),
+        presses: Vec<(InputAction, Instant)>,
+    }
+
+    impl InputBackend for Timed {
+        fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+            self.position = (x, y);
+            Ok(())
+        }
+
+        fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+            self.position = (self.position.0 + dx, self.position.1 + dy);
+            Ok(())
+        }
+
+        fn mouse_down(&mut self) -> AppResult<()> {
+            self.presses.push((InputAction::MouseDown, Instant::now()));
+            Ok(())
+        }
+
+        fn mouse_up(&mut self) -> AppResult<()> {
+            self.presses.push((InputAction::MouseUp, Instant::now()));
+            Ok(())
+        }
+
+        fn get_position(&mut self) -> AppResult<(i32, i32)> {
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn settle_delay_separates_consecutive_merges() {
+        let mut settings = Settings::default();
+        settings.human_like_movement.enabled = false;
+        settings.automation.merge.settle_ms = 150;
+        let barrels: Vec<DetectionResult> = (0..4).map(|i| barrel(1, i * 100, 0)).collect();
+        let grid = ObjectDetector::build_occupancy_grid(&barrels, 1, 4);
+        let mut input = Timed::default();
+
+        process_barrels(
+            &mut input,
+            &mut FixedWindow,
+            0,
+            0,
+            barrels,
+            grid,
+            &mut narrowed_detector(),
+            &settings,
+            &mut MergeStats::new(),
+        )
+        .unwrap();
+
+        // Между отпусканием кнопки и следующим нажатием - не меньше settle_ms
+        let gaps: Vec<Duration> = input
+            .presses
+            .windows(2)
+            .filter(|pair| pair[0].0 == InputAction::MouseUp)
+            .map(|pair| pair[1].1 - pair[0].1)
+            .collect();
+        assert!(!gaps.is_empty(), "only one merge in {:?}", input.presses);
+        for gap in gaps {
+            assert!(gap >= Duration::from_millis(150), "merges {:?} apart", gap);
+        }
+    }
 }
@@ -0,0 +1,235 @@
+use crate::capture::AppResult;
+use log::debug;
+use log::warn;
+use opencv::core::Mat;
+use opencv::core::Vector;
+use opencv::imgcodecs::imwrite;
+use opencv::prelude::MatTraitConst;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Сколько кадров может ждать записи; сверх этого кадры отбрасываются,
+// чтобы медленный диск не тормозил основной цикл
+const QUEUE_LEN: usize = 8;
+
+// Пишет размеченные кадры в каталог в фоновом потоке
+pub struct FrameRecorder {
+    sender: Option<SyncSender<Mat>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl FrameRecorder {
+    // max_frames: хранить только столько последних кадров, старые удаляются
+    pub fn new(dir: &str, max_frames: Option<usize>) -> AppResult<Self> {
+        fs::create_dir_all(dir)?;
+        let (sender, receiver) = sync_channel(QUEUE_LEN);
+        let dir = PathBuf::from(dir);
+        let worker = thread::spawn(move || write_frames(&dir, receiver, max_frames));
+
+        Ok(Self {
+            sender: Some(sender),
+            worker: Some(worker),
+        })
+    }
+
+    pub fn record(&self, frame: &Mat) -> AppResult<()> {
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+        match sender.try_send(frame.try_clone()?) {
+            Err(TrySendError::Full(_)) => debug!("Recorder queue full, dropping frame"),
+            Err(TrySendError::Disconnected(_)) => warn!("Recorder thread stopped"),
+            Ok(()) => {}
+        }
+        Ok(())
+    }
+}
+
+// Дописывает очередь до конца, чтобы последние кадры перед выходом не терялись
+impl Drop for FrameRecorder {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn write_frames(dir: &Path, receiver: Receiver<Mat>, max_frames: Option<usize>) {
+    let mut written: VecDeque<PathBuf> = VecDeque::new();
+
+    for (sequence, frame) in receiver.iter().enumerate() {
+        if frame.empty() {
+            continue;
+        }
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        // Номер кадра в имени на случай нескольких кадров за одну миллисекунду
+        let path = dir.join(format!("frame_{}_{:06}.png", timestamp_ms, sequence));
+
+        match imwrite(&path.to_string_lossy(), &frame, &Vector::new()) {
+            Ok(true) => written.push_back(path),
+            Ok(false) => warn!("Could not write {}", path.display()),
+            Err(e) => warn!("Could not write {}: {}", path.display(), e),
+        }
+
+        if let Some(max_frames) = max_frames {
+            while written.len() > max_frames {
+                if let Some(oldest) = written.pop_front() {
+                    let _ = fs::remove_file(oldest);
+                }
+            }
+        }
+    }
+}
+
+// Последние кадры в памяти; на диск попадают, только если основной цикл упал с ошибкой
+pub struct FrameRing {
+    frames: VecDeque<Mat>,
+    capacity: usize,
+}
+
+impl FrameRing {
+    // capacity 0 - кадры не копируются вовсе
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, frame: &Mat) -> AppResult<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame.try_clone()?);
+        Ok(())
+    }
+
+    // Кадры от старого к новому как crash_000.png, crash_001.png, ...
+    pub fn dump(&self, dir: &Path) -> AppResult<usize> {
+        if self.frames.is_empty() {
+            return Ok(0);
+        }
+        fs::create_dir_all(dir)?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            let path = dir.join(format!("crash_{:03}.png", i));
+            imwrite(&path.to_string_lossy(), frame, &Vector::new())?;
+        }
+        Ok(self.frames.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::CV_8UC3;
+    use opencv::core::Scalar;
+    use opencv::core::Vec3b;
+    use opencv::imgcodecs::IMREAD_COLOR;
+    use opencv::imgcodecs::imread;
+    use std::env;
+
+    fn frame(value: f64) -> Mat {
+        Mat::new_rows_cols_with_default(4, 4, CV_8UC3, Scalar::all(value)).unwrap()
+    }
+
+    fn png_count(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().is_some_and(|ext| ext == "png")
+            })
+            .count()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("scrap2_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn recorder_writes_one_file_per_frame() {
+        let dir = temp_dir("record_all");
+        // Drop дожидается, пока фоновый поток допишет очередь
+        {
+            let recorder = FrameRecorder::new(&dir.to_string_lossy(), None).unwrap();
+            for i in 0..5 {
+                recorder.record(&frame(i as f64 * 40.0)).unwrap();
+            }
+            // Пустой кадр не записывается
+            recorder.record(&Mat::default()).unwrap();
+        }
+
+        assert_eq!(png_count(&dir), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recorder_keeps_only_the_newest_frames() {
+        let dir = temp_dir("record_max");
+        {
+            let recorder = FrameRecorder::new(&dir.to_string_lossy(), Some(2)).unwrap();
+            for i in 0..6 {
+                recorder.record(&frame(i as f64 * 40.0)).unwrap();
+            }
+        }
+
+        assert_eq!(png_count(&dir), 2);
+        let mut sequences: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter_map(|name| Some(name.rsplit_once('_')?.1.to_string()))
+            .collect();
+        sequences.sort();
+        assert_eq!(sequences, vec!["000004.png", "000005.png"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn frame_ring_keeps_the_last_frames_oldest_first() {
+        let mut ring = FrameRing::new(3);
+        for i in 0..5 {
+            ring.push(&frame(i as f64 * 40.0)).unwrap();
+        }
+
+        let dir = temp_dir("frame_ring");
+        assert_eq!(ring.dump(&dir).unwrap(), 3);
+        assert_eq!(png_count(&dir), 3);
+        // Первые два кадра вытеснены, порядок от старого к новому
+        for (i, expected) in [80, 120, 160].into_iter().enumerate() {
+            let path = dir.join(format!("crash_{:03}.png", i));
+            let saved = imread(&path.to_string_lossy(), IMREAD_COLOR).unwrap();
+            let pixel = saved.at_2d::<Vec3b>(0, 0).unwrap();
+            assert_eq!(pixel[0], expected, "{}", path.display());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_frame_ring_writes_nothing() {
+        let dir = temp_dir("frame_ring_off");
+        let mut ring = FrameRing::new(0);
+        ring.push(&frame(40.0)).unwrap();
+        assert_eq!(ring.dump(&dir).unwrap(), 0);
+        assert!(!dir.exists());
+    }
+}
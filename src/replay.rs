@@ -0,0 +1,259 @@
+use crate::capture::AppResult;
+use crate::input::InputBackend;
+use crate::utils::shutdown_requested;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Паузы длиннее этой при воспроизведении сокращаются: между записями бот мог
+// стоять на паузе или ждать окно
+const MAX_REPLAY_GAP_MS: u64 = 5000;
+
+// Команда мыши в том виде, в каком её получил бэкенд
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum InputAction {
+    MoveTo { x: i32, y: i32 },
+    MoveRelative { dx: i32, dy: i32 },
+    MouseDown,
+    MouseUp,
+    MoveAlong { path: Vec<(i32, i32, u64)> },
+}
+
+impl InputAction {
+    pub fn execute(&self, input: &mut dyn InputBackend) -> AppResult<()> {
+        match self {
+            InputAction::MoveTo { x, y } => input.move_to(*x, *y),
+            InputAction::MoveRelative { dx, dy } => input.move_relative(*dx, *dy),
+            InputAction::MouseDown => input.mouse_down(),
+            InputAction::MouseUp => input.mouse_up(),
+            InputAction::MoveAlong { path } => input.move_along(path),
+        }
+    }
+}
+
+// Одна строка журнала; время в мс от эпохи Unix
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoggedAction {
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub action: InputAction,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Пишет каждую команду в журнал, по JSON-строке на действие, и передаёт её дальше.
+// Команда записывается до выполнения, так что упавший drag тоже останется в журнале
+pub struct ActionLogBackend {
+    inner: Box<dyn InputBackend>,
+    log: File,
+}
+
+impl ActionLogBackend {
+    // Файл дописывается: после перечитывания настроек бэкенд создаётся заново
+    pub fn new(path: &str, inner: Box<dyn InputBackend>) -> AppResult<Self> {
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { inner, log })
+    }
+
+    fn record(&mut self, action: InputAction) -> AppResult<()> {
+        let entry = LoggedAction {
+            timestamp_ms: now_ms(),
+            action,
+        };
+        writeln!(self.log, "{}", serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+}
+
+impl InputBackend for ActionLogBackend {
+    fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+        self.record(InputAction::MoveTo { x, y })?;
+        self.inner.move_to(x, y)
+    }
+
+    fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+        self.record(InputAction::MoveRelative { dx, dy })?;
+        self.inner.move_relative(dx, dy)
+    }
+
+    fn mouse_down(&mut self) -> AppResult<()> {
+        self.record(InputAction::MouseDown)?;
+        self.inner.mouse_down()
+    }
+
+    fn mouse_up(&mut self) -> AppResult<()> {
+        self.record(InputAction::MouseUp)?;
+        self.inner.mouse_up()
+    }
+
+    fn get_position(&mut self) -> AppResult<(i32, i32)> {
+        self.inner.get_position()
+    }
+
+    fn move_along(&mut self, path: &[(i32, i32, u64)]) -> AppResult<()> {
+        self.record(InputAction::MoveAlong {
+            path: path.to_vec(),
+        })?;
+        self.inner.move_along(path)
+    }
+}
+
+pub fn read_action_log(path: &str) -> AppResult<Vec<LoggedAction>> {
+    let content = fs::read_to_string(path)?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path, i + 1, e),
+                )
+                .into()
+            })
+        })
+        .collect()
+}
+
+// --replay: те же команды мыши с теми же паузами, без захвата и детекции.
+// Координаты экранные, так что окно игры должно стоять там же, где при записи
+pub fn replay_actions(actions: &[LoggedAction], input: &mut dyn InputBackend) -> AppResult<usize> {
+    let mut pressed = false;
+    let result = replay_entries(actions, input, &mut pressed);
+    // Прерванный drag не должен оставить кнопку зажатой
+    if pressed {
+        let _ = input.mouse_up();
+    }
+    result
+}
+
+fn replay_entries(
+    actions: &[LoggedAction],
+    input: &mut dyn InputBackend,
+    pressed: &mut bool,
+) -> AppResult<usize> {
+    let mut previous: Option<(u64, Instant)> = None;
+    let mut replayed = 0;
+
+    for entry in actions {
+        if shutdown_requested() {
+            break;
+        }
+        // Время самой команды уже прошло, досыпаем только остаток паузы
+        if let Some((timestamp_ms, started)) = previous {
+            let gap = entry
+                .timestamp_ms
+                .saturating_sub(timestamp_ms)
+                .min(MAX_REPLAY_GAP_MS);
+            thread::sleep(Duration::from_millis(gap).saturating_sub(started.elapsed()));
+        }
+        previous = Some((entry.timestamp_ms, Instant::now()));
+
+        match entry.action {
+            InputAction::MouseDown => *pressed = true,
+            InputAction::MouseUp => *pressed = false,
+            _ => {}
+        }
+        entry.action.execute(input)?;
+        replayed += 1;
+    }
+
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::rc::Rc;
+
+    // Складывает полученные команды в общий список, который видно и после Box
+    struct Recorded(Rc<RefCell<Vec<InputAction>>>);
+
+    impl InputBackend for Recorded {
+        fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+            self.0.borrow_mut().push(InputAction::MoveTo { x, y });
+            Ok(())
+        }
+
+        fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+            self.0
+                .borrow_mut()
+                .push(InputAction::MoveRelative { dx, dy });
+            Ok(())
+        }
+
+        fn mouse_down(&mut self) -> AppResult<()> {
+            self.0.borrow_mut().push(InputAction::MouseDown);
+            Ok(())
+        }
+
+        fn mouse_up(&mut self) -> AppResult<()> {
+            self.0.borrow_mut().push(InputAction::MouseUp);
+            Ok(())
+        }
+
+        fn get_position(&mut self) -> AppResult<(i32, i32)> {
+            Ok((0, 0))
+        }
+
+        fn move_along(&mut self, path: &[(i32, i32, u64)]) -> AppResult<()> {
+            self.0.borrow_mut().push(InputAction::MoveAlong {
+                path: path.to_vec(),
+            });
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replay_sends_the_recorded_actions_again() {
+        let path = env::temp_dir().join(format!("scrap2_action_log_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let mut logged = ActionLogBackend::new(path, Box::new(Recorded(sent.clone()))).unwrap();
+        logged.move_to(120, 340).unwrap();
+        logged.mouse_down().unwrap();
+        logged.move_along(&[(130, 345, 0), (180, 360, 0)]).unwrap();
+        logged.move_relative(-2, 1).unwrap();
+        logged.mouse_up().unwrap();
+        drop(logged);
+
+        let actions = read_action_log(path).unwrap();
+        fs::remove_file(path).unwrap();
+        let replayed = Rc::new(RefCell::new(Vec::new()));
+        let count = replay_actions(&actions, &mut Recorded(replayed.clone())).unwrap();
+        assert_eq!(count, 5);
+        assert_eq!(*replayed.borrow(), *sent.borrow());
+
+        // Журнал оборвался посреди drag: кнопку всё равно отпускаем
+        let replayed = Rc::new(RefCell::new(Vec::new()));
+        replay_actions(&actions[..2], &mut Recorded(replayed.clone())).unwrap();
+        assert_eq!(
+            *replayed.borrow(),
+            vec![
+                InputAction::MoveTo { x: 120, y: 340 },
+                InputAction::MouseDown,
+                InputAction::MouseUp,
+            ]
+        );
+    }
+}
@@ -1,55 +1,960 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::capture::parse_window_id_value;
+use crate::wayland::is_wayland_session;
+use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
 
-#[derive(Debug, Deserialize, Serialize)]
+pub const DEFAULT_WINDOW_TITLE: &str = "M2006C3MNG";
+pub const SETTINGS_PATH: &str = "settings.json";
+// Версия схемы settings.json; файлы без поля version считаются версией 1
+pub const SETTINGS_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Settings {
+    pub version: u32,
     pub window_title: String,
+    // Как сравнивать window_title с заголовками окон
+    pub window_match: WindowMatch,
+    // X11 id окна (0x... или десятичный); важнее window_title
+    pub window_id: Option<String>,
     pub resolution: f64,
     pub rescan_delay: u64,
+    // Предел, до которого растёт пауза, пока на поле нечего сливать
+    pub rescan_delay_max: u64,
     pub reference_width: i32,
     pub reference_height: i32,
     pub convert_to_grayscale: bool,
+    // Пространство цветов для сопоставления; если не задано, решает convert_to_grayscale
+    pub match_color_space: Option<MatchColorSpace>,
+    // Поиск на трёх масштабах (±10%): примерно втрое дороже на кадр
+    pub multiscale: bool,
+    // Во сколько раз гасимая вокруг найденного пика область больше шаблона;
+    // больше 1 - плотно стоящие одинаковые спрайты не дают пачку дублей
+    pub suppression_scale: f64,
     pub templates: Vec<TemplateSettings>,
     pub random_offset: RandomOffsetSettings,
     pub human_like_movement: HumanLikeMovementSettings,
     pub automation: Automation,
+    pub input_backend: InputBackendKind,
+    // Кнопка для слияний и облака в нумерации X11: 1 - левая, 2 - средняя, 3 - правая
+    pub mouse_button: u8,
+    pub capture_backend: CaptureBackendKind,
+    // В каком формате maim/import отдают кадр X11-захвату
+    pub capture_format: CaptureFormat,
+    // Серийный номер для adb -s, если подключено несколько устройств
+    pub adb_serial: Option<String>,
+    // Только печатать запланированные действия, не трогая мышь
+    pub dry_run: bool,
+    // Seed генератора случайных смещений и путей: тот же seed - тот же прогон
+    pub seed: Option<u64>,
+    // Без отрисовки в терминале, для запуска без экрана
+    pub quiet: bool,
+    // Что идёт в stdout: таблица или строка JSON на кадр
+    pub output_format: OutputFormat,
+    pub hotkeys: Hotkeys,
+    pub failsafe: FailsafeSettings,
+    pub notifications: Notifications,
+    // Как часто перечитывать положение окна через xwininfo
+    pub geometry_refresh_ms: u64,
+    pub wayland: WaylandSettings,
+    // Область поиска (x, y, ширина, высота) в координатах эталонного окна
+    pub roi: Option<(i32, i32, i32, i32)>,
+    // Порог разницы кадров (0-255) в самой изменившейся клетке 8x8, ниже которого
+    // детекция пропускается; 0 - выключено
+    pub static_frame_threshold: f64,
+    // Сколько кадров держать пропавший объект в таблице; 0 - без трекинга
+    pub tracking_ttl_frames: u32,
+    // Минимальный отрыв лучшего шаблона от другого в том же месте; 0 - выключено
+    pub ambiguity_margin: f64,
+    // match_template через UMat, если OpenCV собран с OpenCL
+    pub use_opencl: bool,
+    pub display: DisplaySettings,
+    // Размер игрового поля для таблицы; если не задан, определяется по найденным бочкам
+    pub grid_cols: Option<usize>,
+    pub grid_rows: Option<usize>,
+    pub window_retry: WindowRetry,
+    pub input_retry: InputRetry,
+    pub limits: StopLimits,
+    // Сколько последних кадров держать в памяти для сохранения при ошибке; 0 - выключено
+    pub crash_frames: usize,
+    pub paths: Paths,
+    // Несколько окон игры в одном процессе; пусто - одно окно из настроек выше
+    pub instances: Vec<InstanceConfig>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            window_title: DEFAULT_WINDOW_TITLE.to_string(),
+            window_match: WindowMatch::default(),
+            window_id: None,
+            resolution: 0.38,
+            rescan_delay: 250,
+            rescan_delay_max: 2000,
+            reference_width: 433,
+            reference_height: 957,
+            convert_to_grayscale: true,
+            match_color_space: None,
+            multiscale: false,
+            suppression_scale: 1.0,
+            templates: Vec::new(),
+            random_offset: RandomOffsetSettings::default(),
+            human_like_movement: HumanLikeMovementSettings::default(),
+            automation: Automation::default(),
+            input_backend: InputBackendKind::default(),
+            capture_format: CaptureFormat::default(),
+            mouse_button: 1,
+            capture_backend: CaptureBackendKind::default(),
+            adb_serial: None,
+            dry_run: false,
+            seed: None,
+            quiet: false,
+            output_format: OutputFormat::default(),
+            hotkeys: Hotkeys::default(),
+            failsafe: FailsafeSettings::default(),
+            notifications: Notifications::default(),
+            geometry_refresh_ms: 1000,
+            wayland: WaylandSettings::default(),
+            roi: None,
+            static_frame_threshold: 0.5,
+            tracking_ttl_frames: 2,
+            ambiguity_margin: 0.0,
+            use_opencl: false,
+            display: DisplaySettings::default(),
+            grid_cols: None,
+            grid_rows: None,
+            window_retry: WindowRetry::default(),
+            input_retry: InputRetry::default(),
+            limits: StopLimits::default(),
+            crash_frames: 30,
+            paths: Paths::default(),
+            instances: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Hotkeys {
+    // Функциональная клавиша F1..F12
+    pub pause: String,
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self {
+            pause: "F9".to_string(),
+        }
+    }
+}
+
+// Капча, потерянное окно или остановка из-за ошибки зовут пользователя
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Notifications {
+    pub enabled: bool,
+    pub method: NotifyMethod,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyMethod {
+    // Звонок терминала
+    #[default]
+    Bell,
+    NotifySend,
+    // notify-rust, нужна сборка с --features notify
+    Desktop,
+}
+
+// Курсор, загнанный в угол любого монитора, останавливает бота
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FailsafeSettings {
+    pub enabled: bool,
+    // Насколько близко к углу, в пикселях
+    pub margin_px: i32,
+    pub poll_ms: u64,
+}
+
+impl Default for FailsafeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            margin_px: 3,
+            poll_ms: 50,
+        }
+    }
+}
+
+// Область экрана для grim: Wayland не сообщает, где находится чужое окно
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WaylandSettings {
+    // x, y, ширина, высота в координатах общей раскладки мониторов
+    pub region: Option<(i32, i32, i32, i32)>,
+    // Имя монитора для grim -o, если область не задана
+    pub output: Option<String>,
+}
+
+impl Settings {
+    // В Wayland-сессии X11-бэкенды по умолчанию ничего не увидят и никуда не нажмут
+    pub fn adapt_to_session(&mut self) {
+        // Под Windows нет ни xwininfo, ни xdotool
+        if cfg!(windows) {
+            if self.capture_backend == CaptureBackendKind::X11 {
+                self.capture_backend = CaptureBackendKind::Windows;
+            }
+            if self.input_backend == InputBackendKind::Xdotool {
+                self.input_backend = InputBackendKind::Enigo;
+            }
+            return;
+        }
+        if !is_wayland_session() {
+            return;
+        }
+        if self.capture_backend == CaptureBackendKind::X11 {
+            self.capture_backend = CaptureBackendKind::Wayland;
+        }
+        if self.input_backend == InputBackendKind::Xdotool {
+            self.input_backend = InputBackendKind::Ydotool;
+        }
+    }
+
+    // Перечитывает файл целиком; при ошибке вызывающий оставляет прежние настройки
+    pub fn reload(path: &str) -> AppResult<Settings> {
+        let content = fs::read_to_string(path)?;
+        let settings = migrate(serde_json::from_str(&content)?)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn save(&self, path: &str) -> AppResult<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Переписывает устаревший файл в текущей схеме, старый остаётся рядом как .v<N>.bak.
+    // Возвращает версию, с которой обновили, или None, если файл уже актуален
+    pub fn migrate_file(path: &str) -> AppResult<Option<u32>> {
+        let content = fs::read_to_string(path)?;
+        let value: Value = serde_json::from_str(&content)?;
+        let version = schema_version(&value);
+        if version >= SETTINGS_VERSION {
+            return Ok(None);
+        }
+
+        let settings = migrate(value)?;
+        settings.validate()?;
+        fs::write(format!("{}.v{}.bak", path, version), content)?;
+        settings.save(path)?;
+        Ok(Some(version))
+    }
+
+    // Отсекает значения, на которых позже упадёт resize или gen_range
+    pub fn validate(&self) -> AppResult<()> {
+        let fail = |msg: String| Err(AppError::SettingsError(msg));
+
+        if let Some(window_id) = &self.window_id {
+            parse_window_id_value(window_id)?;
+        }
+        if !(1..=3).contains(&self.mouse_button) {
+            return fail(format!(
+                "mouse_button must be 1 (left), 2 (middle) or 3 (right), got {}",
+                self.mouse_button
+            ));
+        }
+        if self.window_match == WindowMatch::Regex {
+            if let Err(e) = Regex::new(self.window_title.trim()) {
+                return fail(format!("window_title is not a valid regex: {}", e));
+            }
+        }
+
+        if self.rescan_delay_max < self.rescan_delay {
+            return fail(format!(
+                "rescan_delay_max ({}) is less than rescan_delay ({})",
+                self.rescan_delay_max, self.rescan_delay
+            ));
+        }
+
+        if !(self.resolution > 0.0 && self.resolution <= 4.0) {
+            return fail(format!(
+                "resolution must be in (0.0, 4.0], got {}",
+                self.resolution
+            ));
+        }
+
+        if self.random_offset.max_x_offset < 0 || self.random_offset.max_y_offset < 0 {
+            return fail(format!(
+                "random_offset offsets must be non-negative, got x={} y={}",
+                self.random_offset.max_x_offset, self.random_offset.max_y_offset
+            ));
+        }
+
+        if let Some((x, y, width, height)) = self.roi {
+            if x < 0
+                || y < 0
+                || width <= 0
+                || height <= 0
+                || x + width > self.reference_width
+                || y + height > self.reference_height
+            {
+                return fail(format!(
+                    "roi ({}, {}, {}x{}) must lie inside the {}x{} reference window",
+                    x, y, width, height, self.reference_width, self.reference_height
+                ));
+            }
+        }
+
+        if self.grid_cols == Some(0) || self.grid_rows == Some(0) {
+            return fail("grid_cols and grid_rows must be at least 1".to_string());
+        }
+
+        if !(0.0..1.0).contains(&self.ambiguity_margin) {
+            return fail(format!(
+                "ambiguity_margin must be in [0.0, 1.0), got {}",
+                self.ambiguity_margin
+            ));
+        }
+
+        if !(self.suppression_scale > 0.0 && self.suppression_scale.is_finite()) {
+            return fail(format!(
+                "suppression_scale must be positive, got {}",
+                self.suppression_scale
+            ));
+        }
+
+        if self.static_frame_threshold < 0.0 {
+            return fail(format!(
+                "static_frame_threshold must be non-negative, got {}",
+                self.static_frame_threshold
+            ));
+        }
+
+        let step_fraction = self.automation.shtorm.step_fraction;
+        if !(step_fraction > 0.0 && step_fraction <= 1.0) {
+            return fail(format!(
+                "automation.shtorm.step_fraction must be in (0.0, 1.0], got {}",
+                step_fraction
+            ));
+        }
+
+        if self.failsafe.margin_px < 0 {
+            return fail(format!(
+                "failsafe.margin_px must be non-negative, got {}",
+                self.failsafe.margin_px
+            ));
+        }
+
+        let anticaptcha = &self.automation.anticaptcha;
+        let outside = |&(x, y): &(f64, f64)| !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y);
+        if let Some(point) = anticaptcha
+            .mask_points
+            .iter()
+            .chain(&anticaptcha.solve_path)
+            .find(|point| outside(point))
+        {
+            return fail(format!(
+                "automation.anticaptcha points are fractions of the captcha, got {:?}",
+                point
+            ));
+        }
+
+        let collect = &self.automation.collect;
+        if collect.enabled && collect.level == 0 {
+            return fail("automation.collect.level must be at least 1".to_string());
+        }
+
+        let movement = &self.human_like_movement;
+        if movement.curve_smoothness < 2 {
+            return fail(format!(
+                "human_like_movement.curve_smoothness must be at least 2, got {}",
+                movement.curve_smoothness
+            ));
+        }
+        if movement.max_deviation < 0.0 {
+            return fail(format!(
+                "human_like_movement.max_deviation must be non-negative, got {}",
+                movement.max_deviation
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&movement.overshoot_chance) {
+            return fail(format!(
+                "human_like_movement.overshoot_chance must be in [0, 1], got {}",
+                movement.overshoot_chance
+            ));
+        }
+        if !(0.0..=1.0).contains(&movement.idle_wiggle.chance) {
+            return fail(format!(
+                "human_like_movement.idle_wiggle.chance must be in [0, 1], got {}",
+                movement.idle_wiggle.chance
+            ));
+        }
+        if movement.overshoot_max_px < 0 {
+            return fail(format!(
+                "human_like_movement.overshoot_max_px must be non-negative, got {}",
+                movement.overshoot_max_px
+            ));
+        }
+
+        let ranges = [
+            ("pause", movement.min_pause_ms, movement.max_pause_ms),
+            ("down", movement.min_down_ms, movement.max_down_ms),
+            ("up", movement.min_up_ms, movement.max_up_ms),
+            (
+                "move_delay",
+                movement.min_move_delay_ms,
+                movement.max_move_delay_ms,
+            ),
+        ];
+        for (name, min, max) in ranges {
+            if min > max {
+                return fail(format!(
+                    "human_like_movement.min_{name}_ms ({min}) is greater than max_{name}_ms ({max})"
+                ));
+            }
+        }
+
+        for template in &self.templates {
+            if !(0.0..=1.0).contains(&template.threshold) {
+                return fail(format!(
+                    "template '{}': threshold must be in [0, 1], got {}",
+                    template.name, template.threshold
+                ));
+            }
+            // Маску в match_template поддерживают только CCORR и SQDIFF
+            if template.use_alpha && template.match_method == MatchMethod::CcoeffNormed {
+                return fail(format!(
+                    "template '{}': use_alpha requires match_method ccorr_normed or sqdiff_normed",
+                    template.name
+                ));
+            }
+            if let Some(resolution) = template.resolution {
+                if !(resolution > 0.0 && resolution <= 4.0) {
+                    return fail(format!(
+                        "template '{}': resolution must be in (0.0, 4.0], got {}",
+                        template.name, resolution
+                    ));
+                }
+            }
+        }
+
+        // Каждое окно со своими шаблонами и id проверяется как отдельные настройки
+        if !self.instances.is_empty() {
+            for instance in self.instance_settings() {
+                instance.validate()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Старый флаг convert_to_grayscale работает, пока match_color_space не задан
+    pub fn color_space(&self) -> MatchColorSpace {
+        match self.match_color_space {
+            Some(color_space) => color_space,
+            None if self.convert_to_grayscale => MatchColorSpace::Gray,
+            None => MatchColorSpace::Bgr,
+        }
+    }
+
+    // Единственное правило для stdout: таблица, облако и пауза рисуются только без
+    // --quiet и не в json, где stdout занят строками кадров
+    pub fn renders_tui(&self) -> bool {
+        !self.quiet && self.output_format == OutputFormat::Table
+    }
+
+    // Полные настройки для каждого окна; без instances - единственное окно как есть
+    pub fn instance_settings(&self) -> Vec<Settings> {
+        if self.instances.is_empty() {
+            return vec![self.clone()];
+        }
+
+        self.instances
+            .iter()
+            .map(|instance| {
+                let mut settings = self.clone();
+                settings.instances.clear();
+                settings.window_title = instance.window_title.clone();
+                settings.window_id = instance.window_id.clone();
+                if let Some(templates) = &instance.templates {
+                    settings.templates = templates.clone();
+                }
+                settings
+            })
+            .collect()
+    }
+
+    // Время последнего изменения файла, для опроса в основном цикле
+    pub fn modified_at(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+}
+
+pub fn schema_version(value: &Value) -> u32 {
+    value
+        .get("version")
+        .and_then(Value::as_u64)
+        .map_or(1, |version| version as u32)
+}
+
+// Поднимает сырой JSON до SETTINGS_VERSION по одной версии за шаг
+pub fn migrate(mut value: Value) -> AppResult<Settings> {
+    let mut version = schema_version(&value);
+    if version > SETTINGS_VERSION {
+        return Err(AppError::SettingsError(format!(
+            "settings.json version {} is newer than supported {}",
+            version, SETTINGS_VERSION
+        )));
+    }
+
+    while version < SETTINGS_VERSION {
+        match version {
+            1 => migrate_v1(&mut value)?,
+            _ => unreachable!("no migration from settings version {}", version),
+        }
+        version += 1;
+    }
+
+    if let Some(root) = value.as_object_mut() {
+        root.insert("version".to_string(), Value::from(version));
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+// v1 -> v2: явные категории шаблонов вместо префиксов имени, anticaptcha
+// в automation и удаление ключей, которые бот больше не читает
+fn migrate_v1(value: &mut Value) -> AppResult<()> {
+    let Some(root) = value.as_object_mut() else {
+        return Ok(());
+    };
+    root.remove("use_gpu");
+
+    if let Some(automation) = root.get_mut("automation").and_then(Value::as_object_mut) {
+        automation.remove("fragments");
+        if !automation.contains_key("anticaptcha") {
+            automation.insert(
+                "anticaptcha".to_string(),
+                serde_json::to_value(AntiCaptcha::default())?,
+            );
+        }
+    }
+
+    if let Some(templates) = root.get_mut("templates") {
+        infer_template_categories(templates)?;
+    }
+    for instance in root
+        .get_mut("instances")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+    {
+        if let Some(templates) = instance.get_mut("templates") {
+            infer_template_categories(templates)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn infer_template_categories(templates: &mut Value) -> AppResult<()> {
+    for template in templates
+        .as_array_mut()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+    {
+        if template.contains_key("category") {
+            continue;
+        }
+        let name = template.get("name").and_then(Value::as_str).unwrap_or("");
+        let category = serde_json::to_value(TemplateCategory::from_name(name))?;
+        template.insert("category".to_string(), category);
+    }
+    Ok(())
+}
+
+// Отдельное окно игры: своё окно и, при желании, свой набор шаблонов,
+// остальное берётся из общих настроек
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct InstanceConfig {
+    pub window_title: String,
+    pub window_id: Option<String>,
+    pub templates: Option<Vec<TemplateSettings>>,
+}
+
+// Куда --debug пишет снимок окна и его разметку; по умолчанию во временный каталог,
+// чтобы не засорять рабочий и не мешать другим копиям бота
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Paths {
+    pub screenshot: Option<String>,
+    pub debug_output: Option<String>,
+    // Каталог для кадров перед ошибкой
+    pub crash_dir: Option<String>,
+    // Журнал команд мыши для --replay; не задан - не пишется
+    pub action_log: Option<String>,
+}
+
+impl Paths {
+    pub fn screenshot_path(&self) -> PathBuf {
+        resolve_path(&self.screenshot, "scrap2bot_screenshot.png")
+    }
+
+    pub fn debug_output_path(&self) -> PathBuf {
+        resolve_path(&self.debug_output, "scrap2bot_result.png")
+    }
+
+    pub fn crash_dir_path(&self) -> PathBuf {
+        resolve_path(&self.crash_dir, "scrap2bot_crash")
+    }
+}
+
+fn resolve_path(path: &Option<String>, default_name: &str) -> PathBuf {
+    match path {
+        Some(path) => PathBuf::from(path),
+        None => env::temp_dir().join(default_name),
+    }
+}
+
+// Ожидание пропавшего окна: пауза удваивается с каждой попыткой
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WindowRetry {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+}
+
+impl Default for WindowRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            base_delay_ms: 500,
+        }
+    }
+}
+
+// Повтор команды мыши при сбое (например, занятый X-сервер); attempts - всего попыток
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct InputRetry {
+    pub attempts: u32,
+    pub delay_ms: u64,
+}
+
+impl Default for InputRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay_ms: 20,
+        }
+    }
+}
+
+// Когда остановить --infinite без участия пользователя; None - без ограничения
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StopLimits {
+    pub max_runtime_secs: Option<u64>,
+    pub max_merges: Option<u64>,
+    // Остановиться, как только на поле появится бочка этого уровня
+    pub target_level: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DisplaySettings {
+    pub color_mode: ColorMode,
+    // Бочка с уверенностью ниже threshold + margin помечается в таблице знаком ?; 0 - без пометки
+    pub low_confidence_margin: f64,
+    // Очищать только видимый экран, не трогая прокрутку терминала
+    pub preserve_scrollback: bool,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            color_mode: ColorMode::default(),
+            low_confidence_margin: 0.03,
+            preserve_scrollback: true,
+        }
+    }
+}
+
+// truecolor - фон ячейки цветом шаблона, ansi256 - ближайший из 256 цветов,
+// mono - только номера, для дальтоников и простых терминалов
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    #[default]
+    Truecolor,
+    Ansi256,
+    Mono,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputBackendKind {
+    #[default]
+    Xdotool,
+    Enigo,
+    Adb,
+    Ydotool,
+}
+
+// exact - весь заголовок, contains - подстрока без учёта регистра, regex - регулярное
+// выражение; пробелы по краям заголовка не учитываются ни в одном режиме
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowMatch {
+    #[default]
+    Exact,
+    Contains,
+    Regex,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureBackendKind {
+    #[default]
+    X11,
+    Adb,
+    Wayland,
+    Windows,
+}
+
+// bmp почти ничего не стоит закодировать и разобрать, зато кадр в разы больше png
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureFormat {
+    #[default]
+    Png,
+    Bmp,
+}
+
+impl CaptureFormat {
+    // Имя формата для maim -f и префикса import, оно же расширение файла
+    pub fn extension(self) -> &'static str {
+        match self {
+            CaptureFormat::Png => "png",
+            CaptureFormat::Bmp => "bmp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Automation {
     pub merge: Merge,
+    pub collect: Collect,
     pub shtorm: Shtorm,
-    pub anticaptcha: AntiCaptcha
+    pub anticaptcha: AntiCaptcha,
+    // Нажимать кнопку продолжения (шаблон категории continue) в окне конца уровня
+    pub auto_continue: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct AntiCaptcha {
     pub enabled: bool,
-    pub mode: String,
+    pub mode: AntiCaptchaMode,
+    // Точки для тапов в режиме mask, доли ширины и высоты найденной капчи
+    pub mask_points: Vec<(f64, f64)>,
+    // Путь перетаскивания в режиме solve в тех же долях, кнопка зажата от первой точки до последней
+    pub solve_path: Vec<(f64, f64)>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Default for AntiCaptcha {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: AntiCaptchaMode::default(),
+            mask_points: vec![(0.5, 0.5)],
+            solve_path: vec![(0.1, 0.5), (0.9, 0.5)],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AntiCaptchaMode {
+    // Тапы по mask_points
+    #[default]
+    Mask,
+    // Перетаскивание по solve_path, как ползунок
+    Solve,
+    // Пауза и сигнал, капчу решает человек
+    Notify,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Merge {
     pub enabled: bool,
+    // Проверять каждое слияние отдельным снимком области назначения
+    pub verify_merges: bool,
+    // Сколько не трогать бочки после перетаскивания, пока идёт анимация слияния
+    pub cooldown_ms: u64,
+    // Пауза после каждого слияния, чтобы следующий снимок застал уже слитую бочку;
+    // подбирается под скорость анимации на конкретном устройстве
+    pub settle_ms: u64,
+    // Сливать отслеживаемые бочки, включая те, что пропали на последних кадрах
+    pub use_tracked: bool,
+    pub gesture: MergeGesture,
+    pub direction: MergeDirection,
+}
+
+impl Default for Merge {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            verify_merges: false,
+            cooldown_ms: 400,
+            settle_ms: 0,
+            use_tracked: false,
+            gesture: MergeGesture::default(),
+            direction: MergeDirection::default(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Как игра принимает слияние
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeGesture {
+    // Перетащить бочку на бочку с зажатой кнопкой
+    #[default]
+    Drag,
+    // Тап по исходной бочке, затем тап по целевой
+    TapTap,
+}
+
+// Какая бочка пары перетаскивается; новая бочка появляется на месте второй
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeDirection {
+    // Первая по порядку сканирования в следующую
+    #[default]
+    FirstIntoSecond,
+    // Ближайшая к курсору в дальнюю: короче путь до начала перетаскивания
+    NearestIntoFarthest,
+    // Дальняя от центра скопления бочек в ближнюю: цепочки слияний не уползают в угол
+    SpatialStable,
+    // В ту, рядом с которой по сетке больше бочек нового уровня: поле уплотняется,
+    // а не дробится; при равенстве как spatial_stable
+    Consolidate,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Collect {
+    pub enabled: bool,
+    // Бочки этого уровня и выше уносим на продажу
+    pub level: u32,
+    // Точка продажи в координатах окна
+    pub target_x: i32,
+    pub target_y: i32,
+    pub mode: CollectMode,
+    // Длительность удержания для long_press
+    pub long_press_ms: u64,
+}
+
+impl Default for Collect {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: 10,
+            target_x: 0,
+            target_y: 0,
+            mode: CollectMode::default(),
+            long_press_ms: 800,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollectMode {
+    // Перетащить бочку на точку продажи
+    #[default]
+    Drag,
+    // Зажать бочку на месте
+    LongPress,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Shtorm {
     pub enabled: bool,
     pub retries: usize,
+    pub pattern: CloudPattern,
+    // Шаг маршрута как доля высоты окна
+    pub step_fraction: f64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Default for Shtorm {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retries: 1,
+            pattern: CloudPattern::default(),
+            step_fraction: 1.0 / 22.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudPattern {
+    #[default]
+    Zigzag,
+    Spiral,
+    Columns,
+    Random,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct RandomOffsetSettings {
     pub enabled: bool,
     pub max_x_offset: i32,
     pub max_y_offset: i32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Default for RandomOffsetSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_x_offset: 5,
+            max_y_offset: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct HumanLikeMovementSettings {
     pub enabled: bool,
-    pub max_deviation: f64, // Максимальное отклонение от прямой линии (в пикселях)
+    pub max_deviation: f64, // Максимальный сдвиг контрольных точек Безье от прямой (в пикселях)
     pub speed_variation: f64, // Вариация скорости (0.0 - 1.0)
     pub curve_smoothness: usize, // Количество промежуточных точек для кривой
     pub min_pause_ms: u64,  // Минимальная пауза между движениями
@@ -61,9 +966,69 @@ pub struct HumanLikeMovementSettings {
     pub max_up_ms: u64,
     pub min_move_delay_ms: u64,
     pub max_move_delay_ms: u64,
+    // Профиль скорости вдоль пути; linear - прежнее поведение
+    pub easing: Easing,
+    // Вероятность проскочить цель и вернуться, и насколько далеко
+    pub overshoot_chance: f64,
+    pub overshoot_max_px: i32,
+    // Редкие мелкие движения курсора в паузах между сканированиями
+    pub idle_wiggle: IdleWiggle,
+}
+
+impl Default for HumanLikeMovementSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_deviation: 10.0,
+            speed_variation: 0.3,
+            curve_smoothness: 5,
+            min_pause_ms: 10,
+            max_pause_ms: 50,
+            base_speed: 0.1,
+            min_down_ms: 2,
+            max_down_ms: 5,
+            min_up_ms: 2,
+            max_up_ms: 6,
+            min_move_delay_ms: 5,
+            max_move_delay_ms: 12,
+            easing: Easing::default(),
+            overshoot_chance: 0.0,
+            overshoot_max_px: 12,
+            idle_wiggle: IdleWiggle::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IdleWiggle {
+    pub enabled: bool,
+    // Вероятность шевельнуть мышью за одну паузу
+    pub chance: f64,
+    // Наибольшее отклонение от исходной точки в пикселях
+    pub amplitude_px: i32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Default for IdleWiggle {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            chance: 0.2,
+            amplitude_px: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseInOut,
+    EaseOut,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TemplateSettings {
     pub name: String,
     pub path: String,
@@ -75,4 +1040,329 @@ pub struct TemplateSettings {
     pub resolution: Option<f64>,
     #[serde(default)]
     pub always_active: bool,
+    #[serde(default)]
+    pub match_method: MatchMethod,
+    // Цвет фона спрайта (B, G, R), который зануляется перед сопоставлением
+    #[serde(default)]
+    pub mask_color: Option<(u8, u8, u8)>,
+    #[serde(default)]
+    pub mask_tolerance: Option<u8>,
+    // Использовать альфа-канал PNG как маску сопоставления
+    #[serde(default)]
+    pub use_alpha: bool,
+    // Если не задано, выводится из имени, как было до появления категорий
+    #[serde(default)]
+    pub category: Option<TemplateCategory>,
+    // Искать ещё и слегка повёрнутый спрайт (±6°): дорого, только для качающихся объектов
+    #[serde(default)]
+    pub rotation_tolerant: bool,
+    // Остальные кадры анимации того же размера, что и path; каждый кадр умножает
+    // стоимость сопоставления, а детекция всё равно называется по name
+    #[serde(default)]
+    pub frames: Vec<String>,
+}
+
+impl TemplateSettings {
+    pub fn category(&self) -> TemplateCategory {
+        self.category
+            .unwrap_or_else(|| TemplateCategory::from_name(&self.name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateCategory {
+    Barrel,
+    Cloud,
+    Empty,
+    Captcha,
+    Ui,
+    // Кнопка продолжения в окне конца уровня; её нажимает automation.auto_continue
+    Continue,
+    #[default]
+    Other,
+}
+
+impl TemplateCategory {
+    // Старые настройки различали шаблоны только по префиксу имени
+    pub fn from_name(name: &str) -> Self {
+        if name.starts_with("Barrel") {
+            TemplateCategory::Barrel
+        } else if name.starts_with("Cloud") {
+            TemplateCategory::Cloud
+        } else if name == "Empty" {
+            TemplateCategory::Empty
+        } else if name.starts_with("Captcha") {
+            TemplateCategory::Captcha
+        } else if name.starts_with("Continue") {
+            TemplateCategory::Continue
+        } else {
+            TemplateCategory::Other
+        }
+    }
+}
+
+// hsv различает бочки одной формы и разного цвета, которые в сером сливаются
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchColorSpace {
+    #[default]
+    Gray,
+    Bgr,
+    Hsv,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMethod {
+    #[default]
+    CcoeffNormed,
+    CcorrNormed,
+    SqdiffNormed,
+}
+
+impl MatchMethod {
+    // Для SQDIFF лучшее совпадение - минимум, а не максимум
+    pub fn is_sqdiff(self) -> bool {
+        self == MatchMethod::SqdiffNormed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_anticaptcha_block_gets_defaults() {
+        let settings: Settings =
+            serde_json::from_str(r#"{"automation": {"merge": {"enabled": false}}}"#).unwrap();
+
+        let anticaptcha = &settings.automation.anticaptcha;
+        assert!(anticaptcha.enabled);
+        assert_eq!(anticaptcha.mode, AntiCaptchaMode::Mask);
+        assert_eq!(anticaptcha.mask_points, vec![(0.5, 0.5)]);
+        assert!(!settings.automation.merge.enabled);
+    }
+
+    #[test]
+    fn empty_file_matches_first_run_defaults() {
+        let settings: Settings = serde_json::from_str("{}").unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&settings).unwrap(),
+            serde_json::to_value(Settings::default()).unwrap()
+        );
+        // Первый запуск, как и раньше, с включённой антикапчей
+        assert!(settings.automation.anticaptcha.enabled);
+    }
+
+    fn validate_with(change: impl FnOnce(&mut Settings)) -> AppResult<()> {
+        let mut settings = Settings::default();
+        change(&mut settings);
+        settings.validate()
+    }
+
+    fn template_with_threshold(threshold: f64) -> TemplateSettings {
+        serde_json::from_value(serde_json::json!({
+            "name": "Barrel 1",
+            "path": "templates/barrels/1.png",
+            "threshold": threshold,
+            "min_distance": 5.0,
+            "red": 0.0,
+            "green": 0.0,
+            "blue": 0.0
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn default_settings_are_valid() {
+        assert!(Settings::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_resolution_outside_range() {
+        assert!(matches!(
+            validate_with(|s| s.resolution = 0.0),
+            Err(AppError::SettingsError(_))
+        ));
+        assert!(validate_with(|s| s.resolution = 4.5).is_err());
+        assert!(validate_with(|s| s.resolution = 4.0).is_ok());
+    }
+
+    #[test]
+    fn rejects_min_greater_than_max() {
+        assert!(validate_with(|s| s.human_like_movement.min_pause_ms = 100).is_err());
+        assert!(validate_with(|s| s.human_like_movement.min_down_ms = 10).is_err());
+        assert!(validate_with(|s| s.human_like_movement.min_up_ms = 10).is_err());
+        assert!(validate_with(|s| s.human_like_movement.min_move_delay_ms = 20).is_err());
+        // Равные границы - фиксированная пауза, это допустимо
+        assert!(validate_with(|s| s.human_like_movement.min_down_ms = 5).is_ok());
+    }
+
+    #[test]
+    fn rejects_curve_smoothness_below_two() {
+        assert!(validate_with(|s| s.human_like_movement.curve_smoothness = 1).is_err());
+        assert!(validate_with(|s| s.human_like_movement.curve_smoothness = 2).is_ok());
+    }
+
+    #[test]
+    fn rejects_threshold_outside_unit_range() {
+        let with_threshold = |threshold: f64| {
+            validate_with(|s| s.templates = vec![template_with_threshold(threshold)])
+        };
+        assert!(with_threshold(1.5).is_err());
+        assert!(with_threshold(-0.1).is_err());
+        assert!(with_threshold(0.9).is_ok());
+    }
+
+    #[test]
+    fn rejects_negative_offsets() {
+        assert!(validate_with(|s| s.random_offset.max_x_offset = -1).is_err());
+        assert!(validate_with(|s| s.random_offset.max_y_offset = -1).is_err());
+        assert!(validate_with(|s| s.random_offset.max_x_offset = 0).is_ok());
+    }
+
+    #[test]
+    fn category_is_inferred_from_legacy_names() {
+        let cases = [
+            ("Barrel 12", TemplateCategory::Barrel),
+            ("Cloud 2", TemplateCategory::Cloud),
+            ("Empty", TemplateCategory::Empty),
+            ("Captcha", TemplateCategory::Captcha),
+            ("Continue button", TemplateCategory::Continue),
+            // Только точное имя Empty, а не любое с этим префиксом
+            ("Empty slot", TemplateCategory::Other),
+            ("Magnet 1", TemplateCategory::Other),
+        ];
+        for (name, category) in cases {
+            assert_eq!(TemplateCategory::from_name(name), category, "{}", name);
+        }
+    }
+
+    #[test]
+    fn explicit_category_beats_the_name() {
+        let mut template = template_with_threshold(0.9);
+        assert_eq!(template.category(), TemplateCategory::Barrel);
+
+        template.category = Some(TemplateCategory::Ui);
+        assert_eq!(template.category(), TemplateCategory::Ui);
+    }
+
+    #[test]
+    fn v1_settings_migrate_to_current_version() {
+        let v1 = serde_json::json!({
+            "window_title": "Emulator",
+            "use_gpu": true,
+            "automation": {
+                "merge": { "enabled": false },
+                "fragments": { "enabled": true }
+            },
+            "templates": [
+                {
+                    "name": "Barrel 3",
+                    "path": "templates/barrels/3.png",
+                    "threshold": 0.9,
+                    "min_distance": 5.0,
+                    "red": 0.0,
+                    "green": 0.0,
+                    "blue": 0.0
+                },
+                {
+                    "name": "Cloud",
+                    "path": "templates/ui/cloud.png",
+                    "threshold": 0.8,
+                    "min_distance": 5.0,
+                    "red": 0.0,
+                    "green": 0.0,
+                    "blue": 0.0
+                }
+            ]
+        });
+        assert_eq!(schema_version(&v1), 1);
+
+        let settings = migrate(v1).unwrap();
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!(settings.window_title, "Emulator");
+        assert!(!settings.automation.merge.enabled);
+        assert!(settings.automation.anticaptcha.enabled);
+        assert_eq!(
+            settings.templates[0].category,
+            Some(TemplateCategory::Barrel)
+        );
+        assert_eq!(
+            settings.templates[1].category,
+            Some(TemplateCategory::Cloud)
+        );
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn newer_settings_version_is_rejected() {
+        let future = serde_json::json!({ "version": SETTINGS_VERSION + 1 });
+        assert!(matches!(migrate(future), Err(AppError::SettingsError(_))));
+    }
+
+    #[test]
+    fn match_method_is_parsed_by_name() {
+        let parse = |name: &str| serde_json::from_value::<MatchMethod>(serde_json::json!(name));
+        assert_eq!(parse("ccoeff_normed").unwrap(), MatchMethod::CcoeffNormed);
+        assert_eq!(parse("ccorr_normed").unwrap(), MatchMethod::CcorrNormed);
+        assert_eq!(parse("sqdiff_normed").unwrap(), MatchMethod::SqdiffNormed);
+        assert!(parse("TM_SQDIFF_NORMED").is_err());
+
+        // Без поля - прежний TM_CCOEFF_NORMED
+        let template = template_with_threshold(0.9);
+        assert_eq!(template.match_method, MatchMethod::CcoeffNormed);
+        assert!(!template.match_method.is_sqdiff());
+    }
+
+    #[test]
+    fn terminal_is_drawn_only_for_the_table_without_quiet() {
+        let mut settings = Settings::default();
+        assert!(settings.renders_tui());
+
+        settings.quiet = true;
+        assert!(!settings.renders_tui());
+
+        settings.quiet = false;
+        settings.output_format = OutputFormat::Json;
+        assert!(!settings.renders_tui());
+    }
+
+    #[test]
+    fn debug_paths_default_to_the_temp_dir() {
+        let settings = Settings::default();
+        let temp = env::temp_dir();
+        assert_eq!(
+            settings.paths.screenshot_path(),
+            temp.join("scrap2bot_screenshot.png")
+        );
+        assert_eq!(
+            settings.paths.debug_output_path(),
+            temp.join("scrap2bot_result.png")
+        );
+        assert_eq!(
+            settings.paths.crash_dir_path(),
+            temp.join("scrap2bot_crash")
+        );
+
+        let settings: Settings = serde_json::from_str(
+            r#"{"paths": {"screenshot": "/srv/bot/shot.png", "debug_output": "out/result.png"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.paths.screenshot_path(),
+            PathBuf::from("/srv/bot/shot.png")
+        );
+        assert_eq!(
+            settings.paths.debug_output_path(),
+            PathBuf::from("out/result.png")
+        );
+        // Незаданный путь по-прежнему во временном каталоге
+        assert_eq!(
+            settings.paths.crash_dir_path(),
+            temp.join("scrap2bot_crash")
+        );
+    }
 }
@@ -0,0 +1,156 @@
+use serde::Serialize;
+use std::time::Duration;
+use std::time::Instant;
+
+// Статистика слияний за всю сессию, живёт между итерациями основного цикла
+#[derive(Debug, Clone)]
+pub struct MergeStats {
+    pub merges_performed: u64,
+    pub highest_level: u32,
+    // Высший уровень, замеченный на поле, включая бочки, которые слил не бот
+    pub highest_seen: u32,
+    // Перетаскивания, после которых проверка не нашла бочку нового уровня
+    pub failed_merges: u64,
+    // Бочки, унесённые на продажу
+    pub collected: u64,
+    pub started_at: Instant,
+}
+
+impl MergeStats {
+    pub fn new() -> Self {
+        Self {
+            merges_performed: 0,
+            highest_level: 0,
+            highest_seen: 0,
+            failed_merges: 0,
+            collected: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn record_merge(&mut self, level: u32) {
+        self.merges_performed += 1;
+        self.highest_level = self.highest_level.max(level);
+        self.highest_seen = self.highest_seen.max(level);
+    }
+
+    pub fn observe_level(&mut self, level: u32) {
+        self.highest_seen = self.highest_seen.max(level);
+    }
+
+    pub fn record_failed_merge(&mut self) {
+        self.failed_merges += 1;
+    }
+
+    pub fn record_collect(&mut self) {
+        self.collected += 1;
+    }
+
+    // Доля неудачных слияний среди проверенных попыток, в процентах
+    pub fn failure_rate(&self) -> f64 {
+        let attempts = self.merges_performed + self.failed_merges;
+        if attempts > 0 {
+            self.failed_merges as f64 * 100.0 / attempts as f64
+        } else {
+            0.0
+        }
+    }
+
+    pub fn runtime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn merges_per_minute(&self) -> f64 {
+        let minutes = self.runtime().as_secs_f64() / 60.0;
+        if minutes > 0.0 {
+            self.merges_performed as f64 / minutes
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Default for MergeStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Тайминги одного кадра, считаются один раз и идут в таблицу, json и /status
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub detection_ms: u128,
+    // Время с прошлого кадра того же окна
+    pub frame_ms: f64,
+    pub fps: f64,
+    pub active_range: (usize, usize),
+}
+
+impl FrameStats {
+    pub fn new(detection_ms: u128, frame_time: Duration, active_range: (usize, usize)) -> Self {
+        let frame_ms = frame_time.as_secs_f64() * 1000.0;
+        Self {
+            detection_ms,
+            frame_ms,
+            fps: if frame_ms > 0.0 {
+                1000.0 / frame_ms
+            } else {
+                0.0
+            },
+            active_range,
+        }
+    }
+}
+
+// Снимок состояния бота для внешнего мониторинга (--serve)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BotState {
+    pub window: String,
+    pub fps: f64,
+    pub frame_ms: f64,
+    pub detection_time_ms: u128,
+    pub active_range: (usize, usize),
+    pub paused: bool,
+    pub merges_performed: u64,
+    pub highest_level: u32,
+    pub failed_merges: u64,
+    pub collected: u64,
+    pub merges_per_minute: f64,
+    pub runtime_secs: u64,
+}
+
+impl BotState {
+    pub fn update_frame(&mut self, frame: &FrameStats) {
+        self.fps = frame.fps;
+        self.frame_ms = frame.frame_ms;
+        self.detection_time_ms = frame.detection_ms;
+        self.active_range = frame.active_range;
+    }
+
+    pub fn update_stats(&mut self, stats: &MergeStats) {
+        self.merges_performed = stats.merges_performed;
+        self.highest_level = stats.highest_level;
+        self.failed_merges = stats.failed_merges;
+        self.collected = stats.collected;
+        self.merges_per_minute = stats.merges_per_minute();
+        self.runtime_secs = stats.runtime().as_secs();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fps_is_the_inverse_of_the_frame_time() {
+        let frame = FrameStats::new(7, Duration::from_millis(40), (2, 9));
+        assert_eq!(frame.detection_ms, 7);
+        assert_eq!(frame.frame_ms, 40.0);
+        assert_eq!(frame.fps, 1000.0 / frame.frame_ms);
+        assert_eq!(frame.fps, 25.0);
+        assert_eq!(frame.active_range, (2, 9));
+
+        // Первый кадр без паузы не делит на ноль
+        assert_eq!(FrameStats::new(7, Duration::ZERO, (0, 0)).fps, 0.0);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::capture::AppResult;
+use crate::stats::BotState;
+use log::info;
+use log::warn;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use tiny_http::Header;
+use tiny_http::Response;
+use tiny_http::Server;
+
+// Отвечает на GET /status текущим BotState в JSON, всё остальное - 404.
+// Возвращает адрес, который реально слушается (порт 0 - любой свободный)
+pub fn spawn_status_server(
+    host: &str,
+    port: u16,
+    state: Arc<Mutex<BotState>>,
+) -> AppResult<SocketAddr> {
+    let server = Server::http((host, port)).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let address = server
+        .server_addr()
+        .to_ip()
+        .ok_or_else(|| std::io::Error::other("status server is not on a TCP socket"))?;
+    info!("Status endpoint: http://{}/status", address);
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/status" {
+                // Отравленный мьютекс не повод молчать: снимок всё равно отдаём
+                let snapshot = state.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                let body = serde_json::to_string(&snapshot).unwrap_or_default();
+                let content_type =
+                    Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                        .expect("static header is valid");
+                Response::from_string(body).with_header(content_type)
+            } else {
+                Response::from_string("Not found").with_status_code(404)
+            };
+
+            if let Err(e) = request.respond(response) {
+                warn!("Status response failed: {}", e);
+            }
+        }
+    });
+
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    fn get(address: SocketAddr, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    #[test]
+    fn status_endpoint_serves_the_bot_state_on_localhost() {
+        let state = Arc::new(Mutex::new(BotState::default()));
+        let address = spawn_status_server("127.0.0.1", 0, Arc::clone(&state)).unwrap();
+        assert!(address.ip().is_loopback());
+
+        {
+            let mut state = state.lock().unwrap();
+            state.window = "Scrap II".to_string();
+            state.fps = 4.0;
+            state.active_range = (2, 6);
+            state.paused = true;
+            state.merges_performed = 12;
+        }
+
+        let (head, body) = get(address, "/status");
+        assert!(head.starts_with("HTTP/1.0 200") || head.starts_with("HTTP/1.1 200"));
+        let status: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["window"], "Scrap II");
+        assert_eq!(status["fps"], 4.0);
+        assert_eq!(status["active_range"], serde_json::json!([2, 6]));
+        assert_eq!(status["paused"], true);
+        assert_eq!(status["merges_performed"], 12);
+
+        let (head, _) = get(address, "/metrics");
+        assert!(head.contains(" 404 "));
+    }
+}
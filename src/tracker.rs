@@ -0,0 +1,134 @@
+use crate::objectdetector::DetectionResult;
+
+struct Track {
+    detection: DetectionResult,
+    // Сколько кадров подряд объект не находился
+    missed: u32,
+}
+
+// Связывает детекции соседних кадров по ближайшему положению: объект, на кадр
+// просевший ниже порога, ещё ttl кадров остаётся в результатах
+pub struct DetectionTracker {
+    tracks: Vec<Track>,
+    // 0 - трекинг выключен, результат совпадает с сырыми детекциями
+    pub ttl: u32,
+}
+
+impl DetectionTracker {
+    pub fn new(ttl: u32) -> Self {
+        Self {
+            tracks: Vec::new(),
+            ttl,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+    }
+
+    // Тот же объект: одно имя и сдвиг меньше половины его размера
+    fn distance(track: &DetectionResult, detection: &DetectionResult) -> Option<i32> {
+        if track.object_name != detection.object_name {
+            return None;
+        }
+        let dx = (track.location.x - detection.location.x).abs();
+        let dy = (track.location.y - detection.location.y).abs();
+        if dx * 2 > track.width.max(1) || dy * 2 > track.height.max(1) {
+            return None;
+        }
+        Some(dx + dy)
+    }
+
+    // Сопоставляет свежие детекции с треками и возвращает все живые треки
+    pub fn update(&mut self, detections: &[DetectionResult]) -> Vec<DetectionResult> {
+        let mut matched = vec![false; detections.len()];
+
+        for track in &mut self.tracks {
+            let nearest = detections
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !matched[*i])
+                .filter_map(|(i, d)| Some((i, Self::distance(&track.detection, d)?)))
+                .min_by_key(|&(_, distance)| distance);
+
+            match nearest {
+                Some((i, _)) => {
+                    matched[i] = true;
+                    track.detection = detections[i].clone();
+                    track.missed = 0;
+                }
+                None => track.missed += 1,
+            }
+        }
+
+        let ttl = self.ttl;
+        self.tracks.retain(|track| track.missed <= ttl);
+        self.tracks.extend(
+            detections
+                .iter()
+                .zip(matched)
+                .filter(|(_, matched)| !matched)
+                .map(|(detection, _)| Track {
+                    detection: detection.clone(),
+                    missed: 0,
+                }),
+        );
+
+        self.tracks.iter().map(|t| t.detection.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::TemplateCategory;
+    use opencv::core::Point;
+
+    fn barrel(level: u32, x: i32, y: i32) -> DetectionResult {
+        DetectionResult {
+            object_name: format!("Barrel {}", level),
+            location: Point::new(x, y),
+            confidence: 0.95,
+            width: 40,
+            height: 40,
+            category: TemplateCategory::Barrel,
+        }
+    }
+
+    fn positions(detections: &[DetectionResult]) -> Vec<(i32, i32)> {
+        let mut positions: Vec<(i32, i32)> = detections
+            .iter()
+            .map(|d| (d.location.x, d.location.y))
+            .collect();
+        positions.sort();
+        positions
+    }
+
+    #[test]
+    fn a_dropped_detection_survives_for_ttl_frames() {
+        let mut tracker = DetectionTracker::new(2);
+        tracker.update(&[barrel(1, 10, 10), barrel(2, 60, 10)]);
+
+        // Вторая бочка на кадр просела ниже порога, первая чуть сдвинулась
+        let tracked = tracker.update(&[barrel(1, 12, 11)]);
+        assert_eq!(positions(&tracked), vec![(12, 11), (60, 10)]);
+        assert_eq!(positions(&tracker.update(&[barrel(1, 12, 11)])).len(), 2);
+
+        // ttl исчерпан
+        let tracked = tracker.update(&[barrel(1, 12, 11)]);
+        assert_eq!(positions(&tracked), vec![(12, 11)]);
+    }
+
+    #[test]
+    fn zero_ttl_returns_the_raw_detections() {
+        let mut tracker = DetectionTracker::new(0);
+        tracker.update(&[barrel(1, 10, 10), barrel(2, 60, 10)]);
+        let tracked = tracker.update(&[barrel(2, 60, 10)]);
+        assert_eq!(positions(&tracked), vec![(60, 10)]);
+
+        // Другое имя на том же месте - новый объект, а не тот же трек
+        let tracked = tracker.update(&[barrel(3, 60, 10)]);
+        assert_eq!(tracked.len(), 1);
+        assert_eq!(tracked[0].object_name, "Barrel 3");
+    }
+}
@@ -1,8 +1,158 @@
+use crate::capture::AppError;
 use crate::capture::AppResult;
 use crate::capture::get_window_size;
+use crate::capture::monitor_rects;
+use crate::settings::CaptureBackendKind;
+use crate::settings::InputBackendKind;
+use crate::settings::Settings;
+use log::debug;
+use log::info;
+use log::warn;
+use opencv::core::Rect;
+use rand::Rng;
+use rand::SeedableRng;
+use rand::distributions::uniform::SampleUniform;
+use rand::rngs::StdRng;
+use std::env;
+use std::ffi::OsStr;
 use std::io;
 use std::io::Write;
 use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+// Выставляется обработчиком Ctrl-C, проверяется в основном цикле и при перетаскивании
+pub static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Выставляется failsafe: в отличие от Ctrl-C курсор на место не возвращаем
+pub static FAILSAFE_TRIGGERED: AtomicBool = AtomicBool::new(false);
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst) || failsafe_triggered()
+}
+
+pub fn failsafe_triggered() -> bool {
+    FAILSAFE_TRIGGERED.load(Ordering::SeqCst)
+}
+
+// Курсор в квадрате margin x margin у одного из углов экрана
+pub fn in_screen_corner((x, y): (i32, i32), screen: Rect, margin: i32) -> bool {
+    let near = |value: i32, edge: i32| (value - edge).abs() <= margin;
+    let (right, bottom) = (screen.x + screen.width - 1, screen.y + screen.height - 1);
+    (near(x, screen.x) || near(x, right)) && (near(y, screen.y) || near(y, bottom))
+}
+
+// Опрашивает физический курсор в своём потоке, поэтому останавливает и перетаскивание,
+// которое основной цикл не успел бы прервать; PyAutoGUI называет это FAILSAFE
+pub fn spawn_failsafe_watcher(margin: i32, poll_ms: u64) -> AppResult<()> {
+    let monitors = monitor_rects()?;
+    thread::spawn(move || {
+        while !shutdown_requested() {
+            // Сбой xdotool (нет DISPLAY, занятый сервер) - пропускаем замер:
+            // позиция неизвестна, а не в углу
+            if let Ok(position) = get_current_mouse_position() {
+                if monitors
+                    .iter()
+                    .any(|&monitor| in_screen_corner(position, monitor, margin))
+                {
+                    warn!(
+                        "Failsafe: cursor at ({}, {}) is in a screen corner, stopping",
+                        position.0, position.1
+                    );
+                    FAILSAFE_TRIGGERED.store(true, Ordering::SeqCst);
+                }
+            }
+            thread::sleep(Duration::from_millis(poll_ms));
+        }
+    });
+    Ok(())
+}
+
+// Переключается горячей клавишей; на паузе бот смотрит на экран, но не трогает мышь
+pub static PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+// Общий источник случайности; None - энтропия, как с thread_rng
+static RNG: Mutex<Option<StdRng>> = Mutex::new(None);
+
+pub fn seed_rng(seed: Option<u64>) {
+    *RNG.lock().unwrap_or_else(|e| e.into_inner()) = seed.map(StdRng::seed_from_u64);
+}
+
+// Генератор для одного действия. С seed он выводится из общего, поэтому при том же
+// порядке вызовов прогон повторяется целиком, а замок не держится во время движения
+pub fn rng() -> StdRng {
+    match RNG.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        Some(seeded) => StdRng::seed_from_u64(seeded.r#gen()),
+        None => StdRng::from_entropy(),
+    }
+}
+
+// Повторяет action до attempts раз с паузой delay_ms; отдаёт последнюю ошибку
+pub fn retry<T>(
+    attempts: u32,
+    delay_ms: u64,
+    mut action: impl FnMut() -> AppResult<T>,
+) -> AppResult<T> {
+    let mut attempt = 1;
+    loop {
+        match action() {
+            Err(e) if attempt < attempts => {
+                debug!("Attempt {} of {} failed: {}", attempt, attempts, e);
+                attempt += 1;
+                thread::sleep(Duration::from_millis(delay_ms));
+            }
+            result => return result,
+        }
+    }
+}
+
+// Пауза между сканированиями: после слияний снова базовая, пока ходить нечем -
+// удваивается до max
+pub struct RescanBackoff {
+    base_ms: u64,
+    max_ms: u64,
+    current_ms: u64,
+}
+
+impl RescanBackoff {
+    pub fn new(base_ms: u64, max_ms: u64) -> Self {
+        Self {
+            base_ms,
+            max_ms: max_ms.max(base_ms),
+            current_ms: base_ms,
+        }
+    }
+
+    // actions - сколько слияний и продаж сделано за кадр
+    pub fn next(&mut self, actions: u64) -> Duration {
+        self.current_ms = if actions > 0 {
+            self.base_ms
+        } else {
+            self.current_ms.saturating_mul(2).min(self.max_ms)
+        };
+        Duration::from_millis(self.current_ms)
+    }
+}
+
+// Случайное значение из [min, max]; при min >= max возвращает min вместо паники gen_range
+pub fn jitter<T, R>(rng: &mut R, min: T, max: T) -> T
+where
+    T: SampleUniform + PartialOrd,
+    R: Rng + ?Sized,
+{
+    if min >= max {
+        min
+    } else {
+        rng.gen_range(min..=max)
+    }
+}
 
 pub fn check_and_suggest_window_size(
     window_title: &str,
@@ -17,30 +167,26 @@ pub fn check_and_suggest_window_size(
     let height_diff = (current_height - recommended_height).abs();
 
     if width_diff > TOLERANCE || height_diff > TOLERANCE {
-        print!(
-            "Current window size: {}x{}\n",
-            current_width, current_height
-        );
-        print!(
-            "Recommended window size: {}x{} (with ±{}px tolerance)\n",
-            recommended_width, recommended_height, TOLERANCE
+        warn!(
+            "Current window size: {}x{}, recommended: {}x{} (with ±{}px tolerance)",
+            current_width, current_height, recommended_width, recommended_height, TOLERANCE
         );
 
         // Show exact difference information
         if width_diff > TOLERANCE {
-            print!(
-                "Width difference: {}px (tolerance: {}px)\n",
+            warn!(
+                "Width difference: {}px (tolerance: {}px)",
                 width_diff, TOLERANCE
             );
         }
         if height_diff > TOLERANCE {
-            print!(
-                "Height difference: {}px (tolerance: {}px)\n",
+            warn!(
+                "Height difference: {}px (tolerance: {}px)",
                 height_diff, TOLERANCE
             );
         }
 
-        print!("Would you like to resize the window to the recommended size? (y/n)\n");
+        eprintln!("Would you like to resize the window to the recommended size? (y/n)");
 
         let mut input = String::new();
         std::io::stdin().read_line(&mut input)?;
@@ -54,22 +200,92 @@ pub fn check_and_suggest_window_size(
                     &format!("0,-1,-1,{},{}", recommended_width, recommended_height),
                 ])
                 .status()?;
-            print!(
-                "Window size changed to {}x{}. Please restart the program.\n",
+            info!(
+                "Window size changed to {}x{}. Please restart the program.",
                 recommended_width, recommended_height
             );
         } else {
-            print!(
-                "Continuing with current window size. Detection results may be less accurate.\n"
-            );
+            warn!("Continuing with current window size. Detection results may be less accurate.");
         }
     }
 
     Ok(())
 }
 
-pub fn clear_screen() -> Result<(), Box<dyn std::error::Error>> {
-    print!("\x1B[2J\x1B[3J\x1B[H");
+pub fn is_in_path(binary: &str) -> bool {
+    env::var_os("PATH").is_some_and(|paths| is_in_dirs(&paths, binary))
+}
+
+// Есть ли файл binary в одном из каталогов списка в формате PATH
+fn is_in_dirs(paths: &OsStr, binary: &str) -> bool {
+    env::split_paths(paths).any(|dir| dir.join(binary).is_file())
+}
+
+pub fn check_dependencies(settings: &Settings) -> AppResult<()> {
+    check_dependencies_in(settings, &env::var_os("PATH").unwrap_or_default())
+}
+
+// Проверка по явному списку каталогов вместо PATH процесса
+fn check_dependencies_in(settings: &Settings, paths: &OsStr) -> AppResult<()> {
+    let is_in_path = |binary: &str| is_in_dirs(paths, binary);
+    let mut missing = Vec::new();
+
+    let uses_x11_capture = settings.capture_backend == CaptureBackendKind::X11;
+    let uses_adb = settings.capture_backend == CaptureBackendKind::Adb
+        || (settings.input_backend == InputBackendKind::Adb && !settings.dry_run);
+
+    if uses_x11_capture && !is_in_path("xwininfo") {
+        missing.push("xwininfo");
+    }
+    // import используется только как запасной вариант для maim
+    if uses_x11_capture && !is_in_path("maim") && !is_in_path("import") {
+        missing.push("maim (or import)");
+    }
+    if settings.input_backend == InputBackendKind::Xdotool
+        && !settings.dry_run
+        && !is_in_path("xdotool")
+    {
+        missing.push("xdotool");
+    }
+    if uses_adb && !is_in_path("adb") {
+        missing.push("adb");
+    }
+    if settings.capture_backend == CaptureBackendKind::Wayland && !is_in_path("grim") {
+        missing.push("grim");
+    }
+    if settings.input_backend == InputBackendKind::Ydotool
+        && !settings.dry_run
+        && !is_in_path("ydotool")
+    {
+        missing.push("ydotool");
+    }
+
+    // wmctrl нужен только для изменения размера окна
+    if uses_x11_capture && !is_in_path("wmctrl") {
+        warn!("wmctrl not found, window resizing will be unavailable");
+    }
+
+    if !missing.is_empty() {
+        return Err(AppError::SettingsError(format!(
+            "Missing required tools: {}",
+            missing.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+// \x1B[3J стирает ещё и прокрутку терминала, а с ней логи, которые пользователь листал
+pub fn clear_screen_sequence(preserve_scrollback: bool) -> &'static str {
+    if preserve_scrollback {
+        "\x1B[2J\x1B[H"
+    } else {
+        "\x1B[2J\x1B[3J\x1B[H"
+    }
+}
+
+pub fn clear_screen(preserve_scrollback: bool) -> Result<(), Box<dyn std::error::Error>> {
+    print!("{}", clear_screen_sequence(preserve_scrollback));
     io::stdout().flush().map_err(|e| {
         opencv::Error::new(
             opencv::core::StsError,
@@ -79,25 +295,45 @@ pub fn clear_screen() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-pub fn get_currect_mouse_potision() -> Result<(i32, i32), Box<dyn std::error::Error>> {
-    let original_pos = Command::new("xdotool")
+// Позиция курсора через xdotool; единственное место, где разбирается его вывод
+pub fn get_current_mouse_position() -> AppResult<(i32, i32)> {
+    let output = Command::new("xdotool")
         .args(&["getmouselocation", "--shell"])
         .output()?;
+    if !output.status.success() {
+        return Err(AppError::Input(format!(
+            "xdotool getmouselocation exited with {}",
+            output.status
+        )));
+    }
+
+    let stdout = String::from_utf8(output.stdout)?;
+    parse_mouse_location(&stdout).ok_or_else(|| {
+        AppError::Input(format!(
+            "Unexpected xdotool getmouselocation output: {:?}",
+            stdout.trim()
+        ))
+    })
+}
 
-    let original_pos = String::from_utf8(original_pos.stdout)?;
-    let mut original_x = 0;
-    let mut original_y = 0;
+// Строки X=.. и Y=.. из `xdotool getmouselocation --shell`; без любой из них - None,
+// а не (0, 0), иначе сбой xdotool выглядел бы как курсор в углу экрана
+pub fn parse_mouse_location(output: &str) -> Option<(i32, i32)> {
+    let mut x = None;
+    let mut y = None;
 
-    for line in original_pos.lines() {
-        if line.starts_with("X=") {
-            original_x = line[2..].parse().unwrap_or(0);
-        } else if line.starts_with("Y=") {
-            original_y = line[2..].parse().unwrap_or(0);
+    for line in output.lines() {
+        if let Some(value) = line.strip_prefix("X=") {
+            x = value.trim().parse().ok();
+        } else if let Some(value) = line.strip_prefix("Y=") {
+            y = value.trim().parse().ok();
         }
     }
-    Ok((original_x, original_y))
+    Some((x?, y?))
 }
 
+// Уровень из имени шаблона или детекции: всё после первой точки - расширение,
+// берётся последняя группа цифр ("Level99v2" -> 2); без цифр или при переполнении - None
 pub fn extract_barrel_number(name: &str) -> Option<u32> {
     // Remove any file extensions first
     let clean_name = name.split('.').next().unwrap_or(name);
@@ -116,14 +352,226 @@ pub fn extract_barrel_number(name: &str) -> Option<u32> {
         }
     }
 
-    if !number_str.is_empty() {
-        return number_str.parse().ok();
+    // Без цифр разбирать нечего: прежний запасной разбор последнего слова
+    // всё равно никогда не давал числа
+    number_str.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn jitter_returns_min_for_equal_bounds() {
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(jitter(&mut rng, 7u64, 7u64), 7);
+    }
+
+    #[test]
+    fn jitter_returns_min_for_inverted_bounds() {
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(jitter(&mut rng, 9u64, 3u64), 9);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            assert!((2..=5).contains(&jitter(&mut rng, 2u64, 5u64)));
+        }
+    }
+
+    #[test]
+    fn barrel_number_examples() {
+        assert_eq!(extract_barrel_number("Barrel 12"), Some(12));
+        assert_eq!(extract_barrel_number("Barrel_7.png"), Some(7));
+        assert_eq!(extract_barrel_number("Cloud"), None);
+        assert_eq!(extract_barrel_number("Empty"), None);
+        // Из нескольких чисел берётся последнее
+        assert_eq!(extract_barrel_number("Level99v2"), Some(2));
     }
 
-    // Fallback: try splitting by spaces and take last token
-    if let Some(last_word) = clean_name.split_whitespace().last() {
-        return last_word.parse().ok();
+    #[test]
+    fn barrel_number_is_the_trailing_number() {
+        const PREFIX_CHARS: &[u8] = b"Barel xv_-0123456789";
+        const LETTERS: &[u8] = b"Barelxv";
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for _ in 0..1000 {
+            let length = rng.gen_range(0..12);
+            let mut prefix: String = (0..length)
+                .map(|_| PREFIX_CHARS[rng.gen_range(0..PREFIX_CHARS.len())] as char)
+                .collect();
+            // Цифры перед числом слились бы с ним в одну группу
+            prefix.push(LETTERS[rng.gen_range(0..LETTERS.len())] as char);
+            let number: u32 = rng.r#gen();
+
+            let name = format!("{}{}", prefix, number);
+            assert_eq!(extract_barrel_number(&name), Some(number), "{}", name);
+        }
+    }
+
+    #[test]
+    fn names_without_digits_have_no_number() {
+        const CHARS: &[u8] = b"Barel Cloud_Empty.png";
+        let mut rng = StdRng::seed_from_u64(42);
+
+        for _ in 0..1000 {
+            let length = rng.gen_range(0..16);
+            let name: String = (0..length)
+                .map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char)
+                .collect();
+            assert_eq!(extract_barrel_number(&name), None, "{}", name);
+        }
     }
 
-    None
+    #[test]
+    fn parses_getmouselocation_shell_output() {
+        assert_eq!(parse_mouse_location("X=100\nY=200\n"), Some((100, 200)));
+        assert_eq!(
+            parse_mouse_location("X=1234\nY=567\nSCREEN=0\nWINDOW=44040195\n"),
+            Some((1234, 567))
+        );
+        // Монитор левее основного
+        assert_eq!(
+            parse_mouse_location("X=-1280\nY=40\nSCREEN=0\nWINDOW=0\n"),
+            Some((-1280, 40))
+        );
+    }
+
+    #[test]
+    fn malformed_mouse_location_is_none() {
+        assert_eq!(parse_mouse_location(""), None);
+        assert_eq!(parse_mouse_location("X=100\n"), None);
+        assert_eq!(parse_mouse_location("X=abc\nY=200\n"), None);
+        assert_eq!(parse_mouse_location("SCREEN=0\nWINDOW=44040195\n"), None);
+    }
+
+    #[test]
+    fn corner_of_a_single_screen() {
+        let screen = Rect::new(0, 0, 1920, 1080);
+        assert!(in_screen_corner((0, 0), screen, 3));
+        assert!(in_screen_corner((3, 3), screen, 3));
+        assert!(in_screen_corner((1919, 1079), screen, 3));
+        assert!(in_screen_corner((1916, 0), screen, 3));
+        // На краю, но не в углу
+        assert!(!in_screen_corner((4, 0), screen, 3));
+        assert!(!in_screen_corner((0, 540), screen, 3));
+        assert!(!in_screen_corner((960, 540), screen, 3));
+        // Без запаса срабатывает только сам угловой пиксель
+        assert!(in_screen_corner((1919, 0), screen, 0));
+        assert!(!in_screen_corner((1918, 0), screen, 0));
+    }
+
+    #[test]
+    fn corners_of_every_monitor() {
+        let monitors = [
+            Rect::new(-1280, 0, 1280, 1024),
+            Rect::new(0, 0, 1920, 1080),
+            Rect::new(1920, 0, 1280, 1024),
+        ];
+        let in_any_corner = |position: (i32, i32)| {
+            monitors
+                .iter()
+                .any(|&monitor| in_screen_corner(position, monitor, 3))
+        };
+
+        assert!(in_any_corner((-1280, 0)));
+        assert!(in_any_corner((-1, 1023)));
+        assert!(in_any_corner((3199, 1023)));
+        assert!(in_any_corner((1920, 2)));
+        // Середина общей стороны двух мониторов - не угол
+        assert!(!in_any_corner((1920, 500)));
+        assert!(!in_any_corner((-1, 500)));
+        // Под более низким правым монитором основной ещё не кончился
+        assert!(!in_any_corner((2000, 1050)));
+    }
+
+    #[test]
+    fn retry_succeeds_after_two_failures() {
+        let mut calls = 0;
+        let result = retry(3, 0, || {
+            calls += 1;
+            if calls < 3 {
+                Err(AppError::Input(format!("attempt {} failed", calls)))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn retry_returns_the_last_error() {
+        let mut calls = 0;
+        let result: AppResult<()> = retry(2, 0, || {
+            calls += 1;
+            Err(AppError::Input(format!("attempt {} failed", calls)))
+        });
+        assert!(matches!(result, Err(AppError::Input(message)) if message == "attempt 2 failed"));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn rescan_backoff_follows_merge_counts() {
+        let mut backoff = RescanBackoff::new(250, 2000);
+        let delays: Vec<u64> = [0, 0, 0, 0, 0, 3, 0, 1]
+            .into_iter()
+            .map(|merges| backoff.next(merges).as_millis() as u64)
+            .collect();
+        // Пока сливать нечего, пауза удваивается до потолка; любое слияние её сбрасывает
+        assert_eq!(delays, vec![500, 1000, 2000, 2000, 2000, 250, 500, 250]);
+    }
+
+    #[test]
+    fn rescan_backoff_max_below_base_keeps_base() {
+        let mut backoff = RescanBackoff::new(250, 100);
+        assert_eq!(backoff.next(0), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn preserved_scrollback_is_never_cleared() {
+        let sequence = clear_screen_sequence(true);
+        assert!(!sequence.contains("3J"));
+        assert!(sequence.contains("\x1B[2J"));
+        assert!(clear_screen_sequence(false).contains("\x1B[3J"));
+    }
+
+    #[test]
+    fn missing_tools_are_listed_by_name() {
+        let dir = env::temp_dir().join(format!("scrap2_path_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Только xwininfo и import: maim заменяется import, xdotool нет
+        for tool in ["xwininfo", "import"] {
+            fs::write(dir.join(tool), "").unwrap();
+        }
+        let empty = env::temp_dir().join(format!("scrap2_empty_path_{}", std::process::id()));
+        fs::create_dir_all(&empty).unwrap();
+
+        let message =
+            |settings: &Settings, paths: &OsStr| match check_dependencies_in(settings, paths) {
+                Err(AppError::SettingsError(message)) => message,
+                other => panic!("expected missing tools, got {:?}", other),
+            };
+        let settings = Settings::default();
+        assert_eq!(
+            message(&settings, dir.as_os_str()),
+            "Missing required tools: xdotool"
+        );
+        assert_eq!(
+            message(&settings, empty.as_os_str()),
+            "Missing required tools: xwininfo, maim (or import), xdotool"
+        );
+        // В dry_run мышь не нужна
+        let dry_run = Settings {
+            dry_run: true,
+            ..Settings::default()
+        };
+        assert!(check_dependencies_in(&dry_run, dir.as_os_str()).is_ok());
+
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(&empty);
+    }
 }
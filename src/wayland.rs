@@ -0,0 +1,236 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::capture::CaptureBackend;
+use crate::input::InputBackend;
+use crate::settings::WaylandSettings;
+use opencv::core::Mat;
+use opencv::core::Vector;
+use opencv::imgcodecs::IMREAD_COLOR;
+use opencv::imgcodecs::imdecode;
+use opencv::prelude::MatTraitConst;
+use std::env;
+use std::process::Command;
+
+// Под Wayland xwininfo и maim не видят окон других клиентов
+pub fn is_wayland_session() -> bool {
+    session_is_wayland(
+        env::var("XDG_SESSION_TYPE").ok().as_deref(),
+        env::var("WAYLAND_DISPLAY").ok().as_deref(),
+    )
+}
+
+// XDG_SESSION_TYPE выставляет логин-менеджер; без него (sway, запущенный из tty)
+// о Wayland говорит только WAYLAND_DISPLAY
+pub fn session_is_wayland(session_type: Option<&str>, wayland_display: Option<&str>) -> bool {
+    match session_type.filter(|s| !s.is_empty()) {
+        Some(session_type) => session_type.eq_ignore_ascii_case("wayland"),
+        None => wayland_display.is_some_and(|display| !display.is_empty()),
+    }
+}
+
+// Что снимать через grim. Геометрию окна Wayland не отдаёт,
+// поэтому область или монитор указываются в настройках вручную
+#[derive(Debug, Clone)]
+enum GrimTarget {
+    Region {
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    },
+    Output(String),
+}
+
+// Снимки через grim (wlroots: Sway, Hyprland). GNOME и KDE пускают к экрану только
+// через xdg-desktop-portal, который на каждый снимок показывает запрос разрешения,
+// так что для непрерывной работы там этот бэкенд не годится
+pub struct WaylandCaptureBackend {
+    target: GrimTarget,
+    size: Option<(i32, i32)>,
+}
+
+impl WaylandCaptureBackend {
+    pub fn new(settings: &WaylandSettings) -> AppResult<Self> {
+        let target = match (settings.region, &settings.output) {
+            (Some((x, y, width, height)), _) => GrimTarget::Region {
+                x,
+                y,
+                width,
+                height,
+            },
+            (None, Some(output)) => GrimTarget::Output(output.clone()),
+            (None, None) => {
+                return Err(AppError::SettingsError(
+                    "Wayland capture needs wayland.region or wayland.output in settings"
+                        .to_string(),
+                ));
+            }
+        };
+        Ok(Self { target, size: None })
+    }
+}
+
+impl CaptureBackend for WaylandCaptureBackend {
+    fn capture(&mut self) -> AppResult<(Mat, i32, i32)> {
+        let mut command = Command::new("grim");
+        let origin = match &self.target {
+            GrimTarget::Region {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                command.args(["-g", &format!("{},{} {}x{}", x, y, width, height)]);
+                (*x, *y)
+            }
+            // Смещение монитора в общей раскладке неизвестно, считаем его первым
+            GrimTarget::Output(output) => {
+                command.args(["-o", output]);
+                (0, 0)
+            }
+        };
+
+        // "-" - PNG в stdout
+        let output = command.args(["-t", "png", "-"]).output()?;
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(AppError::ScrotFailed(format!(
+                "grim failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let image = imdecode(&Vector::<u8>::from_slice(&output.stdout), IMREAD_COLOR)?;
+        if image.empty() {
+            return Err(AppError::ImageProcessing(
+                "Captured image is empty".to_string(),
+            ));
+        }
+        self.size = Some((image.cols(), image.rows()));
+
+        Ok((image, origin.0, origin.1))
+    }
+
+    fn window_size(&mut self) -> AppResult<(i32, i32)> {
+        match (&self.target, self.size) {
+            (GrimTarget::Region { width, height, .. }, _) => Ok((*width, *height)),
+            (GrimTarget::Output(_), Some(size)) => Ok(size),
+            (GrimTarget::Output(_), None) => {
+                let (image, _, _) = self.capture()?;
+                Ok((image.cols(), image.rows()))
+            }
+        }
+    }
+}
+
+// ydotool пишет в /dev/uinput и работает в любом композиторе, но курсор прочитать
+// не может: позиция хранится в памяти, как у adb
+pub struct YdotoolBackend {
+    position: (i32, i32),
+    // Код кнопки ydotool: 0 - левая, 1 - правая, 2 - средняя
+    button: u8,
+}
+
+impl YdotoolBackend {
+    pub fn new(mouse_button: u8) -> Self {
+        let button = match mouse_button {
+            2 => 2,
+            3 => 1,
+            _ => 0,
+        };
+        Self {
+            position: (0, 0),
+            button,
+        }
+    }
+
+    fn run(&self, args: &[&str]) -> AppResult<()> {
+        let status = Command::new("ydotool").args(args).status()?;
+        if !status.success() {
+            return Err(AppError::Input(format!(
+                "ydotool {} failed",
+                args.join(" ")
+            )));
+        }
+        Ok(())
+    }
+
+    // Флаг 0x40 - нажать кнопку, 0x80 - отпустить
+    fn click_code(&self, flag: u8) -> String {
+        format!("0x{:02x}", flag | self.button)
+    }
+}
+
+impl InputBackend for YdotoolBackend {
+    fn move_to(&mut self, x: i32, y: i32) -> AppResult<()> {
+        self.run(&[
+            "mousemove",
+            "--absolute",
+            "-x",
+            &x.to_string(),
+            "-y",
+            &y.to_string(),
+        ])?;
+        self.position = (x, y);
+        Ok(())
+    }
+
+    fn move_relative(&mut self, dx: i32, dy: i32) -> AppResult<()> {
+        self.run(&["mousemove", "-x", &dx.to_string(), "-y", &dy.to_string()])?;
+        self.position = (self.position.0 + dx, self.position.1 + dy);
+        Ok(())
+    }
+
+    fn mouse_down(&mut self) -> AppResult<()> {
+        self.run(&["click", &self.click_code(0x40)])
+    }
+
+    fn mouse_up(&mut self) -> AppResult<()> {
+        self.run(&["click", &self.click_code(0x80)])
+    }
+
+    fn get_position(&mut self) -> AppResult<(i32, i32)> {
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_type_wins_over_wayland_display() {
+        let cases = [
+            (Some("wayland"), None, true),
+            (Some("Wayland"), Some("wayland-1"), true),
+            (Some("x11"), None, false),
+            // Вложенный Wayland-композитор в X11-сессии: окна игры всё равно в X11
+            (Some("x11"), Some("wayland-1"), false),
+            (Some("tty"), None, false),
+            (None, Some("wayland-0"), true),
+            (Some(""), Some("wayland-0"), true),
+            (None, Some(""), false),
+            (None, None, false),
+        ];
+        for (session_type, wayland_display, expected) in cases {
+            assert_eq!(
+                session_is_wayland(session_type, wayland_display),
+                expected,
+                "XDG_SESSION_TYPE={:?} WAYLAND_DISPLAY={:?}",
+                session_type,
+                wayland_display
+            );
+        }
+    }
+
+    #[test]
+    fn ydotool_clicks_carry_the_configured_button() {
+        // Нумерация X11 переводится в коды ydotool: правая 1, средняя 2
+        let codes = |mouse_button| {
+            let backend = YdotoolBackend::new(mouse_button);
+            (backend.click_code(0x40), backend.click_code(0x80))
+        };
+        assert_eq!(codes(1), ("0x40".to_string(), "0x80".to_string()));
+        assert_eq!(codes(2), ("0x42".to_string(), "0x82".to_string()));
+        assert_eq!(codes(3), ("0x41".to_string(), "0x81".to_string()));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::capture::CaptureBackend;
+use opencv::core::AlgorithmHint;
+use opencv::core::CV_8UC4;
+use opencv::core::Mat;
+use opencv::core::Scalar;
+use opencv::imgproc::COLOR_BGRA2BGR;
+use opencv::imgproc::cvt_color;
+use opencv::prelude::MatTrait;
+use std::ptr;
+use windows_sys::Win32::Foundation::HWND;
+use windows_sys::Win32::Foundation::RECT;
+use windows_sys::Win32::Graphics::Gdi::BI_RGB;
+use windows_sys::Win32::Graphics::Gdi::BITMAPINFO;
+use windows_sys::Win32::Graphics::Gdi::BITMAPINFOHEADER;
+use windows_sys::Win32::Graphics::Gdi::CreateCompatibleBitmap;
+use windows_sys::Win32::Graphics::Gdi::CreateCompatibleDC;
+use windows_sys::Win32::Graphics::Gdi::DIB_RGB_COLORS;
+use windows_sys::Win32::Graphics::Gdi::DeleteDC;
+use windows_sys::Win32::Graphics::Gdi::DeleteObject;
+use windows_sys::Win32::Graphics::Gdi::GetDIBits;
+use windows_sys::Win32::Graphics::Gdi::GetWindowDC;
+use windows_sys::Win32::Graphics::Gdi::ReleaseDC;
+use windows_sys::Win32::Graphics::Gdi::SelectObject;
+use windows_sys::Win32::Storage::Xps::PRINT_WINDOW_FLAGS;
+use windows_sys::Win32::Storage::Xps::PrintWindow;
+use windows_sys::Win32::UI::WindowsAndMessaging::FindWindowW;
+use windows_sys::Win32::UI::WindowsAndMessaging::GetWindowRect;
+use windows_sys::Win32::UI::WindowsAndMessaging::IsIconic;
+
+// PW_RENDERFULLCONTENT: без него окна с аппаратным ускорением (эмуляторы) снимаются чёрными
+const PW_RENDERFULLCONTENT: PRINT_WINDOW_FLAGS = 2;
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(Some(0)).collect()
+}
+
+// Окно эмулятора (BlueStacks и т.п.) по точному заголовку
+pub fn find_window(title: &str) -> AppResult<HWND> {
+    let title = wide(title);
+    let hwnd = unsafe { FindWindowW(ptr::null(), title.as_ptr()) };
+    if hwnd.is_null() {
+        return Err(AppError::WindowNotFound(String::from_utf16_lossy(
+            &title[..title.len() - 1],
+        )));
+    }
+    Ok(hwnd)
+}
+
+// Аналог xwininfo: положение и размер окна вместе с рамкой
+pub fn window_rect(hwnd: HWND) -> AppResult<(i32, i32, i32, i32)> {
+    let mut rect = RECT {
+        left: 0,
+        top: 0,
+        right: 0,
+        bottom: 0,
+    };
+    if unsafe { GetWindowRect(hwnd, &mut rect) } == 0 {
+        return Err(AppError::WindowNotFound(
+            "GetWindowRect failed, window is gone".to_string(),
+        ));
+    }
+    Ok((
+        rect.left,
+        rect.top,
+        rect.right - rect.left,
+        rect.bottom - rect.top,
+    ))
+}
+
+// Содержимое окна через PrintWindow в 32-битный DIB; работает и для перекрытого окна
+fn print_window(hwnd: HWND, width: i32, height: i32) -> AppResult<Mat> {
+    let mut bgra = Mat::new_rows_cols_with_default(height, width, CV_8UC4, Scalar::all(0.0))?;
+    let pixels = bgra.data_bytes_mut()?.as_mut_ptr();
+
+    let copied = unsafe {
+        let window_dc = GetWindowDC(hwnd);
+        let memory_dc = CreateCompatibleDC(window_dc);
+        let bitmap = CreateCompatibleBitmap(window_dc, width, height);
+        let previous = SelectObject(memory_dc, bitmap);
+
+        let printed = PrintWindow(hwnd, memory_dc, PW_RENDERFULLCONTENT) != 0;
+        let mut info: BITMAPINFO = std::mem::zeroed();
+        info.bmiHeader = BITMAPINFOHEADER {
+            biSize: size_of::<BITMAPINFOHEADER>() as u32,
+            biWidth: width,
+            // Отрицательная высота - строки сверху вниз, как в Mat
+            biHeight: -height,
+            biPlanes: 1,
+            biBitCount: 32,
+            biCompression: BI_RGB,
+            ..std::mem::zeroed()
+        };
+        let rows = if printed {
+            GetDIBits(
+                memory_dc,
+                bitmap,
+                0,
+                height as u32,
+                pixels.cast(),
+                &mut info,
+                DIB_RGB_COLORS,
+            )
+        } else {
+            0
+        };
+
+        SelectObject(memory_dc, previous);
+        DeleteObject(bitmap);
+        DeleteDC(memory_dc);
+        ReleaseDC(hwnd, window_dc);
+        rows == height
+    };
+    if !copied {
+        return Err(AppError::ScrotFailed("PrintWindow failed".to_string()));
+    }
+
+    let mut image = Mat::default();
+    cvt_color(
+        &bgra,
+        &mut image,
+        COLOR_BGRA2BGR,
+        0,
+        AlgorithmHint::ALGO_HINT_DEFAULT,
+    )?;
+    Ok(image)
+}
+
+// Захват окна под Windows; мышь при этом ведёт EnigoBackend (SendInput)
+pub struct WindowsCaptureBackend {
+    window_title: String,
+    size: Option<(i32, i32)>,
+}
+
+impl WindowsCaptureBackend {
+    pub fn new(window_title: &str) -> Self {
+        Self {
+            window_title: window_title.to_string(),
+            size: None,
+        }
+    }
+}
+
+impl CaptureBackend for WindowsCaptureBackend {
+    // Окно ищется каждый кадр: эмулятор мог перезапуститься с новым HWND
+    fn capture(&mut self) -> AppResult<(Mat, i32, i32)> {
+        let hwnd = find_window(&self.window_title)?;
+        let (x, y, width, height) = window_rect(hwnd)?;
+        // Свёрнутое окно висит за экраном в -32000 с размером заголовка
+        if unsafe { IsIconic(hwnd) } != 0 || width <= 0 || height <= 0 {
+            return Err(AppError::WindowNotFound(format!(
+                "{} is minimized",
+                self.window_title
+            )));
+        }
+        let image = print_window(hwnd, width, height)?;
+        self.size = Some((width, height));
+        Ok((image, x, y))
+    }
+
+    fn window_size(&mut self) -> AppResult<(i32, i32)> {
+        match self.size {
+            Some(size) => Ok(size),
+            None => {
+                let (_, _, width, height) = window_rect(find_window(&self.window_title)?)?;
+                Ok((width, height))
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::capture::AppError;
+use crate::capture::AppResult;
+use crate::capture::CaptureBackend;
+use crate::capture::get_dominant_colors;
+use crate::objectdetector::DEFAULT_MASK_TOLERANCE;
+use crate::settings::MatchMethod;
+use crate::settings::Settings;
+use crate::settings::TemplateCategory;
+use crate::settings::TemplateSettings;
+use log::info;
+use opencv::core::Mat;
+use opencv::core::Rect;
+use opencv::core::Vector;
+use opencv::imgcodecs;
+use opencv::prelude::MatTraitConst;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+// Вопрос с ответом по умолчанию в квадратных скобках
+fn prompt(question: &str, default: &str) -> AppResult<String> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.to_string()
+    } else {
+        answer.to_string()
+    })
+}
+
+fn parse_crop(answer: &str, frame: &Mat) -> AppResult<Rect> {
+    let values: Vec<i32> = answer
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| AppError::SettingsError(format!("Not a crop region: {}", answer)))?;
+
+    let [x, y, width, height] = values[..] else {
+        return Err(AppError::SettingsError(
+            "Expected four numbers: x y width height".to_string(),
+        ));
+    };
+    if x < 0 || y < 0 || width <= 0 || height <= 0 {
+        return Err(AppError::SettingsError(format!(
+            "Crop region {} is empty or negative",
+            answer
+        )));
+    }
+    if x + width > frame.cols() || y + height > frame.rows() {
+        return Err(AppError::SettingsError(format!(
+            "Crop region {} leaves the {}x{} frame",
+            answer,
+            frame.cols(),
+            frame.rows()
+        )));
+    }
+    Ok(Rect::new(x, y, width, height))
+}
+
+fn default_template_path(name: &str) -> String {
+    let file = name.trim().to_lowercase().replace(' ', "_");
+    format!("templates/{}.png", file)
+}
+
+// Дописывает шаблон в файл настроек, не трогая остальные; шаблон с тем же именем заменяется
+pub fn append_template(path: &str, template: TemplateSettings) -> AppResult<()> {
+    let mut settings = Settings::reload(path)?;
+    settings.templates.retain(|t| t.name != template.name);
+    settings.templates.push(template);
+    settings.validate()?;
+    settings.save(path)
+}
+
+// --add-template: снимок окна, вырезка спрайта по координатам, подсказка цветов
+// и запись нового шаблона в settings.json
+pub fn run_template_wizard(
+    capture: &mut dyn CaptureBackend,
+    settings: &Settings,
+    settings_path: &str,
+) -> AppResult<()> {
+    let (frame, _, _) = capture.capture()?;
+    let preview = settings.paths.screenshot_path();
+    imgcodecs::imwrite(&preview.to_string_lossy(), &frame, &Vector::new())?;
+    println!(
+        "Captured a {}x{} frame, saved to {} for picking coordinates",
+        frame.cols(),
+        frame.rows(),
+        preview.display()
+    );
+
+    let crop = parse_crop(&prompt("Crop region (x y width height)", "")?, &frame)?;
+    let name = prompt("Template name, e.g. \"Barrel 12\"", "")?;
+    if name.is_empty() {
+        return Err(AppError::SettingsError(
+            "Template name is required".to_string(),
+        ));
+    }
+    let path = prompt("Save sprite as", &default_template_path(&name))?;
+
+    if let Some(dir) = Path::new(&path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let sprite = Mat::roi(&frame, crop)?.try_clone()?;
+    imgcodecs::imwrite(&path, &sprite, &Vector::new())?;
+    info!("Sprite saved to {}", path);
+
+    let colors = get_dominant_colors(&path, 3)?;
+    println!("Dominant colors (B, G, R):");
+    for (i, (b, g, r)) in colors.iter().enumerate() {
+        println!("  {}: {}, {}, {}", i, b, g, r);
+    }
+
+    // Фон спрайта и сама бочка обычно попадают в разные кластеры
+    let pick = |question: &str, default: &str| -> AppResult<Option<(u8, u8, u8)>> {
+        let answer = prompt(question, default)?;
+        if answer.is_empty() || answer == "-" {
+            return Ok(None);
+        }
+        answer
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| colors.get(i).copied())
+            .map(Some)
+            .ok_or_else(|| AppError::SettingsError(format!("No color {}", answer)))
+    };
+    let display = pick("Table color index", "0")?.unwrap_or((128, 128, 128));
+    let mask_color = pick("Background color index to mask, - for none", "-")?;
+
+    let threshold = prompt("Threshold", "0.85")?
+        .parse()
+        .map_err(|_| AppError::SettingsError("Threshold must be a number".to_string()))?;
+
+    let (blue, green, red) = display;
+    let template = TemplateSettings {
+        name: name.clone(),
+        path,
+        threshold,
+        min_distance: 5.0,
+        red: red as f32,
+        green: green as f32,
+        blue: blue as f32,
+        resolution: None,
+        always_active: false,
+        match_method: MatchMethod::default(),
+        mask_color,
+        mask_tolerance: mask_color.map(|_| DEFAULT_MASK_TOLERANCE),
+        use_alpha: false,
+        category: Some(TemplateCategory::from_name(&name)),
+        rotation_tolerant: false,
+        frames: Vec::new(),
+    };
+
+    append_template(settings_path, template)?;
+    info!("Template {} added to {}", name, settings_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn template(name: &str, threshold: f64) -> TemplateSettings {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "path": default_template_path(name),
+            "threshold": threshold,
+            "min_distance": 5.0,
+            "red": 0.0,
+            "green": 0.0,
+            "blue": 0.0
+        }))
+        .unwrap()
+    }
+
+    fn names_and_thresholds(settings: &Settings) -> Vec<(String, f64)> {
+        settings
+            .templates
+            .iter()
+            .map(|t| (t.name.clone(), t.threshold))
+            .collect()
+    }
+
+    #[test]
+    fn appended_template_keeps_the_existing_ones() {
+        let path = env::temp_dir().join(format!("scrap2_wizard_{}.json", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let settings = Settings {
+            window_title: "Scrap II".to_string(),
+            templates: vec![template("Barrel 1", 0.9), template("Empty", 0.8)],
+            ..Settings::default()
+        };
+        settings.save(&path).unwrap();
+
+        append_template(&path, template("Barrel 2", 0.85)).unwrap();
+        let saved = Settings::reload(&path).unwrap();
+        assert_eq!(saved.window_title, "Scrap II");
+        assert_eq!(
+            names_and_thresholds(&saved),
+            vec![
+                ("Barrel 1".to_string(), 0.9),
+                ("Empty".to_string(), 0.8),
+                ("Barrel 2".to_string(), 0.85),
+            ]
+        );
+        assert_eq!(saved.templates[2].path, "templates/barrel_2.png");
+
+        // Повторный шаблон с тем же именем заменяет прежний, а не дублируется
+        append_template(&path, template("Barrel 1", 0.7)).unwrap();
+        let saved = Settings::reload(&path).unwrap();
+        assert_eq!(
+            names_and_thresholds(&saved),
+            vec![
+                ("Empty".to_string(), 0.8),
+                ("Barrel 2".to_string(), 0.85),
+                ("Barrel 1".to_string(), 0.7),
+            ]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
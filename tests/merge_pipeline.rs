@@ -0,0 +1,139 @@
+// Путь бочек целиком, без окна и мыши: детекция на снимке из tests/fixtures,
+// затем слияния process_barrels в режиме dry_run с фиксированным seed
+
+use opencv::core::Mat;
+use opencv::imgcodecs::IMREAD_COLOR;
+use opencv::imgcodecs::imread;
+use opencv::prelude::MatTraitConst;
+use scrap2_bot::capture::AppResult;
+use scrap2_bot::capture::CaptureBackend;
+use scrap2_bot::input::create_input_backend;
+use scrap2_bot::objectdetector::DetectionResult;
+use scrap2_bot::objectdetector::ObjectDetector;
+use scrap2_bot::processors::process_barrels;
+use scrap2_bot::settings::MatchColorSpace;
+use scrap2_bot::settings::Settings;
+use scrap2_bot::settings::TemplateCategory;
+use scrap2_bot::settings::TemplateSettings;
+use scrap2_bot::stats::MergeStats;
+use scrap2_bot::utils::seed_rng;
+
+// board.png - поле 4x2 клеток по 40 px, спрайт 24x24 со сдвигом 8 px в клетке:
+// бочки 1 в (0,0), (0,1), (0,3), (1,1) и бочки 2 в (0,2), (1,0)
+const GRID_COLS: usize = 4;
+const GRID_ROWS: usize = 2;
+
+fn fixture_path(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+// Захват, который всегда отдаёт снимок из фикстуры, как окно в (0, 0)
+struct FixtureCapture {
+    frame: Mat,
+}
+
+impl CaptureBackend for FixtureCapture {
+    fn capture(&mut self) -> AppResult<(Mat, i32, i32)> {
+        Ok((self.frame.clone(), 0, 0))
+    }
+
+    fn window_size(&mut self) -> AppResult<(i32, i32)> {
+        Ok((self.frame.cols(), self.frame.rows()))
+    }
+}
+
+fn fixture_settings() -> Settings {
+    let templates = (1..=4)
+        .map(|level| {
+            serde_json::from_value::<TemplateSettings>(serde_json::json!({
+                "name": format!("Barrel {}", level),
+                "path": fixture_path(&format!("templates/barrel_{}.png", level)),
+                "threshold": 0.9,
+                "min_distance": 5.0,
+                "red": 0.0,
+                "green": 0.0,
+                "blue": 0.0
+            }))
+            .unwrap()
+        })
+        .collect();
+
+    Settings {
+        resolution: 1.0,
+        dry_run: true,
+        seed: Some(1),
+        quiet: true,
+        templates,
+        ..Settings::default()
+    }
+}
+
+fn positions(barrels: &[DetectionResult]) -> Vec<(String, i32, i32)> {
+    barrels
+        .iter()
+        .map(|b| (b.object_name.clone(), b.location.x, b.location.y))
+        .collect()
+}
+
+fn barrel(level: u32, x: i32, y: i32) -> (String, i32, i32) {
+    (format!("Barrel {}", level), x, y)
+}
+
+#[test]
+fn fixture_board_merges_up_to_barrel_4() {
+    let settings = fixture_settings();
+    seed_rng(settings.seed);
+
+    let mut detector = ObjectDetector::new(settings.resolution);
+    // Результат не должен зависеть от того, есть ли на машине CUDA
+    detector.set_use_cuda(false);
+    detector.load_templates(&settings.templates).unwrap();
+
+    let frame = imread(&fixture_path("board.png"), IMREAD_COLOR).unwrap();
+    assert!(!frame.empty(), "board.png could not be read");
+    let (detections, _) = detector
+        .detect_objects_optimized(&frame, MatchColorSpace::Gray)
+        .unwrap();
+
+    // Как в основном цикле по уровню, а внутри уровня по положению,
+    // чтобы пары не зависели от порядка, в котором потоки вернули совпадения
+    let mut barrels: Vec<DetectionResult> = detections
+        .into_iter()
+        .filter(|d| d.category == TemplateCategory::Barrel)
+        .collect();
+    barrels.sort_by_key(|b| (b.barrel_level().unwrap_or(0), b.location.y, b.location.x));
+    assert_eq!(
+        positions(&barrels),
+        vec![
+            barrel(1, 8, 8),
+            barrel(1, 48, 8),
+            barrel(1, 128, 8),
+            barrel(1, 48, 48),
+            barrel(2, 88, 8),
+            barrel(2, 8, 48),
+        ]
+    );
+
+    let grid = ObjectDetector::build_occupancy_grid(&barrels, GRID_ROWS, GRID_COLS);
+    let mut input = create_input_backend(&settings).unwrap();
+    let mut capture = FixtureCapture { frame };
+    let mut stats = MergeStats::new();
+    let remaining = process_barrels(
+        input.as_mut(),
+        &mut capture,
+        0,
+        0,
+        barrels,
+        grid,
+        &mut detector,
+        &settings,
+        &mut stats,
+    )
+    .unwrap();
+
+    // Первый проход: 1+1 -> 2 в (48,8), 1+1 -> 2 в (48,48), 2+2 -> 3 в (8,48);
+    // второй: 2+2 -> 3 в (48,8); третий: 3+3 -> 4 на месте второй бочки
+    assert_eq!(stats.merges_performed, 5);
+    assert_eq!(stats.highest_level, 4);
+    assert_eq!(positions(&remaining), vec![barrel(4, 8, 48)]);
+}
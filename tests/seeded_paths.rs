@@ -0,0 +1,37 @@
+// Один --seed - один и тот же путь мыши. Отдельный тестовый бинарник: общий
+// генератор в utils не должны трогать параллельные тесты библиотеки
+
+use scrap2_bot::moving::generate_human_like_path;
+use scrap2_bot::settings::HumanLikeMovementSettings;
+use scrap2_bot::utils::seed_rng;
+
+fn paths(settings: &HumanLikeMovementSettings) -> Vec<Vec<(i32, i32)>> {
+    [
+        ((20, 300), (340, 300)),
+        ((340, 300), (60, 40)),
+        ((5, 5), (9, 7)),
+    ]
+    .into_iter()
+    .map(|(start, end)| generate_human_like_path(start, end, settings, None))
+    .collect()
+}
+
+#[test]
+fn the_same_seed_gives_the_same_paths() {
+    let settings = HumanLikeMovementSettings {
+        curve_smoothness: 12,
+        ..HumanLikeMovementSettings::default()
+    };
+
+    seed_rng(Some(7));
+    let first = paths(&settings);
+    seed_rng(Some(7));
+    let second = paths(&settings);
+    assert_eq!(first, second);
+
+    // Другой seed даёт другие кривые
+    seed_rng(Some(8));
+    assert_ne!(paths(&settings), first);
+
+    seed_rng(None);
+}